@@ -0,0 +1,146 @@
+// Criterion benchmarks for the hot paths of the core, run with `cargo bench`.
+//
+// The workloads run nestest.nes, embedded so the numbers don't depend on where
+// the benchmarks are started from:
+//  * dispatch - nestest's automated mode, which goes through every official
+//               opcode in a few thousand instructions
+//  * frame    - whole frames of the nestest menu, CPU and PPU together
+//  * snapshot - taking and restoring in-memory snapshots, as run-ahead and
+//               rollback do several times per frame
+//  * savestate - a full savestate to bytes and back
+//  * trace    - nestest's automated mode with a nestest.log line formatted
+//               for every instruction, as the trace logger does
+//  * palette  - converting a frame of palette indices to RGB, build with
+//               --features simd to measure the SIMD path
+//  * apu      - a frame's worth of CPU cycles of the APU with every channel
+//               playing, samples included
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use nes_book_emu::apu::Apu;
+use nes_book_emu::bus::Bus;
+use nes_book_emu::cartridge::Rom;
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::render::convert::PaletteConverter;
+use nes_book_emu::render::frame::{Frame, HEIGHT, WIDTH};
+use nes_book_emu::snapshot::SnapshotPool;
+use nes_book_emu::trace::{self, TraceFormat};
+use std::hint::black_box;
+
+const NESTEST: &[u8] = include_bytes!("../nestest.nes");
+
+fn console() -> CPU {
+    let rom = Rom::new(&NESTEST.to_vec()).unwrap();
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    cpu
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut cpu = console();
+    // automated mode, see nestest.log
+    cpu.program_counter = 0xC000;
+    c.bench_function("dispatch 5000 instructions", |b| {
+        b.iter_batched_ref(
+            || cpu.clone(),
+            |cpu| {
+                for _ in 0..5000 {
+                    cpu.step();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn frame(c: &mut Criterion) {
+    let mut cpu = console();
+    c.bench_function("frame", |b| b.iter(|| cpu.run_frame()));
+}
+
+fn snapshot(c: &mut Criterion) {
+    let mut cpu = console();
+    cpu.run_frame();
+    let mut pool = SnapshotPool::new();
+    c.bench_function("snapshot and restore", |b| {
+        b.iter(|| {
+            let snapshot = pool.take(&cpu);
+            cpu.restore_snapshot(black_box(&snapshot)).unwrap();
+            pool.release(snapshot);
+        })
+    });
+}
+
+fn savestate(c: &mut Criterion) {
+    let mut cpu = console();
+    cpu.run_frame();
+    c.bench_function("savestate save and load", |b| {
+        b.iter(|| {
+            let state = cpu.save_state();
+            cpu.load_state(black_box(&state)).unwrap();
+        })
+    });
+}
+
+fn trace(c: &mut Criterion) {
+    let mut cpu = console();
+    cpu.program_counter = 0xC000;
+    let mut line = String::new();
+    c.bench_function("trace 5000 instructions", |b| {
+        b.iter_batched_ref(
+            || cpu.clone(),
+            |cpu| {
+                for _ in 0..5000 {
+                    line.clear();
+                    trace::write_trace_as(&mut line, cpu, TraceFormat::Nestest, None).unwrap();
+                    black_box(&line);
+                    cpu.step();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn palette(c: &mut Criterion) {
+    let converter = PaletteConverter::default();
+    let indices: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| (i * 7 % 64) as u8).collect();
+    let mut frame = Frame::new();
+    c.bench_function("palette to rgb", |b| {
+        b.iter(|| converter.convert(black_box(&indices), frame.pixels_mut()))
+    });
+}
+
+/// CPU cycles in an NTSC frame, rounded up
+const FRAME_CYCLES: usize = 29781;
+
+fn apu(c: &mut Criterion) {
+    let mut apu = Apu::new();
+    apu.write_register(0x4015, 0b1111);
+    // pulses at 50% and 25% duty, a mid triangle and short noise, all at
+    // constant volume and with halted length counters so they keep playing
+    for (addr, value) in [
+        (0x4000, 0b1011_1111),
+        (0x4002, 0xFD),
+        (0x4003, 0x00),
+        (0x4004, 0b0111_1000),
+        (0x4006, 0x7F),
+        (0x4007, 0x01),
+        (0x4008, 0b1111_1111),
+        (0x400A, 0x40),
+        (0x400B, 0x00),
+        (0x400C, 0b0011_1010),
+        (0x400E, 0x84),
+        (0x400F, 0x00),
+    ] {
+        apu.write_register(addr, value);
+    }
+    c.bench_function("apu frame", |b| {
+        b.iter(|| {
+            apu.tick(black_box(FRAME_CYCLES));
+            black_box(apu.samples());
+            apu.clear_samples();
+        })
+    });
+}
+
+criterion_group!(benches, dispatch, frame, snapshot, savestate, trace, palette, apu);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+// ROM images from the fuzzer, run with cargo fuzz run rom.
+//
+// Covers the iNES and NES 2.0 header parsing of `Rom::new` and `RomInfo::new`,
+// and a frame of emulation for the images that load, so mappers and the PPU
+// see whatever sizes and banks the header claims.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nes_book_emu::cartridge::Rom;
+use nes_book_emu::nes::Nes;
+use nes_book_emu::rominfo::RomInfo;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(info) = RomInfo::new(data) {
+        let _ = info.to_string();
+        let _ = info.detect_region(Some("fuzz (E).nes"));
+    }
+    if let Ok(rom) = Rom::new(&data.to_vec()) {
+        let _ = Nes::new(rom).run_frame();
+    }
+});
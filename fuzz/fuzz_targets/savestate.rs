@@ -0,0 +1,20 @@
+// Savestates from the fuzzer, run with cargo fuzz run savestate.
+//
+// Covers the container format, decompression and the bincode sections, and
+// restoring whatever register and memory values decode into a console, which
+// then runs a frame. The state is forced on, the fuzzer can't guess the SHA1
+// of the ROM.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nes_book_emu::bus::Bus;
+use nes_book_emu::cartridge::RomBuilder;
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::savestate;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = savestate::read_thumbnail(data);
+    let mut cpu = CPU::new(Bus::new(RomBuilder::new().build()));
+    if cpu.force_load_state(data).is_ok() {
+        cpu.run_frame();
+    }
+});
@@ -0,0 +1,53 @@
+/*
+ * C API of the nes_book_emu library, built with the `ffi` feature.
+ * Kept in sync with src/ffi.rs by hand, see there for the details.
+ */
+#ifndef NES_BOOK_EMU_H
+#define NES_BOOK_EMU_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define NES_FRAME_WIDTH 256
+#define NES_FRAME_HEIGHT 240
+
+/* Controller buttons for nes_set_buttons */
+#define NES_BUTTON_A      0x01
+#define NES_BUTTON_B      0x02
+#define NES_BUTTON_SELECT 0x04
+#define NES_BUTTON_START  0x08
+#define NES_BUTTON_UP     0x10
+#define NES_BUTTON_DOWN   0x20
+#define NES_BUTTON_LEFT   0x40
+#define NES_BUTTON_RIGHT  0x80
+
+typedef struct NesConsole NesConsole;
+
+/* A powered on console with the iNES image inserted, NULL if it can't be loaded */
+NesConsole *nes_create(const uint8_t *rom, size_t len);
+void nes_destroy(NesConsole *console);
+
+/* 0 on success, -1 on failure, see nes_last_error */
+int nes_run_frame(NesConsole *console);
+void nes_reset(NesConsole *console);
+void nes_set_buttons(NesConsole *console, uint32_t port, uint8_t buttons);
+
+/* NES_FRAME_WIDTH * NES_FRAME_HEIGHT pixels, 0x00RRGGBB */
+const uint32_t *nes_frame_buffer(const NesConsole *console);
+
+/* Returns the size of the state, copied to buf only if it fits in len bytes */
+size_t nes_save_state(NesConsole *console, uint8_t *buf, size_t len);
+int nes_load_state(NesConsole *console, const uint8_t *buf, size_t len);
+
+/* Valid until the next call on the console */
+const char *nes_last_error(const NesConsole *console);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,307 @@
+// RetroAchievements: the emulator's side of the rcheevos runtime.
+//
+// rcheevos is a C library that checks each achievement's conditions against
+// the console's memory once a frame and talks to the RetroAchievements server
+// for logins, game data and unlocks. It isn't linked into this crate, a
+// frontend that has bindings to it implements `Runtime` over them. What the
+// emulator owes the runtime is here: side-effect free reads of memory in the
+// layout rcheevos expects, the ROM hash it identifies games by, a call every
+// frame and somewhere for the results to show up, `Notifier`, which the OSD
+// implements.
+//
+// The NES memory map of rcheevos is the CPU's address space, so RAM is at
+// $0000-$07FF and cartridge RAM at $6000-$7FFF. Reads go through `Bus::peek`
+// and never acknowledge interrupts or move the PPU's address.
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::render::osd::{MessageKind, Osd};
+
+/// Something the runtime reports back from a frame or a server call
+#[derive(Debug, Clone, PartialEq)]
+pub enum AchievementEvent {
+    Unlocked {
+        title: String,
+        description: String,
+        points: u32,
+    },
+    /// Every achievement of the game is unlocked
+    Mastered {
+        game: String,
+    },
+    LeaderboardSubmitted {
+        title: String,
+        score: String,
+    },
+    /// A server call failed, the runtime retries on its own
+    ServerError(String),
+}
+
+/// The rcheevos runtime as the emulator sees it, implemented by a frontend
+/// over its bindings
+pub trait Runtime {
+    /// Logs in with a password or a token saved from an earlier login,
+    /// returns the name to show for the user
+    fn login(&mut self, user: &str, secret: &str) -> Result<String, String>;
+
+    fn logout(&mut self);
+
+    /// Loads the achievements of the game with the hash `rom_hash` made,
+    /// returns the game's title
+    fn load_game(&mut self, hash: &str) -> Result<String, String>;
+
+    fn unload_game(&mut self);
+
+    /// Checks the achievements against `memory`, after every emulated frame
+    fn do_frame(&mut self, memory: &Memory, events: &mut Vec<AchievementEvent>);
+
+    /// The console was reset, conditions that were underway start over
+    fn reset(&mut self) {}
+}
+
+/// Where unlocks and the other runtime events are shown
+pub trait Notifier {
+    fn notify(&mut self, event: &AchievementEvent);
+}
+
+impl Notifier for Osd {
+    fn notify(&mut self, event: &AchievementEvent) {
+        let (kind, text) = match event {
+            AchievementEvent::Unlocked { title, points, .. } => (
+                MessageKind::Info,
+                format!("Achievement unlocked: {} ({})", title, points),
+            ),
+            AchievementEvent::Mastered { game } => {
+                (MessageKind::Info, format!("{} mastered", game))
+            }
+            AchievementEvent::LeaderboardSubmitted { title, score } => {
+                (MessageKind::Info, format!("{}: {}", title, score))
+            }
+            AchievementEvent::ServerError(e) => {
+                (MessageKind::Warning, format!("RetroAchievements: {}", e))
+            }
+        };
+        self.push(kind, &text);
+    }
+}
+
+/// The console's memory in the layout of rcheevos, handed to the runtime
+/// every frame
+pub struct Memory<'a> {
+    bus: &'a Bus,
+}
+
+impl<'a> Memory<'a> {
+    pub fn new(bus: &'a Bus) -> Self {
+        Memory { bus }
+    }
+
+    /// Addresses past $FFFF read as 0
+    pub fn peek(&self, address: u32) -> u8 {
+        if address > 0xFFFF {
+            return 0;
+        }
+        self.bus.peek(address as u16)
+    }
+
+    /// Fills `buffer` from `address` on, returns how many bytes there were,
+    /// what rcheevos' read callback returns
+    pub fn read(&self, address: u32, buffer: &mut [u8]) -> usize {
+        let available = (0x10000 - address.min(0x10000)) as usize;
+        let len = buffer.len().min(available);
+        for (offset, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = self.bus.peek((address as usize + offset) as u16);
+        }
+        len
+    }
+}
+
+/// The MD5 rcheevos identifies NES games by, of PRG and CHR ROM without the
+/// iNES header
+pub fn rom_hash(rom: &Rom) -> String {
+    let mut ctx = md5::Context::new();
+    ctx.consume(&rom.prg_rom);
+    ctx.consume(&rom.chr_rom);
+    format!("{:x}", ctx.compute())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Session {
+    LoggedOut,
+    LoggedIn { user: String },
+    Playing { user: String, game: String },
+}
+
+/// A runtime with the login and the loaded game, run once a frame
+pub struct Achievements<R: Runtime> {
+    runtime: R,
+    session: Session,
+    events: Vec<AchievementEvent>,
+}
+
+impl<R: Runtime> Achievements<R> {
+    pub fn new(runtime: R) -> Self {
+        Achievements {
+            runtime,
+            session: Session::LoggedOut,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn runtime(&self) -> &R {
+        &self.runtime
+    }
+
+    pub fn login(&mut self, user: &str, secret: &str) -> Result<(), String> {
+        self.logout();
+        let user = self.runtime.login(user, secret)?;
+        self.session = Session::LoggedIn { user };
+        Ok(())
+    }
+
+    pub fn logout(&mut self) {
+        self.unload_game();
+        if self.session != Session::LoggedOut {
+            self.runtime.logout();
+            self.session = Session::LoggedOut;
+        }
+    }
+
+    /// Loads the game's achievements, the game before is unloaded. Needs a
+    /// login
+    pub fn load_game(&mut self, rom: &Rom) -> Result<(), String> {
+        self.unload_game();
+        let user = match &self.session {
+            Session::LoggedIn { user } => user.clone(),
+            _ => return Err("not logged in to RetroAchievements".to_string()),
+        };
+        let game = self.runtime.load_game(&rom_hash(rom))?;
+        self.session = Session::Playing { user, game };
+        Ok(())
+    }
+
+    pub fn unload_game(&mut self) {
+        if let Session::Playing { user, .. } = &self.session {
+            self.session = Session::LoggedIn { user: user.clone() };
+            self.runtime.unload_game();
+        }
+    }
+
+    /// Call after every frame, with the bus the frame ran on. Does nothing
+    /// without a loaded game
+    pub fn do_frame(&mut self, bus: &Bus, notifier: &mut dyn Notifier) {
+        if let Session::Playing { .. } = self.session {
+            self.runtime.do_frame(&Memory::new(bus), &mut self.events);
+            for event in self.events.drain(..) {
+                notifier.notify(&event);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        if let Session::Playing { .. } = self.session {
+            self.runtime.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    /// Unlocks one achievement when $0010 becomes 1
+    #[derive(Default)]
+    struct FakeRuntime {
+        unlocked: bool,
+        resets: usize,
+    }
+
+    impl Runtime for FakeRuntime {
+        fn login(&mut self, user: &str, secret: &str) -> Result<String, String> {
+            match secret {
+                "secret" => Ok(user.to_uppercase()),
+                _ => Err("wrong password".to_string()),
+            }
+        }
+
+        fn logout(&mut self) {}
+
+        fn load_game(&mut self, hash: &str) -> Result<String, String> {
+            assert_eq!(hash.len(), 32);
+            Ok("Test Game".to_string())
+        }
+
+        fn unload_game(&mut self) {}
+
+        fn do_frame(&mut self, memory: &Memory, events: &mut Vec<AchievementEvent>) {
+            if !self.unlocked && memory.peek(0x0010) == 1 {
+                self.unlocked = true;
+                events.push(AchievementEvent::Unlocked {
+                    title: "First Step".to_string(),
+                    description: "Set the flag".to_string(),
+                    points: 5,
+                });
+            }
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    #[test]
+    fn test_memory_reads_the_cpu_address_space() {
+        let mut bus = Bus::new(test::test_rom_builder().fill_prg(0xEA).build());
+        bus.mem_write(0x0003, 0x42);
+        bus.mem_write(0x6001, 0x24);
+        let memory = Memory::new(&bus);
+        assert_eq!(memory.peek(0x0803), 0x42);
+        assert_eq!(memory.peek(0x8000), 0xEA);
+        assert_eq!(memory.peek(0x1_0000), 0);
+
+        let mut buffer = [0; 4];
+        assert_eq!(memory.read(0x6000, &mut buffer), 4);
+        assert_eq!(buffer, [0, 0x24, 0, 0]);
+        assert_eq!(memory.read(0xFFFE, &mut buffer), 2);
+        assert_eq!(memory.read(0x2_0000, &mut buffer), 0);
+    }
+
+    #[test]
+    fn test_unlocks_show_on_the_osd() {
+        let rom = test::test_rom();
+        let mut bus = Bus::new(rom.clone());
+        let mut osd = Osd::new();
+        let mut achievements = Achievements::new(FakeRuntime::default());
+
+        assert!(achievements.load_game(&rom).is_err());
+        assert!(achievements.login("player", "guess").is_err());
+        achievements.login("player", "secret").unwrap();
+        achievements.load_game(&rom).unwrap();
+        assert_eq!(
+            achievements.session(),
+            &Session::Playing {
+                user: "PLAYER".to_string(),
+                game: "Test Game".to_string()
+            }
+        );
+
+        achievements.do_frame(&bus, &mut osd);
+        assert_eq!(osd.messages().count(), 0);
+        bus.mem_write(0x0010, 1);
+        achievements.do_frame(&bus, &mut osd);
+        achievements.do_frame(&bus, &mut osd);
+        let texts: Vec<_> = osd.messages().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, ["Achievement unlocked: First Step (5)"]);
+
+        achievements.reset();
+        achievements.logout();
+        achievements.reset();
+        assert_eq!(achievements.runtime().resets, 1);
+        assert_eq!(achievements.session(), &Session::LoggedOut);
+    }
+}
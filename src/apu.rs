@@ -0,0 +1,877 @@
+// The APU: two pulse channels, the triangle, noise and the frame counter that
+// steps their envelopes, sweeps and length counters.
+//
+// The bus runs it the way it runs the PPU, lazily: it catches up when one of
+// its registers is accessed, at the end of every frame, and when the frame
+// counter is due to raise its IRQ, which the CPU polls between instructions.
+// Timers count CPU cycles; the pulse timers only every other one, as on
+// hardware.
+//
+// The channels are mixed with the nonlinear formulas of the real DACs and
+// resampled to the output rate by averaging every CPU cycle in a sample,
+// then the DC offset is taken out by the 90 Hz high-pass the console's output
+// has too. Samples are collected in a buffer sized for a few frames that the
+// frontend empties once a frame, see `samples`; what doesn't fit is dropped
+// so a frontend without audio never makes it grow. Timing is NTSC, and the
+// DMC channel isn't emulated: its registers are ignored and it stays silent.
+#[cfg(feature = "std")]
+use crate::audio::SampleProducer;
+use crate::prelude::*;
+use crate::savestate::{
+    ApuState, EnvelopeState, LengthState, NoiseState, PulseState, TriangleState,
+};
+use tracing::trace;
+
+/// NTSC CPU clock
+pub const CPU_CLOCK: u32 = 1_789_773;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [u8; 4] = [0b0100_0000, 0b0110_0000, 0b0111_1000, 0b1001_1111];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// frame counter steps, in CPU cycles since it was last reset
+const QUARTER_1: usize = 7457;
+const HALF_1: usize = 14913;
+const QUARTER_3: usize = 22371;
+const FOUR_STEP_END: usize = 29829;
+const FIVE_STEP_END: usize = 37281;
+
+/// $4015 bits
+const STATUS_FRAME_IRQ: u8 = 0b0100_0000;
+
+#[derive(Debug, Clone, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    /// The constant volume, or the period of the decay
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn save_state(&self) -> EnvelopeState {
+        EnvelopeState {
+            start: self.start,
+            looping: self.looping,
+            constant: self.constant,
+            volume: self.volume,
+            divider: self.divider,
+            decay: self.decay,
+        }
+    }
+
+    fn load_state(&mut self, state: &EnvelopeState) {
+        self.start = state.start;
+        self.looping = state.looping;
+        self.constant = state.constant;
+        self.volume = state.volume & 0x0F;
+        self.divider = state.divider;
+        self.decay = state.decay & 0x0F;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0b0010_0000 != 0;
+        self.constant = value & 0b0001_0000 != 0;
+        self.volume = value & 0b0000_1111;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    fn save_state(&self) -> LengthState {
+        LengthState {
+            enabled: self.enabled,
+            halted: self.halted,
+            count: self.count,
+        }
+    }
+
+    fn load_state(&mut self, state: &LengthState) {
+        self.enabled = state.enabled;
+        self.halted = state.halted;
+        self.count = state.count;
+    }
+
+    fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halted && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.count > 0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Pulse {
+    /// Pulse 1 negates its sweep in ones' complement, pulse 2 in two's
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn save_state(&self) -> PulseState {
+        PulseState {
+            duty: self.duty,
+            step: self.step,
+            period: self.period,
+            timer: self.timer,
+            envelope: self.envelope.save_state(),
+            length: self.length.save_state(),
+            sweep_enabled: self.sweep_enabled,
+            sweep_period: self.sweep_period,
+            sweep_negate: self.sweep_negate,
+            sweep_shift: self.sweep_shift,
+            sweep_reload: self.sweep_reload,
+            sweep_divider: self.sweep_divider,
+        }
+    }
+
+    fn load_state(&mut self, state: &PulseState) {
+        // out of range values from a broken state are masked, not trusted
+        self.duty = state.duty & 0b11;
+        self.step = state.step & 7;
+        self.period = state.period & 0x7FF;
+        self.timer = state.timer & 0x7FF;
+        self.envelope.load_state(&state.envelope);
+        self.length.load_state(&state.length);
+        self.sweep_enabled = state.sweep_enabled;
+        self.sweep_period = state.sweep_period & 0b111;
+        self.sweep_negate = state.sweep_negate;
+        self.sweep_shift = state.sweep_shift & 0b111;
+        self.sweep_reload = state.sweep_reload;
+        self.sweep_divider = state.sweep_divider;
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halted = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0b1000_0000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0b0000_1000 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    /// Too high or too low to be heard, the sweep silences the channel then
+    fn is_muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let high = DUTY_TABLE[self.duty as usize] & (0b1000_0000 >> self.step) != 0;
+        if !high || !self.length.is_active() || self.is_muted() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: LengthCounter,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn save_state(&self) -> TriangleState {
+        TriangleState {
+            step: self.step,
+            period: self.period,
+            timer: self.timer,
+            length: self.length.save_state(),
+            linear_reload_value: self.linear_reload_value,
+            linear_counter: self.linear_counter,
+            linear_reload: self.linear_reload,
+        }
+    }
+
+    fn load_state(&mut self, state: &TriangleState) {
+        self.step = state.step & 31;
+        self.period = state.period & 0x7FF;
+        self.timer = state.timer & 0x7FF;
+        self.length.load_state(&state.length);
+        self.linear_reload_value = state.linear_reload_value & 0x7F;
+        self.linear_counter = state.linear_counter;
+        self.linear_reload = state.linear_reload;
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                // the length counter halt doubles as the linear counter's control
+                self.length.halted = value & 0b1000_0000 != 0;
+                self.linear_reload_value = value & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.length.halted {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Noise {
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            short_mode: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    fn save_state(&self) -> NoiseState {
+        NoiseState {
+            short_mode: self.short_mode,
+            period: self.period,
+            timer: self.timer,
+            shift: self.shift,
+            envelope: self.envelope.save_state(),
+            length: self.length.save_state(),
+        }
+    }
+
+    fn load_state(&mut self, state: &NoiseState) {
+        self.short_mode = state.short_mode;
+        // a period of 0 would underflow the timer
+        self.period = state.period.max(1);
+        self.timer = state.timer;
+        self.shift = state.shift & 0x7FFF;
+        self.envelope.load_state(&state.envelope);
+        self.length.load_state(&state.length);
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length.halted = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = value & 0b1000_0000 != 0;
+                self.period = NOISE_PERIODS[(value & 0b1111) as usize];
+            }
+            _ => {
+                self.length.load(value);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Apu {
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    /// CPU cycles since the frame counter was reset
+    frame_cycle: usize,
+    /// Whether the next CPU cycle is the second half of an APU cycle
+    odd_cycle: bool,
+    sample_rate: u32,
+    /// Counts up by the sample rate every CPU cycle, a sample is due at
+    /// `CPU_CLOCK`
+    sample_clock: u32,
+    sample_sum: f32,
+    sample_cycles: u32,
+    high_pass: f32,
+    filter_in: f32,
+    filter_out: f32,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Apu {
+            pulse: [
+                Pulse {
+                    ones_complement: true,
+                    ..Pulse::default()
+                },
+                Pulse::default(),
+            ],
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sample_rate: 0,
+            sample_clock: 0,
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            high_pass: 0.0,
+            filter_in: 0.0,
+            filter_out: 0.0,
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        // the triangle rests at its top level, that isn't a pop at power on
+        apu.filter_in = apu.mix();
+        apu
+    }
+
+    /// Samples per second of the output, samples not taken yet are dropped
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        let rate = rate.clamp(1, CPU_CLOCK);
+        self.sample_rate = rate;
+        self.sample_clock = 0;
+        // room for 100ms between `clear_samples`
+        self.samples = Vec::with_capacity(rate as usize / 10 + 1);
+        let rc = 1.0 / (2.0 * core::f32::consts::PI * 90.0);
+        let dt = 1.0 / rate as f32;
+        self.high_pass = rc / (rc + dt);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// The samples made since the last `clear_samples`, mono, about -1 to 1
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Moves the samples into an audio queue, returns how many fit
+    #[cfg(feature = "std")]
+    pub fn push_samples(&mut self, queue: &mut SampleProducer) -> usize {
+        let pushed = queue.push(&self.samples);
+        self.samples.clear();
+        pushed
+    }
+
+    /// Everything but the samples and the resampler, see savestate.rs.
+    /// `pending_cycles` is left to the bus
+    pub fn save_state_into(&self, state: &mut ApuState) {
+        state.pulse = [self.pulse[0].save_state(), self.pulse[1].save_state()];
+        state.triangle = self.triangle.save_state();
+        state.noise = self.noise.save_state();
+        state.five_step = self.five_step;
+        state.irq_inhibit = self.irq_inhibit;
+        state.frame_irq = self.frame_irq;
+        state.frame_cycle = self.frame_cycle as u32;
+        state.odd_cycle = self.odd_cycle;
+    }
+
+    pub fn load_state(&mut self, state: &ApuState) {
+        for (pulse, saved) in self.pulse.iter_mut().zip(state.pulse.iter()) {
+            pulse.load_state(saved);
+        }
+        self.triangle.load_state(&state.triangle);
+        self.noise.load_state(&state.noise);
+        self.five_step = state.five_step;
+        self.irq_inhibit = state.irq_inhibit;
+        self.frame_irq = state.frame_irq;
+        // past the end of the sequence it would never step again
+        let end = if self.five_step {
+            FIVE_STEP_END
+        } else {
+            FOUR_STEP_END
+        };
+        self.frame_cycle = (state.frame_cycle as usize).min(end - 1);
+        self.odd_cycle = state.odd_cycle;
+    }
+
+    /// Whether the frame counter is asserting its IRQ
+    pub fn irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    /// CPU cycles until the frame counter raises its IRQ, `usize::MAX` if it
+    /// won't before the next register write
+    pub fn cycles_until_irq(&self) -> usize {
+        if self.five_step || self.irq_inhibit || self.frame_irq {
+            usize::MAX
+        } else {
+            FOUR_STEP_END.saturating_sub(self.frame_cycle).max(1)
+        }
+    }
+
+    /// $4015 without clearing the frame IRQ, for debuggers
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (bit, length) in self.lengths().iter().enumerate() {
+            if length.is_active() {
+                status |= 1 << bit;
+            }
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        status
+    }
+
+    /// $4015, which acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    /// $4000-$4013, $4015 and $4017
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse[0].write(addr & 3, value),
+            0x4004..=0x4007 => self.pulse[1].write(addr & 3, value),
+            0x4008..=0x400B => self.triangle.write(addr & 3, value),
+            0x400C..=0x400F => self.noise.write(addr & 3, value),
+            0x4015 => {
+                self.pulse[0].length.set_enabled(value & 0b0001 != 0);
+                self.pulse[1].length.set_enabled(value & 0b0010 != 0);
+                self.triangle.length.set_enabled(value & 0b0100 != 0);
+                self.noise.length.set_enabled(value & 0b1000 != 0);
+            }
+            0x4017 => {
+                self.five_step = value & 0b1000_0000 != 0;
+                self.irq_inhibit = value & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {
+                trace!(target: "apu", "write of {:02X} to ${:04X}, no DMC", value, addr);
+            }
+        }
+    }
+
+    /// Runs for `cycles` CPU cycles
+    pub fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            // the triangle and noise periods are in CPU cycles, the pulse
+            // periods in APU cycles, which are two CPU cycles
+            self.triangle.clock_timer();
+            self.noise.clock_timer();
+            if self.odd_cycle {
+                self.pulse[0].clock_timer();
+                self.pulse[1].clock_timer();
+            }
+            self.odd_cycle = !self.odd_cycle;
+            self.clock_frame_counter();
+            self.sample();
+        }
+    }
+
+    fn lengths(&self) -> [&LengthCounter; 4] {
+        [
+            &self.pulse[0].length,
+            &self.pulse[1].length,
+            &self.triangle.length,
+            &self.noise.length,
+        ]
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        match self.frame_cycle {
+            QUARTER_1 | QUARTER_3 => self.clock_quarter_frame(),
+            HALF_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FOUR_STEP_END if !self.five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+                self.frame_cycle = 0;
+            }
+            FIVE_STEP_END => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse[0].envelope.clock();
+        self.pulse[1].envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    /// Length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.length.clock();
+            pulse.clock_sweep();
+        }
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulse[0].output() + self.pulse[1].output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    fn sample(&mut self) {
+        self.sample_sum += self.mix();
+        self.sample_cycles += 1;
+        self.sample_clock += self.sample_rate;
+        if self.sample_clock < CPU_CLOCK {
+            return;
+        }
+        self.sample_clock -= CPU_CLOCK;
+        let input = self.sample_sum / self.sample_cycles as f32;
+        self.sample_sum = 0.0;
+        self.sample_cycles = 0;
+        self.filter_out = self.high_pass * (self.filter_out + input - self.filter_in);
+        self.filter_in = input;
+        if self.samples.len() < self.samples.capacity() {
+            self.samples.push(self.filter_out);
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A frame's worth of CPU cycles
+    const FRAME: usize = 29781;
+
+    #[test]
+    fn test_length_counters_and_status() {
+        let mut apu = Apu::new();
+        // nothing is loaded while a channel is disabled
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.peek_status(), 0);
+
+        apu.write_register(0x4015, 0b0000_1111);
+        // length index 1 is 254, index 3 is 2
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4007, 0b0001_1000);
+        apu.write_register(0x400B, 0b0001_1000);
+        apu.write_register(0x400F, 0b0001_1000);
+        assert_eq!(apu.peek_status(), 0b0000_1111);
+
+        // two half frames per 4-step sequence
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.tick(FOUR_STEP_END);
+        assert_eq!(apu.peek_status(), 0b0000_0001);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.peek_status(), 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.cycles_until_irq(), FOUR_STEP_END);
+        apu.tick(FOUR_STEP_END - 1);
+        assert!(!apu.irq());
+        apu.tick(1);
+        assert!(apu.irq());
+        assert_eq!(apu.cycles_until_irq(), usize::MAX);
+        assert_eq!(apu.peek_status(), STATUS_FRAME_IRQ);
+        assert_eq!(apu.read_status(), STATUS_FRAME_IRQ);
+        assert!(!apu.irq());
+
+        // inhibited, and never raised in 5-step mode
+        apu.tick(FOUR_STEP_END - 100);
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.tick(2 * FOUR_STEP_END);
+        assert!(!apu.irq());
+        apu.write_register(0x4017, 0b1000_0000);
+        assert_eq!(apu.cycles_until_irq(), usize::MAX);
+        apu.tick(2 * FIVE_STEP_END);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = Apu::new();
+        apu.tick(FRAME);
+        // 44100 / 60.1
+        assert_eq!(apu.samples().len(), 733);
+        assert!(apu.samples().iter().all(|&sample| sample.abs() < 1e-4));
+        apu.clear_samples();
+
+        // a square wave at 440 Hz, constant volume 15 and 50% duty
+        apu.write_register(0x4015, 0b0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0x00);
+        apu.tick(FRAME);
+        let samples = apu.samples();
+        let (min, max) = samples
+            .iter()
+            .fold((0.0f32, 0.0f32), |(min, max), &sample| {
+                (min.min(sample), max.max(sample))
+            });
+        assert!(min < -0.05 && max > 0.05, "{} {}", min, max);
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        // two a period, about 7 periods a frame
+        assert!((13..=16).contains(&crossings), "{}", crossings);
+
+        // the buffer doesn't grow when nobody takes the samples
+        let capacity = apu.samples.capacity();
+        apu.tick(FRAME * 20);
+        assert_eq!(apu.samples.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_sweep_mutes_and_envelope_decays() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0001);
+        // periods below 8 are muted
+        apu.write_register(0x4002, 0x07);
+        apu.write_register(0x4003, 0x00);
+        assert!(apu.pulse[0].is_muted());
+        apu.write_register(0x4002, 0xFF);
+        apu.write_register(0x4003, 0x07);
+        // an upward sweep that would go past $7FF mutes too
+        apu.write_register(0x4001, 0b1000_0001);
+        assert!(apu.pulse[0].is_muted());
+        apu.write_register(0x4001, 0b1000_1001);
+        assert!(!apu.pulse[0].is_muted());
+
+        // decays from 15, one step every quarter frame with period 0
+        apu.write_register(0x4000, 0b0000_0000);
+        apu.write_register(0x4003, 0x07);
+        apu.tick(QUARTER_1);
+        assert_eq!(apu.pulse[0].envelope.output(), 15);
+        apu.tick(HALF_1 - QUARTER_1);
+        assert_eq!(apu.pulse[0].envelope.output(), 14);
+    }
+
+    #[test]
+    fn test_noise_period_is_in_cpu_cycles() {
+        let mut apu = Apu::new();
+        // period index 0, 4 CPU cycles
+        apu.write_register(0x400E, 0);
+        let mut shifts = 0;
+        let mut last = apu.noise.shift;
+        for _ in 0..400 {
+            apu.tick(1);
+            if apu.noise.shift != last {
+                shifts += 1;
+                last = apu.noise.shift;
+            }
+        }
+        assert_eq!(shifts, 100);
+    }
+}
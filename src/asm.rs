@@ -0,0 +1,418 @@
+// 6502 assembler for writing tests as readable code and for patching code from
+// the debugger. The syntax is the one the disassembler prints:
+//
+//   PPUCTRL = $2000
+//   start:  LDX #$00        ; '$' hex, '%' binary, or decimal
+//   loop:   LDA table,X
+//           STA $0200,X
+//           INX
+//           BNE loop        ; labels work before they are defined
+//           JMP (vector)
+//           LDA #<table     ; low and high byte of an address
+//   table:  .db $01, 2, %11
+//   vector: .dw start + 1
+//
+// Zero page addressing is picked when the operand is known to be below $100 by
+// the time it's used, "$00FD" with four digits forces absolute addressing.
+// Unofficial opcodes are written with their disassembler name, e.g. *LAX $10.
+use crate::cpu::AddressingMode;
+use crate::opcodes::{self, OpCode, Operation};
+use std::collections::HashMap;
+
+const JMP_INDIRECT: Option<&OpCode> = opcodes::lookup(0x6c);
+
+/// Assembles `source` to run from `origin`
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+    let mut symbols = HashMap::new();
+    let mut statements = vec![];
+    let mut addr = origin;
+
+    // first pass: sizes, label addresses and instruction encodings
+    for (n, line) in source.lines().enumerate() {
+        let mut line = line.split(';').next().unwrap_or("").trim();
+        let at = |e: String| format!("line {}: {}", n + 1, e);
+
+        if let Some(colon) = line.find(':') {
+            let (label, rest) = (line[..colon].trim(), &line[colon + 1..]);
+            if is_identifier(label) {
+                define(&mut symbols, label, addr as i64).map_err(at)?;
+                line = rest.trim();
+            }
+        }
+        if let Some(equals) = line.find('=') {
+            let name = line[..equals].trim();
+            if !is_identifier(name) {
+                return Err(at(format!("Invalid constant name: {}", name)));
+            }
+            let value = value(&line[equals + 1..], &symbols)
+                .map_err(at)?
+                .ok_or_else(|| at(format!("{} has to be defined before it's used", name)))?;
+            define(&mut symbols, name, value).map_err(at)?;
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let statement = parse_statement(line, &symbols).map_err(at)?;
+        let size = statement.size();
+        statements.push((n, addr, statement));
+        addr = addr.wrapping_add(size);
+    }
+
+    // second pass: operands, now that all of the labels are known
+    let mut output = vec![];
+    for (n, addr, statement) in statements {
+        let at = |e: String| format!("line {}: {}", n + 1, e);
+        statement.encode(addr, &symbols, &mut output).map_err(at)?;
+    }
+    Ok(output)
+}
+
+enum Statement {
+    Instruction(&'static OpCode, Option<String>),
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+impl Statement {
+    fn size(&self) -> u16 {
+        match self {
+            Statement::Instruction(op, _) => op.len as u16,
+            Statement::Bytes(values) => values.len() as u16,
+            Statement::Words(values) => values.len() as u16 * 2,
+        }
+    }
+
+    fn encode(
+        &self,
+        addr: u16,
+        symbols: &HashMap<String, i64>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        let resolve = |text: &str| {
+            value(text, symbols)?.ok_or_else(|| format!("Unknown label in {}", text.trim()))
+        };
+        match self {
+            Statement::Instruction(op, operand) => {
+                output.push(op.code);
+                let operand = match operand {
+                    Some(operand) => resolve(operand)?,
+                    None => return Ok(()),
+                };
+                if is_branch(op) {
+                    let offset = operand - (addr as i64 + 2);
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!("Branch target ${:04X} is out of range", operand));
+                    }
+                    output.push(offset as u8);
+                } else if op.len == 2 {
+                    output.push(byte(operand)?);
+                } else {
+                    output.extend_from_slice(&word(operand)?.to_le_bytes());
+                }
+            }
+            Statement::Bytes(values) => {
+                for text in values {
+                    output.push(byte(resolve(text)?)?);
+                }
+            }
+            Statement::Words(values) => {
+                for text in values {
+                    output.extend_from_slice(&word(resolve(text)?)?.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_statement(line: &str, symbols: &HashMap<String, i64>) -> Result<Statement, String> {
+    let (mnemonic, operand) = match line.find(char::is_whitespace) {
+        Some(space) => (&line[..space], line[space..].trim()),
+        None => (line, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let list = || -> Vec<String> { operand.split(',').map(|v| v.trim().to_string()).collect() };
+    match mnemonic.as_str() {
+        ".DB" | ".BYTE" => return Ok(Statement::Bytes(list())),
+        ".DW" | ".WORD" => return Ok(Statement::Words(list())),
+        _ => {}
+    }
+    if !opcodes::CPU_OPS_CODES
+        .iter()
+        .any(|op| op.mnemonic == mnemonic)
+    {
+        return Err(format!("Unknown instruction: {}", mnemonic));
+    }
+    let find = |mode: AddressingMode, len: u8| {
+        opcodes::CPU_OPS_CODES
+            .iter()
+            .find(|op| op.mnemonic == mnemonic && op.mode == mode && op.len == len)
+    };
+    let no_mode = || format!("{} doesn't take {}", mnemonic, operand);
+    let upper = operand.to_ascii_uppercase();
+
+    // implied and accumulator
+    if operand.is_empty() || upper == "A" {
+        let op = find(AddressingMode::NoneAddressing, 1).ok_or_else(no_mode)?;
+        return Ok(Statement::Instruction(op, None));
+    }
+    if let Some(value) = operand.strip_prefix('#') {
+        let op = find(AddressingMode::Immediate, 2).ok_or_else(no_mode)?;
+        return Ok(Statement::Instruction(op, Some(value.to_string())));
+    }
+    if upper.starts_with('(') {
+        let (op, inner) = if let Some(inner) = upper.strip_suffix(",X)") {
+            (
+                find(AddressingMode::Indirect_X, 2),
+                &operand[1..inner.len()],
+            )
+        } else if let Some(inner) = upper.strip_suffix("),Y") {
+            (
+                find(AddressingMode::Indirect_Y, 2),
+                &operand[1..inner.len()],
+            )
+        } else if upper.ends_with(')') {
+            let op = if mnemonic == "JMP" {
+                JMP_INDIRECT
+            } else {
+                None
+            };
+            (op, &operand[1..operand.len() - 1])
+        } else {
+            return Err(format!("Invalid operand: {}", operand));
+        };
+        let op = op.ok_or_else(no_mode)?;
+        return Ok(Statement::Instruction(op, Some(inner.to_string())));
+    }
+
+    // branches, JMP and JSR take a plain address
+    if let Some(op) = find(AddressingMode::NoneAddressing, 2).or_else(|| {
+        find(AddressingMode::NoneAddressing, 3).filter(|op| op.operation != Operation::JmpIndirect)
+    }) {
+        return Ok(Statement::Instruction(op, Some(operand.to_string())));
+    }
+
+    let (text, zero_page, absolute) = if let Some(text) = strip_index(operand, 'X') {
+        (text, AddressingMode::ZeroPage_X, AddressingMode::Absolute_X)
+    } else if let Some(text) = strip_index(operand, 'Y') {
+        (text, AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y)
+    } else {
+        (operand, AddressingMode::ZeroPage, AddressingMode::Absolute)
+    };
+    let small = match value(text, symbols) {
+        Ok(Some(value)) => (0..0x100).contains(&value) && !is_wide(text),
+        _ => false,
+    };
+    let op = if small {
+        find(zero_page, 2).or_else(|| find(absolute, 3))
+    } else {
+        find(absolute, 3).or_else(|| find(zero_page, 2))
+    };
+    let op = op.ok_or_else(no_mode)?;
+    Ok(Statement::Instruction(op, Some(text.to_string())))
+}
+
+/// "$10,X" -> "$10"
+fn strip_index(operand: &str, register: char) -> Option<&str> {
+    let (text, index) = operand.rsplit_once(',')?;
+    if index.trim().eq_ignore_ascii_case(&register.to_string()) {
+        Some(text.trim())
+    } else {
+        None
+    }
+}
+
+fn is_branch(op: &OpCode) -> bool {
+    op.mode == AddressingMode::NoneAddressing && op.len == 2
+}
+
+/// Hex numbers with more than two digits ask for absolute addressing
+fn is_wide(text: &str) -> bool {
+    match text.trim().strip_prefix('$') {
+        Some(digits) => digits.len() > 2,
+        None => false,
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn define(symbols: &mut HashMap<String, i64>, name: &str, value: i64) -> Result<(), String> {
+    if symbols.insert(name.to_string(), value).is_some() {
+        return Err(format!("{} is defined twice", name));
+    }
+    Ok(())
+}
+
+/// Evaluates a sum of numbers and labels, `None` while a label is still undefined
+fn value(text: &str, symbols: &HashMap<String, i64>) -> Result<Option<i64>, String> {
+    let text = text.trim();
+    if let Some(rest) = text.strip_prefix('<') {
+        return Ok(value(rest, symbols)?.map(|v| v & 0xFF));
+    }
+    if let Some(rest) = text.strip_prefix('>') {
+        return Ok(value(rest, symbols)?.map(|v| (v >> 8) & 0xFF));
+    }
+    let (mut sign, mut rest) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text),
+    };
+    let mut total = Some(0);
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        if term.is_empty() {
+            return Err(format!("Invalid operand: {}", text));
+        }
+        let term = number(term)?.or_else(|| symbols.get(term).copied());
+        if term.is_none() && !is_identifier(rest[..end].trim()) {
+            return Err(format!("Invalid operand: {}", text));
+        }
+        total = match (total, term) {
+            (Some(total), Some(term)) => Some(total + sign * term),
+            _ => None,
+        };
+        if end == rest.len() {
+            return Ok(total);
+        }
+        sign = if rest[end..].starts_with('-') { -1 } else { 1 };
+        rest = &rest[end + 1..];
+    }
+}
+
+/// `None` if the text isn't a number at all
+fn number(text: &str) -> Result<Option<i64>, String> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix('%') {
+        (bin, 2)
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        (text, 10)
+    } else {
+        return Ok(None);
+    };
+    i64::from_str_radix(digits, radix)
+        .map(Some)
+        .map_err(|_| format!("Invalid number: {}", text))
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    if (-128..=0xFF).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} doesn't fit in a byte", value))
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    if (-0x8000..=0xFFFF).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("{} doesn't fit in a word", value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_addressing_modes() {
+        let code = assemble(
+            "LDA #$05\n\
+             STA $0200\n\
+             STA $10\n\
+             STA $00FD\n\
+             LDA $10,X\n\
+             LDX $10,Y\n\
+             LDA $1234,Y\n\
+             LDA ($20,X)\n\
+             LDA ($20),Y\n\
+             ASL A\n\
+             ROR\n\
+             JMP ($1234)\n\
+             JSR $C000\n\
+             *LAX $10\n\
+             brk",
+            0x0600,
+        )
+        .unwrap();
+        assert_eq!(
+            code,
+            vec![
+                0xa9, 0x05, 0x8d, 0x00, 0x02, 0x85, 0x10, 0x8d, 0xfd, 0x00, 0xb5, 0x10, 0xb6, 0x10,
+                0xb9, 0x34, 0x12, 0xa1, 0x20, 0xb1, 0x20, 0x0a, 0x6a, 0x6c, 0x34, 0x12, 0x20, 0x00,
+                0xc0, 0xa7, 0x10, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_data() {
+        let source = "
+            PTR = $10
+            start:  LDX #0      ; counter
+            loop:   INX
+                    BNE loop
+                    BEQ done
+                    LDA table,X
+                    STA PTR
+                    LDA #<table
+                    LDA #>table
+            done:   RTS
+            table:  .db $01, 2, %11, -1
+                    .dw start, table + 1
+        ";
+        let code = assemble(source, 0x8000).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0xf0, 0x09, 0xbd, 0x11, 0x80, 0x85, 0x10, 0xa9, 0x11,
+                0xa9, 0x80, 0x60, 0x01, 0x02, 0x03, 0xff, 0x00, 0x80, 0x12, 0x80,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(assemble("FOO #1", 0).unwrap_err().starts_with("line 1"));
+        assert!(assemble("NOP\nLDA #$100", 0)
+            .unwrap_err()
+            .starts_with("line 2"));
+        assert!(assemble("JSR #$10", 0).is_err());
+        assert!(assemble("BNE nowhere", 0).is_err());
+        assert!(assemble("a: NOP\na: NOP", 0).is_err());
+        assert!(assemble("LDA $12G", 0).is_err());
+        let far = format!("BNE far\n.db {}\nfar: NOP", vec!["0"; 200].join(","));
+        assert!(assemble(&far, 0).is_err());
+    }
+
+    #[test]
+    fn test_assembled_program_runs() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        let program = assemble(
+            "      LDX #$03
+                   LDA #$00
+             loop: CLC
+                   ADC #$05
+                   DEX
+                   BNE loop
+                   STA $0200
+                   BRK",
+            0x0600,
+        )
+        .unwrap();
+        cpu.load(program);
+        cpu.program_counter = 0x0600;
+        cpu.run();
+        assert_eq!(cpu.bus.peek(0x0200), 15);
+    }
+}
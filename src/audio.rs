@@ -0,0 +1,172 @@
+// Audio samples on their way from the emulation thread to the audio output.
+//
+// The APU pushes samples into a fixed-size ring buffer and the audio callback,
+// which SDL and cpal both run on a thread of their own, pulls them out. There
+// is exactly one producer and one consumer, so the two sides only share a pair
+// of counters: no locks, and nothing is allocated after the queue is created.
+// A callback that finds the queue short repeats the last sample for the rest
+// of its buffer instead of waiting, which turns a brief stall of the emulation
+// thread into a short flat stretch instead of a click or a stuck callback.
+// Samples pushed into a full queue are dropped.
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Ring {
+    // f32 bits, atomics so the slots can be shared without unsafe code
+    slots: Box<[AtomicU32]>,
+    // total samples read and written so far, wrapping
+    read: AtomicUsize,
+    written: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+/// The emulation thread's end of a sample queue
+pub struct SampleProducer {
+    ring: Arc<Ring>,
+}
+
+/// The audio thread's end of a sample queue
+pub struct SampleConsumer {
+    ring: Arc<Ring>,
+    last: f32,
+}
+
+/// A queue holding up to `capacity` samples
+pub fn sample_queue(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    assert!(capacity > 0, "sample queue needs room for samples");
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        read: AtomicUsize::new(0),
+        written: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+    });
+    (
+        SampleProducer { ring: ring.clone() },
+        SampleConsumer { ring, last: 0.0 },
+    )
+}
+
+impl Ring {
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        written.wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+impl SampleProducer {
+    /// Queues as many of `samples` as fit, returns how many did
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let ring = &*self.ring;
+        let capacity = ring.slots.len();
+        let written = ring.written.load(Ordering::Relaxed);
+        let free = capacity - written.wrapping_sub(ring.read.load(Ordering::Acquire));
+        let count = samples.len().min(free);
+        for (i, sample) in samples[..count].iter().enumerate() {
+            ring.slots[written.wrapping_add(i) % capacity]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        ring.written
+            .store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Samples waiting to be played, for adjusting the emulation speed to
+    /// keep the queue from running dry or overflowing
+    pub fn queued(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+}
+
+impl SampleConsumer {
+    /// Fills `out` with queued samples. Returns how many there were, the rest
+    /// of `out` repeats the last sample
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let ring = &*self.ring;
+        let capacity = ring.slots.len();
+        let read = ring.read.load(Ordering::Relaxed);
+        let available = ring.written.load(Ordering::Acquire).wrapping_sub(read);
+        let count = out.len().min(available);
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample =
+                f32::from_bits(ring.slots[read.wrapping_add(i) % capacity].load(Ordering::Relaxed));
+        }
+        ring.read.store(read.wrapping_add(count), Ordering::Release);
+
+        if count > 0 {
+            self.last = out[count - 1];
+        }
+        if count < out.len() {
+            out[count..]
+                .iter_mut()
+                .for_each(|sample| *sample = self.last);
+            ring.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Number of times `pop` found fewer samples than it was asked for
+    pub fn underruns(&self) -> usize {
+        self.ring.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_queue_fills_and_drains() {
+        let (mut producer, mut consumer) = sample_queue(4);
+        assert_eq!(producer.push(&[0.1, 0.2, 0.3]), 3);
+        assert_eq!(producer.push(&[0.4, 0.5]), 1);
+        assert_eq!(producer.queued(), 4);
+
+        let mut out = [0.0; 3];
+        assert_eq!(consumer.pop(&mut out), 3);
+        assert_eq!(out, [0.1, 0.2, 0.3]);
+        assert_eq!(consumer.underruns(), 0);
+
+        // wraps around the end of the ring
+        assert_eq!(producer.push(&[0.6, 0.7]), 2);
+        let mut out = [0.0; 5];
+        assert_eq!(consumer.pop(&mut out), 3);
+        assert_eq!(out, [0.4, 0.6, 0.7, 0.7, 0.7]);
+        assert_eq!(consumer.underruns(), 1);
+        assert_eq!(producer.queued(), 0);
+    }
+
+    #[test]
+    fn test_samples_arrive_in_order_across_threads() {
+        let (mut producer, mut consumer) = sample_queue(64);
+        let total = 20_000;
+        let emulation = thread::spawn(move || {
+            let mut next = 0;
+            while next < total {
+                let chunk: Vec<f32> = (next..(next + 10).min(total)).map(|i| i as f32).collect();
+                match producer.push(&chunk) {
+                    0 => thread::yield_now(),
+                    pushed => next += pushed,
+                }
+            }
+        });
+
+        let mut expected = 0;
+        let mut out = [0.0; 16];
+        while expected < total {
+            let count = consumer.pop(&mut out);
+            if count == 0 {
+                thread::yield_now();
+            }
+            for &sample in &out[..count] {
+                assert_eq!(sample, expected as f32);
+                expected += 1;
+            }
+        }
+        emulation.join().unwrap();
+    }
+}
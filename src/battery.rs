@@ -0,0 +1,302 @@
+// Battery backed PRG RAM, kept in a .sav file per game.
+//
+// The bus tracks whether PRG RAM changed since it was last written out. Once
+// a save file is attached it is rewritten every `interval` frames while dirty,
+// on an explicit flush and when the bus is dropped, so a crash loses at most a
+// few seconds of progress and games that never touch their save RAM never
+// touch the disk either.
+//
+// Saves move to and from other emulators and flash carts as raw PRG RAM
+// dumps. Those come in other sizes: 2 KiB for games that only use that much,
+// 32 KiB from tools that pad every save to the largest RAM a board can have,
+// sometimes with the data mirrored. `resize_save` fits them, refusing to cut
+// anything that isn't padding.
+use crate::error::RomError;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// About 5 seconds at 60 frames per second
+pub const DEFAULT_FLUSH_INTERVAL: usize = 300;
+
+/// PRG RAM, and so the saves this emulator writes, is 8 KiB
+pub const SAVE_SIZE: usize = 0x2000;
+
+/// How other emulators and flash carts name a game's save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaveNaming {
+    /// game.sav, as FCEUX, Mesen, Nestopia and the PowerPak name it
+    Sav,
+    /// game.srm, as RetroArch and the EverDrive N8 name it
+    Srm,
+}
+
+impl SaveNaming {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sav" => Some(SaveNaming::Sav),
+            "srm" => Some(SaveNaming::Srm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SaveNaming::Sav => "sav",
+            SaveNaming::Srm => "srm",
+        }
+    }
+
+    /// The save that goes next to the ROM at `rom_path`
+    pub fn path_for_rom<P: AsRef<Path>>(self, rom_path: P) -> PathBuf {
+        rom_path.as_ref().with_extension(self.extension())
+    }
+}
+
+/// Fits a raw save to `size` bytes. Short saves are padded with zeros, long
+/// ones are cut where the rest is padding: a single fill byte, or repeats of
+/// the part that is kept
+pub fn resize_save(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    if data.len() <= size {
+        let mut save = data.to_vec();
+        save.resize(size, 0);
+        return Ok(save);
+    }
+    let (kept, rest) = data.split_at(size);
+    let filled = rest.iter().all(|&b| b == rest[0]);
+    let mirrored = size > 0 && rest.chunks(size).all(|chunk| kept.starts_with(chunk));
+    if !filled && !mirrored {
+        return Err(format!(
+            "The save is {} bytes and doesn't fit in {}",
+            data.len(),
+            size
+        ));
+    }
+    Ok(kept.to_vec())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BatterySave {
+    path: PathBuf,
+    interval: usize,
+    last_flush: usize,
+}
+
+impl BatterySave {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        BatterySave {
+            path: path.as_ref().to_path_buf(),
+            interval: DEFAULT_FLUSH_INTERVAL,
+            last_flush: 0,
+        }
+    }
+
+    /// The .sav file that belongs to a ROM
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P) -> Self {
+        BatterySave::new(rom_path.as_ref().with_extension("sav"))
+    }
+
+    /// Frames between periodic flushes, 0 only flushes explicitly and on drop
+    pub fn set_interval(&mut self, frames: usize) {
+        self.interval = frames;
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies an existing save into `prg_ram`. Returns false when there is no
+    /// save yet, a shorter file only fills the start of the RAM
+    pub fn load(&self, prg_ram: &mut [u8]) -> Result<bool, RomError> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                let len = data.len().min(prg_ram.len());
+                prg_ram[..len].copy_from_slice(&data[..len]);
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(source) => Err(RomError::Read {
+                path: self.path.clone(),
+                source,
+            }),
+        }
+    }
+
+    pub fn write(&self, prg_ram: &[u8]) -> Result<(), RomError> {
+        fs::write(&self.path, prg_ram).map_err(|source| RomError::Write {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    /// Replaces the save with a raw one from another emulator or a flash cart,
+    /// keeping the old one as a .bak file next to it
+    pub fn import(&self, data: &[u8]) -> Result<(), String> {
+        let save = resize_save(data, SAVE_SIZE)?;
+        if self.path.exists() {
+            let mut backup = self.path.clone().into_os_string();
+            backup.push(".bak");
+            fs::copy(&self.path, &backup)
+                .map_err(|e| format!("Can't back up {}: {}", self.path.display(), e))?;
+        }
+        self.write(&save).map_err(|e| e.to_string())
+    }
+
+    /// The save as a raw one of `size` bytes, for another emulator or a flash
+    /// cart
+    pub fn export(&self, size: usize) -> Result<Vec<u8>, String> {
+        match fs::read(&self.path) {
+            Ok(data) => resize_save(&data, size),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(format!("There is no save at {}", self.path.display()))
+            }
+            Err(e) => Err(format!("Can't read {}: {}", self.path.display(), e)),
+        }
+    }
+
+    /// Whether a periodic flush is due at `frame`, restarting the interval
+    /// when it is
+    pub fn due(&mut self, frame: usize) -> bool {
+        if self.interval == 0 || frame < self.last_flush + self.interval {
+            return false;
+        }
+        self.last_flush = frame;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_battery_save_flush_policy() {
+        let dir = std::env::temp_dir().join(format!("battery_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.sav");
+        let _ = fs::remove_file(&path);
+
+        let mut bus = Bus::new(RomBuilder::new().battery(true).build());
+        let mut save = BatterySave::new(&path);
+        save.set_interval(2);
+        assert!(!bus.attach_battery_save(save).unwrap());
+
+        // nothing written while the RAM is unchanged
+        assert!(!bus.flush_battery_save().unwrap());
+        assert!(!path.exists());
+
+        bus.mem_write(0x6000, 0x42);
+        assert!(bus.prg_ram_dirty());
+        assert!(bus.flush_battery_save().unwrap());
+        assert!(!bus.prg_ram_dirty());
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+
+        // periodic flushes happen as frames finish
+        bus.mem_write(0x6001, 0x43);
+        while bus.frame_count() < 2 {
+            bus.tick(50);
+        }
+        assert_eq!(fs::read(&path).unwrap()[1], 0x43);
+
+        // and whatever is left when the console goes away
+        bus.mem_write(0x7FFF, 0x44);
+        let copy = bus.clone();
+        drop(copy);
+        assert_eq!(fs::read(&path).unwrap()[0x1FFF], 0);
+        drop(bus);
+        assert_eq!(fs::read(&path).unwrap()[0x1FFF], 0x44);
+
+        let mut bus = Bus::new(RomBuilder::new().battery(true).build());
+        assert!(bus.attach_battery_save(BatterySave::new(&path)).unwrap());
+        assert_eq!(bus.prg_ram()[..2], [0x42, 0x43]);
+        assert!(!bus.prg_ram_dirty());
+
+        // another game is inserted: this one's save is written, and the other
+        // game's RAM doesn't end up in it
+        bus.mem_write(0x6002, 0x45);
+        bus.swap_cartridge(RomBuilder::new().battery(true).build())
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap()[2], 0x45);
+        bus.mem_write(0x6002, 0x46);
+        drop(bus);
+        assert_eq!(fs::read(&path).unwrap()[2], 0x45);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resize_save() {
+        let save: Vec<u8> = (0..SAVE_SIZE).map(|i| i as u8).collect();
+        assert_eq!(resize_save(&save, SAVE_SIZE).unwrap(), save);
+
+        let small = resize_save(&save[..0x800], SAVE_SIZE).unwrap();
+        assert_eq!(
+            (small.len(), small[0x7FF], small[0x800]),
+            (SAVE_SIZE, 0xFF, 0)
+        );
+
+        let mut padded = save.clone();
+        padded.resize(4 * SAVE_SIZE, 0xFF);
+        assert_eq!(resize_save(&padded, SAVE_SIZE).unwrap(), save);
+        let mirrored = save.repeat(4);
+        assert_eq!(resize_save(&mirrored, SAVE_SIZE).unwrap(), save);
+        // a game using all 32 KiB loses nothing
+        padded[SAVE_SIZE + 5] = 1;
+        assert!(resize_save(&padded, SAVE_SIZE).is_err());
+
+        assert_eq!(resize_save(&save, 0x8000).unwrap().len(), 0x8000);
+        assert_eq!(
+            SaveNaming::Srm.path_for_rom("a/game.nes"),
+            Path::new("a/game.srm")
+        );
+        assert_eq!(SaveNaming::from_name("sav"), Some(SaveNaming::Sav));
+    }
+
+    #[test]
+    fn test_import_export() {
+        let dir = std::env::temp_dir().join(format!("battery_import_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let save = BatterySave::new(dir.join("game.sav"));
+        assert!(save.export(SAVE_SIZE).is_err());
+
+        save.import(&[0x42; 0x800]).unwrap();
+        let mut bus = Bus::new(RomBuilder::new().battery(true).build());
+        bus.attach_battery_save(BatterySave::new(save.path()))
+            .unwrap();
+        assert_eq!(bus.prg_ram()[0x7FF..0x801], [0x42, 0]);
+        drop(bus);
+
+        save.import(&[0x43; SAVE_SIZE]).unwrap();
+        assert_eq!(fs::read(dir.join("game.sav.bak")).unwrap()[0], 0x42);
+        let exported = save.export(0x8000).unwrap();
+        assert_eq!(
+            (exported.len(), exported[0], exported[SAVE_SIZE]),
+            (0x8000, 0x43, 0)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_battery_save_survives_reload() {
+        let dir = std::env::temp_dir().join(format!("battery_reload_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.sav");
+        let _ = fs::remove_file(&path);
+
+        let rom = RomBuilder::new().battery(true).build();
+        let mut bus = Bus::new(rom.clone());
+        bus.attach_battery_save(BatterySave::new(&path)).unwrap();
+        bus.mem_write(0x6000, 0x42);
+        // written before the old cartridge goes, loaded into the new one
+        bus.reload_rom(rom.clone(), false).unwrap();
+        assert_eq!(bus.prg_ram()[0], 0x42);
+        bus.mem_write(0x6000, 0x43);
+        bus.reload_rom(rom, true).unwrap();
+        assert_eq!(bus.prg_ram()[0], 0x43);
+        drop(bus);
+        assert_eq!(fs::read(&path).unwrap()[0], 0x43);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
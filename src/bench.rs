@@ -0,0 +1,92 @@
+use crate::cpu::CPU;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub struct BenchResult {
+    pub frames: usize,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub ppu_time: Duration,
+}
+
+impl BenchResult {
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn instructions_per_sec(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn percent_of_total(&self, time: Duration) -> f64 {
+        100.0 * time.as_secs_f64() / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpu_time = self.elapsed.checked_sub(self.ppu_time).unwrap_or_default();
+        writeln!(
+            f,
+            "{} frames in {:.3}s: {:.1} fps",
+            self.frames,
+            self.elapsed.as_secs_f64(),
+            self.fps()
+        )?;
+        writeln!(
+            f,
+            "{} instructions: {:.0} instructions/s",
+            self.instructions,
+            self.instructions_per_sec()
+        )?;
+        writeln!(f, "CPU: {:.1}%", self.percent_of_total(cpu_time))?;
+        writeln!(f, "PPU: {:.1}%", self.percent_of_total(self.ppu_time))?;
+        write!(f, "APU: not emulated")
+    }
+}
+
+/// Runs the machine headless as fast as possible. Stops early if the CPU hits BRK
+pub fn run(cpu: &mut CPU, frames: usize) -> BenchResult {
+    cpu.bus.set_ppu_profiling(true);
+    let target = cpu.bus.frame_count() + frames;
+    let mut instructions = 0;
+    let start = Instant::now();
+    while cpu.bus.frame_count() < target {
+        instructions += 1;
+        if !cpu.step() {
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+    let ppu_time = cpu.bus.ppu_time().unwrap_or_default();
+    cpu.bus.set_ppu_profiling(false);
+
+    BenchResult {
+        frames: frames - (target - cpu.bus.frame_count()),
+        instructions,
+        elapsed,
+        ppu_time,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_bench_runs_requested_frames() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: JMP loop
+        cpu.load(vec![0x4c, 0x00, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        let result = run(&mut cpu, 3);
+        assert_eq!(result.frames, 3);
+        assert_eq!(cpu.bus.frame_count(), 3);
+        assert!(result.instructions >= 3 * 262 * 341 / 9);
+        assert!(result.ppu_time <= result.elapsed);
+        assert_eq!(cpu.bus.ppu_time(), None);
+    }
+}
@@ -1,6 +1,24 @@
+use crate::apu::Apu;
+#[cfg(feature = "std")]
+use crate::battery::BatterySave;
 use crate::cartridge::Rom;
 use crate::cpu::Mem;
+use crate::error::{CpuError, EmuError, PpuError, RomError};
+use crate::events::{Event, EventKind, EventLog};
+use crate::heatmap::Heatmap;
+use crate::joypad::{Button, Joypad};
+use crate::mapper::{self, Mapper};
 use crate::nes_ppu::NesPPU;
+use crate::observer::TRACING;
+use crate::ppulog::{self, PpuWrite, PpuWriteLog};
+use crate::prelude::*;
+use crate::savestate::{self, ApuState, BusState, MapperState, PpuState, RomId};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+#[cfg(feature = "std")]
+use tracing::error;
+use tracing::{debug, warn};
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -33,71 +51,749 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 
+#[derive(Clone)]
 pub struct Bus {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: Box<dyn Mapper>,
+    prg_ram: [u8; 0x2000],
+    prg_ram_dirty: bool,
+    #[cfg(feature = "std")]
+    battery: BatteryLink,
     ppu: NesPPU,
     cycles: usize,
+    /// CPU cycles the PPU hasn't caught up with yet
+    ppu_pending: usize,
+    /// `ppu_pending` at which the PPU gets to its next event and has to run
+    ppu_deadline: usize,
+    apu: Apu,
+    /// CPU cycles the APU hasn't caught up with yet
+    apu_pending: usize,
+    /// `apu_pending` at which the APU raises its frame IRQ
+    apu_deadline: usize,
+    /// An OAM DMA copied its page and the CPU hasn't sat out the stall yet
+    oam_dma: bool,
+    frames: usize,
+    ppu_time: Option<Duration>,
+    joypads: [Joypad; 2],
+    events: Option<EventLog>,
+    ppu_writes: Option<PpuWriteLog>,
+    heatmap: Option<Box<Heatmap>>,
+    rom_id: RomId,
+    fault: Option<Fault>,
+    frame_callback: FrameHook,
+}
+
+/// Called when the PPU finishes a frame, with the PPU and cartridge to render
+/// it from, see render/renderer.rs
+pub type FrameCallback = Box<dyn FnMut(&NesPPU, &dyn Mapper) + Send + Sync>;
+
+/// The first access the core couldn't handle since the last `take_fault`
+#[derive(Debug, Clone)]
+enum Fault {
+    Cpu(CpuError),
+    Ppu(PpuError),
+}
+
+impl From<Fault> for EmuError {
+    fn from(fault: Fault) -> EmuError {
+        match fault {
+            Fault::Cpu(error) => EmuError::Cpu(error),
+            Fault::Ppu(error) => EmuError::Ppu(error),
+        }
+    }
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let rom_id = RomId {
+            sha1: rom.sha1(),
+            mapper: rom.mapper,
+        };
+        let mapper = mapper::for_rom(&rom);
+        let ppu = NesPPU::new(mapper.mirroring());
+        let ppu_deadline = ppu.cycles_until_event().div_ceil(3);
+        let apu = Apu::new();
+        let apu_deadline = apu.cycles_until_irq();
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            mapper,
+            prg_ram: [0; 0x2000],
+            prg_ram_dirty: false,
+            #[cfg(feature = "std")]
+            battery: BatteryLink(None),
             ppu,
             cycles: 0,
+            ppu_pending: 0,
+            ppu_deadline,
+            apu,
+            apu_pending: 0,
+            apu_deadline,
+            oam_dma: false,
+            frames: 0,
+            ppu_time: None,
+            joypads: [Joypad::new(); 2],
+            events: None,
+            ppu_writes: None,
+            heatmap: None,
+            rom_id,
+            fault: None,
+            frame_callback: FrameHook(None),
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+    /// Calls `callback` at the end of every frame
+    pub fn new_with_frame_callback<F>(rom: Rom, callback: F) -> Self
+    where
+        F: FnMut(&NesPPU, &dyn Mapper) + Send + Sync + 'static,
+    {
+        let mut bus = Bus::new(rom);
+        bus.set_frame_callback(Some(Box::new(callback)));
+        bus
+    }
+
+    /// Replaces the end of frame callback, None removes it
+    pub fn set_frame_callback(&mut self, callback: Option<FrameCallback>) {
+        self.frame_callback = FrameHook(callback);
+    }
+
+    /// Offset into PRG ROM that a CPU address maps to with the banks selected
+    /// right now, None outside $8000-$FFFF
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
         }
-        self.prg_rom[addr as usize]
+        self.mapper.prg_rom_offset(addr)
     }
 
+    pub fn prg_rom_len(&self) -> usize {
+        self.mapper.memory().prg_rom.len()
+    }
+
+    /// 0 for cartridges with CHR RAM
+    pub fn chr_rom_len(&self) -> usize {
+        self.mapper.memory().chr_rom_len()
+    }
+
+    /// The cartridge board, see mapper.rs
+    pub fn mapper(&self) -> &dyn Mapper {
+        &*self.mapper
+    }
+
+    /// Advances the clock. The PPU isn't run right away but catches up when its
+    /// registers or state are accessed and when it reaches vblank or the end of
+    /// the frame, nothing it does in between can be seen by the CPU. The APU
+    /// catches up the same way, at its registers, its frame IRQ and the end
+    /// of the frame
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
-        self.ppu.tick(cycles * 3);
+        self.ppu_pending += cycles as usize;
+        self.apu_pending += cycles as usize;
+        if self.ppu_pending >= self.ppu_deadline {
+            self.sync_ppu();
+        }
+        if self.apu_pending >= self.apu_deadline {
+            self.sync_apu();
+        }
+    }
+
+    /// Runs the PPU up to the current cycle
+    fn sync_ppu(&mut self) {
+        let cycles = self.ppu_pending * 3;
+        self.ppu_pending = 0;
+        let new_frame = self.run_ppu(cycles);
+        self.ppu_deadline = self.ppu.cycles_until_event().div_ceil(3);
+        if new_frame {
+            self.frames += 1;
+            // the frame's samples are complete when it ends
+            self.sync_apu();
+            if let (true, Some(events)) = (TRACING, self.events.as_mut()) {
+                events.end_frame();
+            }
+            if let Some(callback) = self.frame_callback.0.as_mut() {
+                callback(&self.ppu, &*self.mapper);
+            }
+            #[cfg(feature = "std")]
+            self.flush_battery_save_if_due();
+        }
+    }
+
+    /// Runs the APU up to the current cycle
+    fn sync_apu(&mut self) {
+        self.apu.tick(self.apu_pending);
+        self.apu_pending = 0;
+        self.apu_deadline = self.apu.cycles_until_irq();
+    }
+
+    #[cfg(feature = "std")]
+    fn run_ppu(&mut self, cycles: usize) -> bool {
+        match self.ppu_time.as_mut() {
+            Some(total) => {
+                let start = Instant::now();
+                let new_frame = self.ppu.tick(cycles);
+                *total += start.elapsed();
+                new_frame
+            }
+            None => self.ppu.tick(cycles),
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn run_ppu(&mut self, cycles: usize) -> bool {
+        self.ppu.tick(cycles)
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Halts the CPU for the OAM DMA the last instruction started, if it did:
+    /// 513 cycles, one more to line up with the APU when it starts on an odd
+    /// cycle. The copy itself happened at the write to $4014
+    pub fn finish_oam_dma(&mut self) {
+        if !self.oam_dma {
+            return;
+        }
+        self.oam_dma = false;
+        let mut stall = 513 + self.cycles % 2;
+        while stall > 0 {
+            let cycles = stall.min(u8::MAX as usize);
+            self.tick(cycles as u8);
+            stall -= cycles;
+        }
+    }
+
+    /// Scanline and dot of the PPU, including the cycles it hasn't caught up
+    /// with. `ppu()` only has the position of the last catch-up
+    pub fn ppu_position(&self) -> (u16, usize) {
+        let dot = self.ppu.dot() + self.ppu_pending * 3;
+        (self.ppu.scanline() + (dot / 341) as u16, dot % 341)
+    }
+
+    /// Number of frames the PPU has finished since power on
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Measures the time spent in the PPU, for benchmarking. Adds some overhead to every tick
+    #[cfg(feature = "std")]
+    pub fn set_ppu_profiling(&mut self, enabled: bool) {
+        self.ppu_time = if enabled {
+            Some(Duration::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn ppu_time(&self) -> Option<Duration> {
+        self.ppu_time
     }
 
     pub fn poll_nmi_status(&mut self) -> Option<u8>{
-        self.ppu.poll_nmi_interrupt()
+        let nmi = self.ppu.poll_nmi_interrupt();
+        if nmi.is_some() {
+            self.log_event(EventKind::Nmi);
+        }
+        nmi
+    }
+
+    /// Records register accesses and interrupts for the event viewer
+    pub fn set_event_logging(&mut self, enabled: bool) {
+        self.events = if enabled { Some(EventLog::new()) } else { None };
+    }
+
+    pub fn events(&self) -> Option<&EventLog> {
+        self.events.as_ref()
+    }
+
+    fn log_event(&mut self, kind: EventKind) {
+        if !TRACING {
+            return;
+        }
+        let (scanline, dot) = self.ppu_position();
+        if let Some(events) = self.events.as_mut() {
+            events.push(Event {
+                kind,
+                scanline,
+                dot,
+                cycle: self.cycles,
+            });
+        }
+    }
+
+    /// Keeps the most recent PPU register writes, see ppulog.rs
+    pub fn set_ppu_write_logging(&mut self, enabled: bool) {
+        self.ppu_writes = if enabled {
+            Some(PpuWriteLog::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn ppu_writes(&self) -> Option<&PpuWriteLog> {
+        self.ppu_writes.as_ref()
+    }
+
+    /// Starts counting reads and writes per address with an empty heatmap, or
+    /// stops and drops the counts
+    pub fn set_heatmap(&mut self, enabled: bool) {
+        self.heatmap = if enabled {
+            Some(Box::new(Heatmap::new()))
+        } else {
+            None
+        };
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_deref()
+    }
+
+    /// Ends the capture window and hands over the counts
+    pub fn take_heatmap(&mut self) -> Option<Heatmap> {
+        self.heatmap.take().map(|heatmap| *heatmap)
+    }
+
+    /// Reads memory without side effects, for debuggers and other tooling.
+    /// I/O registers read as 0, except PPUSTATUS which reads without clearing vblank
+    /// and the APU status, which reads without acknowledging the frame IRQ
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END if addr & 0x0007 == 0x0002 => {
+                self.ppu.status.bits()
+            }
+            0x4015 => self.apu.peek_status(),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[prg_ram_index(addr)],
+            0x8000..=0xFFFF => self.mapper.cpu_read(addr),
+            _ => 0,
+        }
+    }
+
+    /// Writes for debuggers. Unlike mem_write this reaches PRG ROM so code can
+    /// be patched, I/O registers are left alone
+    pub fn patch(&mut self, addr: u16, value: u8) -> Result<(), CpuError> {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize] = value,
+            PRG_RAM..=PRG_RAM_END => {
+                self.prg_ram[prg_ram_index(addr)] = value;
+                self.prg_ram_dirty = true;
+            }
+            _ => match self.prg_rom_offset(addr) {
+                Some(offset) => self.mapper.memory_mut().prg_rom_mut()[offset] = value,
+                None => return Err(CpuError::NotPatchable(addr)),
+            },
+        }
+        Ok(())
+    }
+
+    /// The APU as of the last catch-up, which is at least the end of the
+    /// last frame
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    /// For taking the samples and setting the sample rate
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Whether an IRQ is asserted, the CPU takes it unless interrupts are
+    /// disabled
+    pub fn irq_pending(&self) -> bool {
+        self.apu.irq()
+    }
+
+    /// Called by the CPU when it takes an IRQ, for the event viewer
+    pub fn irq_taken(&mut self) {
+        self.log_event(EventKind::Irq);
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut NesPPU {
+        self.sync_ppu();
+        // the caller may move the PPU to another scanline
+        self.ppu_deadline = 0;
+        &mut self.ppu
+    }
+
+    /// The PPU's savestate, caught up with the current cycle
+    pub fn save_ppu_state(&self) -> PpuState {
+        let mut state = PpuState::default();
+        self.save_ppu_state_into(&mut state);
+        state
+    }
+
+    pub fn save_ppu_state_into(&self, state: &mut PpuState) {
+        self.ppu.save_state_into(state);
+        let (scanline, dot) = self.ppu_position();
+        state.scanline = scanline;
+        state.dot = dot as u64;
+    }
+
+    /// Sets the buttons held on controller `port` (0 or 1), one bit per button
+    /// in RLDUTSBA order, Right being bit 7 and A bit 0
+    pub fn set_controller(&mut self, port: usize, buttons: u8) {
+        self.joypads[port].set_buttons(Button::from_bits_truncate(buttons));
+    }
+
+    pub fn controller(&self, port: usize) -> u8 {
+        self.joypads[port].buttons().bits()
+    }
+
+    /// The controller in `port` 0 ($4016) or 1 ($4017), e.g.
+    /// `bus.joypad_mut(0).set_button_pressed_status(Button::START, true)`
+    pub fn joypad(&self, port: usize) -> &Joypad {
+        &self.joypads[port]
+    }
+
+    pub fn joypad_mut(&mut self, port: usize) -> &mut Joypad {
+        &mut self.joypads[port]
+    }
+
+    /// The mapper's registers and CHR RAM
+    pub fn save_mapper_state(&self) -> MapperState {
+        let mut state = MapperState::default();
+        self.save_mapper_state_into(&mut state);
+        state
+    }
+
+    pub fn save_mapper_state_into(&self, state: &mut MapperState) {
+        state.registers.clear();
+        self.mapper.save_registers(&mut state.registers);
+        let memory = self.mapper.memory();
+        if memory.chr_ram {
+            savestate::copy_into(&mut state.chr_ram, &memory.chr);
+        } else {
+            state.chr_ram.clear();
+        }
+    }
+
+    /// Errors if `load_mapper_state` would fail, without changing anything
+    pub fn check_mapper_state(&self, state: &MapperState) -> Result<(), CpuError> {
+        let mut registers = Vec::new();
+        self.mapper.save_registers(&mut registers);
+        savestate::check_len("mapper registers", &state.registers, registers.len())?;
+        let memory = self.mapper.memory();
+        let chr_ram = if memory.chr_ram { memory.chr.len() } else { 0 };
+        savestate::check_len("CHR RAM", &state.chr_ram, chr_ram)?;
+        Ok(())
+    }
+
+    /// Restores the mapper after the PPU, whose mirroring it then sets
+    pub fn load_mapper_state(&mut self, state: &MapperState) -> Result<(), CpuError> {
+        self.check_mapper_state(state)?;
+        self.mapper.load_registers(&state.registers);
+        if let Some(chr_ram) = self.mapper.memory_mut().chr_ram_mut() {
+            chr_ram.copy_from_slice(&state.chr_ram);
+        }
+        self.ppu.mirroring = self.mapper.mirroring();
+        Ok(())
+    }
+
+    /// The APU's savestate, including the cycles it hasn't caught up with
+    pub fn save_apu_state(&self) -> ApuState {
+        let mut state = ApuState::default();
+        self.save_apu_state_into(&mut state);
+        state
+    }
+
+    pub fn save_apu_state_into(&self, state: &mut ApuState) {
+        self.apu.save_state_into(state);
+        state.pending_cycles = self.apu_pending as u32;
+    }
+
+    /// The samples not taken yet are kept
+    pub fn load_apu_state(&mut self, state: &ApuState) {
+        self.apu.load_state(state);
+        self.apu_pending = state.pending_cycles as usize;
+        self.apu_deadline = self.apu.cycles_until_irq();
+    }
+
+    /// Cartridge RAM at $6000-$7FFF
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn save_state(&self) -> BusState {
+        let mut state = BusState::default();
+        self.save_state_into(&mut state);
+        state
+    }
+
+    /// Like `save_state` but reuses the buffers of an earlier state
+    pub fn save_state_into(&self, state: &mut BusState) {
+        savestate::copy_into(&mut state.ram, &self.cpu_vram);
+        savestate::copy_into(&mut state.prg_ram, &self.prg_ram);
+        state.cycles = self.cycles as u64;
+        state.frames = self.frames as u64;
+        state.oam_dma = self.oam_dma;
+        for (port, joypad) in self.joypads.iter().enumerate() {
+            let (shift, strobe) = joypad.latch();
+            state.controllers[port] = joypad.buttons().bits();
+            state.controller_shift[port] = shift;
+            // both controllers see the same writes to $4016
+            state.controller_strobe = strobe;
+        }
+    }
+
+    /// Errors if `load_state` would fail, without changing anything
+    pub fn check_state(&self, state: &BusState) -> Result<(), CpuError> {
+        savestate::check_len("RAM", &state.ram, self.cpu_vram.len())?;
+        savestate::check_len("PRG RAM", &state.prg_ram, self.prg_ram.len())?;
+        Ok(())
+    }
+
+    /// Restores memory, timing and controllers. The PPU has its own state
+    pub fn load_state(&mut self, state: &BusState) -> Result<(), CpuError> {
+        self.check_state(state)?;
+        self.cpu_vram.copy_from_slice(&state.ram);
+        if self.prg_ram[..] != state.prg_ram[..] {
+            self.prg_ram.copy_from_slice(&state.prg_ram);
+            self.prg_ram_dirty = true;
+        }
+        self.cycles = state.cycles as usize;
+        // the PPU's state is saved caught up
+        self.ppu_pending = 0;
+        self.ppu_deadline = 0;
+        self.frames = state.frames as usize;
+        self.oam_dma = state.oam_dma;
+        for (port, joypad) in self.joypads.iter_mut().enumerate() {
+            joypad.set_buttons(Button::from_bits_truncate(state.controllers[port]));
+            joypad.set_latch(state.controller_shift[port], state.controller_strobe);
+        }
+        Ok(())
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram_dirty = true;
+        &mut self.prg_ram
+    }
+
+    /// Whether PRG RAM changed since the battery save was last written
+    pub fn prg_ram_dirty(&self) -> bool {
+        self.prg_ram_dirty
+    }
+
+    /// Loads an existing save into PRG RAM and keeps it up to date from now
+    /// on. Returns whether there was a save to load
+    #[cfg(feature = "std")]
+    pub fn attach_battery_save(&mut self, save: BatterySave) -> Result<bool, RomError> {
+        let loaded = save.load(&mut self.prg_ram)?;
+        self.prg_ram_dirty = false;
+        self.battery = BatteryLink(Some(save));
+        Ok(loaded)
+    }
+
+    /// Writes PRG RAM to the attached save if it changed, returns whether it did
+    #[cfg(feature = "std")]
+    pub fn flush_battery_save(&mut self) -> Result<bool, RomError> {
+        match self.battery.0.as_ref() {
+            Some(save) if self.prg_ram_dirty => {
+                save.write(&self.prg_ram)?;
+                self.prg_ram_dirty = false;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn flush_battery_save_if_due(&mut self) {
+        let frame = self.frames;
+        if let Some(save) = self.battery.0.as_mut() {
+            if save.due(frame) {
+                // a failed write leaves the RAM dirty, so it is retried next time
+                let _ = self.flush_battery_save();
+            }
+        }
+    }
+
+    /// Swaps in a rebuilt cartridge, for reloading a ROM file that changed.
+    /// PRG RAM is kept if asked, otherwise it starts over the way a power
+    /// cycle leaves it: loaded from the battery save if there is one, cleared
+    /// if not. The battery save and the frame callback move to the new
+    /// cartridge
+    pub fn reload_rom(&mut self, rom: Rom, keep_prg_ram: bool) -> Result<(), RomError> {
+        let mut bus = Bus::new(rom);
+        #[cfg(feature = "std")]
+        {
+            self.flush_battery_save()?;
+            if let Some(save) = self.battery.0.take() {
+                bus.attach_battery_save(save)?;
+            }
+        }
+        if keep_prg_ram {
+            bus.prg_ram = self.prg_ram;
+        }
+        self.hand_over(&mut bus);
+        *self = bus;
+        Ok(())
+    }
+
+    /// Swaps in a different game's cartridge, the console starts over as
+    /// after a power cycle. The outgoing game's battery save is written and
+    /// detached, the new game's has to be attached. Nothing changes on error
+    pub fn swap_cartridge(&mut self, rom: Rom) -> Result<(), RomError> {
+        #[cfg(feature = "std")]
+        {
+            self.flush_battery_save()?;
+            self.battery.0 = None;
+        }
+        let mut bus = Bus::new(rom);
+        self.hand_over(&mut bus);
+        *self = bus;
+        Ok(())
+    }
+
+    /// Moves what belongs to the console rather than the cartridge to the bus
+    /// of a new one: the buttons held, the frame callback and the audio
+    /// sample rate
+    fn hand_over(&mut self, bus: &mut Bus) {
+        for (port, joypad) in self.joypads.iter().enumerate() {
+            bus.joypads[port].set_buttons(joypad.buttons());
+        }
+        bus.frame_callback = FrameHook(self.frame_callback.0.take());
+        bus.apu.set_sample_rate(self.apu.sample_rate());
+    }
+
+    /// The cartridge this bus was built for, savestates record it
+    pub fn rom_id(&self) -> &RomId {
+        &self.rom_id
+    }
+
+    /// Whether an access faulted, see error.rs. The CPU stops after the
+    /// instruction that made it
+    pub fn has_fault(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    /// The fault that stopped the CPU, clearing it so the CPU can go on
+    pub fn take_fault(&mut self) -> Option<EmuError> {
+        self.fault.take().map(EmuError::from)
+    }
+
+    pub(crate) fn fault_cpu(&mut self, error: CpuError) {
+        self.fault.get_or_insert(Fault::Cpu(error));
+    }
+
+    fn fault_ppu(&mut self, error: PpuError) {
+        self.fault.get_or_insert(Fault::Ppu(error));
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Bus {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_battery_save() {
+            error!(target: "mapper", "{}", e);
+        }
+    }
+}
+
+/// The save file of a bus. Copies of the bus, for run-ahead or comparisons in
+/// tests, don't get it so only the original ever writes the file
+#[cfg(feature = "std")]
+struct BatteryLink(Option<BatterySave>);
+
+#[cfg(feature = "std")]
+impl Clone for BatteryLink {
+    fn clone(&self) -> Self {
+        BatteryLink(None)
+    }
+}
+
+/// The end of frame callback. Copies of the bus don't get it either, it would
+/// be called for frames of a machine its owner doesn't know about
+struct FrameHook(Option<FrameCallback>);
+
+impl Clone for FrameHook {
+    fn clone(&self) -> Self {
+        FrameHook(None)
     }
 }
 
 impl Mem for Bus {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        if let (true, Some(heatmap)) = (TRACING, self.heatmap.as_mut()) {
+            if !is_ppu_mirror(addr) {
+                heatmap.record_read(addr);
+            }
+        }
+        if is_ppu_register_or_mirror(addr) {
+            self.sync_ppu();
+        }
+        let value = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
             0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                panic!("Attempt to read from write-only PPU address {:x}", addr);
+                self.fault_ppu(PpuError::WriteOnlyRead(addr));
+                0
             }
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(),
+            0x2007 => self.ppu.read_data(&*self.mapper),
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4016 => self.joypads[0].read(),
+            0x4017 => self.joypads[1].read(),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[prg_ram_index(addr)],
+            0x8000..=0xFFFF => self.mapper.cpu_read(addr),
 
+            0x4015 => {
+                self.sync_apu();
+                let status = self.apu.read_status();
+                self.apu_deadline = self.apu.cycles_until_irq();
+                status
+            }
+            0x4000..=0x4013 => {
+                debug!(target: "apu", "read from write-only ${:04X}", addr);
+                0
+            }
             _ => {
-                println!("Ignoring mem access at {}", addr);
+                debug!(target: "cpu", "read from unmapped ${:04X}", addr);
                 0
             }
+        };
+        // mirrors recurse, so only the canonical address gets logged
+        if is_register(addr) {
+            self.log_event(EventKind::RegisterRead { addr, value });
         }
+        value
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if let (true, Some(heatmap)) = (TRACING, self.heatmap.as_mut()) {
+            if !is_ppu_mirror(addr) {
+                heatmap.record_write(addr);
+            }
+        }
+        if is_register(addr) {
+            self.log_event(EventKind::RegisterWrite { addr, value: data });
+        }
+        if is_ppu_register_or_mirror(addr) {
+            self.sync_ppu();
+        }
+        if let (true, Some(log)) = (TRACING, self.ppu_writes.as_mut()) {
+            if ppulog::is_ppu_register(addr) {
+                log.push(PpuWrite {
+                    frame: self.frames,
+                    scanline: self.ppu.scanline(),
+                    dot: self.ppu.dot(),
+                    cycle: self.cycles,
+                    addr,
+                    value: data,
+                });
+            }
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
@@ -110,7 +806,7 @@ impl Mem for Bus {
                 self.ppu.write_to_mask(data);
             }
 
-            0x2002 => panic!("attempt to write to PPU status register"),
+            0x2002 => self.fault_ppu(PpuError::StatusWrite(data)),
 
             0x2003 => {
                 self.ppu.write_to_oam_addr(data);
@@ -126,25 +822,83 @@ impl Mem for Bus {
                 self.ppu.write_to_ppu_addr(data);
             }
             0x2007 => {
-                self.ppu.write_to_data(data);
+                self.ppu.write_to_data(data, &mut *self.mapper);
             }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            0x4016 => {
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(data);
+                }
+            }
+            PRG_RAM..=PRG_RAM_END => {
+                let byte = &mut self.prg_ram[prg_ram_index(addr)];
+                if *byte != data {
+                    *byte = data;
+                    self.prg_ram_dirty = true;
+                }
+            }
+            0x8000..=0xFFFF => {
+                // the PPU sees the old banks up to this cycle
+                self.sync_ppu();
+                if self.mapper.cpu_write(addr, data) {
+                    self.ppu.mirroring = self.mapper.mirroring();
+                } else {
+                    warn!(target: "mapper", "write of {:02X} to ${:04X}, no mapper register", data, addr);
+                    self.fault_cpu(CpuError::RomWrite { addr, value: data })
+                }
+            }
 
+            0x4014 => {
+                // the PPU sees the new sprites from this cycle on
+                self.sync_ppu();
+                let base = (data as u16) << 8;
+                let mut page = [0; 256];
+                for (offset, byte) in page.iter_mut().enumerate() {
+                    *byte = self.mem_read(base + offset as u16);
+                }
+                self.ppu.write_oam_dma(&page);
+                self.oam_dma = true;
+            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                self.sync_apu();
+                self.apu.write_register(addr, data);
+                self.apu_deadline = self.apu.cycles_until_irq();
+            }
+            0x4020..=0x5FFF if self.mapper.expansion_write(addr, data) => {}
             _ => {
-                println!("Ignoring mem write-access at {}", addr);
+                debug!(target: "cpu", "write of {:02X} to unmapped ${:04X}", data, addr);
             }
         }
     }
 }
 
+fn is_register(addr: u16) -> bool {
+    matches!(addr, 0x2000..=0x2007 | 0x4000..=0x4017)
+}
+
+// accesses to these recurse with the canonical address, which is what gets counted
+fn is_ppu_mirror(addr: u16) -> bool {
+    matches!(addr, 0x2008..=PPU_REGISTERS_MIRRORS_END)
+}
+
+fn is_ppu_register_or_mirror(addr: u16) -> bool {
+    matches!(addr, PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END)
+}
+
+/// Index into PRG RAM, masked so the compiler can drop the bounds check
+fn prg_ram_index(addr: u16) -> usize {
+    debug_assert!((PRG_RAM..=PRG_RAM_END).contains(&addr));
+    (addr & (PRG_RAM_END - PRG_RAM)) as usize
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::cartridge::test;
+    use crate::cartridge::{test, Mirroring};
+    use alloc::sync::Arc;
 
     #[test]
     fn test_mem_read_write_to_ram() {
@@ -152,4 +906,290 @@ mod test {
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x6001, 0x55);
+        assert_eq!(bus.mem_read(0x6001), 0x55);
+        assert_eq!(bus.prg_ram()[1], 0x55);
+    }
+
+    #[test]
+    fn test_controller_reads() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.set_controller(0, 0b1000_1001); // Right, Start, A
+        bus.mem_write(0x4016, 1);
+        assert_eq!(bus.mem_read(0x4016), 1);
+        bus.mem_write(0x4016, 0);
+
+        let bits: Vec<u8> = (0..10).map(|_| bus.mem_read(0x4016)).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(bus.mem_read(0x4017), 0);
+
+        // controller 2 is read from $4017
+        bus.joypad_mut(1).set_button_pressed_status(Button::B, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!((bus.mem_read(0x4017), bus.mem_read(0x4017)), (0, 1));
+        assert_eq!(bus.controller(1), Button::B.bits());
+    }
+
+    #[test]
+    fn test_ppu_catches_up_lazily() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.tick(10);
+        bus.tick(10);
+        assert_eq!(bus.ppu().dot(), 0);
+        assert_eq!(bus.ppu_position(), (0, 60));
+        assert_eq!(bus.save_ppu_state().dot, 60);
+        bus.mem_read(0x2002);
+        assert_eq!(bus.ppu().dot(), 60);
+
+        // NMI right as vblank starts on scanline 241, after 241 * 341 PPU cycles
+        // or 27393.7 CPU cycles
+        bus.mem_write(0x2000, 0x80);
+        while bus.ppu().nmi_interrupt.is_none() {
+            bus.tick(1);
+        }
+        assert_eq!(bus.cycles(), 27394);
+        assert_eq!(bus.ppu().scanline(), 241);
+        // and a new frame after 262 scanlines, 29780.7 CPU cycles
+        while bus.frame_count() == 0 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.cycles(), 29781);
+    }
+
+    #[test]
+    fn test_vblank_without_nmi() {
+        // games with NMI off poll PPUSTATUS for vblank, and those reads are
+        // all that makes the PPU catch up
+        let mut bus = Bus::new(test::test_rom());
+        let mut polls = 0;
+        while bus.mem_read(0x2002) & 0x80 == 0 {
+            bus.tick(4);
+            polls += 1;
+            assert!(polls < 10_000, "vblank never showed in PPUSTATUS");
+        }
+        assert_eq!(bus.ppu().scanline(), 241);
+        assert!(bus.ppu().nmi_interrupt.is_none());
+        // reading PPUSTATUS clears the flag
+        assert_eq!(bus.mem_read(0x2002) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = Bus::new(test::test_rom());
+        for offset in 0..256 {
+            bus.mem_write(0x0300 + offset, offset as u8);
+        }
+        bus.mem_write(0x2003, 0x10);
+        bus.mem_write(0x4014, 0x03);
+        assert_eq!(bus.ppu().oam_data[0x10], 0x00);
+        assert_eq!(bus.ppu().oam_data[0x0F], 0xFF);
+        assert_eq!(bus.ppu().oam_addr, 0x10);
+
+        // the stall is left for the CPU, which sits it out before its next
+        // instruction
+        let state = bus.save_state();
+        assert!(state.oam_dma);
+        bus.finish_oam_dma();
+        assert_eq!(bus.cycles(), 513);
+        bus.finish_oam_dma();
+        assert_eq!(bus.cycles(), 513);
+        bus.mem_write(0x4014, 0x03);
+        bus.finish_oam_dma();
+        assert_eq!(bus.cycles(), 513 + 514);
+
+        bus.load_state(&state).unwrap();
+        bus.finish_oam_dma();
+        assert_eq!(bus.cycles(), 513);
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_event_log() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x2000, 0x00);
+        assert!(bus.events().is_none());
+
+        bus.set_event_logging(true);
+        bus.tick(10);
+        bus.mem_write(0x2008, 0x80);
+        bus.mem_read(0x2002);
+        bus.mem_write(0x0010, 0x01);
+        let events = bus.events().unwrap().current_frame().to_vec();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].kind,
+            EventKind::RegisterWrite {
+                addr: 0x2000,
+                value: 0x80
+            }
+        );
+        assert_eq!((events[0].scanline, events[0].dot), (0, 30));
+        assert!(matches!(
+            events[1].kind,
+            EventKind::RegisterRead { addr: 0x2002, .. }
+        ));
+
+        while bus.frame_count() == 0 {
+            bus.tick(80);
+            bus.poll_nmi_status();
+        }
+        let log = bus.events().unwrap();
+        assert_eq!(log.last_frame().len(), 3);
+        assert_eq!(log.last_frame()[2].kind, EventKind::Nmi);
+        assert!(log.current_frame().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_ppu_write_log() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.set_ppu_write_logging(true);
+        bus.tick(10);
+        bus.mem_write(0x200d, 0x20);
+        bus.mem_write(0x2001, 0x1e);
+        bus.mem_write(0x4016, 0x01);
+        let writes: Vec<PpuWrite> = bus.ppu_writes().unwrap().writes().copied().collect();
+        assert_eq!(writes.len(), 2);
+        assert_eq!((writes[0].addr, writes[0].value), (0x2005, 0x20));
+        assert_eq!(
+            (writes[0].frame, writes[0].scanline, writes[0].dot),
+            (0, 0, 30)
+        );
+        assert_eq!(writes[1].addr, 0x2001);
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_heatmap_counts_accesses() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x0010, 1);
+        assert!(bus.heatmap().is_none());
+
+        bus.set_heatmap(true);
+        bus.mem_write(0x0010, 1);
+        bus.mem_read(0x0810);
+        bus.mem_write(0x2008, 0x80);
+        bus.mem_read(0x8000);
+        let heatmap = bus.take_heatmap().unwrap();
+        assert_eq!((heatmap.writes(0x0010), heatmap.reads(0x0810)), (1, 1));
+        assert_eq!((heatmap.writes(0x2000), heatmap.writes(0x2008)), (1, 0));
+        assert_eq!(heatmap.reads(0x8000), 1);
+        assert!(bus.heatmap().is_none());
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x0801, 0x55);
+        assert_eq!(bus.peek(0x01), 0x55);
+        assert_eq!(bus.peek(0x2000), 0);
+        assert_eq!(bus.peek(0x8000), 1);
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.patch(0x8001, 0xea).unwrap();
+        assert_eq!(bus.peek(0x8001), 0xea);
+        bus.patch(0x0801, 0x55).unwrap();
+        assert_eq!(bus.peek(0x01), 0x55);
+        assert!(bus.patch(0x2000, 0).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_accesses_fault() {
+        let mut bus = Bus::new(test::test_rom_builder().mapper(0).build());
+        assert_eq!(bus.mem_read(0x2000), 0);
+        bus.mem_write(0x8000, 0x42);
+        assert!(bus.has_fault());
+        // the first fault is kept
+        assert!(matches!(
+            bus.take_fault(),
+            Some(EmuError::Ppu(PpuError::WriteOnlyRead(0x2000)))
+        ));
+        assert!(bus.take_fault().is_none());
+    }
+
+    #[test]
+    fn test_clones_share_rom_until_patched() {
+        let rom = test::test_rom();
+        let mut bus = Bus::new(rom.clone());
+        let clone = bus.clone();
+        assert!(Arc::ptr_eq(&bus.mapper.memory().prg_rom, &rom.prg_rom));
+        assert!(Arc::ptr_eq(&clone.mapper.memory().chr, &rom.chr_rom));
+
+        bus.patch(0x8001, 0xea).unwrap();
+        assert_eq!(bus.peek(0x8001), 0xea);
+        assert_eq!(clone.peek(0x8001), 0x01);
+        assert!(Arc::ptr_eq(&clone.mapper.memory().prg_rom, &rom.prg_rom));
+    }
+
+    #[test]
+    fn test_mapper_registers() {
+        // MMC1 with CHR RAM
+        let rom = test::test_rom_builder().mapper(1).chr_pages(0).build();
+        let mut bus = Bus::new(rom);
+        for bit in 0..5 {
+            // one-screen mirroring from the upper nametable
+            bus.mem_write(0x8000, 0b0_1101 >> bit);
+        }
+        assert!(!bus.has_fault());
+        assert_eq!(bus.ppu().mirroring, Mirroring::ONE_SCREEN_UPPER);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x42);
+        let state = bus.save_mapper_state();
+        assert_eq!(state.registers.len(), 6);
+        assert_eq!(state.chr_ram[0x1000], 0x42);
+
+        let mut other = Bus::new(test::test_rom_builder().mapper(1).chr_pages(0).build());
+        other.load_mapper_state(&state).unwrap();
+        assert_eq!(other.save_mapper_state(), state);
+        assert_eq!(other.ppu().mirroring, Mirroring::ONE_SCREEN_UPPER);
+        let nrom = Bus::new(test::test_rom_builder().mapper(0).build());
+        assert!(nrom.check_mapper_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_frame_callback() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        let frames = Arc::new(AtomicUsize::new(0));
+        let counter = frames.clone();
+        let mut bus = Bus::new_with_frame_callback(test::test_rom(), move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        // a little over two frames
+        for _ in 0..700 {
+            bus.tick(100);
+        }
+        assert_eq!(bus.frame_count(), 2);
+        assert_eq!(frames.load(Ordering::Relaxed), 2);
+
+        let mut clone = bus.clone();
+        for _ in 0..300 {
+            clone.tick(100);
+        }
+        assert_eq!(frames.load(Ordering::Relaxed), 2);
+        bus.reload_rom(test::test_rom(), false).unwrap();
+        for _ in 0..300 {
+            bus.tick(100);
+        }
+        assert_eq!(frames.load(Ordering::Relaxed), 3);
+
+        // another game keeps the callback and the audio setup
+        bus.apu_mut().set_sample_rate(48_000);
+        bus.swap_cartridge(test::test_rom()).unwrap();
+        assert_eq!(bus.frame_count(), 0);
+        assert_eq!(bus.apu().sample_rate(), 48_000);
+        for _ in 0..300 {
+            bus.tick(100);
+        }
+        assert_eq!(frames.load(Ordering::Relaxed), 4);
+    }
 }
@@ -1,32 +1,81 @@
+#[cfg(feature = "std")]
+use crate::asm;
+use crate::error::RomError;
+use crate::prelude::*;
+use alloc::sync::Arc;
+#[cfg(feature = "serde-state")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{Read, Seek};
+#[cfg(feature = "std")]
+use std::path::Path;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const INST_ROM_SIZE: usize = 8192;
+const PROM_SIZE: usize = 32;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+#[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    /// All four nametables show the first one, set by mappers like MMC1
+    ONE_SCREEN_LOWER,
+    /// All four nametables show the second one
+    ONE_SCREEN_UPPER,
+}
+
+impl Default for Mirroring {
+    /// What an iNES header without any mirroring bits means
+    fn default() -> Self {
+        Mirroring::HORIZONTAL
+    }
 }
 
+/// A parsed cartridge image. PRG and CHR data are shared, not copied, with
+/// the bus and PPU it is inserted into and with every clone of those
+#[derive(Debug, Clone)]
 pub struct Rom {
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
+    pub prg_rom: Arc<[u8]>,
+    pub chr_rom: Arc<[u8]>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    /// PRG RAM is battery backed and should persist between sessions
+    pub battery: bool,
+    /// The arcade board's data of a PlayChoice-10 dump, the game itself runs
+    /// on the standard console
+    pub playchoice: Option<PlayChoice>,
+}
+
+/// What a PlayChoice-10 dump stores after CHR ROM. Dumps often leave out the
+/// PROM, some even the INST-ROM, what is missing is empty
+#[derive(Debug, Clone, Default)]
+pub struct PlayChoice {
+    /// Z80 code and data of the arcade menu and instruction screens
+    pub inst_rom: Arc<[u8]>,
+    /// 16 bytes of PROM data followed by 16 bytes of CounterOut, the
+    /// decryption key of the board
+    pub prom: Arc<[u8]>,
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+    pub fn new(raw: &Vec<u8>) -> Result<Rom, RomError> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err(RomError::NotINes);
         }
 
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
         let ines_ver = (raw[7] >> 2) & 0b11;
         if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+            return Err(RomError::Nes2Unsupported);
         }
 
         let four_screen = raw[6] & 0b1000 != 0;
@@ -44,125 +93,448 @@ impl Rom {
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let expected = chr_rom_start + chr_rom_size;
+        if raw.len() < expected {
+            return Err(RomError::Truncated {
+                len: raw.len(),
+                expected,
+            });
+        }
+
+        // the arcade data follows the game, the console never sees it
+        let playchoice = if raw[7] & 0b10 != 0 {
+            let inst_rom_end = (expected + INST_ROM_SIZE).min(raw.len());
+            let prom_end = (inst_rom_end + PROM_SIZE).min(raw.len());
+            Some(PlayChoice {
+                inst_rom: raw[expected..inst_rom_end].into(),
+                prom: raw[inst_rom_end..prom_end].into(),
+            })
+        } else {
+            None
+        };
 
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].into(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].into(),
             mapper: mapper,
             screen_mirroring: screen_mirroring,
+            battery: raw[6] & 0b10 != 0,
+            playchoice,
         })
     }
+
+    #[cfg(feature = "std")]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Rom, RomError> {
+        Rom::new(&read_image(path)?)
+    }
+
+    /// SHA1 of the PRG and CHR data as hex, the same hash `RomInfo` shows
+    pub fn sha1(&self) -> String {
+        let mut hasher = sha1_smol::Sha1::new();
+        hasher.update(&self.prg_rom);
+        hasher.update(&self.chr_rom);
+        hasher.digest().to_string()
+    }
+
+    /// Loads an iNES image from a zip archive. When `entry` is not specified the
+    /// first .nes file in the archive is used, frontends that want to let the user
+    /// choose can list the candidates with [`Rom::zip_entries`]
+    #[cfg(feature = "std")]
+    pub fn from_zip<P: AsRef<Path>>(path: P, entry: Option<&str>) -> Result<Rom, RomError> {
+        Rom::from_zip_reader(open(path.as_ref())?, entry)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn zip_entries<P: AsRef<Path>>(path: P) -> Result<Vec<String>, RomError> {
+        let mut archive = zip::ZipArchive::new(open(path.as_ref())?)?;
+        Ok(nes_entries(&mut archive))
+    }
+
+    #[cfg(feature = "std")]
+    fn from_zip_reader<R: Read + Seek>(reader: R, entry: Option<&str>) -> Result<Rom, RomError> {
+        Rom::new(&image_from_zip_reader(reader, entry)?)
+    }
 }
 
-pub mod test {
+/// Builds iNES images in code, for tests and tools that need a cartridge
+/// without a file. Defaults to mapper 0 with 32KB of PRG ROM, 8KB of CHR ROM
+/// and horizontal mirroring
+///
+/// ```ignore
+/// let rom = RomBuilder::new()
+///     .code(0x8000, &[0xa9, 0x05, 0x00])
+///     .reset_vector(0x8000)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct RomBuilder {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    trainer: Option<Vec<u8>>,
+    mapper: u8,
+    mirroring: Mirroring,
+    battery: bool,
+}
 
-    use super::*;
+impl RomBuilder {
+    pub fn new() -> Self {
+        RomBuilder {
+            prg_rom: vec![0; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![0; CHR_ROM_PAGE_SIZE],
+            trainer: None,
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+        }
+    }
 
-    struct TestRom {
-        header: Vec<u8>,
-        trainer: Option<Vec<u8>>,
-        pgp_rom: Vec<u8>,
-        chr_rom: Vec<u8>,
+    /// Number of 16KB PRG ROM banks, clears what was placed before
+    pub fn prg_pages(mut self, pages: usize) -> Self {
+        self.prg_rom = vec![0; pages * PRG_ROM_PAGE_SIZE];
+        self
     }
 
-    fn create_rom(rom: TestRom) -> Vec<u8> {
-        let mut result = Vec::with_capacity(
-            rom.header.len()
-                + rom.trainer.as_ref().map_or(0, |t| t.len())
-                + rom.pgp_rom.len()
-                + rom.chr_rom.len(),
-        );
+    /// Number of 8KB CHR ROM banks, clears what was placed before
+    pub fn chr_pages(mut self, pages: usize) -> Self {
+        self.chr_rom = vec![0; pages * CHR_ROM_PAGE_SIZE];
+        self
+    }
+
+    pub fn fill_prg(mut self, value: u8) -> Self {
+        self.prg_rom.iter_mut().for_each(|b| *b = value);
+        self
+    }
 
-        result.extend(&rom.header);
-        if let Some(t) = rom.trainer {
-            result.extend(t);
+    pub fn fill_chr(mut self, value: u8) -> Self {
+        self.chr_rom.iter_mut().for_each(|b| *b = value);
+        self
+    }
+
+    pub fn mapper(mut self, mapper: u8) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+        self.mirroring = mirroring;
+        self
+    }
+
+    pub fn battery(mut self, battery: bool) -> Self {
+        self.battery = battery;
+        self
+    }
+
+    /// 512 bytes stored in front of PRG ROM
+    pub fn trainer(mut self, trainer: Vec<u8>) -> Self {
+        self.trainer = Some(trainer);
+        self
+    }
+
+    /// Places bytes at a CPU address in $8000-$FFFF. With a single PRG bank
+    /// $C000-$FFFF mirrors $8000-$BFFF like it does on the bus
+    pub fn code(mut self, addr: u16, bytes: &[u8]) -> Self {
+        assert!(addr >= 0x8000, "${:04X} is not in PRG ROM", addr);
+        for (i, byte) in bytes.iter().enumerate() {
+            let offset = (addr as usize - 0x8000 + i) % self.prg_rom.len();
+            self.prg_rom[offset] = *byte;
         }
-        result.extend(&rom.pgp_rom);
-        result.extend(&rom.chr_rom);
+        self
+    }
 
-        result
+    /// Same as `code`, reads better for tables
+    pub fn data(self, addr: u16, bytes: &[u8]) -> Self {
+        self.code(addr, bytes)
     }
 
-    pub fn test_rom() -> Rom {
-        let test_rom = create_rom(TestRom {
-            header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
-            ],
-            trainer: None,
-            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
-        });
+    /// Assembles `source` at `addr`, see asm.rs for the syntax
+    #[cfg(feature = "std")]
+    pub fn asm(self, addr: u16, source: &str) -> Result<Self, String> {
+        let code = asm::assemble(source, addr)?;
+        Ok(self.code(addr, &code))
+    }
 
-        Rom::new(&test_rom).unwrap()
+    pub fn nmi_vector(self, addr: u16) -> Self {
+        self.code(0xFFFA, &addr.to_le_bytes())
+    }
+
+    pub fn reset_vector(self, addr: u16) -> Self {
+        self.code(0xFFFC, &addr.to_le_bytes())
+    }
+
+    pub fn irq_vector(self, addr: u16) -> Self {
+        self.code(0xFFFE, &addr.to_le_bytes())
+    }
+
+    /// The raw iNES image, as it would be stored in a .nes file
+    pub fn build_image(&self) -> Vec<u8> {
+        let mut flags6 = (self.mapper & 0x0F) << 4;
+        match self.mirroring {
+            Mirroring::VERTICAL => flags6 |= 0b0001,
+            Mirroring::FOUR_SCREEN => flags6 |= 0b1000,
+            // the header can't tell one-screen mirroring, the mapper sets it
+            Mirroring::HORIZONTAL | Mirroring::ONE_SCREEN_LOWER | Mirroring::ONE_SCREEN_UPPER => {}
+        }
+        if self.battery {
+            flags6 |= 0b0010;
+        }
+        if self.trainer.is_some() {
+            flags6 |= 0b0100;
+        }
+        let mut image = NES_TAG.to_vec();
+        image.extend_from_slice(&[
+            (self.prg_rom.len() / PRG_ROM_PAGE_SIZE) as u8,
+            (self.chr_rom.len() / CHR_ROM_PAGE_SIZE) as u8,
+            flags6,
+            self.mapper & 0xF0,
+        ]);
+        image.resize(16, 0);
+        if let Some(trainer) = &self.trainer {
+            image.extend_from_slice(trainer);
+        }
+        image.extend_from_slice(&self.prg_rom);
+        image.extend_from_slice(&self.chr_rom);
+        image
+    }
+
+    pub fn build(&self) -> Rom {
+        Rom::new(&self.build_image()).unwrap()
+    }
+}
+
+impl Default for RomBuilder {
+    fn default() -> Self {
+        RomBuilder::new()
+    }
+}
+
+/// Reads the raw iNES image from a .nes file or the first .nes file in a zip archive
+#[cfg(feature = "std")]
+pub fn read_image<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, RomError> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("nes") => std::fs::read(path).map_err(|source| RomError::Read {
+            path: path.to_path_buf(),
+            source,
+        }),
+        Some("zip") => image_from_zip_reader(open(path)?, None),
+        _ => Err(RomError::UnsupportedFile(path.to_path_buf())),
+    }
+}
+
+#[cfg(feature = "std")]
+fn open(path: &Path) -> Result<File, RomError> {
+    File::open(path).map_err(|source| RomError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(feature = "std")]
+fn image_from_zip_reader<R: Read + Seek>(
+    reader: R,
+    entry: Option<&str>,
+) -> Result<Vec<u8>, RomError> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let name = match entry {
+        Some(name) => name.to_string(),
+        None => match nes_entries(&mut archive).into_iter().next() {
+            Some(name) => name,
+            None => return Err(RomError::NoRomInArchive),
+        },
+    };
+
+    let mut file = archive.by_name(&name)?;
+    let mut raw = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut raw)
+        .map_err(|source| RomError::Read {
+            path: name.into(),
+            source,
+        })?;
+    Ok(raw)
+}
+
+#[cfg(feature = "std")]
+fn nes_entries<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Vec<String> {
+    // file_names() iterates in hash order, the archive order is more predictable for users
+    (0..archive.len())
+        .filter_map(|i| archive.by_index(i).ok().map(|file| file.name().to_string()))
+        .filter(|name| name.to_ascii_lowercase().ends_with(".nes"))
+        .collect()
+}
+
+pub mod test {
+
+    use super::*;
+
+    /// The cartridge most unit tests run with: mapper 3, vertical mirroring,
+    /// PRG ROM filled with 1s and CHR ROM with 2s
+    pub fn test_rom_builder() -> RomBuilder {
+        RomBuilder::new()
+            .mapper(3)
+            .mirroring(Mirroring::VERTICAL)
+            .fill_prg(1)
+            .fill_chr(2)
+    }
+
+    pub fn test_rom() -> Rom {
+        test_rom_builder().build()
     }
 
     #[test]
     fn test() {
-        let test_rom = create_rom(TestRom {
-            header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
-            ],
-            trainer: None,
-            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
-        });
-
-        let rom: Rom = Rom::new(&test_rom).unwrap();
+        let rom: Rom = Rom::new(&test_rom_builder().build_image()).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
-        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.chr_rom[..], vec!(2; 1 * CHR_ROM_PAGE_SIZE)[..]);
+        assert_eq!(rom.prg_rom[..], vec!(1; 2 * PRG_ROM_PAGE_SIZE)[..]);
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
     #[test]
     fn test_with_trainer() {
-        let test_rom = create_rom(TestRom {
-            header: vec![
-                0x4E,
-                0x45,
-                0x53,
-                0x1A,
-                0x02,
-                0x01,
-                0x31 | 0b100,
-                00,
-                00,
-                00,
-                00,
-                00,
-                00,
-                00,
-                00,
-                00,
-            ],
-            trainer: Some(vec![0; 512]),
-            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
-        });
+        let test_rom = test_rom_builder().trainer(vec![0; 512]).build_image();
 
         let rom: Rom = Rom::new(&test_rom).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
-        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.chr_rom[..], vec!(2; 1 * CHR_ROM_PAGE_SIZE)[..]);
+        assert_eq!(rom.prg_rom[..], vec!(1; 2 * PRG_ROM_PAGE_SIZE)[..]);
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
+    #[test]
+    fn test_from_file_rejects_unsupported_extension() {
+        match Rom::from_file("game.txt") {
+            Result::Ok(_) => assert!(false, "should not load rom"),
+            Result::Err(e) => assert_eq!(e.to_string(), "Unsupported ROM file: game.txt"),
+        }
+    }
+
+    #[cfg(test)]
+    fn zip_archive(files: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(&content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[cfg(test)]
+    fn test_rom_image(chr_value: u8) -> Vec<u8> {
+        test_rom_builder().fill_chr(chr_value).build_image()
+    }
+
+    #[test]
+    fn test_load_first_nes_entry_from_zip() {
+        let archive = zip_archive(vec![
+            ("readme.txt", b"hello".to_vec()),
+            ("game (U).NES", test_rom_image(2)),
+            ("game (E).nes", test_rom_image(3)),
+        ]);
+
+        let rom = Rom::from_zip_reader(std::io::Cursor::new(&archive), None).unwrap();
+        assert_eq!(rom.chr_rom[..], vec!(2; 1 * CHR_ROM_PAGE_SIZE)[..]);
+
+        let rom =
+            Rom::from_zip_reader(std::io::Cursor::new(&archive), Some("game (E).nes")).unwrap();
+        assert_eq!(rom.chr_rom[..], vec!(3; 1 * CHR_ROM_PAGE_SIZE)[..]);
+    }
+
+    #[test]
+    fn test_zip_without_nes_files() {
+        let archive = zip_archive(vec![("readme.txt", b"hello".to_vec())]);
+        match Rom::from_zip_reader(std::io::Cursor::new(&archive), None) {
+            Result::Ok(_) => assert!(false, "should not load rom"),
+            Result::Err(e) => assert!(matches!(e, RomError::NoRomInArchive)),
+        }
+    }
+
     #[test]
     fn test_nes2_is_not_supported() {
-        let test_rom = create_rom(TestRom {
-            header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
-            ],
-            trainer: None,
-            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
-        });
+        let mut test_rom = test_rom_builder().prg_pages(1).build_image();
+        // NES 2.0 identifier in flags 7
+        test_rom[7] |= 0x8;
         let rom = Rom::new(&test_rom);
         match rom {
             Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+            Result::Err(e) => assert!(matches!(e, RomError::Nes2Unsupported)),
         }
     }
+
+    #[test]
+    fn test_playchoice_dump() {
+        let mut image = test_rom_builder().build_image();
+        image[7] |= 0b10;
+        image.extend(vec![7; INST_ROM_SIZE]);
+        image.extend(vec![9; PROM_SIZE]);
+        let rom = Rom::new(&image).unwrap();
+        assert_eq!(rom.prg_rom[..], vec!(1; 2 * PRG_ROM_PAGE_SIZE)[..]);
+        assert_eq!(rom.chr_rom[..], vec!(2; CHR_ROM_PAGE_SIZE)[..]);
+        let playchoice = rom.playchoice.unwrap();
+        assert_eq!(playchoice.inst_rom[..], vec!(7; INST_ROM_SIZE)[..]);
+        assert_eq!(playchoice.prom[..], vec!(9; PROM_SIZE)[..]);
+
+        // without the PROM
+        image.truncate(image.len() - PROM_SIZE);
+        let playchoice = Rom::new(&image).unwrap().playchoice.unwrap();
+        assert_eq!(playchoice.inst_rom.len(), INST_ROM_SIZE);
+        assert!(playchoice.prom.is_empty());
+        assert!(test_rom().playchoice.is_none());
+    }
+
+    #[test]
+    fn test_truncated_image() {
+        let mut test_rom = test_rom_builder().build_image();
+        test_rom.truncate(test_rom.len() - 1);
+        assert!(matches!(
+            Rom::new(&test_rom),
+            Err(RomError::Truncated {
+                len: 40975,
+                expected: 40976
+            })
+        ));
+        assert!(matches!(Rom::new(&vec![0x4E]), Err(RomError::NotINes)));
+    }
+
+    #[test]
+    fn test_builder_places_code_and_vectors() {
+        let rom = RomBuilder::new()
+            .prg_pages(1)
+            .code(0x8000, &[0xa9, 0x05])
+            .data(0xc010, &[0xde, 0xad])
+            .asm(0x8002, "STA $0200\nBRK")
+            .unwrap()
+            .reset_vector(0x8000)
+            .nmi_vector(0x8010)
+            .build();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(&rom.prg_rom[..6], &[0xa9, 0x05, 0x8d, 0x00, 0x02, 0x00]);
+        // $C000 mirrors $8000 with a single bank
+        assert_eq!(&rom.prg_rom[0x10..0x12], &[0xde, 0xad]);
+        assert_eq!(
+            &rom.prg_rom[0x3ffa..],
+            &[0x10, 0x80, 0x00, 0x80, 0x00, 0x00]
+        );
+
+        let rom = RomBuilder::new()
+            .mapper(0x42)
+            .mirroring(Mirroring::FOUR_SCREEN)
+            .chr_pages(2)
+            .build();
+        assert_eq!(rom.mapper, 0x42);
+        assert_eq!(rom.screen_mirroring, Mirroring::FOUR_SCREEN);
+        assert_eq!(rom.chr_rom.len(), 2 * CHR_ROM_PAGE_SIZE);
+    }
 }
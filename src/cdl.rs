@@ -0,0 +1,218 @@
+// Code/Data Logger, compatible with the FCEUX .cdl format: one flag byte per
+// PRG ROM byte followed by one per CHR ROM byte.
+//
+//  7 6 5 4 3 2 1 0
+//  _ P d c A A D C
+//  | | | | | | | +--- Executed as code
+//  | | | | | | +----- Read as data
+//  | | | | +-+------- CPU bank ($8000/$A000/$C000/$E000) the byte was mapped to
+//  | | | +----------- Destination of an indirect jump
+//  | | +------------- Read through an indirect pointer, e.g. LDA ($nn),Y
+//  | +--------------- PCM audio data, never set since there is no APU yet
+//
+// CHR flags are kept as loaded, this emulator doesn't log PPU fetches.
+use crate::bus::Bus;
+use crate::cpu::{AddressingMode, CPU};
+use crate::disasm::RawInstruction;
+use bitflags::bitflags;
+use std::path::Path;
+
+bitflags! {
+    pub struct CdlFlags: u8 {
+        const CODE          = 0b0000_0001;
+        const DATA          = 0b0000_0010;
+        const BANK          = 0b0000_1100;
+        const INDIRECT_CODE = 0b0001_0000;
+        const INDIRECT_DATA = 0b0010_0000;
+        const PCM_DATA      = 0b0100_0000;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    /// Empty log sized for the cartridge plugged into the bus
+    pub fn for_bus(bus: &Bus) -> Self {
+        CodeDataLog::new(bus.prg_rom_len(), bus.chr_rom_len())
+    }
+
+    /// Parses a .cdl file, which has to match the cartridge's ROM sizes
+    pub fn from_bytes(bytes: &[u8], prg_size: usize, chr_size: usize) -> Result<Self, String> {
+        if bytes.len() != prg_size + chr_size {
+            return Err(format!(
+                "CDL file is {} bytes, expected {} for this ROM",
+                bytes.len(),
+                prg_size + chr_size
+            ));
+        }
+        Ok(CodeDataLog {
+            prg: bytes[..prg_size].to_vec(),
+            chr: bytes[prg_size..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, bus: &Bus) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        CodeDataLog::from_bytes(&bytes, bus.prg_rom_len(), bus.chr_rom_len())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    /// Flags of the PRG ROM byte at the given offset
+    pub fn prg_flags(&self, offset: usize) -> CdlFlags {
+        CdlFlags::from_bits_truncate(self.prg.get(offset).copied().unwrap_or(0))
+    }
+
+    /// Flags of the PRG ROM byte a CPU address maps to, empty outside of PRG ROM
+    pub fn flags_at(&self, bus: &Bus, addr: u16) -> CdlFlags {
+        match bus.prg_rom_offset(addr) {
+            Some(offset) => self.prg_flags(offset),
+            None => CdlFlags::empty(),
+        }
+    }
+
+    /// Bytes that were read as data but never executed, the disassembler
+    /// shows these as `.db` instead of decoding them
+    pub fn is_data_only(&self, bus: &Bus, addr: u16) -> bool {
+        let flags = self.flags_at(bus, addr);
+        flags.contains(CdlFlags::DATA) && !flags.contains(CdlFlags::CODE)
+    }
+
+    /// Number of PRG bytes logged as code and as data
+    pub fn coverage(&self) -> (usize, usize) {
+        let count = |flag: CdlFlags| {
+            self.prg
+                .iter()
+                .filter(|&&b| CdlFlags::from_bits_truncate(b).contains(flag))
+                .count()
+        };
+        (count(CdlFlags::CODE), count(CdlFlags::DATA))
+    }
+
+    /// Logs the instruction at PC, to be called before it is executed
+    pub fn log(&mut self, cpu: &CPU) {
+        let bus = &cpu.bus;
+        let ins = RawInstruction::decode(bus, cpu.program_counter);
+        for addr in ins.addr..ins.addr.wrapping_add(ins.bytes().len() as u16) {
+            self.mark(bus, addr, CdlFlags::CODE);
+        }
+
+        if ins.is_jmp_indirect() {
+            let ptr = u16::from_le_bytes([ins.bytes()[1], ins.bytes()[2]]);
+            self.mark(bus, ptr, CdlFlags::DATA);
+            self.mark(bus, ptr.wrapping_add(1), CdlFlags::DATA);
+            if let Some(target) = ins.target {
+                self.mark(bus, target, CdlFlags::INDIRECT_CODE);
+            }
+            return;
+        }
+        if is_store(ins.mnemonic) {
+            return;
+        }
+        if let Some(addr) = ins.effective_address(cpu) {
+            let flags = match ins.mode {
+                AddressingMode::Indirect_X | AddressingMode::Indirect_Y => {
+                    CdlFlags::DATA | CdlFlags::INDIRECT_DATA
+                }
+                _ => CdlFlags::DATA,
+            };
+            self.mark(bus, addr, flags);
+        }
+    }
+
+    fn mark(&mut self, bus: &Bus, addr: u16, flags: CdlFlags) {
+        if let Some(offset) = bus.prg_rom_offset(addr) {
+            let bank = ((addr >> 13) & 0x03) as u8;
+            self.prg[offset] |= flags.bits() | (bank << 2);
+        }
+    }
+}
+
+/// Stores only write to their operand, writes to ROM go to mapper registers
+fn is_store(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "STA" | "STX" | "STY" | "*SAX" | "*AHX" | "*SHX" | "*SHY" | "*TAS"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    fn test_cpu(program: &[u8]) -> CPU {
+        let rom = test::test_rom_builder().code(0x8000, program).build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.program_counter = 0x8000;
+        cpu
+    }
+
+    #[test]
+    fn test_log_code_and_data() {
+        // LDA $8010; LDA ($10),Y; STA $0200; JMP ($8012)
+        let mut cpu = test_cpu(&[
+            0xad, 0x10, 0x80, 0xb1, 0x10, 0x8d, 0x00, 0x02, 0x6c, 0x12, 0x80,
+        ]);
+        cpu.mem_write(0x10, 0x00);
+        cpu.mem_write(0x11, 0xc0);
+        let mut cdl = CodeDataLog::for_bus(&cpu.bus);
+        for _ in 0..4 {
+            cdl.log(&cpu);
+            cpu.step();
+        }
+
+        let bus = &cpu.bus;
+        assert_eq!(cdl.flags_at(bus, 0x8000), CdlFlags::CODE);
+        assert_eq!(cdl.flags_at(bus, 0x800a), CdlFlags::CODE);
+        assert_eq!(cdl.flags_at(bus, 0x8010), CdlFlags::DATA);
+        assert!(cdl.is_data_only(bus, 0x8010));
+        assert!(cdl.flags_at(bus, 0x8011).is_empty());
+        assert_eq!(cdl.flags_at(bus, 0x8012), CdlFlags::DATA);
+        // $C000 is mapped to the third 8KB slot
+        let indirect = cdl.flags_at(bus, 0xc000);
+        assert!(indirect.contains(CdlFlags::DATA | CdlFlags::INDIRECT_DATA));
+        assert_eq!(
+            indirect & CdlFlags::BANK,
+            CdlFlags::from_bits_truncate(0b1000)
+        );
+        assert_eq!(cdl.coverage(), (11, 4));
+    }
+
+    #[test]
+    fn test_cdl_file_round_trip() {
+        let cpu = test_cpu(&[0xea]);
+        let mut cdl = CodeDataLog::for_bus(&cpu.bus);
+        cdl.log(&cpu);
+        let bytes = cdl.to_bytes();
+        assert_eq!(bytes.len(), cpu.bus.prg_rom_len() + cpu.bus.chr_rom_len());
+        assert_eq!(bytes[0], 0x01);
+
+        let loaded =
+            CodeDataLog::from_bytes(&bytes, cpu.bus.prg_rom_len(), cpu.bus.chr_rom_len()).unwrap();
+        assert_eq!(loaded, cdl);
+        assert!(CodeDataLog::from_bytes(&bytes[1..], cpu.bus.prg_rom_len(), 0).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cpu::{Mem, CPU};
+
+const RAM_SIZE: u16 = 0x0800;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchFilter {
+    Equal(u8),
+    NotEqual(u8),
+    Greater(u8),
+    Less(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    /// Current value minus the value at the previous search, e.g. -1 after losing a life
+    ChangedBy(i16),
+}
+
+impl SearchFilter {
+    fn matches(&self, previous: u8, current: u8) -> bool {
+        match *self {
+            SearchFilter::Equal(v) => current == v,
+            SearchFilter::NotEqual(v) => current != v,
+            SearchFilter::Greater(v) => current > v,
+            SearchFilter::Less(v) => current < v,
+            SearchFilter::Changed => current != previous,
+            SearchFilter::Unchanged => current == previous,
+            SearchFilter::Increased => current > previous,
+            SearchFilter::Decreased => current < previous,
+            SearchFilter::ChangedBy(delta) => current as i16 - previous as i16 == delta,
+        }
+    }
+}
+
+/// Narrows down the RAM addresses holding a value by filtering them against
+/// successive snapshots
+pub struct CheatSearch {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl CheatSearch {
+    pub fn new(cpu: &CPU) -> Self {
+        CheatSearch {
+            snapshot: ram_snapshot(cpu),
+            candidates: (0..RAM_SIZE).collect(),
+        }
+    }
+
+    /// Drops candidates not matching the filter and takes a new snapshot.
+    /// Returns the number of remaining candidates
+    pub fn filter(&mut self, cpu: &CPU, filter: SearchFilter) -> usize {
+        let current = ram_snapshot(cpu);
+        let previous = &self.snapshot;
+        self.candidates
+            .retain(|&addr| filter.matches(previous[addr as usize], current[addr as usize]));
+        self.snapshot = current;
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// Value of a candidate as of the last snapshot
+    pub fn value(&self, addr: u16) -> u8 {
+        self.snapshot[(addr % RAM_SIZE) as usize]
+    }
+
+    pub fn reset(&mut self, cpu: &CPU) {
+        *self = CheatSearch::new(cpu);
+    }
+}
+
+fn ram_snapshot(cpu: &CPU) -> Vec<u8> {
+    (0..RAM_SIZE).map(|addr| cpu.bus.peek(addr)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub description: String,
+    pub addr: u16,
+    pub value: u8,
+    pub enabled: bool,
+}
+
+/// Freeze cheats, re-applied once per frame
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: Vec::new() }
+    }
+
+    pub fn add_freeze(&mut self, description: &str, addr: u16, value: u8) {
+        self.cheats.retain(|c| c.addr != addr);
+        self.cheats.push(Cheat {
+            description: description.to_string(),
+            addr,
+            value,
+            enabled: true,
+        });
+    }
+
+    pub fn remove(&mut self, addr: u16) {
+        self.cheats.retain(|c| c.addr != addr);
+    }
+
+    pub fn set_enabled(&mut self, addr: u16, enabled: bool) {
+        for cheat in self.cheats.iter_mut().filter(|c| c.addr == addr) {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn apply(&self, cpu: &mut CPU) {
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            cpu.mem_write(cheat.addr, cheat.value);
+        }
+    }
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_find_lives_counter() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.mem_write(0x75, 3); // lives
+        cpu.mem_write(0x76, 3); // something else that happens to be 3
+        cpu.mem_write(0x77, 9);
+
+        let mut search = CheatSearch::new(&cpu);
+        search.filter(&cpu, SearchFilter::Equal(3));
+        assert_eq!(search.candidates(), &[0x75, 0x76]);
+
+        cpu.mem_write(0x75, 2);
+        cpu.mem_write(0x77, 8);
+        assert_eq!(search.filter(&cpu, SearchFilter::ChangedBy(-1)), 1);
+        assert_eq!(search.candidates(), &[0x75]);
+        assert_eq!(search.value(0x75), 2);
+
+        search.reset(&cpu);
+        cpu.mem_write(0x10, 1);
+        search.filter(&cpu, SearchFilter::Increased);
+        assert_eq!(search.candidates(), &[0x10]);
+    }
+
+    #[test]
+    fn test_freeze_cheats() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        let mut cheats = Cheats::new();
+        cheats.add_freeze("infinite lives", 0x75, 9);
+        cheats.add_freeze("more lives", 0x75, 99);
+        assert_eq!(cheats.cheats().len(), 1);
+
+        cheats.apply(&mut cpu);
+        assert_eq!(cpu.mem_read(0x75), 99);
+
+        cpu.mem_write(0x75, 1);
+        cheats.set_enabled(0x75, false);
+        cheats.apply(&mut cpu);
+        assert_eq!(cpu.mem_read(0x75), 1);
+    }
+}
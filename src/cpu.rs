@@ -1,6 +1,11 @@
 use crate::bus::Bus;
-use crate::opcodes;
-use std::collections::HashMap;
+use crate::cartridge::Rom;
+use crate::error::{CpuError, EmuError, RomError};
+use crate::observer::{NoopObserver, Observer};
+use crate::opcodes::{self, Operation};
+use crate::prelude::*;
+use crate::savestate::{ApuState, BusState, CpuState, MapperState, PpuState, SaveState};
+use crate::snapshot::Snapshot;
 
 bitflags! {
     /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
@@ -29,7 +34,68 @@ bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
+// the hardware stack can't hold more return addresses than this
+pub(crate) const MAX_CALL_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Subroutine,
+    Nmi,
+    Irq,
+}
 
+/// Entry of the shadow call stack kept next to the hardware one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the JSR, or of the instruction an interrupt preempted
+    pub call_site: u16,
+    /// Subroutine or interrupt handler entry point
+    pub target: u16,
+    pub return_addr: u16,
+    /// Stack pointer right after the return address was pushed
+    pub stack_pointer: u8,
+}
+
+/// What last pushed a byte onto the hardware stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackOrigin {
+    /// Not pushed to since reset
+    Unknown,
+    /// JSR return address, minus one as RTS expects it
+    ReturnAddress,
+    /// PHA
+    Accumulator,
+    /// PHP
+    Status,
+    /// Return address pushed by an NMI or IRQ
+    InterruptReturn,
+    /// Status pushed by an NMI or IRQ
+    InterruptStatus,
+}
+
+impl StackOrigin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StackOrigin::Unknown => "",
+            StackOrigin::ReturnAddress => "JSR return",
+            StackOrigin::Accumulator => "PHA",
+            StackOrigin::Status => "PHP",
+            StackOrigin::InterruptReturn => "interrupt return",
+            StackOrigin::InterruptStatus => "interrupt status",
+        }
+    }
+}
+
+/// Byte on the hardware stack, see `CPU::stack_entries`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackEntry {
+    pub addr: u16,
+    pub value: u8,
+    pub origin: StackOrigin,
+}
+
+#[derive(Clone)]
 pub struct CPU {
     pub register_a: u8,
     pub register_x: u8,
@@ -38,9 +104,11 @@ pub struct CPU {
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub bus: Bus,
+    call_stack: Vec<CallFrame>,
+    stack_origins: [StackOrigin; 256],
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -101,6 +169,9 @@ impl CPU {
             program_counter: 0,
             status: CpuFlags::from_bits_truncate(0b100100),
             bus: bus,
+            // full size up front, a deeper call must not allocate mid-frame
+            call_stack: Vec::with_capacity(MAX_CALL_DEPTH),
+            stack_origins: [StackOrigin::Unknown; 256],
         }
     }
 
@@ -230,14 +301,6 @@ impl CPU {
         }
     }
 
-    fn update_negative_flags(&mut self, result: u8) {
-        if result >> 7 == 1 {
-            self.status.insert(CpuFlags::NEGATIV)
-        } else {
-            self.status.remove(CpuFlags::NEGATIV)
-        }
-    }
-
     fn inx(&mut self) {
         self.register_x = self.register_x.wrapping_add(1);
         self.update_zero_and_negative_flags(self.register_x);
@@ -268,9 +331,141 @@ impl CPU {
         self.register_y = 0;
         self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b100100);
+        self.call_stack.clear();
+        self.stack_origins = [StackOrigin::Unknown; 256];
         // self.memory = [0; 0xFFFF];
 
         self.program_counter = self.mem_read_u16(0xFFFC);
+        // the reset sequence takes 7 cycles
+        self.bus.tick(7);
+    }
+
+    /// Serializes the whole console, see savestate.rs
+    #[cfg(feature = "serde-state")]
+    pub fn save_state(&self) -> Vec<u8> {
+        SaveState::capture(self).to_bytes()
+    }
+
+    /// Restores a state from `save_state`. Nothing changes if it is malformed
+    /// or was made with another ROM
+    #[cfg(feature = "serde-state")]
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let state = SaveState::from_bytes(data)?;
+        state.check_rom(self.bus.rom_id())?;
+        self.restore(&state)
+    }
+
+    /// Like `load_state` but a state made with another ROM is loaded anyway,
+    /// the mismatch is returned as a warning
+    #[cfg(feature = "serde-state")]
+    pub fn force_load_state(&mut self, data: &[u8]) -> Result<Option<String>, String> {
+        let state = SaveState::from_bytes(data)?;
+        let warning = state.check_rom(self.bus.rom_id()).err();
+        self.restore(&state)?;
+        Ok(warning)
+    }
+
+    pub fn restore(&mut self, state: &SaveState) -> Result<(), String> {
+        self.restore_hardware(
+            &state.bus,
+            &state.ppu,
+            state.mapper.as_ref(),
+            state.apu.as_ref(),
+        )
+        .map_err(|e| e.to_string())?;
+        self.restore_registers(&state.cpu);
+        // the debugger's view of the stack can't be reconstructed
+        self.call_stack.clear();
+        self.stack_origins = [StackOrigin::Unknown; 256];
+        Ok(())
+    }
+
+    /// Hash of all state that affects emulation, see statehash.rs
+    pub fn state_hash(&self) -> u64 {
+        crate::statehash::state_hash(self)
+    }
+
+    /// Copies the whole console into memory, see snapshot.rs
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        self.snapshot_into(&mut snapshot);
+        snapshot
+    }
+
+    /// Like `snapshot` but reuses the buffers of an earlier snapshot
+    pub fn snapshot_into(&self, snapshot: &mut Snapshot) {
+        snapshot.cpu = CpuState::capture(self);
+        self.bus.save_state_into(&mut snapshot.bus);
+        self.bus.save_ppu_state_into(&mut snapshot.ppu);
+        self.bus.save_mapper_state_into(&mut snapshot.mapper);
+        self.bus.save_apu_state_into(&mut snapshot.apu);
+        snapshot.call_stack.clone_from(&self.call_stack);
+        snapshot.stack_origins = self.stack_origins;
+    }
+
+    /// Goes back to a snapshot taken from this console
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), EmuError> {
+        self.restore_hardware(
+            &snapshot.bus,
+            &snapshot.ppu,
+            Some(&snapshot.mapper),
+            Some(&snapshot.apu),
+        )?;
+        self.restore_registers(&snapshot.cpu);
+        self.call_stack.clone_from(&snapshot.call_stack);
+        self.stack_origins = snapshot.stack_origins;
+        Ok(())
+    }
+
+    /// Without a mapper or APU state the mapper or APU is left as it is
+    fn restore_hardware(
+        &mut self,
+        bus: &BusState,
+        ppu: &PpuState,
+        mapper: Option<&MapperState>,
+        apu: Option<&ApuState>,
+    ) -> Result<(), EmuError> {
+        // all are checked before any changes, a bad state changes nothing
+        self.bus.check_state(bus)?;
+        self.bus.ppu().check_state(ppu)?;
+        if let Some(mapper) = mapper {
+            self.bus.check_mapper_state(mapper)?;
+        }
+        self.bus.load_state(bus)?;
+        self.bus.ppu_mut().load_state(ppu)?;
+        if let Some(mapper) = mapper {
+            self.bus.load_mapper_state(mapper)?;
+        }
+        if let Some(apu) = apu {
+            self.bus.load_apu_state(apu);
+        }
+        Ok(())
+    }
+
+    fn restore_registers(&mut self, state: &CpuState) {
+        self.register_a = state.a;
+        self.register_x = state.x;
+        self.register_y = state.y;
+        self.status = CpuFlags::from_bits_truncate(state.status);
+        self.program_counter = state.program_counter;
+        self.stack_pointer = state.stack_pointer;
+    }
+
+    /// Replaces the cartridge (and with it the whole bus state) and resets the CPU,
+    /// as if the console was powered off, a new cartridge inserted and powered on again.
+    /// See `Bus::swap_cartridge` for what happens to battery saves. Nothing changes on error
+    pub fn swap_cartridge(&mut self, rom: Rom) -> Result<(), RomError> {
+        self.bus.swap_cartridge(rom)?;
+        self.reset();
+        Ok(())
+    }
+
+    /// Swaps in a new build of the same game and resets, see
+    /// `Bus::reload_rom` for what happens to PRG RAM. Nothing changes on error
+    pub fn reload_rom(&mut self, rom: Rom, keep_prg_ram: bool) -> Result<(), RomError> {
+        self.bus.reload_rom(rom, keep_prg_ram)?;
+        self.reset();
+        Ok(())
     }
 
     fn set_carry_flag(&mut self) {
@@ -327,7 +522,6 @@ impl CPU {
         self.set_register_a(data | self.register_a);
     }
 
-
     fn sbc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(&mode);
         let data = self.mem_read(addr);
@@ -345,16 +539,29 @@ impl CPU {
         self.mem_read((STACK as u16) + self.stack_pointer as u16)
     }
 
-    fn stack_push(&mut self, data: u8) {
+    fn stack_push(&mut self, data: u8, origin: StackOrigin) {
         self.mem_write((STACK as u16) + self.stack_pointer as u16, data);
+        self.stack_origins[self.stack_pointer as usize] = origin;
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
 
-    fn stack_push_u16(&mut self, data: u16) {
+    fn stack_push_u16(&mut self, data: u16, origin: StackOrigin) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
-        self.stack_push(hi);
-        self.stack_push(lo);
+        self.stack_push(hi, origin);
+        self.stack_push(lo, origin);
+    }
+
+    /// Bytes currently on the hardware stack, top of the stack first, each
+    /// with what pushed it. Stores into the stack page aren't tracked
+    pub fn stack_entries(&self) -> Vec<StackEntry> {
+        (self.stack_pointer as u16 + 1..=0xFF)
+            .map(|offset| StackEntry {
+                addr: STACK + offset,
+                value: self.bus.peek(STACK + offset),
+                origin: self.stack_origins[offset as usize],
+            })
+            .collect()
     }
 
     fn stack_pop_u16(&mut self) -> u16 {
@@ -429,7 +636,7 @@ impl CPU {
             data = data | 1;
         }
         self.mem_write(addr, data);
-        self.update_negative_flags(data);
+        self.update_zero_and_negative_flags(data);
         data
     }
 
@@ -464,7 +671,7 @@ impl CPU {
             data = data | 0b10000000;
         }
         self.mem_write(addr, data);
-        self.update_negative_flags(data);
+        self.update_zero_and_negative_flags(data);
         data
     }
 
@@ -528,7 +735,7 @@ impl CPU {
         let mut flags = self.status.clone();
         flags.insert(CpuFlags::BREAK);
         flags.insert(CpuFlags::BREAK2);
-        self.stack_push(flags.bits());
+        self.stack_push(flags.bits(), StackOrigin::Status);
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
@@ -565,562 +772,574 @@ impl CPU {
                 .wrapping_add(1)
                 .wrapping_add(jump as u16);
 
+            // taken branches cost a cycle, one more if they land on another page
+            self.bus.tick(1);
+            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
+                self.bus.tick(1);
+            }
             self.program_counter = jump_addr;
         }
     }
 
+    /// Indexed reads take an extra cycle when adding the index crosses a page
+    fn page_crossed(&mut self, mode: &AddressingMode) -> bool {
+        let base = match mode {
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+                self.mem_read_u16(self.program_counter)
+            }
+            AddressingMode::Indirect_Y => {
+                let ptr = self.mem_read(self.program_counter);
+                let lo = self.mem_read(ptr as u16);
+                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                u16::from_le_bytes([lo, hi])
+            }
+            _ => return false,
+        };
+        let addr = self.get_absolute_address(mode, self.program_counter);
+        base & 0xFF00 != addr & 0xFF00
+    }
+
     fn interrupt_nmi(&mut self) {
-        self.stack_push_u16(self.program_counter);
+        self.interrupt(0xFFFA, CallKind::Nmi);
+    }
+
+    fn interrupt_irq(&mut self) {
+        self.bus.irq_taken();
+        self.interrupt(0xFFFE, CallKind::Irq);
+    }
+
+    fn interrupt(&mut self, vector: u16, kind: CallKind) {
+        self.stack_push_u16(self.program_counter, StackOrigin::InterruptReturn);
         let mut flag = self.status.clone();
         flag.set(CpuFlags::BREAK, false);
         flag.set(CpuFlags::BREAK2, true);
 
-        self.stack_push(flag.bits());
+        self.stack_push(flag.bits(), StackOrigin::InterruptStatus);
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
 
         self.bus.tick(2);
-        self.program_counter = self.mem_read_u16(0xFFFA);
+        let return_addr = self.program_counter;
+        self.program_counter = self.mem_read_u16(vector);
+        self.push_call(kind, return_addr, return_addr);
+    }
+
+    /// Subroutines and interrupt handlers currently executing, innermost last
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    fn push_call(&mut self, kind: CallKind, call_site: u16, return_addr: u16) {
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(CallFrame {
+            kind,
+            call_site,
+            target: self.program_counter,
+            return_addr,
+            stack_pointer: self.stack_pointer,
+        });
+    }
+
+    /// Drops the frames whose return address is no longer on the stack. That's
+    /// how RTS and RTI return, and it also covers code that discards return
+    /// addresses with PLA or TXS. An RTS used as a jump to a pushed address
+    /// leaves the stack pointer where it was and so doesn't unwind anything
+    fn unwind_call_stack(&mut self) {
+        while let Some(frame) = self.call_stack.last() {
+            if frame.stack_pointer >= self.stack_pointer {
+                break;
+            }
+            self.call_stack.pop();
+        }
     }
 
     pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+        self.run_with_observer(&mut NoopObserver);
     }
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        self.run_with_observer(&mut callback);
+    }
 
-        loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi();
-            }
-            callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+    /// Runs until BRK or a fault, calling the observer before every instruction
+    pub fn run_with_observer<O: Observer>(&mut self, observer: &mut O) {
+        while self.step_with_observer(observer) {}
+    }
 
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
+    /// Executes a single instruction, handling a pending NMI or IRQ first.
+    /// Returns false when the CPU hits BRK or the instruction faulted, see
+    /// `Bus::take_fault`
+    pub fn step(&mut self) -> bool {
+        self.step_with_observer(&mut NoopObserver)
+    }
 
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
+    /// Runs until the PPU finishes the current frame.
+    /// Returns false when the CPU hits BRK or faults
+    pub fn run_frame(&mut self) -> bool {
+        let frame = self.bus.frame_count();
+        while self.bus.frame_count() == frame {
+            if !self.step() {
+                return false;
+            }
+        }
+        true
+    }
 
-                0xAA => self.tax(),
-                0xe8 => self.inx(),
-                0x00 => return,
+    /// `step` with the observer called before the instruction
+    pub fn step_with_observer<O: Observer>(&mut self, observer: &mut O) -> bool {
+        self.bus.finish_oam_dma();
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt_nmi();
+        } else if self.bus.irq_pending() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            self.interrupt_irq();
+        }
+        observer.before_instruction(self);
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+
+        let opcode = match opcodes::lookup(code) {
+            Some(opcode) => opcode,
+            None => {
+                // stays on the opcode for crash reports and debuggers
+                self.program_counter -= 1;
+                let addr = self.program_counter;
+                self.bus.fault_cpu(CpuError::UnknownOpcode { code, addr });
+                return false;
+            }
+        };
+        if opcode.page_cross_penalty && self.page_crossed(&opcode.mode) {
+            self.bus.tick(1);
+        }
 
-                /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+        match opcode.operation {
+            Operation::Lda => {
+                self.lda(&opcode.mode);
+            }
 
-                /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+            Operation::Tax => self.tax(),
+            Operation::Inx => self.inx(),
+            Operation::Brk => return false,
 
-                /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+            Operation::Cld => self.status.remove(CpuFlags::DECIMAL_MODE),
 
-                /* CLC */ 0x18 => self.clear_carry_flag(),
+            Operation::Cli => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
 
-                /* SEC */ 0x38 => self.set_carry_flag(),
+            Operation::Clv => self.status.remove(CpuFlags::OVERFLOW),
 
-                /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            Operation::Clc => self.clear_carry_flag(),
 
-                /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            Operation::Sec => self.set_carry_flag(),
 
-                /* PHA */ 0x48 => self.stack_push(self.register_a),
+            Operation::Sei => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
 
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                }
+            Operation::Sed => self.status.insert(CpuFlags::DECIMAL_MODE),
 
-                /* PHP */
-                0x08 => {
-                    self.php();
-                }
+            Operation::Pha => self.stack_push(self.register_a, StackOrigin::Accumulator),
 
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                }
-
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-
-                /* SBC */
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
-
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
+            Operation::Pla => {
+                self.pla();
+            }
 
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+            Operation::Php => {
+                self.php();
+            }
 
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+            Operation::Plp => {
+                self.plp();
+            }
 
-                /* LSR */ 0x4a => self.lsr_accumulator(),
+            Operation::Adc => {
+                self.adc(&opcode.mode);
+            }
 
-                /* LSR */
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
+            Operation::Sbc => {
+                self.sbc(&opcode.mode);
+            }
 
-                /*ASL*/ 0x0a => self.asl_accumulator(),
+            Operation::And => {
+                self.and(&opcode.mode);
+            }
 
-                /* ASL */
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
+            Operation::Eor => {
+                self.eor(&opcode.mode);
+            }
 
-                /*ROL*/ 0x2a => self.rol_accumulator(),
+            Operation::Ora => {
+                self.ora(&opcode.mode);
+            }
 
-                /* ROL */
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
+            Operation::LsrAccumulator => self.lsr_accumulator(),
 
-                /* ROR */ 0x6a => self.ror_accumulator(),
+            Operation::Lsr => {
+                self.lsr(&opcode.mode);
+            }
 
-                /* ROR */
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
+            Operation::AslAccumulator => self.asl_accumulator(),
 
-                /* INC */
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
+            Operation::Asl => {
+                self.asl(&opcode.mode);
+            }
 
-                /* INY */
-                0xc8 => self.iny(),
+            Operation::RolAccumulator => self.rol_accumulator(),
 
-                /* DEC */
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
+            Operation::Rol => {
+                self.rol(&opcode.mode);
+            }
 
-                /* DEX */
-                0xca => {
-                    self.dex();
-                }
+            Operation::RorAccumulator => self.ror_accumulator(),
 
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                }
+            Operation::Ror => {
+                self.ror(&opcode.mode);
+            }
 
-                /* CMP */
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
+            Operation::Inc => {
+                self.inc(&opcode.mode);
+            }
 
-                /* CPY */
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
+            Operation::Iny => self.iny(),
 
-                /* CPX */
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+            Operation::Dec => {
+                self.dec(&opcode.mode);
+            }
 
-                /* JMP Absolute */
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+            Operation::Dex => {
+                self.dex();
+            }
 
-                /* JMP Indirect */
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    // let indirect_ref = self.mem_read_u16(mem_address);
-                    //6502 bug mode with with page boundary:
-                    //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
-                    // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
-                    // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
-
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = indirect_ref;
-                }
+            Operation::Dey => {
+                self.dey();
+            }
 
-                /* JSR */
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
+            Operation::Cmp => {
+                self.compare(&opcode.mode, self.register_a);
+            }
 
-                /* RTS */
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
+            Operation::Cpy => {
+                self.compare(&opcode.mode, self.register_y);
+            }
 
-                /* RTI */
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::BREAK2);
+            Operation::Cpx => self.compare(&opcode.mode, self.register_x),
 
-                    self.program_counter = self.stack_pop_u16();
-                }
+            Operation::JmpAbsolute => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
 
-                /* BNE */
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
+            Operation::JmpIndirect => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                // let indirect_ref = self.mem_read_u16(mem_address);
+                //6502 bug mode with with page boundary:
+                //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+                // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+                // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.program_counter = indirect_ref;
+            }
 
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
+            Operation::Jsr => {
+                self.stack_push_u16(self.program_counter + 2 - 1, StackOrigin::ReturnAddress);
+                let target_address = self.mem_read_u16(self.program_counter);
+                let call_site = self.program_counter - 1;
+                self.program_counter = target_address;
+                self.push_call(CallKind::Subroutine, call_site, call_site + 3);
+            }
 
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
+            Operation::Rts => {
+                self.program_counter = self.stack_pop_u16() + 1;
+            }
 
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIV));
-                }
+            Operation::Rti => {
+                self.status.bits = self.stack_pop();
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::BREAK2);
 
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIV));
-                }
+                self.program_counter = self.stack_pop_u16();
+            }
 
-                /* BEQ */
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
+            Operation::Bne => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* BCS */
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
+            Operation::Bvs => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
+            Operation::Bvc => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BIT */
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
+            Operation::Bpl => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            Operation::Bmi => {
+                self.branch(self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* STX */
-                0x86 | 0x96 | 0x8e => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
+            Operation::Beq => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* STY */
-                0x84 | 0x94 | 0x8c => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
+            Operation::Bcs => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LDX */
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
+            Operation::Bcc => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LDY */
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
+            Operation::Bit => {
+                self.bit(&opcode.mode);
+            }
 
-                /* NOP */
-                0xea => {
-                    //do nothing
-                }
+            Operation::Sta => {
+                self.sta(&opcode.mode);
+            }
 
-                /* TAY */
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
+            Operation::Stx => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            }
 
-                /* TSX */
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
+            Operation::Sty => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            }
 
-                /* TXA */
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            Operation::Ldx => {
+                self.ldx(&opcode.mode);
+            }
 
-                /* TXS */
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                }
+            Operation::Ldy => {
+                self.ldy(&opcode.mode);
+            }
 
-                /* TYA */
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* NOP, SKB (2 byte NOP, immediate) and the unofficial implied NOPs */
+            Operation::Nop => {
+                // todo: SKB might be worth doing the read
+            }
 
-                /* unofficial */
+            Operation::Tay => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
 
-                /* DCP */
-                0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-                    // self._update_zero_and_negative_flags(data);
-                    if data <= self.register_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
+            Operation::Tsx => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
 
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                }
+            Operation::Txa => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                /* RLA */
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&opcode.mode);
-                    self.and_with_register_a(data);
-                }
+            Operation::Txs => {
+                self.stack_pointer = self.register_x;
+            }
 
-                /* SLO */ //todo tests
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&opcode.mode);
-                    self.or_with_register_a(data);
-                }
+            Operation::Tya => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                /* SRE */ //todo tests
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&opcode.mode);
-                    self.xor_with_register_a(data);
+            /* unofficial */
+            Operation::Dcp => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+                // self._update_zero_and_negative_flags(data);
+                if data <= self.register_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
 
-                /* SKB */
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
-                    /* 2 byte NOP (immediate ) */
-                    // todo: might be worth doing the read
-                }
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
 
-                /* AXS */
-                0xCB => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
+            Operation::Rla => {
+                let data = self.rol(&opcode.mode);
+                self.and_with_register_a(data);
+            }
+            Operation::Slo => {
+                let data = self.asl(&opcode.mode);
+                self.or_with_register_a(data);
+            }
+            Operation::Sre => {
+                let data = self.lsr(&opcode.mode);
+                self.xor_with_register_a(data);
+            }
 
-                    if data <= x_and_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
-                    self.update_zero_and_negative_flags(result);
+            Operation::Axs => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
 
-                    self.register_x = result;
+                if data <= x_and_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
+                self.update_zero_and_negative_flags(result);
 
-                /* ARR */
-                0x6B => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.ror_accumulator();
-                    //todo: registers
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status.insert(CpuFlags::CARRY)
-                    } else {
-                        self.status.remove(CpuFlags::CARRY)
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status.insert(CpuFlags::OVERFLOW);
-                    } else {
-                        self.status.remove(CpuFlags::OVERFLOW);
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                }
+                self.register_x = result;
+            }
 
-                /* unofficial SBC */
-                0xeb => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.sub_from_register_a(data);
-                }
+            Operation::Arr => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.ror_accumulator();
+                //todo: registers
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
 
-                /* ANC */
-                0x0b | 0x2b => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    if self.status.contains(CpuFlags::NEGATIV) {
-                        self.status.insert(CpuFlags::CARRY);
-                    } else {
-                        self.status.remove(CpuFlags::CARRY);
-                    }
+                if bit_6 == 1 {
+                    self.status.insert(CpuFlags::CARRY)
+                } else {
+                    self.status.remove(CpuFlags::CARRY)
                 }
 
-                /* ALR */
-                0x4b => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.lsr_accumulator();
+                if bit_5 ^ bit_6 == 1 {
+                    self.status.insert(CpuFlags::OVERFLOW);
+                } else {
+                    self.status.remove(CpuFlags::OVERFLOW);
                 }
 
-                //todo: test for everything bellow
-
-                /* NOP read */
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    /* do nothing */
-                }
+                self.update_zero_and_negative_flags(result);
+            }
 
-                /* RRA */
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&opcode.mode);
-                    self.add_to_register_a(data);
+            Operation::Anc => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                if self.status.contains(CpuFlags::NEGATIV) {
+                    self.status.insert(CpuFlags::CARRY);
+                } else {
+                    self.status.remove(CpuFlags::CARRY);
                 }
+            }
 
-                /* ISB */
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&opcode.mode);
-                    self.sub_from_register_a(data);
-                }
+            Operation::Alr => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.lsr_accumulator();
+            }
 
-                /* NOPs */
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* do nothing */ }
+            //todo: test for everything bellow
+            Operation::NopRead => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                /* do nothing */
+            }
 
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+            Operation::Rra => {
+                let data = self.ror(&opcode.mode);
+                self.add_to_register_a(data);
+            }
 
-                /* LAX */
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.set_register_a(data);
-                    self.register_x = self.register_a;
-                }
+            Operation::Isb => {
+                let data = self.inc(&opcode.mode);
+                self.sub_from_register_a(data);
+            }
 
-                /* SAX */
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.register_a & self.register_x;
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, data);
-                }
+            Operation::Lax => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a(data);
+                self.register_x = self.register_a;
+            }
 
-                /* LXA */
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                }
+            Operation::Sax => {
+                let data = self.register_a & self.register_x;
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, data);
+            }
 
-                /* XAA */
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                }
+            Operation::Lxa => {
+                self.lda(&opcode.mode);
+                self.tax();
+            }
 
-                /* LAS */
-                0xbb => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.stack_pointer;
-                    self.register_a = data;
-                    self.register_x = data;
-                    self.stack_pointer = data;
-                    self.update_zero_and_negative_flags(data);
-                }
+            Operation::Xaa => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let addr = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+            }
 
-                /* TAS */
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            Operation::Las => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+            }
 
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data)
-                }
+            Operation::Tas => {
+                let data = self.register_a & self.register_x;
+                self.stack_pointer = data;
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-                /* AHX  Indirect Y */
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
+                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
+                self.mem_write(mem_address, data)
+            }
 
-                /* AHX Absolute Y*/
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            Operation::AhxIndirectY => {
+                let pos: u8 = self.mem_read(self.program_counter);
+                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
 
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
+            Operation::AhxAbsoluteY => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-                /* SHX */
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
 
-                    // todo if cross page boundry {
-                    //     mem_address &= (self.x as u16) << 8;
-                    // }
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+            Operation::Shx => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-                /* SHY */
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+                // todo if cross page boundry {
+                //     mem_address &= (self.x as u16) << 8;
+                // }
+                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
 
-                _ => todo!(),
+            Operation::Shy => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_x as u16;
+                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
             }
+        }
+        self.unwind_call_stack();
 
-            self.bus.tick(opcode.cycles);
+        self.bus.tick(opcode.cycles);
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
-            }
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
+        !self.bus.has_fault()
     }
 }
 
@@ -1128,6 +1347,7 @@ impl CPU {
 mod test {
     use super::*;
     use crate::cartridge::test;
+    use proptest::prelude::*;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
@@ -1143,12 +1363,82 @@ mod test {
     fn test_0xaa_tax_move_a_to_x() {
         let bus = Bus::new(test::test_rom());
         let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xaa, 0x00]);
+        // reset clears the registers, so A is set after it
+        cpu.reset();
         cpu.register_a = 10;
-        cpu.load_and_run(vec![0xaa, 0x00]);
+        cpu.program_counter = 0x0600;
+        cpu.run();
 
         assert_eq!(cpu.register_x, 10)
     }
 
+    #[test]
+    fn test_fault_stops_after_the_instruction() {
+        let bus = Bus::new(test::test_rom_builder().mapper(0).build());
+        let mut cpu = CPU::new(bus);
+        // STA $8000, INX, BRK
+        cpu.load_and_run(vec![0x8d, 0x00, 0x80, 0xe8, 0x00]);
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 0);
+        assert!(matches!(
+            cpu.bus.take_fault(),
+            Some(EmuError::Cpu(CpuError::RomWrite {
+                addr: 0x8000,
+                value: 0
+            }))
+        ));
+
+        cpu.run();
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let bus = Bus::new(test::test_rom_builder().irq_vector(0x0610).build());
+        let mut cpu = CPU::new(bus);
+        // LDA #$00; STA $4017; CLI; loop: JMP loop, then at $0610 the handler
+        // LDA $4015; STA $10; BRK
+        let mut program = vec![0xa9, 0x00, 0x8d, 0x17, 0x40, 0x58, 0x4c, 0x06, 0x06];
+        program.resize(0x10, 0xea);
+        program.extend_from_slice(&[0xad, 0x15, 0x40, 0x85, 0x10, 0x00]);
+        cpu.load_and_run(program);
+        assert_eq!(cpu.program_counter, 0x0616);
+        assert_eq!(cpu.mem_read(0x10), 0b0100_0000);
+        assert!(cpu.bus.cycles() > 29829);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        let irq = cpu.call_stack()[0];
+        assert_eq!(irq.kind, CallKind::Irq);
+        assert_eq!(irq.return_addr, 0x0606);
+        // acknowledged by the read
+        assert!(!cpu.bus.irq_pending());
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_irq_event() {
+        use crate::events::EventKind;
+
+        let mut bus = Bus::new(test::test_rom_builder().irq_vector(0x0610).build());
+        bus.set_event_logging(true);
+        let mut cpu = CPU::new(bus);
+        // same program as test_frame_irq
+        let mut program = vec![0xa9, 0x00, 0x8d, 0x17, 0x40, 0x58, 0x4c, 0x06, 0x06];
+        program.resize(0x10, 0xea);
+        program.extend_from_slice(&[0xad, 0x15, 0x40, 0x85, 0x10, 0x00]);
+        cpu.load_and_run(program);
+        let log = cpu.bus.events().unwrap();
+        let irq = log
+            .last_frame()
+            .iter()
+            .chain(log.current_frame())
+            .find(|e| e.kind == EventKind::Irq)
+            .unwrap();
+        // 29830 cycles in is 89490 dots, just past the first frame's 89342
+        assert!(irq.cycle > 29829);
+        assert_eq!(irq.scanline, 0);
+    }
+
     #[test]
     fn test_5_ops_working_together() {
         let bus = Bus::new(test::test_rom());
@@ -1162,12 +1452,85 @@ mod test {
     fn test_inx_overflow() {
         let bus = Bus::new(test::test_rom());
         let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xe8, 0xe8, 0x00]);
+        cpu.reset();
         cpu.register_x = 0xff;
-        cpu.load_and_run(vec![0xe8, 0xe8, 0x00]);
+        cpu.program_counter = 0x0600;
+        cpu.run();
 
         assert_eq!(cpu.register_x, 1)
     }
 
+    #[test]
+    fn test_stack_ops() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // LDA #$80, PHA, LDA #$00, PLA: PLA sets the flags
+        cpu.load_and_run(vec![0xa9, 0x80, 0x48, 0xa9, 0x00, 0x68, 0x00]);
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+
+        // SEC, PHP, PLA: PHP pushes the B flag and bit 5 set
+        cpu.load_and_run(vec![0x38, 0x08, 0x68, 0x00]);
+        assert_eq!(cpu.register_a, 0b0011_0101);
+
+        // LDA #$D3, PHA, PLP: PLP drops the B flag and keeps bit 5 set
+        cpu.load_and_run(vec![0xa9, 0xd3, 0x48, 0x28, 0x00]);
+        assert_eq!(cpu.status.bits(), 0b1110_0011);
+    }
+
+    #[test]
+    fn test_transfers_and_flags() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // LDX #$FF, LDA #$00, TXS: TXS leaves the flags alone
+        cpu.load_and_run(vec![0xa2, 0xff, 0xa9, 0x00, 0x9a, 0x00]);
+        assert_eq!(cpu.stack_pointer, 0xff);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+        assert!(!cpu.status.contains(CpuFlags::NEGATIV));
+
+        // LDX #$80, TXS, LDX #$00, TSX, TXA, TAY, LDA #$00, TYA
+        cpu.load_and_run(vec![
+            0xa2, 0x80, 0x9a, 0xa2, 0x00, 0xba, 0x8a, 0xa8, 0xa9, 0x00, 0x98, 0x00,
+        ]);
+        assert_eq!(
+            (cpu.register_a, cpu.register_x, cpu.register_y),
+            (0x80, 0x80, 0x80)
+        );
+        assert!(cpu.status.contains(CpuFlags::NEGATIV));
+
+        // SEC, SED, SEI, ORA #$00 with A = 0, then CLD
+        cpu.load_and_run(vec![0x38, 0xf8, 0x78, 0x09, 0x00, 0xd8, 0x00]);
+        assert!(cpu
+            .status
+            .contains(CpuFlags::CARRY | CpuFlags::INTERRUPT_DISABLE | CpuFlags::ZERO));
+        assert!(!cpu.status.contains(CpuFlags::DECIMAL_MODE));
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // JSR sub, LDX #$01, BRK, NOP, sub: LDY #$02, RTS
+        cpu.load_and_run(vec![
+            0x20, 0x07, 0x06, 0xa2, 0x01, 0x00, 0xea, 0xa0, 0x02, 0x60,
+        ]);
+        assert_eq!((cpu.register_x, cpu.register_y), (1, 2));
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+
+        // pushes $060D and the flags $C3, RTI, BRK x3, LDX #$07, BRK
+        cpu.load_and_run(vec![
+            0xa9, 0x06, 0x48, 0xa9, 0x0d, 0x48, 0xa9, 0xc3, 0x48, 0x40, 0x00, 0x00, 0x00, 0xa2,
+            0x07, 0x00,
+        ]);
+        assert_eq!(cpu.register_x, 7);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW | CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::NEGATIV));
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
     #[test]
     fn test_lda_from_memory() {
         let bus = Bus::new(test::test_rom());
@@ -1178,4 +1541,239 @@ mod test {
 
         assert_eq!(cpu.register_a, 0x55);
     }
+
+    #[test]
+    fn test_swap_cartridge_resets_machine() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x55);
+        cpu.register_a = 0x42;
+
+        cpu.swap_cartridge(test::test_rom()).unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.program_counter, 0x0101);
+    }
+
+    #[test]
+    fn test_extra_cycles() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // LDX #$01; LDA $06FF,X; BNE +0; STA $06FF,X
+        cpu.load(vec![
+            0xa2, 0x01, 0xbd, 0xff, 0x06, 0xd0, 0x00, 0x9d, 0xff, 0x06,
+        ]);
+        cpu.program_counter = 0x0600;
+        cpu.mem_write(0x0700, 0x42);
+
+        let mut cycles = vec![];
+        for _ in 0..4 {
+            let before = cpu.bus.cycles();
+            cpu.step();
+            cycles.push(cpu.bus.cycles() - before);
+        }
+        assert_eq!(cycles, vec![2, 5, 3, 5]);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // loop: JMP loop
+        cpu.load(vec![0x4c, 0x00, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        assert!(cpu.run_frame());
+        assert_eq!(cpu.bus.frame_count(), 1);
+        assert!(cpu.bus.cycles() >= 262 * 341 / 3);
+        assert!(cpu.run_frame());
+        assert_eq!(cpu.bus.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // JSR a; NOP
+        // a: JSR b; RTS
+        // b: PLA; PLA; RTS (returns straight to the NOP)
+        // c: LDA #$06; PHA; LDA #$14; PHA; RTS (jumps to $0615)
+        // JSR c; NOP
+        cpu.load(vec![
+            0x20, 0x04, 0x06, 0xea, 0x20, 0x08, 0x06, 0x60, 0x68, 0x68, 0x60, 0xa9, 0x06, 0x48,
+            0xa9, 0x14, 0x48, 0x60, 0x20, 0x0b, 0x06, 0xea,
+        ]);
+        cpu.program_counter = 0x0600;
+
+        cpu.step();
+        cpu.step();
+        let targets: Vec<u16> = cpu.call_stack().iter().map(|f| f.target).collect();
+        assert_eq!(targets, vec![0x0604, 0x0608]);
+        assert_eq!(cpu.call_stack()[1].call_site, 0x0604);
+        assert_eq!(cpu.call_stack()[1].return_addr, 0x0607);
+        assert_eq!(cpu.call_stack()[1].kind, CallKind::Subroutine);
+
+        // PLA; PLA discards the inner return address
+        cpu.step();
+        assert_eq!(cpu.call_stack().len(), 1);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert!(cpu.call_stack().is_empty());
+
+        // RTS to a pushed address is a jump, not a return
+        cpu.program_counter = 0x0612;
+        for _ in 0..6 {
+            cpu.step();
+        }
+        assert_eq!(cpu.program_counter, 0x0615);
+        assert_eq!(cpu.call_stack().len(), 1);
+        assert_eq!(cpu.call_stack()[0].target, 0x060b);
+
+        cpu.bus.ppu_mut().nmi_interrupt = Some(1);
+        cpu.step();
+        let nmi = cpu.call_stack()[1];
+        assert_eq!(nmi.kind, CallKind::Nmi);
+        assert_eq!(nmi.return_addr, 0x0615);
+
+        cpu.reset();
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_stack_entries() {
+        let bus = Bus::new(test::test_rom());
+        let mut cpu = CPU::new(bus);
+        // JSR sub; sub: LDA #$42; PHA; PHP
+        cpu.load(vec![0x20, 0x03, 0x06, 0xa9, 0x42, 0x48, 0x08]);
+        cpu.program_counter = 0x0600;
+        for _ in 0..4 {
+            cpu.step();
+        }
+        cpu.bus.ppu_mut().nmi_interrupt = Some(1);
+        cpu.step();
+
+        let entries = cpu.stack_entries();
+        let origins: Vec<StackOrigin> = entries.iter().map(|e| e.origin).collect();
+        assert_eq!(
+            origins,
+            vec![
+                StackOrigin::InterruptStatus,
+                StackOrigin::InterruptReturn,
+                StackOrigin::InterruptReturn,
+                StackOrigin::Status,
+                StackOrigin::Accumulator,
+                StackOrigin::ReturnAddress,
+                StackOrigin::ReturnAddress,
+                // below where the stack pointer starts after reset
+                StackOrigin::Unknown,
+                StackOrigin::Unknown,
+            ]
+        );
+        assert_eq!(entries[0].addr, 0x0100 + cpu.stack_pointer as u16 + 1);
+        assert_eq!(entries[4].value, 0x42);
+        assert_eq!((entries[5].value, entries[6].value), (0x02, 0x06));
+    }
+
+    /// Runs one instruction on `a`, with the carry set or not and `m` at $10
+    fn run_alu(program: &[u8], a: u8, m: u8, carry: bool) -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.load(program.to_vec());
+        cpu.program_counter = 0x0600;
+        cpu.register_a = a;
+        cpu.register_x = a;
+        cpu.register_y = a;
+        cpu.status.set(CpuFlags::CARRY, carry);
+        cpu.mem_write(0x10, m);
+        cpu.step();
+        cpu
+    }
+
+    /// The flags an instruction must leave, as (carry, zero, overflow, negative)
+    fn flags(cpu: &CPU) -> (bool, bool, bool, bool) {
+        (
+            cpu.status.contains(CpuFlags::CARRY),
+            cpu.status.contains(CpuFlags::ZERO),
+            cpu.status.contains(CpuFlags::OVERFLOW),
+            cpu.status.contains(CpuFlags::NEGATIV),
+        )
+    }
+
+    /// A shift or rotate of a value, with the carry it starts with
+    type Shift = fn(u8, u8) -> u8;
+
+    /// Any byte, with the ones where carry, overflow and zero change picked
+    /// more often than chance would
+    fn byte() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            prop::sample::select(vec![0x00, 0x01, 0x7f, 0x80, 0x81, 0xfe, 0xff]),
+            any::<u8>(),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_adc_sbc(a in byte(), m in byte(), carry: bool) {
+            let c = carry as i16;
+            // the 6502 subtracts by adding the complement
+            for (opcode, signed_m) in [(0x69, m as i8 as i16), (0xe9, -(m as i8 as i16) - 1)] {
+                let cpu = run_alu(&[opcode, m], a, 0, carry);
+                let unsigned = if opcode == 0x69 {
+                    a as i16 + m as i16 + c
+                } else {
+                    a as i16 - m as i16 - (1 - c) + 0x100
+                };
+                let signed = a as i8 as i16 + signed_m + c;
+                let result = unsigned as u8;
+                prop_assert_eq!(cpu.register_a, result);
+                prop_assert_eq!(
+                    flags(&cpu),
+                    (unsigned > 0xff, result == 0, !(-128..=127).contains(&signed), result >= 0x80)
+                );
+            }
+        }
+
+        #[test]
+        fn test_compare(a in byte(), m in byte(), carry: bool) {
+            // CMP, CPX and CPY immediate and zero page
+            for program in [[0xc9, m], [0xe0, m], [0xc0, m], [0xc5, 0x10], [0xe4, 0x10], [0xc4, 0x10]] {
+                let cpu = run_alu(&program, a, m, carry);
+                let diff = a.wrapping_sub(m);
+                prop_assert_eq!(cpu.register_a, a);
+                prop_assert_eq!(flags(&cpu), (a >= m, a == m, false, diff >= 0x80));
+            }
+        }
+
+        #[test]
+        fn test_shifts(a in byte(), m in byte(), carry: bool) {
+            let c = carry as u8;
+            // (accumulator opcode, zero page opcode, result, carry out) of
+            // ASL, LSR, ROL and ROR
+            let shifts: [(u8, u8, Shift, u8); 4] = [
+                (0x0a, 0x06, |v, _| v << 1, 0x80),
+                (0x4a, 0x46, |v, _| v >> 1, 0x01),
+                (0x2a, 0x26, |v, c| v << 1 | c, 0x80),
+                (0x6a, 0x66, |v, c| v >> 1 | c << 7, 0x01),
+            ];
+            for (accumulator, zero_page, shift, carry_bit) in shifts.iter().copied() {
+                let cpu = run_alu(&[accumulator], a, m, carry);
+                let result = shift(a, c);
+                prop_assert_eq!(cpu.register_a, result);
+                prop_assert_eq!(
+                    flags(&cpu),
+                    (a & carry_bit != 0, result == 0, false, result >= 0x80)
+                );
+
+                let mut cpu = run_alu(&[zero_page, 0x10], a, m, carry);
+                let result = shift(m, c);
+                prop_assert_eq!(cpu.mem_read(0x10), result);
+                prop_assert_eq!(cpu.register_a, a);
+                prop_assert_eq!(
+                    flags(&cpu),
+                    (m & carry_bit != 0, result == 0, false, result >= 0x80)
+                );
+            }
+        }
+    }
 }
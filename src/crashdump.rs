@@ -0,0 +1,268 @@
+// Crash reports for when the core gives up: a fault like an unknown opcode,
+// a write to cartridge ROM or a PPU access it doesn't support, or a panic. The
+// report has what a bug report needs to reproduce the problem: the registers,
+// the instructions leading up to the crash, the stack and the PPU state.
+use crate::cpu::{CallFrame, StackEntry, CPU};
+use crate::disasm;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Instructions kept when no length is given
+pub const DEFAULT_HISTORY_LEN: usize = 64;
+
+/// CPU state right before an instruction ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryEntry {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub scanline: u16,
+    pub dot: usize,
+    pub cycle: usize,
+}
+
+/// The most recently executed instructions, cheap enough to keep recording
+/// all the time
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// To be called before every instruction
+    pub fn record(&mut self, cpu: &CPU) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        let (scanline, dot) = cpu.bus.ppu_position();
+        self.entries.push_back(HistoryEntry {
+            pc: cpu.program_counter,
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            p: cpu.status.bits(),
+            sp: cpu.stack_pointer,
+            scanline,
+            dot,
+            cycle: cpu.bus.cycles(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY_LEN)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    pub reason: String,
+    pub program_counter: u16,
+    pub registers: [u8; 3],
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub cycles: usize,
+    pub frame: usize,
+    pub scanline: u16,
+    pub dot: usize,
+    pub ppu_ctrl: u8,
+    pub ppu_mask: u8,
+    pub ppu_status: u8,
+    /// Recent instructions as trace lines, the one that crashed last
+    pub history: Vec<String>,
+    pub stack: Vec<StackEntry>,
+    pub call_stack: Vec<CallFrame>,
+}
+
+impl CrashReport {
+    /// Snapshots the machine as the crash left it
+    pub fn capture(reason: &str, cpu: &CPU, history: &History) -> Self {
+        let ppu = cpu.bus.ppu();
+        let (scanline, dot) = cpu.bus.ppu_position();
+        let history = history
+            .entries()
+            .map(|entry| {
+                // decoded now rather than while recording, code in RAM may
+                // have changed since
+                let ins = disasm::disassemble_one(&cpu.bus, entry.pc);
+                let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!(
+                    "{:04X}  {:8}  {:<14} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                    entry.pc,
+                    bytes.join(" "),
+                    ins.to_string(),
+                    entry.a,
+                    entry.x,
+                    entry.y,
+                    entry.p,
+                    entry.sp,
+                    entry.scanline,
+                    entry.dot,
+                    entry.cycle
+                )
+            })
+            .collect();
+        CrashReport {
+            reason: reason.to_string(),
+            program_counter: cpu.program_counter,
+            registers: [cpu.register_a, cpu.register_x, cpu.register_y],
+            status: cpu.status.bits(),
+            stack_pointer: cpu.stack_pointer,
+            cycles: cpu.bus.cycles(),
+            frame: cpu.bus.frame_count(),
+            scanline,
+            dot,
+            ppu_ctrl: ppu.ctrl.bits(),
+            ppu_mask: ppu.mask.bits(),
+            ppu_status: ppu.status.bits(),
+            history,
+            stack: cpu.stack_entries(),
+            call_stack: cpu.call_stack().to_vec(),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_string())
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Emulator crashed: {}", self.reason)?;
+        writeln!(f)?;
+        writeln!(f, "CPU")?;
+        writeln!(
+            f,
+            "  PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.program_counter,
+            self.registers[0],
+            self.registers[1],
+            self.registers[2],
+            self.status,
+            self.stack_pointer,
+            self.cycles
+        )?;
+        writeln!(f, "PPU")?;
+        writeln!(
+            f,
+            "  frame {} line {} dot {} CTRL:{:02X} MASK:{:02X} STATUS:{:02X}",
+            self.frame, self.scanline, self.dot, self.ppu_ctrl, self.ppu_mask, self.ppu_status
+        )?;
+        writeln!(f, "Last {} instructions", self.history.len())?;
+        for line in &self.history {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "Call stack")?;
+        for frame in self.call_stack.iter().rev() {
+            writeln!(
+                f,
+                "  ${:04X} {:?} from ${:04X}",
+                frame.target, frame.kind, frame.call_site
+            )?;
+        }
+        writeln!(f, "Stack")?;
+        for entry in &self.stack {
+            writeln!(
+                f,
+                "  {:04X}  {:02X}  {}",
+                entry.addr,
+                entry.value,
+                entry.origin.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `f`, turning a panic into its message so a crash report can be made
+/// from whatever state the machine was left in
+pub fn catch_panic<R, F: FnOnce() -> R>(f: F) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message)
+}
+
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "emulator panicked".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+
+    #[test]
+    fn test_crash_report() {
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "LDX #$42
+                 JSR write
+                 write: PHA
+                 STA $8000",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        let mut history = History::new(3);
+
+        cpu.run_with_callback(|cpu| history.record(cpu));
+        let reason = cpu.bus.take_fault().unwrap().to_string();
+        assert!(reason.contains("Cartridge ROM"));
+
+        let report = CrashReport::capture(&reason, &cpu, &history);
+        assert_eq!(report.registers[1], 0x42);
+        assert_eq!(report.history.len(), 3);
+        assert!(report.history[2].starts_with("8006  8D 00 80  STA $8000"));
+        assert_eq!(report.call_stack[0].target, 0x8005);
+        assert_eq!(report.stack[0].origin.name(), "PHA");
+
+        let text = report.to_string();
+        assert!(text.starts_with("Emulator crashed: Attempt to write 00 to Cartridge ROM"));
+        assert!(text.contains("Last 3 instructions\n  8002  20 05 80  JSR $8005"));
+        assert!(text.contains("  $8005 Subroutine from $8002"));
+    }
+}
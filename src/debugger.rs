@@ -0,0 +1,318 @@
+use crate::cpu::CPU;
+use crate::expr::Expr;
+use std::collections::{BTreeSet, HashMap};
+
+const JSR: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    /// A single instruction was executed
+    Step,
+    /// Execution reached an address with a breakpoint
+    Breakpoint(u16),
+    /// The CPU executed BRK
+    Halted,
+}
+
+/// Expression the frontend shows next to the code, re-evaluated each time
+/// `Debugger::update_watches` is called
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub text: String,
+    pub expr: Expr,
+    pub value: i64,
+    /// Whether the value differs from the one before the last update
+    pub changed: bool,
+}
+
+/// Execution control shared by the debugging frontends
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    conditions: HashMap<u16, Expr>,
+    watches: Vec<Watch>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            conditions: HashMap::new(),
+            watches: Vec::new(),
+        }
+    }
+
+    /// Returns false if there already was a breakpoint at that address
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Sets a breakpoint that only stops when `condition` evaluates to true,
+    /// replacing the condition of an existing breakpoint at that address
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, condition: Expr) {
+        self.breakpoints.insert(addr);
+        self.conditions.insert(addr, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.conditions.remove(&addr);
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn condition(&self, addr: u16) -> Option<&Expr> {
+        self.conditions.get(&addr)
+    }
+
+    /// Whether the instruction at PC has a breakpoint whose condition holds
+    pub fn should_stop(&self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter;
+        self.has_breakpoint(pc) && self.conditions.get(&pc).is_none_or(|c| c.is_true(cpu))
+    }
+
+    pub fn has_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watch(&mut self, text: &str, cpu: &CPU) -> Result<(), String> {
+        let expr = Expr::parse(text)?;
+        let value = expr.eval(cpu);
+        self.watches.push(Watch {
+            text: text.trim().to_string(),
+            expr,
+            value,
+            changed: false,
+        });
+        Ok(())
+    }
+
+    pub fn remove_watch(&mut self, index: usize) -> bool {
+        if index < self.watches.len() {
+            self.watches.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /// Re-evaluates the watches, to be called whenever execution stops or
+    /// once per frame while running
+    pub fn update_watches(&mut self, cpu: &CPU) {
+        for watch in &mut self.watches {
+            let value = watch.expr.eval(cpu);
+            watch.changed = value != watch.value;
+            watch.value = value;
+        }
+    }
+
+    pub fn step(&mut self, cpu: &mut CPU) -> StopReason {
+        if cpu.step() {
+            StopReason::Step
+        } else {
+            StopReason::Halted
+        }
+    }
+
+    /// Like `step`, but runs a JSR until the subroutine returns. Stops early on
+    /// breakpoints inside the subroutine, returns `None` if it takes more than
+    /// `max_instructions`
+    pub fn step_over(&mut self, cpu: &mut CPU, max_instructions: usize) -> Option<StopReason> {
+        if cpu.bus.peek(cpu.program_counter) != JSR {
+            return Some(self.step(cpu));
+        }
+        // a temporary breakpoint at the return address that only counts once the
+        // return address was popped, so recursive calls don't stop early
+        let return_addr = cpu.program_counter.wrapping_add(3);
+        let stack_pointer = cpu.stack_pointer;
+        self.run_until(cpu, max_instructions, |cpu| {
+            cpu.program_counter == return_addr && cpu.stack_pointer >= stack_pointer
+        })
+    }
+
+    /// Runs until the innermost subroutine or interrupt handler on the call
+    /// stack returns. Outside of any call this is a single step
+    pub fn step_out(&mut self, cpu: &mut CPU, max_instructions: usize) -> Option<StopReason> {
+        let frame = match cpu.call_stack().last() {
+            Some(frame) => *frame,
+            None => return Some(self.step(cpu)),
+        };
+        self.run_until(cpu, max_instructions, |cpu| {
+            cpu.stack_pointer > frame.stack_pointer
+        })
+    }
+
+    /// Runs until a breakpoint is hit or `max_instructions` were executed, in which
+    /// case `None` is returned so the caller can check for user interrupts and resume.
+    /// A breakpoint at the current PC doesn't stop the first instruction
+    pub fn resume(&mut self, cpu: &mut CPU, max_instructions: usize) -> Option<StopReason> {
+        self.run_until(cpu, max_instructions, |_| false)
+    }
+
+    /// `resume` that also stops with `StopReason::Step` once `done` holds
+    fn run_until<F: Fn(&CPU) -> bool>(
+        &mut self,
+        cpu: &mut CPU,
+        max_instructions: usize,
+        done: F,
+    ) -> Option<StopReason> {
+        for _ in 0..max_instructions {
+            if !cpu.step() {
+                return Some(StopReason::Halted);
+            }
+            if self.should_stop(cpu) {
+                return Some(StopReason::Breakpoint(cpu.program_counter));
+            }
+            if done(cpu) {
+                return Some(StopReason::Step);
+            }
+        }
+        None
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_resume_stops_at_breakpoint() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        let mut debugger = Debugger::new();
+        assert!(debugger.add_breakpoint(0x0603));
+        assert!(!debugger.add_breakpoint(0x0603));
+
+        assert_eq!(
+            debugger.resume(&mut cpu, 100),
+            Some(StopReason::Breakpoint(0x0603))
+        );
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(
+            debugger.resume(&mut cpu, 100),
+            Some(StopReason::Breakpoint(0x0603))
+        );
+        assert_eq!(cpu.register_x, 2);
+
+        assert!(debugger.remove_breakpoint(0x0603));
+        assert_eq!(debugger.resume(&mut cpu, 10), None);
+        assert_eq!(debugger.step(&mut cpu), StopReason::Step);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        let mut debugger = Debugger::new();
+        debugger.add_conditional_breakpoint(0x0603, Expr::parse("x == 3").unwrap());
+        assert_eq!(
+            debugger.resume(&mut cpu, 100),
+            Some(StopReason::Breakpoint(0x0603))
+        );
+        assert_eq!(cpu.register_x, 3);
+        assert!(debugger.condition(0x0603).is_some());
+
+        debugger.add_conditional_breakpoint(0x0603, Expr::parse("x >= 5 && x % 2 == 0").unwrap());
+        debugger.resume(&mut cpu, 100);
+        assert_eq!(cpu.register_x, 6);
+
+        debugger.remove_breakpoint(0x0603);
+        assert!(debugger.condition(0x0603).is_none());
+    }
+
+    #[test]
+    fn test_watches() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        let mut debugger = Debugger::new();
+        debugger.add_watch("x * 2", &cpu).unwrap();
+        debugger.add_watch(" oam[0].y ", &cpu).unwrap();
+        assert!(debugger.add_watch("x *", &cpu).is_err());
+
+        debugger.step(&mut cpu);
+        debugger.step(&mut cpu);
+        debugger.update_watches(&cpu);
+        let watches = debugger.watches();
+        assert_eq!((watches[0].value, watches[0].changed), (2, true));
+        assert_eq!((watches[1].value, watches[1].changed), (0, false));
+        assert_eq!(watches[1].text, "oam[0].y");
+
+        debugger.update_watches(&cpu);
+        assert!(!debugger.watches()[0].changed);
+        assert!(debugger.remove_watch(0));
+        assert!(!debugger.remove_watch(1));
+    }
+
+    #[test]
+    fn test_step_over_runs_subroutine() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // JSR sub; NOP; ... sub: INX; INX; RTS
+        cpu.load(vec![0x20, 0x05, 0x06, 0xea, 0x00, 0xe8, 0xe8, 0x60]);
+        cpu.program_counter = 0x0600;
+
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 2);
+        assert!(!debugger.has_breakpoint(0x0603));
+
+        assert_eq!(debugger.step_over(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x0604);
+    }
+
+    #[test]
+    fn test_step_out_returns_to_caller() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // JSR outer; NOP; BRK; outer: JSR inner; RTS; inner: INX; INX; RTS
+        cpu.load(vec![
+            0x20, 0x05, 0x06, 0xea, 0x00, 0x20, 0x09, 0x06, 0x60, 0xe8, 0xe8, 0x60,
+        ]);
+        cpu.program_counter = 0x0600;
+
+        let mut debugger = Debugger::new();
+        debugger.step(&mut cpu);
+        debugger.step(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x0609);
+        assert_eq!(debugger.step_out(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0608, 2));
+        assert_eq!(debugger.step_out(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x0603);
+        // nothing left to return from
+        assert_eq!(debugger.step_out(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter, 0x0604);
+    }
+
+    #[test]
+    fn test_resume_stops_on_brk() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.mem_write(0x0600, 0x00);
+        cpu.program_counter = 0x0600;
+        assert_eq!(
+            Debugger::new().resume(&mut cpu, 10),
+            Some(StopReason::Halted)
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::bus::Bus;
+use crate::cpu::{AddressingMode, CPU};
+use crate::labels::Labels;
+use crate::opcodes;
+use std::fmt;
+
+const JMP_INDIRECT: u8 = 0x6c;
+const DATA_BYTE: &str = ".db";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// ".db" for bytes that are not a known opcode
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    /// Operand as written in assembly, e.g. "#$01", "($33),Y" or "A"
+    pub operand: String,
+    /// Address the operand refers to when it is known without running the code:
+    /// branch and jump destinations and non-indexed memory operands
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.size())
+    }
+
+    pub fn is_jmp_indirect(&self) -> bool {
+        self.bytes[0] == JMP_INDIRECT
+    }
+
+    /// Address the instruction would access with the current register values.
+    /// Reads the pointers for indirect modes without side effects
+    pub fn effective_address(&self, cpu: &CPU) -> Option<u16> {
+        self.raw().effective_address(cpu)
+    }
+
+    /// Operand with its address replaced by a label, e.g. "JMP Reset"
+    pub fn labeled_operand(&self, labels: &Labels, bus: &Bus) -> String {
+        let raw = self.raw();
+        match raw.label(labels, bus) {
+            Some(name) => {
+                let mut operand = String::new();
+                let _ = raw.write_operand(&mut operand, Some(name));
+                operand
+            }
+            None => self.operand.clone(),
+        }
+    }
+
+    /// `to_string` with labels substituted
+    pub fn to_labeled_string(&self, labels: &Labels, bus: &Bus) -> String {
+        let operand = self.labeled_operand(labels, bus);
+        if operand.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, operand)
+        }
+    }
+
+    fn raw(&self) -> RawInstruction {
+        let mut bytes = [0; 3];
+        let len = self.bytes.len().min(3);
+        bytes[..len].copy_from_slice(&self.bytes[..len]);
+        RawInstruction {
+            addr: self.addr,
+            bytes,
+            len: len as u8,
+            mnemonic: self.mnemonic,
+            mode: self.mode,
+            target: self.target,
+        }
+    }
+}
+
+impl From<RawInstruction> for Instruction {
+    fn from(raw: RawInstruction) -> Self {
+        let mut operand = String::new();
+        let _ = raw.write_operand(&mut operand, None);
+        Instruction {
+            addr: raw.addr,
+            bytes: raw.bytes().to_vec(),
+            mnemonic: raw.mnemonic,
+            mode: raw.mode,
+            operand,
+            target: raw.target,
+        }
+    }
+}
+
+/// An instruction decoded without allocating: the bytes are kept inline and
+/// the operand is written out on demand. The tracer decodes one of these for
+/// every instruction executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawInstruction {
+    pub addr: u16,
+    bytes: [u8; 3],
+    len: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    /// Same as `Instruction::target`
+    pub target: Option<u16>,
+}
+
+impl RawInstruction {
+    /// Decodes the instruction at `addr` without side effects
+    pub fn decode(bus: &Bus, addr: u16) -> RawInstruction {
+        let code = bus.peek(addr);
+        let op = match opcodes::lookup(code) {
+            Some(op) => op,
+            None => return RawInstruction::data_byte(bus, addr),
+        };
+
+        let mut bytes = [0; 3];
+        for (i, byte) in bytes.iter_mut().enumerate().take(op.len as usize) {
+            *byte = bus.peek(addr.wrapping_add(i as u16));
+        }
+        let lo = bytes[1];
+        let word = u16::from_le_bytes([bytes[1], bytes[2]]);
+        let target = match (op.mode, op.len) {
+            (AddressingMode::ZeroPage, _) => Some(lo as u16),
+            (AddressingMode::Absolute, _) => Some(word),
+            // relative branches
+            (AddressingMode::NoneAddressing, 2) => {
+                Some(addr.wrapping_add(2).wrapping_add(lo as i8 as u16))
+            }
+            (AddressingMode::NoneAddressing, 3) if code == JMP_INDIRECT => {
+                Some(peek_jmp_indirect(bus, word))
+            }
+            (AddressingMode::NoneAddressing, 3) => Some(word),
+            _ => None,
+        };
+
+        RawInstruction {
+            addr,
+            bytes,
+            len: op.len,
+            mnemonic: op.mnemonic,
+            mode: op.mode,
+            target,
+        }
+    }
+
+    /// The byte at `addr` as a `.db` directive
+    pub fn data_byte(bus: &Bus, addr: u16) -> RawInstruction {
+        RawInstruction {
+            addr,
+            bytes: [bus.peek(addr), 0, 0],
+            len: 1,
+            mnemonic: DATA_BYTE,
+            mode: AddressingMode::NoneAddressing,
+            target: None,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn is_jmp_indirect(&self) -> bool {
+        self.bytes[0] == JMP_INDIRECT && self.mnemonic != DATA_BYTE
+    }
+
+    /// False for implied instructions like "INX"
+    pub fn has_operand(&self) -> bool {
+        self.mnemonic == DATA_BYTE
+            || self.len > 1
+            || matches!(self.bytes[0], 0x0a | 0x4a | 0x2a | 0x6a)
+    }
+
+    /// See `Instruction::effective_address`
+    pub fn effective_address(&self, cpu: &CPU) -> Option<u16> {
+        let lo = self.bytes[1];
+        let word = self.word();
+        let bus = &cpu.bus;
+        match self.mode {
+            AddressingMode::ZeroPage => Some(lo as u16),
+            AddressingMode::ZeroPage_X => Some(lo.wrapping_add(cpu.register_x) as u16),
+            AddressingMode::ZeroPage_Y => Some(lo.wrapping_add(cpu.register_y) as u16),
+            AddressingMode::Absolute => Some(word),
+            AddressingMode::Absolute_X => Some(word.wrapping_add(cpu.register_x as u16)),
+            AddressingMode::Absolute_Y => Some(word.wrapping_add(cpu.register_y as u16)),
+            AddressingMode::Indirect_X => {
+                Some(peek_zero_page_u16(bus, lo.wrapping_add(cpu.register_x)))
+            }
+            AddressingMode::Indirect_Y => {
+                Some(peek_zero_page_u16(bus, lo).wrapping_add(cpu.register_y as u16))
+            }
+            AddressingMode::Immediate | AddressingMode::NoneAddressing => None,
+        }
+    }
+
+    /// Label for the address written in the operand
+    pub fn label<'a>(&self, labels: &'a Labels, bus: &Bus) -> Option<&'a str> {
+        labels.get(bus, self.operand_addr()?)
+    }
+
+    /// Writes the operand as in assembly, with `name` in place of the address
+    pub fn write_operand<W: fmt::Write>(&self, out: &mut W, name: Option<&str>) -> fmt::Result {
+        let lo = self.bytes[1];
+        let zero_page = |out: &mut W| match name {
+            Some(name) => out.write_str(name),
+            None => write!(out, "${:02X}", lo),
+        };
+        let absolute = |out: &mut W, addr: u16| match name {
+            Some(name) => out.write_str(name),
+            None => write!(out, "${:04X}", addr),
+        };
+
+        if self.mnemonic == DATA_BYTE {
+            return write!(out, "${:02X}", self.bytes[0]);
+        }
+        match (self.mode, self.len) {
+            (AddressingMode::Immediate, _) => write!(out, "#${:02X}", lo),
+            (AddressingMode::ZeroPage, _) => zero_page(out),
+            (AddressingMode::ZeroPage_X, _) => {
+                zero_page(out)?;
+                out.write_str(",X")
+            }
+            (AddressingMode::ZeroPage_Y, _) => {
+                zero_page(out)?;
+                out.write_str(",Y")
+            }
+            (AddressingMode::Absolute, _) => absolute(out, self.word()),
+            (AddressingMode::Absolute_X, _) => {
+                absolute(out, self.word())?;
+                out.write_str(",X")
+            }
+            (AddressingMode::Absolute_Y, _) => {
+                absolute(out, self.word())?;
+                out.write_str(",Y")
+            }
+            (AddressingMode::Indirect_X, _) => {
+                out.write_char('(')?;
+                zero_page(out)?;
+                out.write_str(",X)")
+            }
+            (AddressingMode::Indirect_Y, _) => {
+                out.write_char('(')?;
+                zero_page(out)?;
+                out.write_str("),Y")
+            }
+            (AddressingMode::NoneAddressing, 1) if self.has_operand() => out.write_char('A'),
+            (AddressingMode::NoneAddressing, 1) => Ok(()),
+            (AddressingMode::NoneAddressing, 2) => absolute(out, self.target.unwrap_or(0)),
+            (AddressingMode::NoneAddressing, _) if self.is_jmp_indirect() => {
+                out.write_char('(')?;
+                absolute(out, self.word())?;
+                out.write_char(')')
+            }
+            (AddressingMode::NoneAddressing, _) => absolute(out, self.word()),
+        }
+    }
+
+    /// Address as written in the operand, before indexing
+    fn operand_addr(&self) -> Option<u16> {
+        match (self.mode, self.len) {
+            _ if self.mnemonic == DATA_BYTE => None,
+            (AddressingMode::ZeroPage, _)
+            | (AddressingMode::ZeroPage_X, _)
+            | (AddressingMode::ZeroPage_Y, _)
+            | (AddressingMode::Indirect_X, _)
+            | (AddressingMode::Indirect_Y, _) => Some(self.bytes[1] as u16),
+            (AddressingMode::Absolute, _)
+            | (AddressingMode::Absolute_X, _)
+            | (AddressingMode::Absolute_Y, _)
+            | (AddressingMode::NoneAddressing, 3) => Some(self.word()),
+            // relative branches
+            (AddressingMode::NoneAddressing, 2) => self.target,
+            _ => None,
+        }
+    }
+
+    fn word(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[1], self.bytes[2]])
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+/// Decodes the instruction at `addr` without side effects
+pub fn disassemble_one(bus: &Bus, addr: u16) -> Instruction {
+    RawInstruction::decode(bus, addr).into()
+}
+
+/// The byte at `addr` as a `.db` directive
+pub fn data_byte(bus: &Bus, addr: u16) -> Instruction {
+    RawInstruction::data_byte(bus, addr).into()
+}
+
+/// Decodes `count` consecutive instructions starting at `addr`
+pub fn disassemble(bus: &Bus, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut result = Vec::with_capacity(count);
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = disassemble_one(bus, addr);
+        addr = instruction.next_addr();
+        result.push(instruction);
+    }
+    result
+}
+
+fn peek_zero_page_u16(bus: &Bus, ptr: u8) -> u16 {
+    u16::from_le_bytes([bus.peek(ptr as u16), bus.peek(ptr.wrapping_add(1) as u16)])
+}
+
+/// JMP ($xxFF) fetches the high byte from $xx00 instead of crossing the page
+fn peek_jmp_indirect(bus: &Bus, ptr: u16) -> u16 {
+    let hi_addr = (ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF);
+    u16::from_le_bytes([bus.peek(ptr), bus.peek(hi_addr)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_disassemble() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop; BNE loop; unofficial NOP
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06, 0xd0, 0xfa, 0x02]);
+
+        let listing: Vec<String> = disassemble(&cpu.bus, 0x0600, 5)
+            .iter()
+            .map(|i| format!("{:04X} {}", i.addr, i))
+            .collect();
+        assert_eq!(
+            listing,
+            vec![
+                "0600 LDX #$00",
+                "0602 INX",
+                "0603 JMP $0602",
+                "0606 BNE $0602",
+                "0608 *NOP",
+            ]
+        );
+
+        let jmp = disassemble_one(&cpu.bus, 0x0603);
+        assert_eq!(jmp.bytes, vec![0x4c, 0x02, 0x06]);
+        assert_eq!(jmp.target, Some(0x0602));
+        assert_eq!(jmp.next_addr(), 0x0606);
+    }
+
+    #[test]
+    fn test_labeled_operands() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: LDA ($10),Y; BNE loop; JMP ($0300); LDA #$10
+        cpu.load(vec![0xb1, 0x10, 0xd0, 0xfc, 0x6c, 0x00, 0x03, 0xa9, 0x10]);
+        let mut labels = Labels::new();
+        labels.add(0x0600, "loop");
+        labels.add(0x0010, "ptr");
+        labels.add(0x0300, "vector");
+
+        let listing: Vec<String> = disassemble(&cpu.bus, 0x0600, 4)
+            .iter()
+            .map(|i| i.to_labeled_string(&labels, &cpu.bus))
+            .collect();
+        assert_eq!(
+            listing,
+            vec!["LDA (ptr),Y", "BNE loop", "JMP (vector)", "LDA #$10"]
+        );
+    }
+
+    #[test]
+    fn test_effective_address() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDA ($33),Y; JMP ($02FF)
+        cpu.load(vec![0xb1, 0x33, 0x6c, 0xff, 0x02]);
+        cpu.mem_write(0x33, 0x00);
+        cpu.mem_write(0x34, 0x04);
+        cpu.mem_write(0x02ff, 0x34);
+        cpu.mem_write(0x0200, 0x12);
+        cpu.register_y = 2;
+
+        let lda = disassemble_one(&cpu.bus, 0x0600);
+        assert_eq!(lda.to_string(), "LDA ($33),Y");
+        assert_eq!(lda.effective_address(&cpu), Some(0x0402));
+        assert_eq!(lda.target, None);
+
+        let jmp = disassemble_one(&cpu.bus, 0x0602);
+        assert!(jmp.is_jmp_indirect());
+        assert_eq!(jmp.target, Some(0x1234));
+    }
+}
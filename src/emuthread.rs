@@ -0,0 +1,227 @@
+// Emulation on a thread of its own, so the UI thread stays responsive while
+// a frame is emulated and emulation keeps its pace while the UI is busy.
+//
+// The thread owns the `Nes`. The UI sends it commands over a channel (input,
+// reset, pause, savestates) and presents the frames it publishes through a
+// triple buffer, see render/frame.rs. Audio takes the sample queue of
+// audio.rs, which is built for this split. Commands are handled between
+// frames, in the order they were sent. Stopping the thread hands the console
+// back, or the error that stopped emulation.
+use crate::audio::SampleProducer;
+use crate::error::EmuError;
+use crate::joypad::Button;
+use crate::nes::Nes;
+use crate::pacer::FramePacer;
+use crate::render::frame::{self, Frame, FrameReader, FrameWriter};
+use crate::render::renderer::Renderer;
+use crate::savestate::SaveState;
+use std::panic;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+enum Command {
+    SetButtons(usize, Button),
+    Reset,
+    Pause(bool),
+    SaveState(Sender<SaveState>),
+    LoadState(Box<SaveState>, Sender<Result<(), String>>),
+    Stop,
+}
+
+pub struct EmuThread {
+    commands: Sender<Command>,
+    frames: FrameReader,
+    handle: JoinHandle<Result<Nes, EmuError>>,
+}
+
+impl EmuThread {
+    /// Starts emulating `nes`, at the pacer's frame rate or, without one, as
+    /// fast as the host allows
+    pub fn spawn(nes: Nes, pacer: Option<FramePacer>) -> Self {
+        EmuThread::start(nes, pacer, None)
+    }
+
+    /// `spawn`, with every frame's audio pushed into `samples`
+    pub fn spawn_with_audio(nes: Nes, pacer: Option<FramePacer>, samples: SampleProducer) -> Self {
+        EmuThread::start(nes, pacer, Some(samples))
+    }
+
+    fn start(nes: Nes, pacer: Option<FramePacer>, samples: Option<SampleProducer>) -> Self {
+        let (commands, receiver) = mpsc::channel();
+        let (writer, frames) = frame::triple_buffer();
+        let handle = thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || run(nes, pacer, samples, receiver, writer))
+            .expect("can't start the emulation thread");
+        EmuThread {
+            commands,
+            frames,
+            handle,
+        }
+    }
+
+    /// Sets the buttons held on controller `port` from the next frame on
+    pub fn set_buttons(&self, port: usize, buttons: Button) {
+        self.send(Command::SetButtons(port, buttons));
+    }
+
+    pub fn reset(&self) {
+        self.send(Command::Reset);
+    }
+
+    pub fn pause(&self, paused: bool) {
+        self.send(Command::Pause(paused));
+    }
+
+    /// The state after the frame being emulated, None if emulation stopped
+    pub fn save_state(&self) -> Option<SaveState> {
+        let (reply, result) = mpsc::channel();
+        self.send(Command::SaveState(reply));
+        result.recv().ok()
+    }
+
+    /// Loads the state before the next frame, see `Nes::load_state`
+    pub fn load_state(&self, state: SaveState) -> Result<(), String> {
+        let (reply, result) = mpsc::channel();
+        self.send(Command::LoadState(Box::new(state), reply));
+        result
+            .recv()
+            .unwrap_or_else(|_| Err("Emulation has stopped".to_string()))
+    }
+
+    /// Picks up the latest finished frame, returns false if there is none
+    /// since the last call
+    pub fn update_frame(&mut self) -> bool {
+        self.frames.update()
+    }
+
+    /// The frame picked up last
+    pub fn frame(&self) -> &Frame {
+        self.frames.front()
+    }
+
+    /// Whether emulation stopped on an error, `stop` tells which
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Stops after the frame being emulated and hands the console back
+    pub fn stop(self) -> Result<Nes, EmuError> {
+        self.send(Command::Stop);
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        // a stopped thread reports why in `stop`
+        let _ = self.commands.send(command);
+    }
+}
+
+fn run(
+    mut nes: Nes,
+    mut pacer: Option<FramePacer>,
+    mut samples: Option<SampleProducer>,
+    commands: Receiver<Command>,
+    mut frames: FrameWriter,
+) -> Result<Nes, EmuError> {
+    let mut renderer = Renderer::new();
+    let mut paused = false;
+    loop {
+        // while paused nothing happens until the next command
+        let command = if paused {
+            commands.recv().map_err(|_| TryRecvError::Disconnected)
+        } else {
+            commands.try_recv()
+        };
+        match command {
+            Ok(Command::SetButtons(port, buttons)) => nes.set_buttons(port, buttons),
+            Ok(Command::Reset) => nes.reset(),
+            Ok(Command::Pause(pause)) => paused = pause,
+            Ok(Command::SaveState(reply)) => {
+                let _ = reply.send(nes.save_state());
+            }
+            Ok(Command::LoadState(state, reply)) => {
+                let _ = reply.send(nes.load_state(&state));
+            }
+            Ok(Command::Stop) | Err(TryRecvError::Disconnected) => return Ok(nes),
+            Err(TryRecvError::Empty) => {
+                nes.run_frame()?;
+                nes.render(&mut renderer, frames.back_mut());
+                frames.publish();
+                let apu = nes.cpu_mut().bus.apu_mut();
+                match samples.as_mut() {
+                    Some(samples) => {
+                        apu.push_samples(samples);
+                    }
+                    None => apu.clear_samples(),
+                }
+                if let Some(pacer) = pacer.as_mut() {
+                    pacer.wait();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio;
+    use crate::cartridge::RomBuilder;
+    use crate::error::CpuError;
+
+    #[test]
+    fn test_emulation_thread() {
+        // copies controller 1's A button to $10, halts once $11 is set
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: LDA #$01
+                 STA $4016
+                 LDA #$00
+                 STA $4016
+                 LDA $4016
+                 STA $10
+                 LDA $11
+                 BEQ loop
+                 BRK",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let (producer, mut consumer) = audio::sample_queue(4096);
+        let mut emulation = EmuThread::spawn_with_audio(Nes::new(rom), None, producer);
+        emulation.set_buttons(0, Button::A);
+        let state = loop {
+            let state = emulation.save_state().unwrap();
+            if state.bus.ram[0x10] == 1 {
+                break state;
+            }
+        };
+        while !emulation.update_frame() {}
+        assert_eq!(emulation.frame().pixels().len(), 256 * 240);
+        let mut received = 0;
+        while received < 1000 {
+            received += consumer.pop(&mut [0.0; 64]);
+        }
+
+        emulation.pause(true);
+        let paused = emulation.save_state().unwrap();
+        assert_eq!(emulation.save_state().unwrap(), paused);
+        let nes = emulation.stop().unwrap();
+        assert_eq!(nes.buttons(0), Button::A);
+
+        let mut halted = state;
+        halted.bus.ram[0x11] = 1;
+        let emulation = EmuThread::spawn(nes, None);
+        assert!(emulation.load_state(halted).is_ok());
+        while !emulation.is_finished() {}
+        assert!(matches!(
+            emulation.stop(),
+            Err(EmuError::Cpu(CpuError::Halted { .. }))
+        ));
+    }
+}
@@ -0,0 +1,209 @@
+// A console as an environment for reinforcement learning agents.
+//
+// The interface is the usual reset/step loop: `reset` starts an episode and
+// every `step` holds the agent's buttons on controller 1 for one frame. Each
+// step returns the screen, the bytes of RAM the environment was told to
+// observe (a game's score, lives or positions, found with the RAM search of
+// the debugger) and whether the episode is over, which is when one of the
+// `done_when` conditions holds or after `max_frames` frames.
+//
+// Episodes are reproducible. The seed passed to `reset` fills work RAM the
+// way power on leaves it, with garbage, and is the only source of randomness:
+// the same seed and the same actions give the same episode, on any machine.
+use crate::cartridge::Rom;
+use crate::cpu::Mem;
+use crate::error::EmuError;
+use crate::joypad::Button;
+use crate::nes::Nes;
+use crate::prelude::*;
+use crate::render::frame::Frame;
+use crate::render::renderer::Renderer;
+
+const WORK_RAM_SIZE: u16 = 0x0800;
+
+#[derive(Clone)]
+pub struct Env {
+    rom: Rom,
+    nes: Nes,
+    frame: Frame,
+    renderer: Renderer,
+    observed: Vec<u16>,
+    done_when: Vec<(u16, u8)>,
+    max_frames: Option<usize>,
+    frames: usize,
+}
+
+/// What an agent sees after `reset` or `step`
+#[derive(Debug)]
+pub struct Step<'a> {
+    /// Black until the first step
+    pub frame: &'a Frame,
+    /// The observed bytes, in the order they were added
+    pub observation: Vec<u8>,
+    pub done: bool,
+}
+
+impl Env {
+    /// An environment for the cartridge, `reset` starts the first episode
+    pub fn new(rom: Rom) -> Self {
+        Env {
+            nes: Nes::new(rom.clone()),
+            rom,
+            frame: Frame::new(),
+            renderer: Renderer::new(),
+            observed: Vec::new(),
+            done_when: Vec::new(),
+            max_frames: None,
+            frames: 0,
+        }
+    }
+
+    /// Adds the bytes at `addrs` to every observation
+    pub fn observe(mut self, addrs: impl IntoIterator<Item = u16>) -> Self {
+        self.observed.extend(addrs);
+        self
+    }
+
+    /// Ends episodes once the byte at `addr` is `value`
+    pub fn done_when(mut self, addr: u16, value: u8) -> Self {
+        self.done_when.push((addr, value));
+        self
+    }
+
+    /// Ends episodes after `frames` steps
+    pub fn max_frames(mut self, frames: usize) -> Self {
+        self.max_frames = Some(frames);
+        self
+    }
+
+    /// Powers on a fresh console with work RAM filled from `seed`
+    pub fn reset(&mut self, seed: u64) -> Step<'_> {
+        self.nes = Nes::new(self.rom.clone());
+        let mut random = SplitMix64(seed);
+        for addr in (0..WORK_RAM_SIZE).step_by(8) {
+            for (i, byte) in random.next().to_le_bytes().iter().enumerate() {
+                self.nes.cpu_mut().mem_write(addr + i as u16, *byte);
+            }
+        }
+        self.frames = 0;
+        self.frame = Frame::new();
+        self.observation()
+    }
+
+    /// Holds `action`, controller 1's buttons in RLDUTSBA order, for a frame
+    pub fn step(&mut self, action: u8) -> Result<Step<'_>, EmuError> {
+        self.nes.set_buttons(0, Button::from_bits_truncate(action));
+        self.nes.run_frame()?;
+        self.nes.render(&mut self.renderer, &mut self.frame);
+        self.frames += 1;
+        Ok(self.observation())
+    }
+
+    /// Frames stepped since the last reset
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn nes(&self) -> &Nes {
+        &self.nes
+    }
+
+    fn observation(&self) -> Step<'_> {
+        let bus = &self.nes.cpu().bus;
+        let done = matches!(self.max_frames, Some(max) if self.frames >= max)
+            || self
+                .done_when
+                .iter()
+                .any(|&(addr, value)| bus.peek(addr) == value);
+        Step {
+            frame: &self.frame,
+            observation: self.observed.iter().map(|&addr| bus.peek(addr)).collect(),
+            done,
+        }
+    }
+}
+
+/// The seed's random numbers, small and the same on every platform
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    fn counter_rom() -> Rom {
+        // adds A on controller 1 to $10 once a frame, $11 counts frames up to 5
+        RomBuilder::new()
+            .asm(
+                0x8000,
+                "LDA #$00
+                 STA $10
+                 STA $11
+                 LDA #$80
+                 STA $2000
+                 idle: JMP idle",
+            )
+            .unwrap()
+            .asm(
+                0x9000,
+                "LDA #$01
+                 STA $4016
+                 LDA #$00
+                 STA $4016
+                 LDA $4016
+                 AND #$01
+                 CLC
+                 ADC $10
+                 STA $10
+                 INC $11
+                 RTI",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .nmi_vector(0x9000)
+            .build()
+    }
+
+    #[test]
+    fn test_episode() {
+        let mut env = Env::new(counter_rom())
+            .observe([0x10, 0x11])
+            .done_when(0x11, 5);
+        let step = env.reset(1);
+        assert!(!step.done);
+        for i in 0..4u8 {
+            let step = env.step(Button::A.bits()).unwrap();
+            assert_eq!(step.observation, vec![i + 1, i + 1]);
+            assert!(!step.done);
+        }
+        let step = env.step(Button::B.bits()).unwrap();
+        assert_eq!(step.observation, vec![4, 5]);
+        assert!(step.done);
+
+        let mut env = env.max_frames(2);
+        env.reset(1);
+        assert!(!env.step(0).unwrap().done);
+        assert!(env.step(0).unwrap().done);
+        assert_eq!(env.frames(), 2);
+    }
+
+    #[test]
+    fn test_seeds() {
+        let ram = |seed| {
+            let mut env = Env::new(counter_rom()).observe(0x200..0x220);
+            env.reset(seed).observation
+        };
+        assert_eq!(ram(7), ram(7));
+        assert_ne!(ram(7), ram(8));
+    }
+}
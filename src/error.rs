@@ -0,0 +1,96 @@
+// Errors of the emulation core.
+//
+// Loading a cartridge fails with a `RomError`. Running one never panics on
+// what a game does: an access the core doesn't support, like a write to
+// cartridge ROM or an unknown opcode, is recorded on the bus as a fault, the
+// instruction finishes and `CPU::step` returns false. The frontend takes the
+// fault with `Bus::take_fault` to report it or to carry on. Restoring a state
+// that doesn't fit the console fails with the error of the part it doesn't fit.
+//
+// `EmuError` wraps the three kinds for callers that handle all of them the same.
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum EmuError {
+    #[error(transparent)]
+    Rom(#[from] RomError),
+    #[error(transparent)]
+    Cpu(#[from] CpuError),
+    #[error(transparent)]
+    Ppu(#[from] PpuError),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RomError {
+    #[error("File is not in iNES file format")]
+    NotINes,
+    #[error("NES2.0 format is not supported")]
+    Nes2Unsupported,
+    /// The header promises more PRG and CHR data than the image has
+    #[error("ROM image is {len} bytes, the header needs {expected}")]
+    Truncated { len: usize, expected: usize },
+    #[cfg(feature = "std")]
+    #[error("Unsupported ROM file: {0}")]
+    UnsupportedFile(PathBuf),
+    #[cfg(feature = "std")]
+    #[error("Archive doesn't contain .nes files")]
+    NoRomInArchive,
+    #[cfg(feature = "std")]
+    #[error("Invalid archive: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    /// Reading a ROM file or archive entry, or a battery save
+    #[cfg(feature = "std")]
+    #[error("Can't read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[cfg(feature = "std")]
+    #[error("Can't write {path}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum CpuError {
+    /// The core stops at BRK instead of running the IRQ handler
+    #[error("CPU halted on BRK at ${addr:04X}")]
+    Halted { addr: u16 },
+    #[error("OpCode {code:x} is not recognized at ${addr:04X}")]
+    UnknownOpcode { code: u8, addr: u16 },
+    /// The cartridge's mapper has no register at the address
+    #[error("Attempt to write {value:02X} to Cartridge ROM space: ${addr:04X}")]
+    RomWrite { addr: u16, value: u8 },
+    /// Only RAM, PRG RAM and PRG ROM can be patched
+    #[error("${0:04X} can't be patched")]
+    NotPatchable(u16),
+    #[error(transparent)]
+    State(#[from] StateSizeError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum PpuError {
+    #[error("Attempt to read from write-only PPU address ${0:04X}")]
+    WriteOnlyRead(u16),
+    #[error("Attempt to write {0:02X} to PPU status register")]
+    StatusWrite(u8),
+    #[error(transparent)]
+    State(#[from] StateSizeError),
+}
+
+/// A section of a savestate that doesn't match the size of what it restores
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Malformed savestate: {section} is {len} bytes, expected {expected}")]
+pub struct StateSizeError {
+    pub section: &'static str,
+    pub len: usize,
+    pub expected: usize,
+}
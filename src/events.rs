@@ -0,0 +1,132 @@
+// Event viewer data: register accesses and interrupts stamped with the PPU
+// position they happened at, collected one frame at a time so a frontend can
+// plot them on a scanline by dot grid.
+//
+// Only what the emulator models is recorded. IRQs come from the APU frame
+// counter, the only source there is; there's no sprite 0 hit detection yet,
+// so that doesn't show up.
+use crate::prelude::*;
+
+/// Dots per scanline and scanlines per frame, the size of the event grid
+pub const GRID_WIDTH: usize = 341;
+pub const GRID_HEIGHT: usize = 262;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    RegisterWrite { addr: u16, value: u8 },
+    RegisterRead { addr: u16, value: u8 },
+    Nmi,
+    /// The CPU taking an IRQ, not the line being asserted
+    Irq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub scanline: u16,
+    pub dot: usize,
+    /// CPU cycles since power on
+    pub cycle: usize,
+}
+
+impl Event {
+    /// Color used on the grid, one per register like Mesen does
+    pub fn color(&self) -> (u8, u8, u8) {
+        let addr = match self.kind {
+            EventKind::Nmi => return (0xFF, 0x40, 0x40),
+            EventKind::Irq => return (0xFF, 0xC0, 0x40),
+            EventKind::RegisterWrite { addr, .. } | EventKind::RegisterRead { addr, .. } => addr,
+        };
+        match addr {
+            0x2000 => (0xFF, 0x80, 0x00),
+            0x2001 => (0x80, 0xFF, 0x00),
+            0x2002 => (0x00, 0xC0, 0xFF),
+            0x2003 | 0x2004 | 0x4014 => (0xFF, 0x80, 0xFF),
+            0x2005 => (0xFF, 0xFF, 0x00),
+            0x2006 => (0x00, 0xFF, 0xC0),
+            0x2007 => (0x80, 0x80, 0xFF),
+            0x4016 | 0x4017 => (0xC0, 0xC0, 0xC0),
+            _ => (0x80, 0x80, 0x80),
+        }
+    }
+}
+
+/// Events of the frame in progress and of the last completed one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventLog {
+    current: Vec<Event>,
+    last_frame: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.current.push(event);
+    }
+
+    /// Called by the bus when the PPU wraps around to scanline 0
+    pub fn end_frame(&mut self) {
+        core::mem::swap(&mut self.current, &mut self.last_frame);
+        self.current.clear();
+    }
+
+    pub fn current_frame(&self) -> &[Event] {
+        &self.current
+    }
+
+    /// The complete event list of the previous frame, what the viewer shows
+    pub fn last_frame(&self) -> &[Event] {
+        &self.last_frame
+    }
+}
+
+/// Plots events into an RGB24 image of `GRID_WIDTH` x `GRID_HEIGHT` pixels
+pub fn draw_grid(events: &[Event], frame: &mut [u8]) {
+    for pixel in frame.iter_mut() {
+        *pixel = 0;
+    }
+    for event in events {
+        let (x, y) = (event.dot, event.scanline as usize);
+        if x >= GRID_WIDTH || y >= GRID_HEIGHT {
+            continue;
+        }
+        let (r, g, b) = event.color();
+        let base = (y * GRID_WIDTH + x) * 3;
+        frame[base] = r;
+        frame[base + 1] = g;
+        frame[base + 2] = b;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frames_and_grid() {
+        let mut log = EventLog::new();
+        let event = |scanline, dot| Event {
+            kind: EventKind::RegisterWrite {
+                addr: 0x2000,
+                value: 0x80,
+            },
+            scanline,
+            dot,
+            cycle: 0,
+        };
+        log.push(event(1, 2));
+        log.end_frame();
+        log.push(event(3, 4));
+        assert_eq!(log.last_frame(), &[event(1, 2)]);
+        assert_eq!(log.current_frame(), &[event(3, 4)]);
+
+        let mut frame = vec![0xAA; GRID_WIDTH * GRID_HEIGHT * 3];
+        draw_grid(log.last_frame(), &mut frame);
+        let base = (GRID_WIDTH + 2) * 3;
+        assert_eq!(&frame[base..base + 3], &[0xFF, 0x80, 0x00]);
+        assert_eq!(frame.iter().filter(|&&b| b != 0).count(), 2);
+    }
+}
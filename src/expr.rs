@@ -0,0 +1,450 @@
+// Small expression language for breakpoint conditions, e.g.
+//
+//   a == 0x3F && scanline > 200
+//   read($2002) & $80
+//   word(0x00FD) != pc
+//   oam[0].y < 0xEF
+//
+// Numbers are decimal, or hex with a "0x" or "$" prefix. Values are integers,
+// zero is false and anything else is true. Variables are the CPU registers
+// a, x, y, sp, p and pc, the flags c, z, i, d, v and n, and scanline, dot,
+// cycle and frame. read(ADDR) and word(ADDR) read memory without side effects,
+// oam[N].y, .tile, .attr and .x are the fields of sprite N.
+// Operators, loosest binding first:
+//
+//   ||   &&   == != < <= > >=   | ^ &   + -   * / %   unary ! - ~
+use crate::cpu::{CpuFlags, CPU};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Var {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Flag(CpuFlags),
+    Scanline,
+    Dot,
+    Cycle,
+    Frame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    Invert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// Byte of a sprite's OAM entry, in memory order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OamField {
+    Y,
+    Tile,
+    Attr,
+    X,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Var(Var),
+    Read(Box<Expr>),
+    Word(Box<Expr>),
+    Oam(Box<Expr>, OamField),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected {} in expression", token)),
+        }
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Var(var) => eval_var(*var, cpu),
+            Expr::Read(addr) => cpu.bus.peek(addr.eval(cpu) as u16) as i64,
+            Expr::Word(addr) => {
+                let addr = addr.eval(cpu) as u16;
+                let lo = cpu.bus.peek(addr);
+                let hi = cpu.bus.peek(addr.wrapping_add(1));
+                u16::from_le_bytes([lo, hi]) as i64
+            }
+            Expr::Oam(sprite, field) => {
+                let sprite = (sprite.eval(cpu) as usize) % 64;
+                cpu.bus.ppu().oam_data[sprite * 4 + *field as usize] as i64
+            }
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(cpu);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Invert => !value,
+                }
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => (lhs.is_true(cpu) || rhs.is_true(cpu)) as i64,
+            Expr::Binary(BinOp::And, lhs, rhs) => (lhs.is_true(cpu) && rhs.is_true(cpu)) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let (l, r) = (lhs.eval(cpu), rhs.eval(cpu));
+                match op {
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                    // dividing by zero gives zero rather than stopping the emulator
+                    BinOp::Div => l.checked_div(r).unwrap_or(0),
+                    BinOp::Rem => l.checked_rem(r).unwrap_or(0),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+fn eval_var(var: Var, cpu: &CPU) -> i64 {
+    let (scanline, dot) = cpu.bus.ppu_position();
+    match var {
+        Var::A => cpu.register_a as i64,
+        Var::X => cpu.register_x as i64,
+        Var::Y => cpu.register_y as i64,
+        Var::Sp => cpu.stack_pointer as i64,
+        Var::P => cpu.status.bits() as i64,
+        Var::Pc => cpu.program_counter as i64,
+        Var::Flag(flag) => cpu.status.contains(flag) as i64,
+        Var::Scanline => scanline as i64,
+        Var::Dot => dot as i64,
+        Var::Cycle => cpu.bus.cycles() as i64,
+        Var::Frame => cpu.bus.frame_count() as i64,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Op(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+// longer operators first so "<=" isn't read as "<"
+const OPERATORS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~",
+    "(", ")", "[", "]", ".",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() || c == '$' {
+            let (digits, radix) = if let Some(hex) = rest.strip_prefix('$') {
+                (hex, 16)
+            } else if let Some(hex) = rest.strip_prefix("0x") {
+                (hex, 16)
+            } else {
+                (rest, 10)
+            };
+            let len = digits
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(digits.len());
+            let number = &rest[..rest.len() - digits.len() + len];
+            let value = i64::from_str_radix(&digits[..len], radix)
+                .map_err(|_| format!("Invalid number in expression: {}", number))?;
+            tokens.push(Token::Num(value));
+            rest = &digits[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_ascii_lowercase()));
+            rest = &rest[len..];
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("Unexpected '{}' in expression", c))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consumes the operator if it comes next
+    fn accept(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(next)) if *next == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.accept(op) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' in expression", op))
+        }
+    }
+
+    /// Parses a left associative chain of the given operators
+    fn chain(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        operand: fn(&mut Parser) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut lhs = operand(self)?;
+        'outer: loop {
+            for (text, op) in ops {
+                if self.accept(text) {
+                    let rhs = operand(self)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.chain(&[("||", BinOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.chain(&[("&&", BinOp::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        self.chain(
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            Parser::bitwise,
+        )
+    }
+
+    fn bitwise(&mut self) -> Result<Expr, String> {
+        self.chain(
+            &[
+                ("|", BinOp::BitOr),
+                ("^", BinOp::BitXor),
+                ("&", BinOp::BitAnd),
+            ],
+            Parser::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.chain(&[("+", BinOp::Add), ("-", BinOp::Sub)], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        self.chain(
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for (text, op) in &[
+            ("!", UnaryOp::Not),
+            ("-", UnaryOp::Neg),
+            ("~", UnaryOp::Invert),
+        ] {
+            if self.accept(text) {
+                return Ok(Expr::Unary(*op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Op("(")) => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "read" | "word" => {
+                    self.expect("(")?;
+                    let addr = Box::new(self.or()?);
+                    self.expect(")")?;
+                    Ok(if name == "read" {
+                        Expr::Read(addr)
+                    } else {
+                        Expr::Word(addr)
+                    })
+                }
+                "oam" => {
+                    self.expect("[")?;
+                    let sprite = Box::new(self.or()?);
+                    self.expect("]")?;
+                    self.expect(".")?;
+                    let field = match self.next() {
+                        Some(Token::Ident(field)) => match field.as_str() {
+                            "y" => OamField::Y,
+                            "tile" => OamField::Tile,
+                            "attr" => OamField::Attr,
+                            "x" => OamField::X,
+                            _ => return Err(format!("Unknown sprite field: {}", field)),
+                        },
+                        _ => return Err("Sprite field expected after 'oam[N].'".to_string()),
+                    };
+                    Ok(Expr::Oam(sprite, field))
+                }
+                _ => variable(&name)
+                    .map(Expr::Var)
+                    .ok_or_else(|| format!("Unknown variable in expression: {}", name)),
+            },
+            Some(token) => Err(format!("Unexpected {} in expression", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn variable(name: &str) -> Option<Var> {
+    let var = match name {
+        "a" => Var::A,
+        "x" => Var::X,
+        "y" => Var::Y,
+        "sp" => Var::Sp,
+        "p" => Var::P,
+        "pc" => Var::Pc,
+        "c" => Var::Flag(CpuFlags::CARRY),
+        "z" => Var::Flag(CpuFlags::ZERO),
+        "i" => Var::Flag(CpuFlags::INTERRUPT_DISABLE),
+        "d" => Var::Flag(CpuFlags::DECIMAL_MODE),
+        "v" => Var::Flag(CpuFlags::OVERFLOW),
+        "n" => Var::Flag(CpuFlags::NEGATIV),
+        "scanline" => Var::Scanline,
+        "dot" => Var::Dot,
+        "cycle" => Var::Cycle,
+        "frame" => Var::Frame,
+        _ => return None,
+    };
+    Some(var)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    fn eval(text: &str, cpu: &CPU) -> i64 {
+        Expr::parse(text).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.register_a = 0x3f;
+        cpu.mem_write(0xfd, 0x34);
+        cpu.mem_write(0xfe, 0x12);
+
+        assert_eq!(eval("A == 0x3F", &cpu), 1);
+        assert_eq!(eval("a == $3f && scanline > 200", &cpu), 0);
+        assert_eq!(eval("a == 1 || scanline < 200", &cpu), 1);
+        assert_eq!(eval("word(0x00FD)", &cpu), 0x1234);
+        assert_eq!(eval("read($fd + 1) * 2", &cpu), 0x24);
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2", &cpu), 5);
+        assert_eq!(eval("(1 + 2) * 3 % 5", &cpu), 4);
+        assert_eq!(eval("a & $0f | $80", &cpu), 0x8f);
+        assert_eq!(eval("!z && -1 < 0 && ~0 == -1", &cpu), 1);
+        assert_eq!(eval("i", &cpu), 1);
+        assert_eq!(eval("10 / 0", &cpu), 0);
+
+        cpu.bus.ppu_mut().oam_data[4..8].copy_from_slice(&[0x20, 0x01, 0x42, 0x80]);
+        assert_eq!(eval("oam[1].y", &cpu), 0x20);
+        assert_eq!(eval("oam[a - $3e].attr + oam[1].x", &cpu), 0xc2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("a ==").is_err());
+        assert!(Expr::parse("(a").is_err());
+        assert!(Expr::parse("foo > 1").is_err());
+        assert!(Expr::parse("a # 1").is_err());
+        assert!(Expr::parse("0xZZ").is_err());
+        assert!(Expr::parse("a 1").is_err());
+        assert!(Expr::parse("oam[0].z").is_err());
+        assert!(Expr::parse("oam[0]").is_err());
+    }
+}
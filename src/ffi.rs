@@ -0,0 +1,219 @@
+// C API for hosts written in other languages, see include/nes_book_emu.h.
+//
+// A host creates a console from a ROM image in memory, gets back an opaque
+// pointer and passes it to every other call. Functions that can fail return 0
+// on success and -1 on failure, with a message for `nes_last_error`. Panics
+// don't cross the boundary: a console that panicked reports it as an error
+// and should be destroyed. Pointers from the host must be valid for the
+// lengths passed along with them, null pointers are refused.
+use crate::cartridge::Rom;
+use crate::joypad::Button;
+use crate::nes::Nes;
+use crate::render::frame::Frame;
+use crate::render::renderer::Renderer;
+use crate::savestate::SaveState;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+pub struct NesConsole {
+    nes: Nes,
+    frame: Frame,
+    renderer: Renderer,
+    error: CString,
+}
+
+impl NesConsole {
+    fn fail(&mut self, message: &str) -> c_int {
+        self.error = CString::new(message.replace('\0', "")).unwrap_or_default();
+        -1
+    }
+}
+
+/// A console with the iNES image of `len` bytes at `rom` inserted and powered
+/// on, or null when the image can't be loaded
+///
+/// # Safety
+/// `rom` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn nes_create(rom: *const u8, len: usize) -> *mut NesConsole {
+    if rom.is_null() {
+        return ptr::null_mut();
+    }
+    let image = slice::from_raw_parts(rom, len).to_vec();
+    match Rom::new(&image) {
+        Ok(rom) => Box::into_raw(Box::new(NesConsole {
+            nes: Nes::new(rom),
+            frame: Frame::new(),
+            renderer: Renderer::new(),
+            error: CString::default(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `console` must come from `nes_create` and not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn nes_destroy(console: *mut NesConsole) {
+    if !console.is_null() {
+        drop(Box::from_raw(console));
+    }
+}
+
+/// # Safety
+/// `console` must come from `nes_create`
+#[no_mangle]
+pub unsafe extern "C" fn nes_run_frame(console: *mut NesConsole) -> c_int {
+    let console = match console.as_mut() {
+        Some(console) => console,
+        None => return -1,
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| console.nes.run_frame())) {
+        Ok(Ok(())) => {
+            console
+                .nes
+                .render(&mut console.renderer, &mut console.frame);
+            0
+        }
+        Ok(Err(e)) => console.fail(&e.to_string()),
+        Err(_) => console.fail("The emulator panicked"),
+    }
+}
+
+/// # Safety
+/// `console` must come from `nes_create`
+#[no_mangle]
+pub unsafe extern "C" fn nes_reset(console: *mut NesConsole) {
+    if let Some(console) = console.as_mut() {
+        console.nes.reset();
+    }
+}
+
+/// Holds `buttons`, in RLDUTSBA order, on controller `port` 0 or 1
+///
+/// # Safety
+/// `console` must come from `nes_create`
+#[no_mangle]
+pub unsafe extern "C" fn nes_set_buttons(console: *mut NesConsole, port: u32, buttons: u8) {
+    if let Some(console) = console.as_mut() {
+        if port < 2 {
+            console
+                .nes
+                .set_buttons(port as usize, Button::from_bits_truncate(buttons));
+        }
+    }
+}
+
+/// The 256x240 pixels of the last frame, 0x00RRGGBB, row after row. Valid
+/// until the console is destroyed, black before the first frame
+///
+/// # Safety
+/// `console` must come from `nes_create`
+#[no_mangle]
+pub unsafe extern "C" fn nes_frame_buffer(console: *const NesConsole) -> *const u32 {
+    match console.as_ref() {
+        Some(console) => console.frame.pixels().as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Saves the console and returns the size of the state. It is copied to `buf`
+/// only if it fits in `len` bytes, so a first call with a null `buf` asks for
+/// the size. Returns 0 for a null console
+///
+/// # Safety
+/// `console` must come from `nes_create`, `buf` must point to `len` writable
+/// bytes unless it is null
+#[no_mangle]
+pub unsafe extern "C" fn nes_save_state(
+    console: *mut NesConsole,
+    buf: *mut u8,
+    len: usize,
+) -> usize {
+    let console = match console.as_mut() {
+        Some(console) => console,
+        None => return 0,
+    };
+    let state = console.nes.save_state().to_bytes();
+    if !buf.is_null() && state.len() <= len {
+        ptr::copy_nonoverlapping(state.as_ptr(), buf, state.len());
+    }
+    state.len()
+}
+
+/// Restores a state from `nes_save_state`. States of other ROMs are refused
+///
+/// # Safety
+/// `console` must come from `nes_create`, `buf` must point to `len` readable
+/// bytes
+#[no_mangle]
+pub unsafe extern "C" fn nes_load_state(
+    console: *mut NesConsole,
+    buf: *const u8,
+    len: usize,
+) -> c_int {
+    let console = match console.as_mut() {
+        Some(console) => console,
+        None => return -1,
+    };
+    if buf.is_null() {
+        return console.fail("No savestate");
+    }
+    let loaded = SaveState::from_bytes(slice::from_raw_parts(buf, len))
+        .and_then(|state| console.nes.load_state(&state));
+    match loaded {
+        Ok(()) => 0,
+        Err(e) => console.fail(&e),
+    }
+}
+
+/// Why the last call that returned -1 failed, empty if none did. Valid until
+/// the next call on the console
+///
+/// # Safety
+/// `console` must come from `nes_create`
+#[no_mangle]
+pub unsafe extern "C" fn nes_last_error(console: *const NesConsole) -> *const c_char {
+    match console.as_ref() {
+        Some(console) => console.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_c_api() {
+        let image = RomBuilder::new()
+            .asm(0x8000, "loop: LDA $4016\n STA $10\n JMP loop")
+            .unwrap()
+            .reset_vector(0x8000)
+            .build_image();
+        unsafe {
+            assert!(nes_create(image.as_ptr(), 10).is_null());
+            let console = nes_create(image.as_ptr(), image.len());
+            assert!(!console.is_null());
+            assert_eq!(nes_run_frame(console), 0);
+            assert!(!nes_frame_buffer(console).is_null());
+
+            let size = nes_save_state(console, ptr::null_mut(), 0);
+            let mut state = vec![0; size];
+            assert_eq!(nes_save_state(console, state.as_mut_ptr(), size), size);
+            nes_set_buttons(console, 0, Button::A.bits());
+            assert_eq!(nes_run_frame(console), 0);
+            assert_eq!(nes_load_state(console, state.as_ptr(), size), 0);
+
+            assert_eq!(nes_load_state(console, state.as_ptr(), 3), -1);
+            let error = CStr::from_ptr(nes_last_error(console));
+            assert!(!error.to_bytes().is_empty());
+            nes_destroy(console);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Game Genie codes.
+//
+// A code is 6 or 8 letters out of "APZLGITYEOXUKSVN", each standing for a
+// nibble. The nibbles are a shuffled PRG ROM address in $8000-$FFFF, the value
+// the Game Genie returns for reads of it and, in 8 letter codes, a compare
+// value: the substitution only happens while ROM holds that byte, so a code
+// for one bank doesn't hit the others mapped at the same address. The third
+// letter's high bit tells the console which length was entered.
+use crate::bus::Bus;
+use crate::error::CpuError;
+use crate::prelude::*;
+
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameGenieCode {
+    pub addr: u16,
+    pub value: u8,
+    /// Only 8 letter codes have one
+    pub compare: Option<u8>,
+}
+
+impl GameGenieCode {
+    /// Decodes 6 or 8 letters, ignoring case
+    pub fn decode(code: &str) -> Result<GameGenieCode, String> {
+        let mut n = [0u8; 8];
+        let len = code.len();
+        if len != 6 && len != 8 {
+            return Err(format!(
+                "Game Genie code {} must have 6 or 8 letters, not {}",
+                code, len
+            ));
+        }
+        for (nibble, letter) in n.iter_mut().zip(code.bytes()) {
+            *nibble = LETTERS
+                .iter()
+                .position(|&l| l == letter.to_ascii_uppercase())
+                .ok_or_else(|| format!("'{}' is not a Game Genie letter", letter as char))?
+                as u8;
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) as u16) << 12
+            | ((n[5] & 7) as u16) << 8
+            | ((n[4] & 8) as u16) << 8
+            | ((n[2] & 7) as u16) << 4
+            | ((n[1] & 8) as u16) << 4
+            | (n[4] & 7) as u16
+            | (n[3] & 8) as u16;
+        let value = (n[1] & 7) << 4 | (n[0] & 8) << 4 | n[0] & 7;
+        Ok(if len == 6 {
+            GameGenieCode {
+                addr,
+                value: value | n[5] & 8,
+                compare: None,
+            }
+        } else {
+            GameGenieCode {
+                addr,
+                value: value | n[7] & 8,
+                compare: Some((n[7] & 7) << 4 | (n[6] & 8) << 4 | n[6] & 7 | n[5] & 8),
+            }
+        })
+    }
+
+    /// The letters of the code, upper case. Bit 15 of the address is implied
+    pub fn encode(&self) -> String {
+        let (addr, value) = (self.addr, self.value);
+        let mut n = [0u8; 8];
+        n[0] = (value & 7) | (value >> 4) & 8;
+        n[1] = (value >> 4) & 7 | (addr >> 4) as u8 & 8;
+        n[2] = (addr >> 4) as u8 & 7;
+        n[3] = (addr >> 12) as u8 & 7 | addr as u8 & 8;
+        n[4] = addr as u8 & 7 | (addr >> 8) as u8 & 8;
+        n[5] = (addr >> 8) as u8 & 7;
+        let len = match self.compare {
+            None => {
+                n[5] |= value & 8;
+                6
+            }
+            Some(compare) => {
+                n[2] |= 8;
+                n[5] |= compare & 8;
+                n[6] = compare & 7 | (compare >> 4) & 8;
+                n[7] = (compare >> 4) & 7 | value & 8;
+                8
+            }
+        };
+        n[..len]
+            .iter()
+            .map(|&nibble| LETTERS[nibble as usize] as char)
+            .collect()
+    }
+
+    /// Patches the cartridge's PRG ROM, unless the compare value doesn't
+    /// match what is there. Returns whether it did
+    pub fn apply(&self, bus: &mut Bus) -> Result<bool, CpuError> {
+        if matches!(self.compare, Some(compare) if bus.peek(self.addr) != compare) {
+            return Ok(false);
+        }
+        bus.patch(self.addr, self.value)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    #[test]
+    fn test_decode() {
+        // infinite lives in Super Mario Bros.
+        assert_eq!(
+            GameGenieCode::decode("SXIOPO"),
+            Ok(GameGenieCode {
+                addr: 0x91D9,
+                value: 0xAD,
+                compare: None
+            })
+        );
+        assert_eq!(
+            GameGenieCode::decode("zexpygla"),
+            Ok(GameGenieCode {
+                addr: 0x94A7,
+                value: 0x02,
+                compare: Some(0x03)
+            })
+        );
+        assert!(GameGenieCode::decode("SXIOP").is_err());
+        assert!(GameGenieCode::decode("SXIOPB").is_err());
+        assert!(GameGenieCode::decode("SXIOP1").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(GameGenieCode::decode("SXIOPO").unwrap().encode(), "SXIOPO");
+        assert_eq!(
+            GameGenieCode::decode("ZEXPYGLA").unwrap().encode(),
+            "ZEXPYGLA"
+        );
+        for addr in (0x8000..=0xFFFF).step_by(0x7F) {
+            for value in (0..=0xFF).step_by(0x1D) {
+                let code = GameGenieCode {
+                    addr,
+                    value,
+                    compare: None,
+                };
+                assert_eq!(GameGenieCode::decode(&code.encode()), Ok(code));
+                let code = GameGenieCode {
+                    compare: Some(value ^ 0xA5),
+                    ..code
+                };
+                let letters = code.encode();
+                assert_eq!(letters.len(), 8);
+                assert_eq!(GameGenieCode::decode(&letters), Ok(code));
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let rom = RomBuilder::new()
+            .prg_pages(1)
+            .data(0x8010, &[0x03, 0x03])
+            .build();
+        let mut bus = Bus::new(rom);
+        let code = GameGenieCode {
+            addr: 0x8010,
+            value: 9,
+            compare: Some(3),
+        };
+        assert_eq!(code.apply(&mut bus), Ok(true));
+        assert_eq!(bus.peek(0x8010), 9);
+        // already patched, 9 doesn't compare equal anymore
+        assert_eq!(code.apply(&mut bus), Ok(false));
+
+        let code = GameGenieCode {
+            addr: 0x8011,
+            value: 7,
+            compare: None,
+        };
+        assert_eq!(code.apply(&mut bus), Ok(true));
+        assert_eq!(bus.peek(0x8011), 7);
+    }
+}
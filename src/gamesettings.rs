@@ -0,0 +1,359 @@
+// Per-game overrides, reapplied whenever the same ROM is loaded again.
+//
+// Settings are keyed by the SHA1 of the ROM data, so renaming or moving a ROM
+// keeps them and two different dumps under the same name don't share them.
+// Each game gets a text file in the config directory, games/<sha1>.cfg, with
+// one setting per line and only what differs from the defaults:
+//
+//   region PAL
+//   palette /home/nes/palettes/smooth.pal
+//   brightness 0.1
+//   saturation 1.2
+//   gamma 1.1
+//   filter deuteranopia
+//   crt 0.5 0.3 0.4
+//   bind 1 A Z
+//   bind 1 T Return
+//   cheat 0075 09 on Infinite lives
+//
+// `palette` is a .pal file or the name of a built in palette, the color
+// settings adjust it. `filter` is one of the accessibility filters of
+// render/filter.rs. `crt` turns on the CRT filter of render/crt.rs with the
+// strength of its scanlines, curvature and phosphor glow. `bind` maps a keyboard key (by name, last so it may contain spaces) to a
+// controller port and one of the RLDUTSBA buttons. Cheats are freeze cheats
+// with a hex address and value, enabled or not, and a description.
+use crate::cartridge::Rom;
+use crate::cheats::{Cheat, Cheats};
+use crate::render::crt::CrtSettings;
+use crate::render::filter::ColorFilter;
+use crate::render::palette::{ColorCorrection, Palette};
+use crate::rominfo::Region;
+use crate::storage::{Kind, Storage};
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const GAMES_DIR: &str = "games";
+const BUTTON_LETTERS: &str = "RLDUTSBA";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBinding {
+    pub key: String,
+    /// Controller port, 0 or 1
+    pub port: usize,
+    /// The button's bit in RLDUTSBA order
+    pub button: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GameSettings {
+    pub region: Option<Region>,
+    pub palette: Option<PathBuf>,
+    pub color_correction: ColorCorrection,
+    pub filter: ColorFilter,
+    /// The CRT filter, off if None
+    pub crt: Option<CrtSettings>,
+    pub bindings: Vec<KeyBinding>,
+    pub cheats: Vec<Cheat>,
+}
+
+impl GameSettings {
+    pub fn new() -> Self {
+        GameSettings::default()
+    }
+
+    /// The file holding the settings of `rom`
+    pub fn path(storage: &Storage, rom: &Rom) -> PathBuf {
+        storage
+            .dir(Kind::Config)
+            .join(GAMES_DIR)
+            .join(format!("{}.cfg", rom.sha1()))
+    }
+
+    /// The saved settings of `rom`, or defaults if there are none yet
+    pub fn load(storage: &Storage, rom: &Rom) -> Result<GameSettings, String> {
+        let path = GameSettings::path(storage, rom);
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                GameSettings::from_text(&text).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(GameSettings::new()),
+            Err(e) => Err(format!("Can't read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, storage: &Storage, rom: &Rom) -> Result<(), String> {
+        let path = GameSettings::path(storage, rom);
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        fs::write(&path, self.to_text())
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    /// The buttons bound to `key` as (port, button bit)
+    pub fn buttons_for_key<'a>(&'a self, key: &'a str) -> impl Iterator<Item = (usize, u8)> + 'a {
+        self.bindings
+            .iter()
+            .filter(move |binding| binding.key == key)
+            .map(|binding| (binding.port, binding.button))
+    }
+
+    /// The game's palette, or `default`, the one picked for all games, when
+    /// it has none, with the game's color correction. Either may name a built
+    /// in palette instead of a .pal file. The system palette when neither is set
+    pub fn load_palette(&self, default: Option<&Path>) -> Result<Palette, String> {
+        let palette = match self.palette.as_deref().or(default) {
+            Some(path) => match path.to_str().and_then(Palette::builtin) {
+                Some(palette) => palette,
+                None => Palette::load(path)?,
+            },
+            None => Palette::system(),
+        };
+        if self.color_correction.is_identity() {
+            return Ok(palette);
+        }
+        Ok(palette.corrected(&self.color_correction))
+    }
+
+    pub fn set_cheats(&mut self, cheats: &Cheats) {
+        self.cheats = cheats.cheats().to_vec();
+    }
+
+    pub fn cheat_list(&self) -> Cheats {
+        let mut cheats = Cheats::new();
+        for cheat in &self.cheats {
+            cheats.add_freeze(&cheat.description, cheat.addr, cheat.value);
+            cheats.set_enabled(cheat.addr, cheat.enabled);
+        }
+        cheats
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        if let Some(region) = self.region {
+            writeln!(out, "region {}", region.name()).unwrap();
+        }
+        if let Some(palette) = &self.palette {
+            writeln!(out, "palette {}", palette.display()).unwrap();
+        }
+        let (color, default) = (self.color_correction, ColorCorrection::default());
+        if color.brightness != default.brightness {
+            writeln!(out, "brightness {}", color.brightness).unwrap();
+        }
+        if color.saturation != default.saturation {
+            writeln!(out, "saturation {}", color.saturation).unwrap();
+        }
+        if color.gamma != default.gamma {
+            writeln!(out, "gamma {}", color.gamma).unwrap();
+        }
+        if self.filter != ColorFilter::None {
+            writeln!(out, "filter {}", self.filter.name()).unwrap();
+        }
+        if let Some(crt) = self.crt {
+            let (scanlines, curvature, phosphor) = (crt.scanlines, crt.curvature, crt.phosphor);
+            writeln!(out, "crt {} {} {}", scanlines, curvature, phosphor).unwrap();
+        }
+        for binding in &self.bindings {
+            let letter = BUTTON_LETTERS.as_bytes()[7 - binding.button.trailing_zeros() as usize];
+            writeln!(
+                out,
+                "bind {} {} {}",
+                binding.port + 1,
+                letter as char,
+                binding.key
+            )
+            .unwrap();
+        }
+        for cheat in &self.cheats {
+            let enabled = if cheat.enabled { "on" } else { "off" };
+            let line = format!(
+                "cheat {:04X} {:02X} {} {}",
+                cheat.addr, cheat.value, enabled, cheat.description
+            );
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<GameSettings, String> {
+        let mut settings = GameSettings::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let malformed = || format!("line {}: malformed setting: {}", i + 1, line);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(' ').ok_or_else(malformed)?;
+            let value = value.trim();
+            match name {
+                "region" => settings.region = Some(Region::from_name(value).ok_or_else(malformed)?),
+                "palette" => settings.palette = Some(PathBuf::from(value)),
+                "brightness" => {
+                    settings.color_correction.brightness = value.parse().map_err(|_| malformed())?
+                }
+                "saturation" => {
+                    settings.color_correction.saturation = value.parse().map_err(|_| malformed())?
+                }
+                "gamma" => match value.parse() {
+                    Ok(gamma) if gamma > 0.0 => settings.color_correction.gamma = gamma,
+                    _ => return Err(malformed()),
+                },
+                "filter" => {
+                    settings.filter = ColorFilter::from_name(value).ok_or_else(malformed)?
+                }
+                "crt" => {
+                    let values = value
+                        .split_whitespace()
+                        .map(|v| v.parse::<f32>().map_err(|_| malformed()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    match values.as_slice() {
+                        &[scanlines, curvature, phosphor] => {
+                            settings.crt = Some(CrtSettings {
+                                scanlines,
+                                curvature,
+                                phosphor,
+                            })
+                        }
+                        _ => return Err(malformed()),
+                    }
+                }
+                "bind" => {
+                    let fields: Vec<&str> = value.splitn(3, ' ').collect();
+                    let (port, letter, key) = match fields.as_slice() {
+                        [port, letter, key] => (*port, *letter, key.trim()),
+                        _ => return Err(malformed()),
+                    };
+                    let port = match port {
+                        "1" => 0,
+                        "2" => 1,
+                        _ => return Err(malformed()),
+                    };
+                    let index = match letter.len() {
+                        1 => BUTTON_LETTERS.find(letter).ok_or_else(malformed)?,
+                        _ => return Err(malformed()),
+                    };
+                    settings.bindings.push(KeyBinding {
+                        key: key.to_string(),
+                        port,
+                        button: 1 << (7 - index),
+                    });
+                }
+                "cheat" => {
+                    let fields: Vec<&str> = value.splitn(4, ' ').collect();
+                    let (addr, cheat_value, enabled, description) = match fields.as_slice() {
+                        [addr, value, enabled] => (*addr, *value, *enabled, ""),
+                        [addr, value, enabled, description] => {
+                            (*addr, *value, *enabled, description.trim())
+                        }
+                        _ => return Err(malformed()),
+                    };
+                    settings.cheats.push(Cheat {
+                        description: description.to_string(),
+                        addr: u16::from_str_radix(addr, 16).map_err(|_| malformed())?,
+                        value: u8::from_str_radix(cheat_value, 16).map_err(|_| malformed())?,
+                        enabled: match enabled {
+                            "on" => true,
+                            "off" => false,
+                            _ => return Err(malformed()),
+                        },
+                    });
+                }
+                _ => return Err(format!("line {}: unknown setting {}", i + 1, name)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    #[test]
+    fn test_settings_round_trip() {
+        let text = "region PAL\n\
+                    palette /palettes/smooth.pal\n\
+                    saturation 1.25\n\
+                    gamma 0.5\n\
+                    filter high-contrast\n\
+                    crt 0.5 0.25 0\n\
+                    bind 1 A Z\n\
+                    bind 2 R Left Shift\n\
+                    cheat 0075 09 on Infinite lives\n\
+                    cheat 07A0 01 off\n";
+        let settings = GameSettings::from_text(text).unwrap();
+        assert_eq!(settings.region, Some(Region::Pal));
+        assert_eq!(settings.crt.map(|crt| crt.curvature), Some(0.25));
+        assert_eq!(
+            settings.buttons_for_key("Z").collect::<Vec<_>>(),
+            [(0, 0x01)]
+        );
+        assert_eq!(
+            settings.buttons_for_key("Left Shift").collect::<Vec<_>>(),
+            [(1, 0x80)]
+        );
+        let cheats = settings.cheat_list();
+        assert_eq!(cheats.cheats()[0].description, "Infinite lives");
+        assert!(!cheats.cheats()[1].enabled);
+        assert_eq!(settings.to_text(), text);
+
+        assert!(GameSettings::from_text("bind 3 A Z").is_err());
+        assert!(GameSettings::from_text("cheat 0075 09 maybe").is_err());
+        assert!(GameSettings::from_text("volume 11").is_err());
+        assert!(GameSettings::from_text("gamma 0").is_err());
+        assert!(GameSettings::from_text("filter sepia").is_err());
+        assert!(GameSettings::from_text("crt 0.5 0.25").is_err());
+    }
+
+    #[test]
+    fn test_game_palette_beats_default() {
+        let dir = std::env::temp_dir().join(format!("palette_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (game, global) = (dir.join("game.pal"), dir.join("global.pal"));
+        fs::write(&game, vec![1; 192]).unwrap();
+        fs::write(&global, vec![2; 192]).unwrap();
+
+        let mut settings = GameSettings::new();
+        assert_eq!(settings.load_palette(None), Ok(Palette::system()));
+        let palette = settings.load_palette(Some(&global)).unwrap();
+        assert_eq!(palette.colors(0)[0], (2, 2, 2));
+        settings.palette = Some(game);
+        let palette = settings.load_palette(Some(&global)).unwrap();
+        assert_eq!(palette.colors(0)[0], (1, 1, 1));
+        let missing = dir.join("missing.pal");
+        assert!(settings.load_palette(Some(&missing)).is_ok());
+        settings.palette = Some(missing);
+        assert!(settings.load_palette(None).is_err());
+
+        settings.palette = Some(PathBuf::from("fceux"));
+        settings.color_correction.saturation = 0.0;
+        let (r, g, b) = settings.load_palette(None).unwrap().colors(0)[0x16];
+        assert!(r == g && g == b);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_settings_follow_the_rom() {
+        let dir = std::env::temp_dir().join(format!("settings_test_{}", std::process::id()));
+        let storage = Storage::portable(&dir);
+        let rom = RomBuilder::new().fill_prg(1).build();
+        let other = RomBuilder::new().fill_prg(2).build();
+        assert_eq!(GameSettings::load(&storage, &rom), Ok(GameSettings::new()));
+
+        let mut settings = GameSettings::new();
+        settings.region = Some(Region::Dendy);
+        let mut cheats = Cheats::new();
+        cheats.add_freeze("Max health", 0x0100, 0x40);
+        settings.set_cheats(&cheats);
+        settings.save(&storage, &rom).unwrap();
+
+        assert_eq!(GameSettings::load(&storage, &rom), Ok(settings));
+        assert_eq!(
+            GameSettings::load(&storage, &other),
+            Ok(GameSettings::new())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
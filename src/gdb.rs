@@ -0,0 +1,425 @@
+// GDB remote serial protocol stub.
+//
+// Registers are exposed in the order a, x, y, p, sp (one byte each) and pc
+// (two bytes, little endian); `p`/`P` packets use the same numbering (0-5).
+// Memory reads go through Bus::peek, so reading I/O registers from the debugger
+// doesn't disturb the PPU. Writes are limited to RAM.
+//
+// GDB has no packets for stepping over or out of a subroutine, those are
+// monitor commands: "monitor next" runs a JSR to completion and "monitor finish"
+// runs until the current subroutine returns. Use "flushregs" afterwards so GDB
+// picks up the new state.
+use crate::cpu::{CpuFlags, Mem, CPU};
+use crate::debugger::{Debugger, StopReason};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const INTERRUPT: u8 = 0x03;
+const INSTRUCTIONS_PER_POLL: usize = 10_000;
+/// Limit for monitor commands, those can't be interrupted
+const MAX_MONITOR_INSTRUCTIONS: usize = 1_000_000;
+
+enum Action {
+    Reply(String),
+    Step,
+    Resume,
+    Detach,
+}
+
+pub struct GdbStub {
+    debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        GdbStub {
+            debugger: Debugger::new(),
+        }
+    }
+
+    /// Blocks until a debugger connects
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Serves a connected debugger until it detaches or disconnects. The machine
+    /// only runs while the debugger asks it to
+    pub fn serve(&mut self, cpu: &mut CPU, mut stream: TcpStream) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut stream)? {
+            match self.handle_packet(cpu, &packet) {
+                Action::Reply(reply) => send_packet(&mut stream, &reply)?,
+                Action::Step => {
+                    let reason = self.debugger.step(cpu);
+                    send_packet(&mut stream, &stop_reply(reason))?;
+                }
+                Action::Resume => {
+                    let reply = self.resume(cpu, &mut stream)?;
+                    send_packet(&mut stream, &reply)?;
+                }
+                Action::Detach => {
+                    send_packet(&mut stream, "OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, stream: &mut TcpStream) -> io::Result<String> {
+        loop {
+            if let Some(reason) = self.debugger.resume(cpu, INSTRUCTIONS_PER_POLL) {
+                return Ok(stop_reply(reason));
+            }
+            if interrupt_requested(stream)? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, cpu: &mut CPU, packet: &str) -> Action {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => format!("S{:02x}", SIGTRAP),
+            Some(b'g') => to_hex(&registers(cpu)),
+            Some(b'G') => match from_hex(&packet[1..]) {
+                Some(regs) if regs.len() == 7 => {
+                    set_registers(cpu, &regs);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(5) => to_hex(&cpu.program_counter.to_le_bytes()),
+                Ok(n) if n < 5 => to_hex(&registers(cpu)[n..n + 1]),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => self.write_register(cpu, &packet[1..]),
+            Some(b'm') => self.read_memory(cpu, &packet[1..]),
+            Some(b'M') => self.write_memory(cpu, &packet[1..]),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b'c') => {
+                if let Ok(addr) = u16::from_str_radix(&packet[1..], 16) {
+                    cpu.program_counter = addr;
+                }
+                return Action::Resume;
+            }
+            Some(b's') => {
+                if let Ok(addr) = u16::from_str_radix(&packet[1..], 16) {
+                    cpu.program_counter = addr;
+                }
+                return Action::Step;
+            }
+            Some(b'D') | Some(b'k') => return Action::Detach,
+            Some(b'H') => "OK".to_string(),
+            _ if packet.starts_with("qRcmd,") => self.monitor(cpu, &packet[6..]),
+            _ if packet.starts_with("qSupported") => "PacketSize=1000".to_string(),
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    fn write_register(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let reg = parts.next().and_then(|r| usize::from_str_radix(r, 16).ok());
+        let value = parts.next().and_then(from_hex);
+        match (reg, value) {
+            (Some(5), Some(ref v)) if v.len() == 2 => {
+                cpu.program_counter = u16::from_le_bytes([v[0], v[1]]);
+            }
+            (Some(n), Some(ref v)) if n < 5 && v.len() == 1 => {
+                let mut regs = registers(cpu);
+                regs[n] = v[0];
+                set_registers(cpu, &regs);
+            }
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&mut self, cpu: &mut CPU, args: &str) -> String {
+        match parse_addr_len(args) {
+            Some((addr, len)) => {
+                let data: Vec<u8> = (0..len)
+                    .map(|i| cpu.bus.peek(addr.wrapping_add(i as u16)))
+                    .collect();
+                to_hex(&data)
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let target = parts.next().and_then(parse_addr_len);
+        let data = parts.next().and_then(from_hex);
+        match (target, data) {
+            (Some((addr, len)), Some(data)) if data.len() == len => {
+                if addr as usize + len > 0x2000 {
+                    return "E03".to_string();
+                }
+                for (i, byte) in data.iter().enumerate() {
+                    cpu.mem_write(addr + i as u16, *byte);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Runs a hex encoded monitor command, the reply is its hex encoded output
+    fn monitor(&mut self, cpu: &mut CPU, command: &str) -> String {
+        let command = match from_hex(command).and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(command) => command,
+            None => return "E01".to_string(),
+        };
+        let result = match command.trim() {
+            "next" => self.debugger.step_over(cpu, MAX_MONITOR_INSTRUCTIONS),
+            "finish" => self.debugger.step_out(cpu, MAX_MONITOR_INSTRUCTIONS),
+            _ => return to_hex(format!("Unknown command: {}\n", command).as_bytes()),
+        };
+        let output = match result {
+            Some(StopReason::Halted) => "CPU halted on BRK\n".to_string(),
+            Some(_) => format!("Stopped at ${:04X}\n", cpu.program_counter),
+            None => format!("Still running at ${:04X}\n", cpu.program_counter),
+        };
+        to_hex(output.as_bytes())
+    }
+
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let mut parts = packet[1..].split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        match (kind, addr) {
+            // software and hardware breakpoints are the same thing for us
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                if packet.starts_with('Z') {
+                    self.debugger.add_breakpoint(addr);
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                "OK".to_string()
+            }
+            (Some("0"), None) | (Some("1"), None) => "E01".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        GdbStub::new()
+    }
+}
+
+fn registers(cpu: &CPU) -> [u8; 7] {
+    let pc = cpu.program_counter.to_le_bytes();
+    [
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        pc[0],
+        pc[1],
+    ]
+}
+
+fn set_registers(cpu: &mut CPU, regs: &[u8]) {
+    cpu.register_a = regs[0];
+    cpu.register_x = regs[1];
+    cpu.register_y = regs[2];
+    cpu.status = CpuFlags::from_bits_truncate(regs[3]);
+    cpu.stack_pointer = regs[4];
+    cpu.program_counter = u16::from_le_bytes([regs[5], regs[6]]);
+}
+
+fn stop_reply(_reason: StopReason) -> String {
+    format!("S{:02x}", SIGTRAP)
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn send_packet<W: Write>(stream: &mut W, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+/// Reads the next packet and acknowledges it, `None` means the debugger disconnected
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    loop {
+        // skip acks and stray interrupts until the start of a packet
+        match read_byte(stream)? {
+            Some(b'$') => {}
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+
+        let data = String::from_utf8_lossy(&data).to_string();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn read_byte<R: Read>(stream: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn interrupt_requested(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+    match result {
+        // a closed connection stops the machine as well
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(byte[0] == INTERRUPT),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    fn test_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    fn reply(stub: &mut GdbStub, cpu: &mut CPU, packet: &str) -> String {
+        match stub.handle_packet(cpu, packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("expected a reply to {}", packet),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = test_cpu();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, &mut cpu, "g"), "00000024fd0006");
+        assert_eq!(reply(&mut stub, &mut cpu, "G112233a5f03412"), "OK");
+        assert_eq!(cpu.register_a, 0x11);
+        assert_eq!(cpu.stack_pointer, 0xf0);
+        assert_eq!(cpu.program_counter, 0x1234);
+        assert_eq!(reply(&mut stub, &mut cpu, "P1=42"), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, "p1"), "42");
+        assert_eq!(reply(&mut stub, &mut cpu, "p5"), "3412");
+
+        assert_eq!(reply(&mut stub, &mut cpu, "m600,3"), "a200e8");
+        assert_eq!(reply(&mut stub, &mut cpu, "M10,2:beef"), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, "m10,2"), "beef");
+        assert_eq!(reply(&mut stub, &mut cpu, "M8000,1:00"), "E03");
+        assert_eq!(reply(&mut stub, &mut cpu, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn test_monitor_commands() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // JSR sub; NOP; sub: INX; INX; RTS
+        cpu.load(vec![0x20, 0x04, 0x06, 0xea, 0xe8, 0xe8, 0x60]);
+        cpu.program_counter = 0x0600;
+        let mut stub = GdbStub::new();
+        let monitor = |stub: &mut GdbStub, cpu: &mut CPU, command: &str| {
+            let packet = format!("qRcmd,{}", to_hex(command.as_bytes()));
+            let output = from_hex(&reply(stub, cpu, &packet)).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(monitor(&mut stub, &mut cpu, "next"), "Stopped at $0603\n");
+        assert_eq!(cpu.register_x, 2);
+        cpu.program_counter = 0x0600;
+        stub.debugger.step(&mut cpu);
+        assert_eq!(monitor(&mut stub, &mut cpu, "finish"), "Stopped at $0603\n");
+        assert!(monitor(&mut stub, &mut cpu, "frobnicate").starts_with("Unknown"));
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut exchange = |packet: &str| {
+                send_packet(&mut stream, packet).unwrap();
+                read_packet(&mut stream).unwrap().unwrap()
+            };
+            let replies = vec![
+                exchange("Z0,603,1"),
+                exchange("c"),
+                exchange("p1"),
+                exchange("s"),
+                exchange("p5"),
+                exchange("D"),
+            ];
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut cpu = test_cpu();
+        GdbStub::new().serve(&mut cpu, stream).unwrap();
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies, vec!["OK", "S05", "01", "S05", "0206", "OK"]);
+    }
+}
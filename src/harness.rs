@@ -0,0 +1,77 @@
+use crate::cpu::CPU;
+
+const RAM_SIZE: u16 = 0x0800;
+
+/// Controller state held for one frame, one byte per port in RLDUTSBA order
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameInput {
+    pub pads: [u8; 2],
+}
+
+impl FrameInput {
+    pub fn new(pad1: u8, pad2: u8) -> Self {
+        FrameInput { pads: [pad1, pad2] }
+    }
+
+    pub fn apply(&self, cpu: &mut CPU) {
+        cpu.bus.set_controller(0, self.pads[0]);
+        cpu.bus.set_controller(1, self.pads[1]);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSnapshot {
+    /// PPU frame count at the end of the frame
+    pub frame: usize,
+    pub ram: Vec<u8>,
+}
+
+impl CPU {
+    /// Plays scheduled input, one entry per frame, and snapshots RAM after every
+    /// frame. Stops early if the CPU hits BRK, so fewer snapshots than inputs
+    /// are returned in that case
+    pub fn run_frames_with_inputs(&mut self, inputs: &[FrameInput]) -> Vec<FrameSnapshot> {
+        let mut snapshots = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            input.apply(self);
+            if !self.run_frame() {
+                break;
+            }
+            snapshots.push(FrameSnapshot {
+                frame: self.bus.frame_count(),
+                ram: (0..RAM_SIZE).map(|addr| self.bus.peek(addr)).collect(),
+            });
+        }
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_run_frames_with_inputs() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: strobe the controller, read A into $10, JMP loop
+        cpu.load(vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x85,
+            0x10, 0x4c, 0x00, 0x06,
+        ]);
+        cpu.program_counter = 0x0600;
+
+        let inputs = [
+            FrameInput::default(),
+            FrameInput::new(0x01, 0),
+            FrameInput::default(),
+        ];
+        let snapshots = cpu.run_frames_with_inputs(&inputs);
+        let frames: Vec<usize> = snapshots.iter().map(|s| s.frame).collect();
+        let a_pressed: Vec<u8> = snapshots.iter().map(|s| s.ram[0x10]).collect();
+        assert_eq!(frames, vec![1, 2, 3]);
+        assert_eq!(a_pressed, vec![0, 1, 0]);
+        assert_eq!(snapshots[0].ram.len(), RAM_SIZE as usize);
+    }
+}
@@ -0,0 +1,128 @@
+// Read and write counts per CPU address over a capture window, laid out as a
+// 256x256 image with one pixel per address: the low byte is the column and
+// the page is the row. Busy buffers, unused RAM and accesses where there
+// shouldn't be any stand out at a glance.
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+pub const SIZE: usize = 256;
+const ADDRESSES: usize = SIZE * SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            reads: vec![0; ADDRESSES],
+            writes: vec![0; ADDRESSES],
+        }
+    }
+
+    pub fn record_read(&mut self, addr: u16) {
+        let count = &mut self.reads[addr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn record_write(&mut self, addr: u16) {
+        let count = &mut self.writes[addr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.iter_mut().for_each(|count| *count = 0);
+        self.writes.iter_mut().for_each(|count| *count = 0);
+    }
+
+    pub fn reads(&self, addr: u16) -> u32 {
+        self.reads[addr as usize]
+    }
+
+    pub fn writes(&self, addr: u16) -> u32 {
+        self.writes[addr as usize]
+    }
+
+    /// Counts for the whole address space, indexed by address
+    pub fn read_counts(&self) -> &[u32] {
+        &self.reads
+    }
+
+    pub fn write_counts(&self) -> &[u32] {
+        &self.writes
+    }
+
+    /// The map as 256x256 RGB24 pixels, reads in green and writes in red. The
+    /// brightness is log scaled against the busiest address so rarely touched
+    /// addresses still show up. Needs std for the logarithm
+    #[cfg(feature = "std")]
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let max_reads = self.reads.iter().copied().max().unwrap_or(0);
+        let max_writes = self.writes.iter().copied().max().unwrap_or(0);
+        let mut pixels = Vec::with_capacity(ADDRESSES * 3);
+        for addr in 0..ADDRESSES {
+            pixels.push(intensity(self.writes[addr], max_writes));
+            pixels.push(intensity(self.reads[addr], max_reads));
+            pixels.push(0);
+        }
+        pixels
+    }
+
+    /// Writes `to_rgb` as a binary PPM image
+    #[cfg(feature = "std")]
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", SIZE, SIZE)?;
+        out.write_all(&self.to_rgb())
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap::new()
+    }
+}
+
+#[cfg(feature = "std")]
+fn intensity(count: u32, max: u32) -> u8 {
+    if count == 0 {
+        return 0;
+    }
+    // touched addresses never go completely dark
+    let scaled = (count as f64).ln_1p() / (max as f64).ln_1p();
+    (64.0 + scaled * 191.0) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_heatmap_image() {
+        let mut heatmap = Heatmap::new();
+        for _ in 0..100 {
+            heatmap.record_read(0x0010);
+        }
+        heatmap.record_read(0x0011);
+        heatmap.record_write(0x0201);
+        assert_eq!((heatmap.reads(0x0010), heatmap.writes(0x0201)), (100, 1));
+
+        let pixels = heatmap.to_rgb();
+        assert_eq!(pixels.len(), 256 * 256 * 3);
+        let pixel = |x: usize, y: usize| &pixels[(y * 256 + x) * 3..(y * 256 + x) * 3 + 3];
+        assert_eq!(pixel(0x10, 0), &[0, 255, 0]);
+        assert!(pixel(0x11, 0)[1] > 64 && pixel(0x11, 0)[1] < 255);
+        assert_eq!(pixel(0x01, 2), &[255, 0, 0]);
+        assert_eq!(pixel(0x12, 0), &[0, 0, 0]);
+
+        let mut ppm = vec![];
+        heatmap.write_ppm(&mut ppm).unwrap();
+        assert!(ppm.starts_with(b"P6\n256 256\n255\n"));
+        assert_eq!(ppm.len(), 15 + 256 * 256 * 3);
+
+        heatmap.clear();
+        assert!(heatmap.to_rgb().iter().all(|&c| c == 0));
+    }
+}
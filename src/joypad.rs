@@ -0,0 +1,115 @@
+// The standard controller.
+//
+// The bus takes the held buttons as one byte per port in RLDUTSBA order, the
+// order movies, game settings and scripts store them in. `Button` names the
+// bits so frontends don't have to spell out the masks.
+//
+// Games read a controller one button at a time. Writing 1 to $4016 sets the
+// strobe, which keeps reloading the shift register with the held buttons;
+// writing 0 freezes it. Every read of $4016 (controller 1) or $4017
+// (controller 2) then returns the next button, A first and Right last, and
+// 1s once all 8 are out, like an official controller.
+bitflags! {
+    /// Buttons of a standard controller, Right in bit 7 and A in bit 0
+    #[derive(Default)]
+    pub struct Button: u8 {
+        const A      = 0b0000_0001;
+        const B      = 0b0000_0010;
+        const SELECT = 0b0000_0100;
+        const START  = 0b0000_1000;
+        const UP     = 0b0001_0000;
+        const DOWN   = 0b0010_0000;
+        const LEFT   = 0b0100_0000;
+        const RIGHT  = 0b1000_0000;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Joypad {
+    buttons: Button,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad::default()
+    }
+
+    /// A write to $4016, only bit 0, the strobe, matters
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.shift = self.buttons.bits();
+        }
+    }
+
+    /// A read of the controller's port, the next button in bit 0
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons.bits() & 1;
+        }
+        let bit = self.shift & 1;
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: Button, pressed: bool) {
+        self.set_buttons(if pressed {
+            self.buttons | button
+        } else {
+            self.buttons - button
+        });
+    }
+
+    /// Sets all held buttons at once
+    pub fn set_buttons(&mut self, buttons: Button) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons.bits();
+        }
+    }
+
+    pub fn buttons(&self) -> Button {
+        self.buttons
+    }
+
+    /// The shift register and the strobe, for savestates
+    pub(crate) fn latch(&self) -> (u8, bool) {
+        (self.shift, self.strobe)
+    }
+
+    pub(crate) fn set_latch(&mut self, shift: u8, strobe: bool) {
+        self.shift = shift;
+        self.strobe = strobe;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_and_shift() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(Button::A, true);
+        joypad.set_button_pressed_status(Button::START, true);
+        joypad.set_button_pressed_status(Button::RIGHT, true);
+        joypad.set_button_pressed_status(Button::START, false);
+        assert_eq!(joypad.buttons(), Button::A | Button::RIGHT);
+
+        // nothing is latched before the first strobe
+        assert_eq!(joypad.read(), 0);
+        joypad.write(1);
+        assert_eq!((joypad.read(), joypad.read()), (1, 1));
+        joypad.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+        // buttons pressed while the strobe is high are latched at once
+        joypad.write(1);
+        joypad.set_buttons(Button::B);
+        joypad.write(0);
+        assert_eq!((joypad.read(), joypad.read()), (0, 1));
+    }
+}
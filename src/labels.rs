@@ -0,0 +1,177 @@
+// Symbol files mapping addresses to names. Supported formats, picked by
+// file extension:
+//
+//   .nl   FCEUX, one "$C000#Reset#comment" per line, CPU addresses
+//   .mlb  Mesen, "P:0000:Reset:comment" where the prefix selects the memory:
+//         P PRG ROM offset, R internal RAM, W/S work RAM, G CPU address.
+//         The Mesen 2 names (NesPrgRom, NesInternalRam, ...) work too
+//   other "C000 Reset" per line, ';' starts a comment
+use crate::bus::Bus;
+use std::collections::HashMap;
+use std::path::Path;
+
+const WORK_RAM: u16 = 0x6000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Labels {
+    cpu: HashMap<u16, String>,
+    prg: HashMap<usize, String>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels::default()
+    }
+
+    /// Adds the labels from a file to the ones already loaded
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let result = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("nl") => self.parse_nl(&text),
+            Some("mlb") => self.parse_mlb(&text),
+            _ => self.parse_native(&text),
+        };
+        result.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse_nl(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if !line.starts_with('$') {
+                continue;
+            }
+            let mut fields = line.split('#');
+            // "$0300/10" labels an array, only its start gets the name
+            let addr = fields.next().unwrap_or("");
+            let addr = addr.split('/').next().unwrap_or(addr);
+            let name = fields.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+            self.add(parse_hex(addr, n)? as u16, name);
+        }
+        Ok(())
+    }
+
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let mut fields = line.trim().splitn(4, ':');
+            let (kind, addr, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(addr), Some(name)) if !name.is_empty() => (kind, addr, name),
+                _ => continue,
+            };
+            // "0010-001F" labels a range, only its start gets the name
+            let addr = parse_hex(addr.split('-').next().unwrap_or(addr), n)?;
+            match kind {
+                "P" | "NesPrgRom" => self.add_prg(addr, name),
+                "R" | "NesInternalRam" => self.add((addr & 0x07FF) as u16, name),
+                "W" | "S" | "NesWorkRam" | "NesSaveRam" => {
+                    self.add(WORK_RAM.wrapping_add(addr as u16), name)
+                }
+                "G" | "NesMemory" => self.add(addr as u16, name),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse_native(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            if let (Some(addr), Some(name)) = (fields.next(), fields.next()) {
+                self.add(parse_hex(addr, n)? as u16, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Names a CPU address
+    pub fn add(&mut self, addr: u16, name: &str) {
+        self.cpu.insert(addr, name.to_string());
+    }
+
+    /// Names a PRG ROM byte wherever it is mapped
+    pub fn add_prg(&mut self, offset: usize, name: &str) {
+        self.prg.insert(offset, name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Name of a CPU address, CPU labels win over PRG ROM ones
+    pub fn get(&self, bus: &Bus, addr: u16) -> Option<&str> {
+        if let Some(name) = self.cpu.get(&addr) {
+            return Some(name);
+        }
+        let offset = bus.prg_rom_offset(addr)?;
+        self.prg.get(&offset).map(|name| name.as_str())
+    }
+
+    /// Reverse lookup for debugger commands, case insensitive
+    pub fn address_of(&self, bus: &Bus, name: &str) -> Option<u16> {
+        let cpu = self
+            .cpu
+            .iter()
+            .find(|(_, label)| label.eq_ignore_ascii_case(name))
+            .map(|(&addr, _)| addr);
+        cpu.or_else(|| {
+            (0x8000..=0xFFFF).find(|&addr| match bus.prg_rom_offset(addr) {
+                Some(offset) => self
+                    .prg
+                    .get(&offset)
+                    .is_some_and(|label| label.eq_ignore_ascii_case(name)),
+                None => false,
+            })
+        })
+    }
+}
+
+fn parse_hex(text: &str, line: usize) -> Result<usize, String> {
+    usize::from_str_radix(text.trim().trim_start_matches('$'), 16)
+        .map_err(|_| format!("line {}: invalid address {}", line + 1, text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_parse_formats() {
+        let bus = Bus::new(test::test_rom());
+        let mut labels = Labels::new();
+        labels
+            .parse_nl("$C000#Reset#entry point\n$0300/10#Buffer#\n$8000##\n")
+            .unwrap();
+        labels
+            .parse_mlb("P:0010:Table:comment\nR:0800:Mirror\nW:0002:Save\nG:2000:PPUCTRL\n")
+            .unwrap();
+        labels
+            .parse_native("; header\n$0020 frame_counter ; counts NMIs\nC100 Nmi\n")
+            .unwrap();
+
+        assert_eq!(labels.get(&bus, 0xc000), Some("Reset"));
+        assert_eq!(labels.get(&bus, 0x0300), Some("Buffer"));
+        assert_eq!(labels.get(&bus, 0x8000), None);
+        assert_eq!(labels.get(&bus, 0x8010), Some("Table"));
+        assert_eq!(labels.get(&bus, 0x0000), Some("Mirror"));
+        assert_eq!(labels.get(&bus, 0x6002), Some("Save"));
+        assert_eq!(labels.get(&bus, 0x2000), Some("PPUCTRL"));
+        assert_eq!(labels.get(&bus, 0x0020), Some("frame_counter"));
+        assert_eq!(labels.len(), 8);
+
+        assert_eq!(labels.address_of(&bus, "nmi"), Some(0xc100));
+        assert_eq!(labels.address_of(&bus, "table"), Some(0x8010));
+        assert_eq!(labels.address_of(&bus, "missing"), None);
+
+        assert!(labels.parse_native("ZZZZ bad").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::render::osd::{self, GLYPH_SIZE};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT_ROMS: usize = 10;
+
+/// Most-recently-used ROM list, persisted as one path per line
+pub struct RecentRoms {
+    entries: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn new() -> Self {
+        RecentRoms {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Missing or unreadable files result in an empty list
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(content) => content
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .take(MAX_RECENT_ROMS)
+                .map(PathBuf::from)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        RecentRoms { entries }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut content = String::new();
+        for entry in self.entries.iter() {
+            content.push_str(&entry.to_string_lossy());
+            content.push('\n');
+        }
+        fs::write(path, content)
+    }
+
+    pub fn add<P: AsRef<Path>>(&mut self, rom: P) {
+        let rom = rom.as_ref().to_path_buf();
+        self.entries.retain(|entry| *entry != rom);
+        self.entries.insert(0, rom);
+        self.entries.truncate(MAX_RECENT_ROMS);
+    }
+
+    pub fn entries(&self) -> &[PathBuf] {
+        &self.entries
+    }
+}
+
+impl Default for RecentRoms {
+    fn default() -> Self {
+        RecentRoms::new()
+    }
+}
+
+/// Keyboard navigable menu listing recent ROMs
+pub struct Launcher {
+    entries: Vec<PathBuf>,
+    selected: usize,
+}
+
+impl Launcher {
+    pub fn new(recent: &RecentRoms) -> Self {
+        Launcher {
+            entries: recent.entries().to_vec(),
+            selected: 0,
+        }
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.entries.is_empty() {
+            self.selected = (self.selected + 1) % self.entries.len();
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        if !self.entries.is_empty() {
+            self.selected = (self.selected + self.entries.len() - 1) % self.entries.len();
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Path> {
+        self.entries.get(self.selected).map(|p| p.as_path())
+    }
+
+    pub fn draw(&self, frame: &mut [u8], width: usize, height: usize) {
+        for pixel in frame.iter_mut() {
+            *pixel = 0;
+        }
+
+        let white = (0xFF, 0xFF, 0xFF);
+        let grey = (0x80, 0x80, 0x80);
+        let highlight = (0xFF, 0xD2, 0x30);
+        let max_chars = (width / GLYPH_SIZE).saturating_sub(3);
+
+        osd::draw_text(frame, width, height, GLYPH_SIZE, GLYPH_SIZE, "RECENT GAMES", white);
+
+        if self.entries.is_empty() {
+            osd::draw_text(frame, width, height, GLYPH_SIZE, GLYPH_SIZE * 3, "NOTHING HERE YET.", grey);
+            osd::draw_text(frame, width, height, GLYPH_SIZE, GLYPH_SIZE * 5, "DROP A ROM FILE", grey);
+            osd::draw_text(frame, width, height, GLYPH_SIZE, GLYPH_SIZE * 6, "ON THIS WINDOW", grey);
+            return;
+        }
+
+        // keep the selected entry visible when the list doesn't fit on the screen
+        let visible = ((height / GLYPH_SIZE).saturating_sub(5) / 2).max(1);
+        let first = (self.selected + 1).saturating_sub(visible);
+
+        for (row, (idx, entry)) in self.entries.iter().enumerate().skip(first).take(visible).enumerate() {
+            let name = entry
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| entry.to_string_lossy().to_string());
+            let name: String = name.chars().take(max_chars).collect();
+            let y = GLYPH_SIZE * 3 + row * GLYPH_SIZE * 2;
+
+            if idx == self.selected {
+                osd::draw_text(frame, width, height, GLYPH_SIZE, y, ">", highlight);
+                osd::draw_text(frame, width, height, GLYPH_SIZE * 3, y, &name, highlight);
+            } else {
+                osd::draw_text(frame, width, height, GLYPH_SIZE * 3, y, &name, white);
+            }
+        }
+
+        let hint_y = height.saturating_sub(GLYPH_SIZE * 2);
+        osd::draw_text(frame, width, height, GLYPH_SIZE, hint_y, "ENTER: PLAY  ESC: QUIT", grey);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_moves_rom_to_front_without_duplicates() {
+        let mut recent = RecentRoms::new();
+        recent.add("a.nes");
+        recent.add("b.nes");
+        recent.add("a.nes");
+        assert_eq!(recent.entries(), &[PathBuf::from("a.nes"), PathBuf::from("b.nes")]);
+
+        for i in 0..MAX_RECENT_ROMS + 5 {
+            recent.add(format!("{}.nes", i));
+        }
+        assert_eq!(recent.entries().len(), MAX_RECENT_ROMS);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("nes_recent_roms_{}", std::process::id()));
+        let mut recent = RecentRoms::new();
+        recent.add("/games/smb.nes");
+        recent.add("/games/zelda.zip");
+        recent.save(&path).unwrap();
+
+        let loaded = RecentRoms::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.entries(), recent.entries());
+    }
+
+    #[test]
+    fn test_launcher_navigation_wraps() {
+        let mut recent = RecentRoms::new();
+        recent.add("b.nes");
+        recent.add("a.nes");
+        let mut launcher = Launcher::new(&recent);
+
+        assert_eq!(launcher.selected(), Some(Path::new("a.nes")));
+        launcher.select_prev();
+        assert_eq!(launcher.selected(), Some(Path::new("b.nes")));
+        launcher.select_next();
+        assert_eq!(launcher.selected(), Some(Path::new("a.nes")));
+
+        let mut frame = vec![0; 256 * 240 * 3];
+        launcher.draw(&mut frame, 256, 240);
+        assert!(frame.iter().any(|&p| p != 0));
+    }
+
+    #[test]
+    fn test_draw_narrower_than_the_margins() {
+        let mut recent = RecentRoms::new();
+        recent.add("a.nes");
+        let launcher = Launcher::new(&recent);
+        let mut frame = vec![0; 16 * 240 * 3];
+        launcher.draw(&mut frame, 16, 240);
+    }
+}
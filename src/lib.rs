@@ -0,0 +1,158 @@
+//! An NES emulator as a library.
+//!
+//! A program that embeds the emulator loads a [`Rom`], inserts it into a
+//! [`Nes`] and then, once per frame, sets the held [`Button`]s and calls
+//! [`Nes::run_frame`]. [`SaveState`]s capture the whole console and go to and
+//! from bytes with `std`. Errors are [`EmuError`]s, see the [`error`] module.
+//!
+//! ```no_run
+//! use nes_book_emu::{Button, Nes, Rom};
+//!
+//! let rom = Rom::from_file("game.nes")?;
+//! let mut nes = Nes::new(rom);
+//! loop {
+//!     nes.set_buttons(0, Button::START);
+//!     nes.run_frame()?;
+//! }
+//! # Ok::<(), nes_book_emu::EmuError>(())
+//! ```
+//!
+//! [`Frame`] is the 256x240 RGB buffer that renderers draw into and frontends
+//! present, [`Nes::render`] draws the picture of the frame just run into one.
+//! [`Nes::take_samples`] hands out its audio, see the [`apu`] module.
+//! Debuggers and other tools work on the [`cpu::CPU`] itself, which
+//! [`Nes::cpu_mut`] hands out.
+//!
+//! The emulation core: CPU, PPU, bus, cartridges and the state structs they
+//! save to, needs nothing but `alloc` and builds as no_std without the default
+//! `std` feature, for embedded boards and other targets without an operating
+//! system. File IO, threads and the tools need `std`. Savestate files
+//! (`serde-state`), Lua scripting (`lua`), the terminal debugger (`tui`) and
+//! the SDL window (`sdl2-frontend`) have a feature each, so embedding the
+//! core doesn't pull in their dependencies. All of them are on by default.
+//! Hosts written in C, C++ or anything that calls C embed the console through
+//! the C API of the `ffi` feature, which is off by default. So is
+//! `rcheevos`, the emulator's side of RetroAchievements, see the
+//! [`achievements`] module.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[macro_use]
+extern crate bitflags;
+
+/// The part of std's prelude that isn't in core's, for the modules that build
+/// without std
+mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+    pub use alloc::{format, vec};
+}
+
+#[cfg(feature = "rcheevos")]
+pub mod achievements;
+pub mod apu;
+#[cfg(feature = "std")]
+pub mod asm;
+#[cfg(feature = "std")]
+pub mod audio;
+#[cfg(feature = "std")]
+pub mod battery;
+#[cfg(feature = "std")]
+pub mod bench;
+pub mod bus;
+pub mod cartridge;
+#[cfg(feature = "std")]
+pub mod cdl;
+#[cfg(feature = "std")]
+pub mod cheats;
+pub mod cpu;
+#[cfg(feature = "std")]
+pub mod crashdump;
+#[cfg(feature = "std")]
+pub mod debugger;
+#[cfg(feature = "std")]
+pub mod disasm;
+#[cfg(feature = "std")]
+pub mod emuthread;
+pub mod env;
+pub mod error;
+pub mod events;
+#[cfg(feature = "std")]
+pub mod expr;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod gamegenie;
+#[cfg(feature = "std")]
+pub mod gamesettings;
+#[cfg(feature = "std")]
+pub mod gdb;
+#[cfg(feature = "std")]
+pub mod harness;
+pub mod heatmap;
+pub mod joypad;
+#[cfg(feature = "std")]
+pub mod labels;
+#[cfg(feature = "std")]
+pub mod launcher;
+pub mod mapper;
+#[cfg(feature = "std")]
+pub mod memview;
+#[cfg(feature = "std")]
+pub mod movie;
+pub mod observer;
+pub mod opcodes;
+#[cfg(feature = "std")]
+pub mod pacer;
+#[cfg(feature = "std")]
+pub mod perf;
+pub mod ppulog;
+#[cfg(feature = "std")]
+pub mod profiler;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod tracediff;
+#[cfg(feature = "std")]
+pub mod tracelog;
+#[cfg(feature = "std")]
+pub mod netplay;
+pub mod nes;
+pub mod nes_ppu;
+#[cfg(feature = "std")]
+pub mod nsf;
+// PPU internals. Some bits, like grayscale and sprite zero hit, aren't
+// emulated yet
+#[allow(dead_code)]
+mod registers;
+pub mod render;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "serde-state")]
+pub mod rewind;
+#[cfg(feature = "std")]
+pub mod rominfo;
+#[cfg(feature = "std")]
+pub mod romwatch;
+#[cfg(feature = "std")]
+pub mod runahead;
+pub mod savestate;
+#[cfg(feature = "std")]
+pub mod scoreboard;
+#[cfg(feature = "lua")]
+pub mod script;
+pub mod snapshot;
+pub mod statehash;
+#[cfg(feature = "std")]
+pub mod stateimport;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use cartridge::Rom;
+pub use error::EmuError;
+pub use joypad::Button;
+pub use nes::Nes;
+pub use render::frame::Frame;
+pub use savestate::SaveState;
@@ -1,32 +1,57 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcodes;
-pub mod trace;
-pub mod nes_ppu;
-pub mod registers;
-pub mod render;
-
-use bus::Bus;
-use cartridge::Rom;
-use cpu::Mem;
-use cpu::CPU;
-use trace::trace;
+use nes_book_emu::apu;
+use nes_book_emu::audio::{self, SampleConsumer};
+use nes_book_emu::battery::{self, BatterySave, SaveNaming};
+use nes_book_emu::bus::Bus;
+use nes_book_emu::cartridge::Rom;
+use nes_book_emu::cdl::CodeDataLog;
+use nes_book_emu::cheats::Cheats;
+use nes_book_emu::cpu::Mem;
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::crashdump::{CrashReport, History};
+use nes_book_emu::gamegenie::GameGenieCode;
+use nes_book_emu::gamesettings::GameSettings;
+use nes_book_emu::gdb::GdbStub;
+use nes_book_emu::harness::FrameInput;
+use nes_book_emu::labels::Labels;
+use nes_book_emu::launcher::{Launcher, RecentRoms};
+use nes_book_emu::nsf::{Nsf, NsfPlayer};
+use nes_book_emu::observer::Observer;
+use nes_book_emu::pacer::FramePacer;
+use nes_book_emu::perf::{FrameTimings, DEFAULT_WINDOW};
+use nes_book_emu::profiler::Profiler;
+use nes_book_emu::render::crt::{CrtFilter, CrtSettings};
+use nes_book_emu::render::frame::Frame;
+use nes_book_emu::render::osd::{MessageKind, Osd};
+use nes_book_emu::render::palette::Palette;
+use nes_book_emu::render::renderer::Renderer;
+use nes_book_emu::replay::{ReplayLog, ReplayRecorder};
+use nes_book_emu::rominfo::{self, Region, RomInfo};
+use nes_book_emu::romwatch::RomWatcher;
+use nes_book_emu::scoreboard::Scoreboard;
+use nes_book_emu::stateimport::ForeignFormat;
+use nes_book_emu::storage::{Kind, Storage};
+use nes_book_emu::trace::{self, TraceFormat};
+use nes_book_emu::tracelog::{TraceFilter, TraceLogger};
+use nes_book_emu::tui::TuiDebugger;
+use nes_book_emu::{
+    bench, crashdump, disasm, observer, replay, scoreboard, stateimport, tracediff, tracelog,
+};
 // use rand::Rng;
 
-use sdl2::event::Event;
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::WindowCanvas;
 use sdl2::EventPump;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
 // use std::time::Duration;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
-
 fn color(byte: u8) -> Color {
     match byte {
         0 => sdl2::pixels::Color::BLACK,
@@ -41,7 +66,7 @@ fn color(byte: u8) -> Color {
     }
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
+fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     let mut frame_idx = 0;
     let mut update = false;
     for i in 0x0200..0x600 {
@@ -58,77 +83,1207 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+/// Presses and releases the controller buttons bound to keys in the game's
+/// settings
+fn apply_key_bindings(cpu: &mut CPU, settings: &GameSettings, event: &Event) {
+    let (key, pressed) = match event {
+        Event::KeyDown {
+            keycode: Some(key),
+            repeat: false,
+            ..
+        } => (key, true),
+        Event::KeyUp {
+            keycode: Some(key), ..
+        } => (key, false),
+        _ => return,
+    };
+    for (port, button) in settings.buttons_for_key(&key.name()) {
+        let buttons = cpu.bus.controller(port);
+        let buttons = if pressed {
+            buttons | button
+        } else {
+            buttons & !button
+        };
+        cpu.bus.set_controller(port, buttons);
+    }
+}
+
+/// What the player asked for in the window, besides pressing buttons
+#[derive(PartialEq)]
+enum Request {
+    Play,
+    Quit,
+    /// A ROM dropped on the window, to swap in for the running game
+    Load(PathBuf),
+}
+
+fn handle_user_input(
+    cpu: &mut CPU,
+    event_pump: &mut EventPump,
+    settings: &GameSettings,
+    video: &mut Video,
+    pacer: &mut FramePacer,
+    deterministic: bool,
+) -> Request {
+    loop {
+        let request = poll_user_input(cpu, event_pump, settings, video, pacer, deterministic);
+        // minimized: wait for the window to come back without using the CPU
+        if request != Request::Play || !pacer.is_idle() {
+            return request;
+        }
+        pacer.wait();
+    }
+}
+
+fn poll_user_input(
+    cpu: &mut CPU,
+    event_pump: &mut EventPump,
+    settings: &GameSettings,
+    video: &mut Video,
+    pacer: &mut FramePacer,
+    deterministic: bool,
+) -> Request {
+    let mut request = Request::Play;
     for event in event_pump.poll_iter() {
+        apply_key_bindings(cpu, settings, &event);
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x77);
+            } => return Request::Quit,
+            Event::DropFile { filename, .. } if deterministic => {
+                println!("Not loading {} in deterministic mode", filename)
             }
+            Event::DropFile { filename, .. } => request = Request::Load(PathBuf::from(filename)),
+            Event::Window { win_event, .. } => match win_event {
+                WindowEvent::Minimized => pacer.set_idle(true),
+                WindowEvent::Restored | WindowEvent::Shown => pacer.set_idle(false),
+                _ => {}
+            },
             Event::KeyDown {
-                keycode: Some(Keycode::S),
+                keycode: Some(Keycode::F7),
+                repeat: false,
                 ..
-            } => {
-                cpu.mem_write(0xff, 0x73);
-            }
+            } => video.next_filter(),
             Event::KeyDown {
-                keycode: Some(Keycode::A),
+                keycode: Some(Keycode::F8),
+                repeat: false,
                 ..
-            } => {
-                cpu.mem_write(0xff, 0x61);
-            }
+            } => video.toggle_crt(),
             Event::KeyDown {
-                keycode: Some(Keycode::D),
+                keycode: Some(Keycode::F9),
+                repeat: false,
                 ..
-            } => {
-                cpu.mem_write(0xff, 0x64);
-            }
+            } => video.toggle_input_display(),
             _ => { /* do nothing */ }
         }
     }
+    request
+}
+
+/// Attaches the game's battery save, loading what the game saved earlier
+fn attach_battery_save(cpu: &mut CPU, storage: &Storage, rom_path: &Path) -> Result<(), String> {
+    storage.create_dir(Kind::BatterySaves)?;
+    let path = storage.rom_file(Kind::BatterySaves, rom_path, "sav");
+    cpu.bus
+        .attach_battery_save(BatterySave::new(path))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// The game's settings, a broken file is reported and ignored
+fn load_settings(storage: &Storage, rom: &Rom) -> GameSettings {
+    GameSettings::load(storage, rom).unwrap_or_else(|e| {
+        println!("{}", e);
+        GameSettings::new()
+    })
+}
+
+/// The command line beats the game's settings, which beat detection
+fn game_region(region: Option<Region>, settings: &GameSettings, rom_path: &Path) -> Region {
+    region.or(settings.region).unwrap_or_else(|| {
+        let file_name = rom_path.file_name().and_then(|name| name.to_str());
+        RomInfo::from_file(rom_path).map_or(Region::Ntsc, |info| info.detect_region(file_name))
+    })
+}
+
+/// Swaps the game in `rom_path` in for the running one, returns its settings.
+/// The outgoing game's battery save is written before the cartridge leaves,
+/// the new game gets its own save
+fn swap_game(cpu: &mut CPU, storage: &Storage, rom_path: &Path) -> Result<GameSettings, String> {
+    let rom = Rom::from_file(rom_path).map_err(|e| e.to_string())?;
+    let settings = load_settings(storage, &rom);
+    let battery = rom.battery;
+    cpu.bus.flush_battery_save().map_err(|e| e.to_string())?;
+    cpu.swap_cartridge(rom).map_err(|e| e.to_string())?;
+    if battery {
+        attach_battery_save(cpu, storage, rom_path)?;
+    }
+    Ok(settings)
+}
+
+fn add_recent_rom(recent: &mut RecentRoms, recent_path: &Path, storage: &Storage, rom_path: &Path) {
+    recent.add(rom_path);
+    let saved = storage
+        .create_dir(Kind::Config)
+        .and_then(|_| recent.save(recent_path).map_err(|e| e.to_string()));
+    if let Err(e) = saved {
+        println!("Failed to save recent ROMs list: {}", e);
+    }
+}
+
+/// The CRT filter draws at the window's size, 3 times the NES picture
+const CRT_SCALE: usize = 3;
+
+/// The picture in the window: the frame the console finished last, in the
+/// colors of the game's palette and color filter, with the OSD over it and
+/// the CRT filter, if it is on, over all of it
+struct Video {
+    renderer: Renderer,
+    frame: Frame,
+    screen: Vec<u8>,
+    osd: Osd,
+    crt_settings: CrtSettings,
+    crt: Option<CrtFilter>,
+    crt_screen: Vec<u8>,
+}
+
+impl Video {
+    fn new() -> Self {
+        Video {
+            renderer: Renderer::new(),
+            frame: Frame::new(),
+            screen: vec![0; 256 * 240 * 3],
+            osd: Osd::new(),
+            crt_settings: CrtSettings::default(),
+            crt: None,
+            crt_screen: Vec::new(),
+        }
+    }
+
+    /// Switches to the game's palette, color filter and CRT settings. The
+    /// game's palette beats `default_palette`, a broken one is reported and
+    /// ignored. `crt` turns the CRT filter on for games without settings of
+    /// their own
+    fn apply_settings(
+        &mut self,
+        settings: &GameSettings,
+        default_palette: Option<&Path>,
+        crt: bool,
+    ) {
+        let palette = settings.load_palette(default_palette).unwrap_or_else(|e| {
+            warn!(target: "video", "{}", e);
+            Palette::system()
+        });
+        self.renderer.set_palette(palette);
+        self.renderer.set_filter(settings.filter);
+        let crt = settings.crt.or_else(|| crt.then(CrtSettings::default));
+        self.crt_settings = crt.unwrap_or_default();
+        self.set_crt(crt.is_some());
+    }
+
+    /// Draws the console's picture as RGB24, returns it with its width: 256
+    /// by 240, or `CRT_SCALE` times that with the CRT filter
+    fn draw(&mut self, cpu: &CPU, timings: Option<&FrameTimings>) -> (&[u8], usize) {
+        self.renderer
+            .render(cpu.bus.ppu(), cpu.bus.mapper(), self.frame.pixels_mut());
+        self.frame.write_rgb24(&mut self.screen);
+        self.osd.set_perf(timings);
+        self.osd
+            .set_pads([cpu.bus.controller(0), cpu.bus.controller(1)]);
+        self.osd.draw(&mut self.screen, 256, 240);
+        self.osd.tick();
+        match self.crt.as_mut() {
+            Some(crt) => {
+                crt.apply(&self.screen, &mut self.crt_screen);
+                (&self.crt_screen, crt.output_size().0)
+            }
+            None => (&self.screen, 256),
+        }
+    }
+
+    fn set_crt(&mut self, enabled: bool) {
+        self.crt = None;
+        if enabled {
+            let crt = CrtFilter::new(256, 240, CRT_SCALE, self.crt_settings);
+            let (width, height) = crt.output_size();
+            self.crt_screen.resize(width * height * 3, 0);
+            self.crt = Some(crt);
+        }
+    }
+
+    /// Shows or hides the buttons held on the controllers, for the F9 hotkey
+    fn toggle_input_display(&mut self) {
+        let enabled = !self.osd.is_input_display_enabled();
+        self.osd.set_input_display(enabled);
+    }
+
+    /// Turns the CRT filter on or off, for the F8 hotkey
+    fn toggle_crt(&mut self) {
+        let enabled = self.crt.is_none();
+        self.set_crt(enabled);
+        let text = if enabled {
+            "CRT filter on"
+        } else {
+            "CRT filter off"
+        };
+        self.osd.push(MessageKind::Info, text);
+    }
+
+    /// Switches to the next color filter, for the F7 hotkey
+    fn next_filter(&mut self) {
+        let filter = self.renderer.filter().next();
+        self.renderer.set_filter(filter);
+        let text = format!("Color filter: {}", filter.name());
+        self.osd.push(MessageKind::Info, &text);
+    }
+}
+
+/// SDL's audio callback, which runs on SDL's audio thread and plays what the
+/// emulation pushed into the sample queue
+struct AudioOutput {
+    samples: SampleConsumer,
+}
+
+impl AudioCallback for AudioOutput {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.samples.pop(out);
+    }
+}
+
+const RECENT_ROMS_FILE: &str = "recent_roms";
+
+fn run_launcher(
+    canvas: &mut WindowCanvas,
+    event_pump: &mut EventPump,
+    recent: &RecentRoms,
+) -> Option<PathBuf> {
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .unwrap();
+    let mut frame = vec![0; 256 * 240 * 3];
+    let mut launcher = Launcher::new(recent);
+
+    loop {
+        // nothing moves on its own, so sleep until there's input or a frame's time passed
+        let first = event_pump.wait_event_timeout(16);
+        for event in first.into_iter().chain(event_pump.poll_iter()) {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return None,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Up),
+                    ..
+                } => launcher.select_prev(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Down),
+                    ..
+                } => launcher.select_next(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    ..
+                } => {
+                    if let Some(path) = launcher.selected() {
+                        return Some(path.to_path_buf());
+                    }
+                }
+                Event::DropFile { filename, .. } => return Some(PathBuf::from(filename)),
+                _ => { /* do nothing */ }
+            }
+        }
+
+        launcher.draw(&mut frame, 256, 240);
+        texture.update(None, &frame, 256 * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+    }
+}
+
+fn run_bench(args: &[String]) {
+    let mut rom_path = None;
+    let mut frames = 1000;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => frames = n,
+                None => return println!("--frames expects a number"),
+            },
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => return println!("Usage: bench ROM [--frames N]"),
+    };
+
+    let rom = match Rom::from_file(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => return println!("{}: {}", rom_path, e),
+    };
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    println!("{}", bench::run(&mut cpu, frames));
+}
+
+fn run_disasm(args: &[String]) {
+    let mut rom_path = None;
+    let mut start = None;
+    let mut count = 32;
+    let mut labels = Labels::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => count = n,
+                None => return println!("--count expects a number"),
+            },
+            "--labels" => {
+                if let Err(e) = labels.load(args.next().map_or("", |path| path.as_str())) {
+                    return println!("{}", e);
+                }
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => match u16::from_str_radix(arg.trim_start_matches('$'), 16) {
+                Ok(addr) => start = Some(addr),
+                Err(_) => return println!("Invalid address: {}", arg),
+            },
+        }
+    }
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => return println!("Usage: disasm ROM [ADDR] [--count N] [--labels FILE]"),
+    };
+
+    let rom = match Rom::from_file(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => return println!("{}: {}", rom_path, e),
+    };
+    let bus = Bus::new(rom);
+    // start at the reset vector by default
+    let start = start.unwrap_or_else(|| u16::from_le_bytes([bus.peek(0xFFFC), bus.peek(0xFFFD)]));
+    for ins in disasm::disassemble(&bus, start, count) {
+        let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        if let Some(name) = labels.get(&bus, ins.addr) {
+            println!("{}:", name);
+        }
+        println!(
+            "{:04X}  {:8}  {}",
+            ins.addr,
+            bytes.join(" "),
+            ins.to_labeled_string(&labels, &bus)
+        );
+    }
+}
+
+fn run_scoreboard(args: &[String]) {
+    let mut dir = None;
+    let mut frames = scoreboard::DEFAULT_MAX_FRAMES;
+    let mut json_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => frames = n,
+                None => return println!("--frames expects a number"),
+            },
+            "--json" => json_path = args.next(),
+            _ => dir = Some(arg),
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return println!("Usage: scoreboard DIR [--frames N] [--json FILE]"),
+    };
+
+    let scoreboard = match Scoreboard::run_dir(dir, frames) {
+        Ok(scoreboard) => scoreboard,
+        Err(e) => return println!("{}", e),
+    };
+    println!("{}", scoreboard);
+    if let Some(path) = json_path {
+        if let Err(e) = std::fs::write(path, scoreboard.to_json()) {
+            println!("Can't write {}: {}", path, e);
+        }
+    }
+}
+
+fn run_tracediff(args: &[String]) {
+    let mut paths = vec![];
+    let mut context = 5;
+    let mut compare_cycles = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) => context = n,
+                None => return println!("--context expects a number"),
+            },
+            "--cycles" => compare_cycles = true,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        return println!("Usage: tracediff MINE REFERENCE [--context N] [--cycles]");
+    }
+
+    let mut logs = vec![];
+    for path in paths {
+        match std::fs::read_to_string(path) {
+            Ok(text) => logs.push(tracediff::parse_log(&text)),
+            Err(e) => return println!("Can't read {}: {}", path, e),
+        }
+    }
+    let reference = &logs[1];
+    let mine = tracediff::align(&logs[0], reference);
+    match tracediff::first_divergence(mine, reference, compare_cycles) {
+        Some(divergence) => {
+            print!(
+                "{}",
+                tracediff::report(mine, reference, &divergence, context)
+            );
+            std::process::exit(1);
+        }
+        None => {
+            let compared = mine.len().min(reference.len());
+            println!("No divergence in {} instructions", compared);
+            if mine.len() != reference.len() {
+                println!(
+                    "mine has {} instructions, reference has {}",
+                    mine.len(),
+                    reference.len()
+                );
+            }
+        }
+    }
+}
+
+fn run_verify_replay(args: &[String]) {
+    let (rom_path, log_path) = match args {
+        [rom, log] => (rom, log),
+        _ => return println!("Usage: verify-replay ROM FILE"),
+    };
+    let rom = match Rom::from_file(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => return println!("{}: {}", rom_path, e),
+    };
+    let log = match ReplayLog::load(log_path) {
+        Ok(log) => log,
+        Err(e) => return println!("{}", e),
+    };
+    match replay::verify(rom, &log) {
+        Ok(checked) => println!(
+            "{} frames replayed, {} state hashes match",
+            log.frames.len(),
+            checked
+        ),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_import_state(args: &[String]) {
+    let (rom_path, state_path, out_path) = match args {
+        [rom, state, out] => (rom, state, out),
+        _ => return println!("Usage: import-state ROM FOREIGN_STATE OUT"),
+    };
+    let rom = match Rom::from_file(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => return println!("{}: {}", rom_path, e),
+    };
+    let data = match std::fs::read(state_path) {
+        Ok(data) => data,
+        Err(e) => return println!("Can't read {}: {}", state_path, e),
+    };
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    let mut state = match stateimport::import(&data, &cpu) {
+        Ok(state) => state,
+        Err(e) => return println!("{}: {}", state_path, e),
+    };
+    // from now on the state belongs to this ROM
+    state.rom = Some(cpu.bus.rom_id().clone());
+    if let Err(e) = std::fs::write(out_path, state.to_bytes()) {
+        return println!("Can't write {}: {}", out_path, e);
+    }
+    let format = ForeignFormat::detect(&data).map_or("", |format| format.name());
+    println!("Imported {} savestate into {}", format, out_path);
+}
+
+fn run_fix_header(args: &[String]) {
+    let (rom_path, out_path) = match args {
+        [rom] => (rom, PathBuf::from(rom).with_extension("fixed.nes")),
+        [rom, out] => (rom, PathBuf::from(out)),
+        _ => return println!("Usage: fix-header ROM [OUT]"),
+    };
+    let raw = match std::fs::read(rom_path) {
+        Ok(raw) => raw,
+        Err(e) => return println!("Can't read {}: {}", rom_path, e),
+    };
+    let repaired = match rominfo::repair_header(&raw) {
+        Ok(repaired) => repaired,
+        Err(e) => return println!("{}: {}", rom_path, e),
+    };
+    if repaired.fixes.is_empty() {
+        return println!("{}: the header is fine", rom_path);
+    }
+    for fix in &repaired.fixes {
+        println!("{}", fix);
+    }
+    match std::fs::write(&out_path, &repaired.image) {
+        Ok(()) => println!("Wrote {}", out_path.display()),
+        Err(e) => println!("Can't write {}: {}", out_path.display(), e),
+    }
+}
+
+fn run_export_save(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut naming = SaveNaming::Sav;
+    let mut size = battery::SAVE_SIZE;
+    let mut storage = Storage::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--naming" => match args.next().and_then(|name| SaveNaming::from_name(name)) {
+                Some(n) => naming = n,
+                None => return println!("--naming expects sav or srm"),
+            },
+            "--size" => match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(kib) => size = kib * 1024,
+                None => return println!("--size expects a number of KiB"),
+            },
+            "--data-dir" => storage = Storage::portable(args.next().map_or("", |dir| dir.as_str())),
+            _ => paths.push(arg),
+        }
+    }
+    let (rom_path, out_path) = match paths[..] {
+        [rom] => (rom, naming.path_for_rom(rom)),
+        [rom, out] => (rom, PathBuf::from(out)),
+        _ => {
+            return println!(
+                "Usage: export-save ROM [OUT] [--naming sav|srm] [--size KIB] [--data-dir DIR]"
+            )
+        }
+    };
+    let save = BatterySave::new(storage.rom_file(Kind::BatterySaves, rom_path, "sav"));
+    let data = match save.export(size) {
+        Ok(data) => data,
+        Err(e) => return println!("{}", e),
+    };
+    match std::fs::write(&out_path, data) {
+        Ok(()) => println!(
+            "Exported {} into {}",
+            save.path().display(),
+            out_path.display()
+        ),
+        Err(e) => println!("Can't write {}: {}", out_path.display(), e),
+    }
+}
+
+fn run_import_save(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut storage = Storage::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => storage = Storage::portable(args.next().map_or("", |dir| dir.as_str())),
+            _ => paths.push(arg),
+        }
+    }
+    let (rom_path, save_path) = match paths[..] {
+        [rom, save] => (rom, save),
+        _ => return println!("Usage: import-save ROM SAVE [--data-dir DIR]"),
+    };
+    match Rom::from_file(rom_path) {
+        Ok(rom) if !rom.battery => {
+            println!("{} has no battery, the game won't use the save", rom_path)
+        }
+        Ok(_) => {}
+        Err(e) => return println!("{}: {}", rom_path, e),
+    }
+    let data = match std::fs::read(save_path) {
+        Ok(data) => data,
+        Err(e) => return println!("Can't read {}: {}", save_path, e),
+    };
+    let imported = storage.create_dir(Kind::BatterySaves).and_then(|_| {
+        let save = BatterySave::new(storage.rom_file(Kind::BatterySaves, rom_path, "sav"));
+        save.import(&data).map(|_| save)
+    });
+    match imported {
+        Ok(save) => println!("Imported {} into {}", save_path, save.path().display()),
+        Err(e) => println!("{}: {}", save_path, e),
+    }
+}
+
+fn run_cheat(args: &[String]) {
+    let byte = |arg: &String| u8::from_str_radix(arg.trim_start_matches('$'), 16).ok();
+    let code = match args {
+        [command, code] if command == "decode" => match GameGenieCode::decode(code) {
+            Ok(code) => code,
+            Err(e) => return println!("{}", e),
+        },
+        [command, addr, value, compare @ ..] if command == "encode" && compare.len() <= 1 => {
+            let addr = match u16::from_str_radix(addr.trim_start_matches('$'), 16) {
+                Ok(addr) if addr >= 0x8000 => addr,
+                _ => return println!("Not a PRG ROM address: {}", addr),
+            };
+            let value = match byte(value) {
+                Some(value) => value,
+                None => return println!("Invalid value: {}", value),
+            };
+            let compare = match compare.first() {
+                Some(arg) => match byte(arg) {
+                    Some(compare) => Some(compare),
+                    None => return println!("Invalid compare value: {}", arg),
+                },
+                None => None,
+            };
+            GameGenieCode {
+                addr,
+                value,
+                compare,
+            }
+        }
+        _ => return println!("Usage: cheat decode CODE | cheat encode ADDR VALUE [COMPARE]"),
+    };
+    match code.compare {
+        Some(compare) => println!(
+            "{} ${:04X}={:02X} if {:02X}",
+            code.encode(),
+            code.addr,
+            code.value,
+            compare
+        ),
+        None => println!("{} ${:04X}={:02X}", code.encode(), code.addr, code.value),
+    }
+}
+
+/// How long `nsf` plays songs the file gives no duration for
+const NSF_DEFAULT_DURATION: Duration = Duration::from_secs(150);
+
+fn run_nsf(args: &[String]) {
+    const USAGE: &str = "Usage: nsf FILE [--track N]";
+    let (path, first) = match args {
+        [path] => (path, 0),
+        [path, flag, n] if flag == "--track" => match n.parse::<usize>() {
+            Ok(n) if n > 0 => (path, n - 1),
+            _ => return println!("{}", USAGE),
+        },
+        _ => return println!("{}", USAGE),
+    };
+    let nsf = match Nsf::from_file(path) {
+        Ok(nsf) => nsf,
+        Err(e) => return println!("{}: {}", path, e),
+    };
+    println!("{}\n{}\n{}\n", nsf.title, nsf.artist, nsf.copyright);
+    let tracks = nsf.track_list();
+    for (n, track) in tracks.iter().enumerate() {
+        match track.duration {
+            Some(duration) => {
+                let secs = duration.as_secs();
+                println!(
+                    "{:3}. {} ({}:{:02})",
+                    n + 1,
+                    track.title,
+                    secs / 60,
+                    secs % 60
+                )
+            }
+            None => println!("{:3}. {}", n + 1, track.title),
+        }
+    }
+    let mut player = match NsfPlayer::new(nsf) {
+        Ok(player) => player,
+        Err(e) => return println!("{}: {}", path, e),
+    };
+
+    let sdl_context = sdl2::init().unwrap();
+    let mut samples = None;
+    let device = sdl_context.audio().and_then(|subsystem| {
+        let spec = AudioSpecDesired {
+            freq: Some(apu::DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        subsystem.open_playback(None, &spec, |spec| {
+            let (producer, consumer) = audio::sample_queue(spec.freq as usize / 4);
+            samples = Some(producer);
+            AudioOutput { samples: consumer }
+        })
+    });
+    let device = match device {
+        Ok(device) => device,
+        Err(e) => return println!("No audio: {}", e),
+    };
+    let mut samples = samples.expect("set when the device opened");
+    player.set_sample_rate(device.spec().freq as u32);
+    device.resume();
+
+    let mut buffer = Vec::new();
+    for (n, track) in tracks.iter().enumerate().skip(first) {
+        println!("Playing {}. {}", n + 1, track.title);
+        if let Err(e) = player.start_song(track.song) {
+            return println!("{}", e);
+        }
+        let end = match track.duration {
+            Some(duration) => duration + track.fade.unwrap_or_default(),
+            None => NSF_DEFAULT_DURATION,
+        };
+        while player.elapsed() < end {
+            // stay half a queue ahead of the audio callback
+            while samples.queued() > samples.capacity() / 2 {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            if let Err(e) = player.run_frame() {
+                return println!("{}", e);
+            }
+            player.take_samples(&mut buffer);
+            samples.push(&buffer);
+            buffer.clear();
+        }
+    }
 }
 
 fn main() {
+    // diagnostics of the core go to stderr, RUST_LOG picks them per subsystem,
+    // for example RUST_LOG=ppu=debug,apu=trace
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) == Some("info") {
+        for path in &args[1..] {
+            match RomInfo::from_file(path) {
+                Ok(info) => println!("{}\n{}\n", path, info),
+                Err(e) => println!("{}: {}\n", path, e),
+            }
+        }
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("bench") {
+        run_bench(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("disasm") {
+        run_disasm(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("scoreboard") {
+        run_scoreboard(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("tracediff") {
+        run_tracediff(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("verify-replay") {
+        run_verify_replay(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("import-state") {
+        run_import_state(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("export-save") {
+        run_export_save(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("import-save") {
+        run_import_save(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("fix-header") {
+        run_fix_header(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("cheat") {
+        run_cheat(&args[1..]);
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("nsf") {
+        run_nsf(&args[1..]);
+        return;
+    }
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
+        .window("NES", 256 * 3, 240 * 3)
         .position_centered()
         .build()
         .unwrap();
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
+    // the game runs silent without an audio device
+    let mut samples = None;
+    let audio_device = sdl_context
+        .audio()
+        .and_then(|subsystem| {
+            let spec = AudioSpecDesired {
+                freq: Some(apu::DEFAULT_SAMPLE_RATE as i32),
+                channels: Some(1),
+                samples: Some(1024),
+            };
+            subsystem.open_playback(None, &spec, |spec| {
+                // a quarter of a second is as far as audio may lag behind
+                let (producer, consumer) = audio::sample_queue(spec.freq as usize / 4);
+                samples = Some(producer);
+                AudioOutput { samples: consumer }
+            })
+        })
+        .map_err(|e| warn!(target: "audio", "No audio: {}", e))
+        .ok();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
+        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .unwrap();
+    let (crt_width, crt_height) = (256 * CRT_SCALE as u32, 240 * CRT_SCALE as u32);
+    let mut crt_texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, crt_width, crt_height)
         .unwrap();
 
     //load the game
-    let bytes: Vec<u8> = std::fs::read("nestest.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let mut rom_arg = None;
+    let mut gdb_port = None;
+    let mut tui_debugger = false;
+    let mut trace_log = None;
+    let mut trace_filter = TraceFilter::new();
+    let mut trace_format = None;
+    let mut cdl_path = None;
+    let mut labels = Labels::new();
+    let mut profiler = None;
+    let mut ppu_log_path = None;
+    let mut replay_path = None;
+    let mut crash_path = None;
+    let mut heatmap_path = None;
+    let mut load_state_path = None;
+    let mut save_state_path = None;
+    let mut force_state = false;
+    let mut deterministic = false;
+    let mut watch = false;
+    let mut keep_ram = false;
+    let mut timings = None;
+    let mut region = None;
+    let mut default_palette = None;
+    let mut crt = false;
+    let mut input_display = false;
+    let mut storage = Storage::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gdb" => gdb_port = args.next().and_then(|port| port.parse::<u16>().ok()),
+            "--debug" => tui_debugger = true,
+            "--trace" => trace_log = args.next().map(TraceLogger::new),
+            "--cdl" => cdl_path = args.next().map(PathBuf::from),
+            "--profile" => profiler = Some(Profiler::new()),
+            "--ppu-log" => ppu_log_path = args.next().map(PathBuf::from),
+            "--record-replay" => replay_path = args.next().map(PathBuf::from),
+            "--crash-dump" => crash_path = args.next().map(PathBuf::from),
+            "--heatmap" => heatmap_path = args.next().map(PathBuf::from),
+            "--load-state" => load_state_path = args.next().map(PathBuf::from),
+            "--save-state" => save_state_path = args.next().map(PathBuf::from),
+            "--force-state" => force_state = true,
+            "--deterministic" => deterministic = true,
+            "--watch" => watch = true,
+            "--keep-ram" => keep_ram = true,
+            "--perf" => timings = Some(FrameTimings::default()),
+            "--palette" => default_palette = args.next().map(PathBuf::from),
+            "--crt" => crt = true,
+            "--input-display" => input_display = true,
+            "--data-dir" => storage = Storage::portable(args.next().unwrap_or_default()),
+            "--labels" => {
+                if let Err(e) = labels.load(args.next().unwrap_or_default()) {
+                    return println!("{}", e);
+                }
+            }
+            "--trace-pc" => match tracelog::parse_range(&args.next().unwrap_or_default()) {
+                Ok(range) => trace_filter.add_pc_range(range),
+                Err(e) => return println!("{}", e),
+            },
+            "--trace-addr" => match tracelog::parse_range(&args.next().unwrap_or_default()) {
+                Ok(range) => trace_filter.add_access_range(range),
+                Err(e) => return println!("{}", e),
+            },
+            "--trace-format" => match TraceFormat::from_name(&args.next().unwrap_or_default()) {
+                Some(format) => trace_format = Some(format),
+                None => return println!("--trace-format expects nestest, mesen, csv or json"),
+            },
+            "--region" => match Region::from_name(&args.next().unwrap_or_default()) {
+                Some(name) => region = Some(name),
+                None => return println!("--region expects ntsc, pal, multi or dendy"),
+            },
+            "--trace-op" => {
+                if let Err(e) = trace_filter.add_op(&args.next().unwrap_or_default()) {
+                    return println!("{}", e);
+                }
+            }
+            _ => rom_arg = Some(PathBuf::from(arg)),
+        }
+    }
+
+    let recent_path = storage.dir(Kind::Config).join(RECENT_ROMS_FILE);
+    let mut recent = RecentRoms::load(&recent_path);
+    let mut rom_path = match rom_arg {
+        Some(path) => path,
+        None => match run_launcher(&mut canvas, &mut event_pump, &recent) {
+            Some(path) => path,
+            None => return,
+        },
+    };
+    let rom = Rom::from_file(&rom_path).unwrap();
+    // overrides saved for this game earlier
+    let mut settings = load_settings(&storage, &rom);
+    // nothing but the ROM and the controllers may affect emulation: no battery
+    // save, savestate, cheats or cartridge swaps. Replays need that to verify
+    let deterministic = deterministic || replay_path.is_some();
+    if deterministic && load_state_path.is_some() {
+        return println!(
+            "--load-state can't be used in deterministic mode or with --record-replay"
+        );
+    }
+    if deterministic && watch {
+        return println!("--watch can't be used in deterministic mode or with --record-replay");
+    }
+    // reloads the ROM when it is rebuilt, PRG RAM survives with --keep-ram
+    let mut watcher = if watch {
+        Some(RomWatcher::new(&rom_path))
+    } else {
+        None
+    };
+    let mut cheats = if deterministic {
+        Cheats::new()
+    } else {
+        settings.cheat_list()
+    };
+    let mut cheat_frame = 0;
+    let mut pacer = FramePacer::new(game_region(region, &settings, &rom_path).frame_rate());
+    let mut paced_frame = 0;
+    let mut frame_start = Instant::now();
+    let mut replay = replay_path
+        .as_ref()
+        .map(|_| ReplayRecorder::new(&rom, replay::DEFAULT_HASH_INTERVAL));
+    add_recent_rom(&mut recent, &recent_path, &storage, &rom_path);
 
+    let battery = rom.battery;
     let bus = Bus::new(rom);
     let mut cpu = CPU::new(bus);
     cpu.reset();
-    cpu.program_counter = 0xC000;
+    if let Some(device) = audio_device.as_ref() {
+        cpu.bus.apu_mut().set_sample_rate(device.spec().freq as u32);
+        device.resume();
+    }
+    if battery && !deterministic {
+        if let Err(e) = attach_battery_save(&mut cpu, &storage, &rom_path) {
+            return println!("{}", e);
+        }
+    }
+    cpu.bus.set_ppu_write_logging(ppu_log_path.is_some());
+    cpu.bus.set_heatmap(heatmap_path.is_some());
+    if let Some(path) = load_state_path {
+        let data = std::fs::read(&path).unwrap();
+        // a state for another ROM is refused unless --force-state is given
+        let loaded = if force_state {
+            cpu.force_load_state(&data)
+        } else {
+            cpu.load_state(&data).map(|_| None)
+        };
+        match loaded {
+            Ok(Some(warning)) => println!("Warning: {}", warning),
+            Ok(None) => {}
+            Err(e) => return println!("Can't load {}: {}", path.display(), e),
+        }
+    }
+
+    if let Some(port) = gdb_port {
+        println!("Waiting for a debugger on port {}", port);
+        let stream = GdbStub::listen(("127.0.0.1", port)).unwrap();
+        GdbStub::new().serve(&mut cpu, stream).unwrap();
+    }
+    if let Some(logger) = trace_log.as_mut() {
+        logger.set_filter(trace_filter);
+        if let Some(format) = trace_format {
+            logger.set_format(format);
+        }
+        logger.set_labels(labels.clone());
+        logger.start().unwrap();
+    }
+    // an existing log is extended, otherwise a new one is written on exit
+    let mut cdl = cdl_path.as_ref().map(|path| {
+        if path.exists() {
+            CodeDataLog::load(path, &cpu.bus).unwrap()
+        } else {
+            CodeDataLog::for_bus(&cpu.bus)
+        }
+    });
+    if tui_debugger {
+        let mut debugger = TuiDebugger::new();
+        debugger.set_cdl(cdl.take());
+        debugger.set_labels(labels.clone());
+        debugger.run(&mut cpu).unwrap();
+        cdl = debugger.take_cdl();
+    }
+    if let Some(profiler) = profiler.as_mut() {
+        profiler.start(&cpu);
+    }
     // let mut screen_state = [0 as u8; 32 * 3 * 32];
     // let mut rng = rand::thread_rng();
 
+    let mut video = Video::new();
+    video.apply_settings(&settings, default_palette.as_deref(), crt);
+    video.osd.set_input_display(input_display);
+
+    let mut history = History::default();
+    let history_ref = &mut history;
+    let mut trace_line = String::new();
+
     // run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
-        // handle_user_input(cpu, &mut event_pump);
+    let game_loop = move |cpu: &mut CPU| {
+        history_ref.record(cpu);
+        if observer::TRACING {
+            match trace_log.as_mut() {
+                Some(logger) => logger.log(cpu).unwrap(),
+                None => {
+                    trace_line.clear();
+                    let _ = match trace_format {
+                        Some(format) => trace::write_trace_as(&mut trace_line, cpu, format, None),
+                        None => trace::write_trace(&mut trace_line, cpu),
+                    };
+                    println!("{}", trace_line);
+                }
+            }
+        }
+        cdl.before_instruction(cpu);
+        profiler.before_instruction(cpu);
+        if let Some(recorder) = replay.as_mut() {
+            if cpu.bus.frame_count() > recorder.frame_count() {
+                let input = FrameInput::new(cpu.bus.controller(0), cpu.bus.controller(1));
+                recorder.record_frame(input, cpu);
+            }
+        }
+        if cpu.bus.frame_count() > cheat_frame {
+            cheat_frame = cpu.bus.frame_count();
+            cheats.apply(cpu);
+        }
+        if cpu.bus.frame_count() > paced_frame {
+            paced_frame = cpu.bus.frame_count();
+            if let Some(reloaded) = watcher.as_mut().and_then(|watcher| watcher.poll()) {
+                match reloaded.and_then(|rom| cpu.reload_rom(rom, keep_ram)) {
+                    Ok(()) => println!("Reloaded {}", rom_path.display()),
+                    Err(e) => println!("{}", e),
+                }
+                // the new cartridge counts frames from 0 again
+                paced_frame = 0;
+                cheat_frame = 0;
+            }
+            if let Some(timings) = timings.as_mut() {
+                timings.record_emulation(frame_start.elapsed());
+            }
+            let present_start = Instant::now();
+            let (screen, width) = video.draw(cpu, timings.as_ref());
+            let target = if width == 256 {
+                &mut texture
+            } else {
+                &mut crt_texture
+            };
+            target.update(None, screen, width * 3).unwrap();
+            canvas.copy(target, None, None).unwrap();
+            canvas.present();
+            if let Some(timings) = timings.as_mut() {
+                timings.record_present(present_start.elapsed());
+            }
+            match samples.as_mut() {
+                Some(samples) => {
+                    let queued = samples.queued();
+                    cpu.bus.apu_mut().push_samples(samples);
+                    if let Some(timings) = timings.as_mut() {
+                        timings.record_audio_fill(queued, samples.capacity());
+                    }
+                }
+                None => cpu.bus.apu_mut().clear_samples(),
+            }
+            pacer.wait();
+            frame_start = Instant::now();
+            // input is read once a frame, games read the controllers once a frame too
+            let request = handle_user_input(
+                cpu,
+                &mut event_pump,
+                &settings,
+                &mut video,
+                &mut pacer,
+                deterministic,
+            );
+            if let Request::Load(path) = &request {
+                match swap_game(cpu, &storage, path) {
+                    Ok(game) => {
+                        settings = game;
+                        cheats = settings.cheat_list();
+                        video.apply_settings(&settings, default_palette.as_deref(), crt);
+                        pacer = FramePacer::new(game_region(region, &settings, path).frame_rate());
+                        if let Some(watcher) = watcher.as_mut() {
+                            *watcher = RomWatcher::new(path);
+                        }
+                        rom_path = path.clone();
+                        add_recent_rom(&mut recent, &recent_path, &storage, &rom_path);
+                        println!("Loaded {}", rom_path.display());
+                    }
+                    Err(e) => println!("Failed to load {}: {}", path.display(), e),
+                }
+                // the new cartridge counts frames from 0 again
+                paced_frame = 0;
+                cheat_frame = 0;
+            }
+            if request == Request::Quit {
+                if let Some(logger) = trace_log.as_mut() {
+                    logger.stop().unwrap();
+                }
+                if let (Some(cdl), Some(path)) = (cdl.as_ref(), cdl_path.as_ref()) {
+                    cdl.save(path).unwrap();
+                }
+                if let Some(profiler) = profiler.as_ref() {
+                    print!("{}", profiler.format_report(&labels, &cpu.bus));
+                }
+                if let Some(timings) = timings.as_ref() {
+                    print!(
+                        "Frame times (P50, P99, max) of up to the last {} frames\n{}",
+                        DEFAULT_WINDOW, timings
+                    );
+                }
+                if let (Some(log), Some(path)) = (cpu.bus.ppu_writes(), ppu_log_path.as_ref()) {
+                    let mut file = File::create(path).unwrap();
+                    log.write_to(&mut file).unwrap();
+                }
+                if let (Some(heatmap), Some(path)) = (cpu.bus.heatmap(), heatmap_path.as_ref()) {
+                    let mut file = File::create(path).unwrap();
+                    heatmap.write_ppm(&mut file).unwrap();
+                }
+                if let (Some(recorder), Some(path)) = (replay.as_ref(), replay_path.as_ref()) {
+                    recorder.log().save(path).unwrap();
+                }
+                if let Some(path) = save_state_path.as_ref() {
+                    std::fs::write(path, cpu.save_state()).unwrap();
+                }
+                // exiting skips Drop, which would otherwise write the save
+                if let Err(e) = cpu.bus.flush_battery_save() {
+                    println!("{}", e);
+                }
+                std::process::exit(0);
+            }
+        }
 
         // cpu.mem_write(0xfe, rng.gen_range(1, 16));
 
@@ -141,5 +1296,23 @@ fn main() {
         // }
 
         // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
+    };
+    let result = crashdump::catch_panic(|| cpu.run_with_callback(game_loop));
+    let crash = match (result, cpu.bus.take_fault()) {
+        (Err(reason), _) => Some(reason),
+        (Ok(()), fault) => fault.map(|fault| fault.to_string()),
+    };
+    if let Some(reason) = crash {
+        let report = CrashReport::capture(&reason, &cpu, &history);
+        eprintln!("{}", report);
+        if let Err(e) = cpu.bus.flush_battery_save() {
+            eprintln!("{}", e);
+        }
+        if let Some(path) = crash_path {
+            if let Err(e) = report.save(path) {
+                eprintln!("{}", e);
+            }
+        }
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,681 @@
+// Cartridge boards: the logic between the console and the ROM chips that
+// decides which part of PRG and CHR the CPU and PPU see.
+//
+// The bus sends every CPU access to $8000-$FFFF and the PPU every access to
+// pattern tables at $0000-$1FFF through the cartridge's `Mapper`. Writes to
+// ROM space are how games talk to the board: they select banks and, on some
+// boards, the nametable mirroring. A board without a register at the address
+// refuses the write and the bus faults, as it did before there were mappers.
+//
+// Supported so far are the four most common boards, which together run most
+// of the library, and the board NSF music plays on:
+//   0  NROM   no registers, 16K or 32K PRG, 8K CHR
+//   1  MMC1   5 bit serial registers, PRG and CHR banking, mirroring control
+//   2  UxROM  switchable 16K PRG bank at $8000, last bank fixed at $C000
+//   3  CNROM  switchable 8K CHR bank
+//   31 NSF    eight switchable 4K PRG banks, registers at $5000-$5FFF
+// Other mapper numbers run as NROM with a warning, which is enough for games
+// that don't switch banks. Cartridges without CHR ROM get 8K of CHR RAM.
+//
+// PRG and CHR ROM are shared with every clone of the bus, CHR RAM and patched
+// PRG ROM are copied on the first write. Savestates store the registers and
+// CHR RAM, see `MapperState`.
+use crate::cartridge::{Mirroring, Rom};
+use crate::prelude::*;
+use alloc::sync::Arc;
+use tracing::warn;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+pub trait Mapper: MapperClone + Send + Sync {
+    /// The iNES mapper number
+    fn number(&self) -> u8;
+
+    fn memory(&self) -> &CartridgeMemory;
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory;
+
+    /// Offset into PRG ROM that a CPU address in $8000-$FFFF maps to, None if
+    /// there is no PRG ROM
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    /// Offset into CHR that a PPU address in $0000-$1FFF maps to
+    fn chr_offset(&self, addr: u16) -> usize;
+
+    fn mirroring(&self) -> Mirroring;
+
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.memory().prg_rom[offset],
+            None => 0,
+        }
+    }
+
+    /// A write to $8000-$FFFF, false if the board has no register there
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool;
+
+    /// A write to $4020-$5FFF, false if the board has no register there. Most
+    /// boards don't
+    fn expansion_write(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory().chr[self.chr_offset(addr)]
+    }
+
+    /// False if CHR is ROM
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        let offset = self.chr_offset(addr);
+        self.memory_mut().write_chr(offset, value)
+    }
+
+    /// Appends the registers to `registers`, boards without any append nothing
+    fn save_registers(&self, _registers: &mut Vec<u8>) {}
+
+    /// Restores what `save_registers` saved, the length is checked already
+    fn load_registers(&mut self, _registers: &[u8]) {}
+}
+
+/// Lets `Box<dyn Mapper>` be cloned along with the bus
+pub trait MapperClone {
+    fn clone_box(&self) -> Box<dyn Mapper>;
+}
+
+impl<T: Mapper + Clone + 'static> MapperClone for T {
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// The chips on the board
+#[derive(Debug, Clone)]
+pub struct CartridgeMemory {
+    pub prg_rom: Arc<[u8]>,
+    /// CHR ROM, or CHR RAM if the cartridge has no CHR ROM
+    pub chr: Arc<[u8]>,
+    pub chr_ram: bool,
+}
+
+impl CartridgeMemory {
+    pub fn new(rom: &Rom) -> Self {
+        let chr_ram = rom.chr_rom.is_empty();
+        CartridgeMemory {
+            prg_rom: rom.prg_rom.clone(),
+            chr: if chr_ram {
+                vec![0; CHR_RAM_SIZE].into()
+            } else {
+                rom.chr_rom.clone()
+            },
+            chr_ram,
+        }
+    }
+
+    /// 0 for CHR RAM
+    pub fn chr_rom_len(&self) -> usize {
+        if self.chr_ram {
+            0
+        } else {
+            self.chr.len()
+        }
+    }
+
+    /// PRG ROM for patching. Consoles cloned from this one keep the unpatched
+    /// data, a copy is made the first time a shared ROM is patched
+    pub fn prg_rom_mut(&mut self) -> &mut [u8] {
+        make_mut(&mut self.prg_rom)
+    }
+
+    pub fn chr_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.chr_ram {
+            Some(make_mut(&mut self.chr))
+        } else {
+            None
+        }
+    }
+
+    /// Returns false if CHR is ROM
+    pub fn write_chr(&mut self, offset: usize, value: u8) -> bool {
+        match self.chr_ram_mut() {
+            Some(chr_ram) => {
+                chr_ram[offset] = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Offset into PRG ROM of `addr` in bank `bank` of `bank_size` bytes,
+    /// banks past the end of the ROM wrap around
+    fn prg_bank_offset(&self, bank: usize, bank_size: usize, addr: u16) -> Option<usize> {
+        if self.prg_rom.is_empty() {
+            return None;
+        }
+        Some((bank * bank_size + (addr as usize & (bank_size - 1))) % self.prg_rom.len())
+    }
+
+    fn chr_bank_offset(&self, bank: usize, bank_size: usize, addr: u16) -> usize {
+        (bank * bank_size + (addr as usize & (bank_size - 1))) % self.chr.len()
+    }
+
+    fn prg_banks(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+}
+
+fn make_mut(data: &mut Arc<[u8]>) -> &mut [u8] {
+    if Arc::get_mut(data).is_none() {
+        *data = Arc::from(&data[..]);
+    }
+    Arc::get_mut(data).expect("just copied")
+}
+
+/// The board for the cartridge's mapper number
+pub fn for_rom(rom: &Rom) -> Box<dyn Mapper> {
+    let memory = CartridgeMemory::new(rom);
+    let mirroring = rom.screen_mirroring.clone();
+    match rom.mapper {
+        0 => Box::new(Nrom::new(memory, mirroring)),
+        1 => Box::new(Mmc1::new(memory)),
+        2 => Box::new(Uxrom::new(memory, mirroring)),
+        3 => Box::new(Cnrom::new(memory, mirroring)),
+        31 => Box::new(NsfBoard::new(memory, mirroring)),
+        number => {
+            warn!(target: "mapper", "mapper {} is not supported, running as NROM", number);
+            Box::new(Nrom {
+                number,
+                memory,
+                mirroring,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Nrom {
+    number: u8,
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(memory: CartridgeMemory, mirroring: Mirroring) -> Self {
+        Nrom {
+            number: 0,
+            memory,
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn number(&self) -> u8 {
+        self.number
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    /// 16K of PRG ROM is mirrored at $C000
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.memory.prg_bank_offset(0, 0x8000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.memory.chr_bank_offset(0, 0x2000, addr)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_write(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mmc1 {
+    memory: CartridgeMemory,
+    /// Bits written so far, the first in bit 0
+    shift: u8,
+    writes: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mmc1 {
+            memory,
+            shift: 0,
+            writes: 0,
+            // PRG mode 3, the last bank is at $C000 on power on
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_mode(&self) -> u8 {
+        (self.control >> 2) & 0b11
+    }
+
+    fn chr_4k_banks(&self) -> bool {
+        self.control & 0b1_0000 != 0
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn number(&self) -> u8 {
+        1
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let memory = &self.memory;
+        match (self.prg_mode(), addr) {
+            (0, _) | (1, _) => memory.prg_bank_offset(bank >> 1, 0x8000, addr),
+            (2, 0x8000..=0xBFFF) => memory.prg_bank_offset(0, PRG_BANK_SIZE, addr),
+            (2, _) => memory.prg_bank_offset(bank, PRG_BANK_SIZE, addr),
+            (_, 0x8000..=0xBFFF) => memory.prg_bank_offset(bank, PRG_BANK_SIZE, addr),
+            _ => memory.prg_bank_offset(memory.prg_banks() - 1, PRG_BANK_SIZE, addr),
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        if !self.chr_4k_banks() {
+            let bank = (self.chr_bank_0 >> 1) as usize;
+            return self.memory.chr_bank_offset(bank, 0x2000, addr);
+        }
+        let bank = if addr < 0x1000 {
+            self.chr_bank_0
+        } else {
+            self.chr_bank_1
+        };
+        self.memory.chr_bank_offset(bank as usize, 0x1000, addr)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::ONE_SCREEN_LOWER,
+            1 => Mirroring::ONE_SCREEN_UPPER,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    /// Registers are loaded a bit at a time, bit 0 of five writes. Bit 7
+    /// starts over and puts PRG ROM back in mode 3
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if value & 0x80 != 0 {
+            self.shift = 0;
+            self.writes = 0;
+            self.control |= 0x0C;
+            return true;
+        }
+        self.shift |= (value & 1) << self.writes;
+        self.writes += 1;
+        if self.writes == 5 {
+            let register = self.shift;
+            match addr {
+                0x8000..=0x9FFF => self.control = register,
+                0xA000..=0xBFFF => self.chr_bank_0 = register,
+                0xC000..=0xDFFF => self.chr_bank_1 = register,
+                _ => self.prg_bank = register,
+            }
+            self.shift = 0;
+            self.writes = 0;
+        }
+        true
+    }
+
+    fn save_registers(&self, registers: &mut Vec<u8>) {
+        registers.extend_from_slice(&[
+            self.shift,
+            self.writes,
+            self.control,
+            self.chr_bank_0,
+            self.chr_bank_1,
+            self.prg_bank,
+        ]);
+    }
+
+    fn load_registers(&mut self, registers: &[u8]) {
+        self.shift = registers[0];
+        self.writes = registers[1] % 5;
+        self.control = registers[2];
+        self.chr_bank_0 = registers[3];
+        self.chr_bank_1 = registers[4];
+        self.prg_bank = registers[5];
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Uxrom {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+    bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(memory: CartridgeMemory, mirroring: Mirroring) -> Self {
+        Uxrom {
+            memory,
+            mirroring,
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn number(&self) -> u8 {
+        2
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let bank = if addr < 0xC000 {
+            self.bank as usize
+        } else {
+            self.memory.prg_banks() - 1
+        };
+        self.memory.prg_bank_offset(bank, PRG_BANK_SIZE, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.memory.chr_bank_offset(0, 0x2000, addr)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_write(&mut self, _addr: u16, value: u8) -> bool {
+        self.bank = value;
+        true
+    }
+
+    fn save_registers(&self, registers: &mut Vec<u8>) {
+        registers.push(self.bank);
+    }
+
+    fn load_registers(&mut self, registers: &[u8]) {
+        self.bank = registers[0];
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cnrom {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+    bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(memory: CartridgeMemory, mirroring: Mirroring) -> Self {
+        Cnrom {
+            memory,
+            mirroring,
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn number(&self) -> u8 {
+        3
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.memory.prg_bank_offset(0, 0x8000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.memory
+            .chr_bank_offset(self.bank as usize, 0x2000, addr)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_write(&mut self, _addr: u16, value: u8) -> bool {
+        self.bank = value;
+        true
+    }
+
+    fn save_registers(&self, registers: &mut Vec<u8>) {
+        registers.push(self.bank);
+    }
+
+    fn load_registers(&mut self, registers: &[u8]) {
+        self.bank = registers[0];
+    }
+}
+
+/// The board of NSF music, and of the NES ports of it. Each register picks
+/// the 4K bank of one slot of $8000-$FFFF, `addr & 7` selects the slot, so
+/// $5FF8-$5FFF of the NSF format are the last mirror
+#[derive(Debug, Clone)]
+pub struct NsfBoard {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+    banks: [u8; 8],
+}
+
+impl NsfBoard {
+    pub fn new(memory: CartridgeMemory, mirroring: Mirroring) -> Self {
+        NsfBoard {
+            memory,
+            mirroring,
+            // bank $FF at $F000 on power on, the last one of most ROMs, so
+            // the vectors are there
+            banks: [0, 0, 0, 0, 0, 0, 0, 0xFF],
+        }
+    }
+}
+
+impl Mapper for NsfBoard {
+    fn number(&self) -> u8 {
+        31
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let bank = self.banks[(addr as usize >> 12) & 7];
+        self.memory.prg_bank_offset(bank as usize, 0x1000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.memory.chr_bank_offset(0, 0x2000, addr)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn cpu_write(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn expansion_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x5000 {
+            return false;
+        }
+        self.banks[addr as usize & 7] = value;
+        true
+    }
+
+    fn save_registers(&self, registers: &mut Vec<u8>) {
+        registers.extend_from_slice(&self.banks);
+    }
+
+    fn load_registers(&mut self, registers: &[u8]) {
+        self.banks.copy_from_slice(&registers[..8]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    /// Every byte of a bank holds the bank's number
+    fn banked_rom(mapper: u8, prg_banks: usize, chr_banks: usize) -> Rom {
+        let mut rom = RomBuilder::new().mapper(mapper).build();
+        rom.prg_rom = (0..prg_banks * PRG_BANK_SIZE)
+            .map(|i| (i / PRG_BANK_SIZE) as u8)
+            .collect::<Vec<_>>()
+            .into();
+        rom.chr_rom = (0..chr_banks * 0x1000)
+            .map(|i| (i / 0x1000) as u8)
+            .collect::<Vec<_>>()
+            .into();
+        rom
+    }
+
+    fn mmc1_write(mapper: &mut dyn Mapper, addr: u16, value: u8) {
+        for bit in 0..5 {
+            assert!(mapper.cpu_write(addr, (value >> bit) & 1));
+        }
+    }
+
+    #[test]
+    fn test_nrom() {
+        let mut nrom = for_rom(&RomBuilder::new().prg_pages(1).fill_prg(7).build());
+        assert_eq!(nrom.prg_rom_offset(0xC001), Some(1));
+        assert_eq!(nrom.cpu_read(0xFFFF), 7);
+        assert!(!nrom.cpu_write(0x8000, 1));
+        assert!(!nrom.ppu_write(0x0000, 1));
+    }
+
+    #[test]
+    fn test_uxrom_switches_the_first_bank() {
+        let mut uxrom = for_rom(&banked_rom(2, 8, 2));
+        assert_eq!((uxrom.cpu_read(0x8000), uxrom.cpu_read(0xC000)), (0, 7));
+        assert!(uxrom.cpu_write(0xFFF0, 5));
+        assert_eq!((uxrom.cpu_read(0xBFFF), uxrom.cpu_read(0xFFFF)), (5, 7));
+    }
+
+    #[test]
+    fn test_cnrom_switches_chr() {
+        let mut cnrom = for_rom(&banked_rom(3, 2, 8));
+        assert_eq!(cnrom.ppu_read(0x1000), 1);
+        cnrom.cpu_write(0x8000, 2);
+        assert_eq!((cnrom.ppu_read(0x0000), cnrom.ppu_read(0x1FFF)), (4, 5));
+        assert!(!cnrom.ppu_write(0x0000, 0));
+    }
+
+    #[test]
+    fn test_nsf_board_switches_4k_banks() {
+        // 16 banks of 4K, four to each 16K bank of `banked_rom`
+        let mut nsf = for_rom(&banked_rom(31, 4, 0));
+        assert_eq!(nsf.cpu_read(0xF000), 3);
+        assert!(nsf.expansion_write(0x5FF8, 5));
+        assert_eq!((nsf.cpu_read(0x8000), nsf.cpu_read(0x9000)), (1, 0));
+        assert!(nsf.expansion_write(0x5003, 8));
+        assert_eq!(nsf.cpu_read(0xBFFF), 2);
+        assert!(!nsf.expansion_write(0x4800, 1));
+        assert!(!nsf.cpu_write(0x8000, 1));
+    }
+
+    #[test]
+    fn test_mmc1() {
+        let mut mmc1 = for_rom(&banked_rom(1, 8, 8));
+        // powers on with the last bank fixed at $C000
+        assert_eq!((mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000)), (0, 7));
+
+        mmc1_write(&mut *mmc1, 0xE000, 3);
+        assert_eq!((mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000)), (3, 7));
+        // fixed first bank, vertical mirroring, 4K CHR banks
+        mmc1_write(&mut *mmc1, 0x8000, 0b1_1010);
+        assert_eq!((mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000)), (0, 3));
+        assert_eq!(mmc1.mirroring(), Mirroring::VERTICAL);
+        mmc1_write(&mut *mmc1, 0xA000, 5);
+        mmc1_write(&mut *mmc1, 0xC000, 2);
+        assert_eq!((mmc1.ppu_read(0x0000), mmc1.ppu_read(0x1000)), (5, 2));
+
+        // 32K PRG and 8K CHR banks ignore the lowest bit of the bank
+        mmc1_write(&mut *mmc1, 0x8000, 0b0_0000);
+        assert_eq!((mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000)), (2, 3));
+        assert_eq!((mmc1.ppu_read(0x0000), mmc1.ppu_read(0x1000)), (4, 5));
+        assert_eq!(mmc1.mirroring(), Mirroring::ONE_SCREEN_LOWER);
+
+        // a write with bit 7 set starts over
+        mmc1.cpu_write(0x8000, 1);
+        mmc1.cpu_write(0x8000, 0x80);
+        mmc1_write(&mut *mmc1, 0xE000, 6);
+        assert_eq!((mmc1.cpu_read(0x8000), mmc1.cpu_read(0xC000)), (6, 7));
+    }
+
+    #[test]
+    fn test_chr_ram_and_registers() {
+        let mut mmc1 = for_rom(&RomBuilder::new().mapper(1).chr_pages(0).build());
+        assert!(mmc1.ppu_write(0x1234, 0x42));
+        assert_eq!(mmc1.ppu_read(0x1234), 0x42);
+        assert_eq!(mmc1.memory().chr_rom_len(), 0);
+
+        let clone = mmc1.clone();
+        assert!(mmc1.ppu_write(0x1234, 0x43));
+        assert_eq!(clone.ppu_read(0x1234), 0x42);
+
+        mmc1.cpu_write(0xE000, 1);
+        let mut registers = Vec::new();
+        mmc1.save_registers(&mut registers);
+        let mut restored = clone.clone();
+        restored.load_registers(&registers);
+        restored.cpu_write(0xE000, 0);
+        mmc1.cpu_write(0xE000, 0);
+        let mut expected = Vec::new();
+        mmc1.save_registers(&mut expected);
+        registers.clear();
+        restored.save_registers(&mut registers);
+        assert_eq!(registers, expected);
+    }
+}
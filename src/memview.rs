@@ -0,0 +1,192 @@
+use crate::cpu::{Mem, CPU};
+
+/// Number of updates a modified byte stays highlighted for
+const HIGHLIGHT_UPDATES: u8 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryRegion {
+    /// The whole CPU address space as seen by Bus::peek
+    Cpu,
+    Ram,
+    PrgRam,
+    Vram,
+    Oam,
+    Palette,
+}
+
+impl MemoryRegion {
+    pub const ALL: [MemoryRegion; 6] = [
+        MemoryRegion::Cpu,
+        MemoryRegion::Ram,
+        MemoryRegion::PrgRam,
+        MemoryRegion::Vram,
+        MemoryRegion::Oam,
+        MemoryRegion::Palette,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryRegion::Cpu => "cpu",
+            MemoryRegion::Ram => "ram",
+            MemoryRegion::PrgRam => "prgram",
+            MemoryRegion::Vram => "vram",
+            MemoryRegion::Oam => "oam",
+            MemoryRegion::Palette => "palette",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MemoryRegion> {
+        let name = name.to_ascii_lowercase();
+        MemoryRegion::ALL.iter().copied().find(|r| r.name() == name)
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            MemoryRegion::Cpu => 0x10000,
+            MemoryRegion::Ram => 0x800,
+            MemoryRegion::PrgRam => 0x2000,
+            MemoryRegion::Vram => 0x800,
+            MemoryRegion::Oam => 0x100,
+            MemoryRegion::Palette => 0x20,
+        }
+    }
+
+    /// Reads without side effects, offsets wrap around the region size
+    pub fn read(&self, cpu: &CPU, offset: usize) -> u8 {
+        let offset = offset % self.size();
+        match self {
+            MemoryRegion::Cpu => cpu.bus.peek(offset as u16),
+            MemoryRegion::Ram => cpu.bus.peek(offset as u16),
+            MemoryRegion::PrgRam => cpu.bus.prg_ram()[offset],
+            MemoryRegion::Vram => cpu.bus.ppu().vram[offset],
+            MemoryRegion::Oam => cpu.bus.ppu().oam_data[offset],
+            MemoryRegion::Palette => cpu.bus.ppu().palette_table[offset],
+        }
+    }
+
+    pub fn write(&self, cpu: &mut CPU, offset: usize, value: u8) -> Result<(), String> {
+        if offset >= self.size() {
+            return Err(format!("Offset {:X} is outside of {}", offset, self.name()));
+        }
+        match self {
+            // only plain memory is writable, I/O registers and ROM are not
+            MemoryRegion::Cpu => match offset {
+                0x0000..=0x1FFF | 0x6000..=0x7FFF => cpu.mem_write(offset as u16, value),
+                _ => return Err(format!("${:04X} is not writable", offset)),
+            },
+            MemoryRegion::Ram => cpu.mem_write(offset as u16, value),
+            MemoryRegion::PrgRam => cpu.bus.prg_ram_mut()[offset] = value,
+            MemoryRegion::Vram => cpu.bus.ppu_mut().vram[offset] = value,
+            MemoryRegion::Oam => cpu.bus.ppu_mut().oam_data[offset] = value,
+            MemoryRegion::Palette => cpu.bus.ppu_mut().palette_table[offset] = value,
+        }
+        Ok(())
+    }
+}
+
+/// Tracks a memory region between updates to highlight recently changed bytes
+pub struct MemoryViewer {
+    region: MemoryRegion,
+    snapshot: Vec<u8>,
+    changed: Vec<u8>,
+}
+
+impl MemoryViewer {
+    pub fn new(region: MemoryRegion) -> Self {
+        MemoryViewer {
+            region,
+            snapshot: Vec::new(),
+            changed: vec![0; region.size()],
+        }
+    }
+
+    pub fn region(&self) -> MemoryRegion {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: MemoryRegion) {
+        if region != self.region {
+            *self = MemoryViewer::new(region);
+        }
+    }
+
+    /// To be called after every step or frame
+    pub fn update(&mut self, cpu: &CPU) {
+        let current: Vec<u8> = (0..self.region.size())
+            .map(|offset| self.region.read(cpu, offset))
+            .collect();
+        for (i, age) in self.changed.iter_mut().enumerate() {
+            if !self.snapshot.is_empty() && self.snapshot[i] != current[i] {
+                *age = HIGHLIGHT_UPDATES;
+            } else {
+                *age = age.saturating_sub(1);
+            }
+        }
+        self.snapshot = current;
+    }
+
+    pub fn is_recently_changed(&self, offset: usize) -> bool {
+        matches!(self.changed.get(offset), Some(&age) if age > 0)
+    }
+
+    pub fn read(&self, cpu: &CPU, offset: usize) -> u8 {
+        self.region.read(cpu, offset)
+    }
+
+    /// Writes a byte and highlights it
+    pub fn write(&mut self, cpu: &mut CPU, offset: usize, value: u8) -> Result<(), String> {
+        self.region.write(cpu, offset, value)?;
+        if let Some(age) = self.changed.get_mut(offset) {
+            *age = HIGHLIGHT_UPDATES;
+        }
+        if let Some(old) = self.snapshot.get_mut(offset) {
+            *old = value;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_regions() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        for region in MemoryRegion::ALL.iter() {
+            assert_eq!(MemoryRegion::from_name(region.name()), Some(*region));
+            if *region != MemoryRegion::Cpu {
+                region.write(&mut cpu, 3, 0x42).unwrap();
+                assert_eq!(region.read(&cpu, 3), 0x42);
+            }
+        }
+        assert_eq!(cpu.bus.ppu().oam_data[3], 0x42);
+        assert_eq!(MemoryRegion::Cpu.read(&cpu, 0x6003), 0x42);
+        assert!(MemoryRegion::Cpu.write(&mut cpu, 0x8000, 0).is_err());
+        assert!(MemoryRegion::Palette.write(&mut cpu, 0x20, 0).is_err());
+    }
+
+    #[test]
+    fn test_highlight_changed_bytes() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        let mut viewer = MemoryViewer::new(MemoryRegion::Ram);
+        viewer.update(&cpu);
+        assert!(!viewer.is_recently_changed(0x10));
+
+        cpu.mem_write(0x10, 1);
+        viewer.update(&cpu);
+        assert!(viewer.is_recently_changed(0x10));
+        assert!(!viewer.is_recently_changed(0x11));
+
+        for _ in 0..HIGHLIGHT_UPDATES {
+            viewer.update(&cpu);
+        }
+        assert!(!viewer.is_recently_changed(0x10));
+
+        viewer.write(&mut cpu, 0x11, 7).unwrap();
+        assert!(viewer.is_recently_changed(0x11));
+        assert_eq!(viewer.read(&cpu, 0x11), 7);
+    }
+}
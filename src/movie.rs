@@ -0,0 +1,438 @@
+// Input movies in FCEUX's FM2 text format.
+//
+// The file is a list of "key value" header lines followed by one line per frame:
+//
+//   |commands|RLDUTSBA|RLDUTSBA||
+//
+// `commands` is a decimal bit set (soft reset, power cycle), each port column lists
+// the pressed buttons by letter and uses '.' for released ones.
+use crate::cartridge::Rom;
+use std::fmt::Write;
+use std::path::Path;
+
+const FM2_VERSION: u32 = 3;
+const EMU_VERSION: u32 = 22020;
+const BUTTON_LETTERS: &[u8; 8] = b"RLDUTSBA";
+
+bitflags! {
+    pub struct MovieCommands: u8 {
+        const SOFT_RESET = 0b00000001;
+        const POWER      = 0b00000010;
+    }
+}
+
+impl Default for MovieCommands {
+    fn default() -> Self {
+        MovieCommands::empty()
+    }
+}
+
+/// Controller state is stored as RLDUTSBA, Right in bit 7 and A in bit 0
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MovieFrame {
+    pub commands: MovieCommands,
+    pub ports: [u8; 2],
+}
+
+impl MovieFrame {
+    fn write_fm2(&self, out: &mut String) {
+        write!(out, "|{}|", self.commands.bits()).unwrap();
+        for port in self.ports.iter() {
+            for (i, letter) in BUTTON_LETTERS.iter().enumerate() {
+                let pressed = port & (0x80 >> i) != 0;
+                out.push(if pressed { *letter as char } else { '.' });
+            }
+            out.push('|');
+        }
+        out.push_str("|\n");
+    }
+
+    fn parse_fm2(line: &str) -> Result<MovieFrame, String> {
+        let fields: Vec<&str> = line.split('|').collect();
+        if fields.len() < 4 {
+            return Err(format!("Malformed input line: {}", line));
+        }
+        let commands = fields[1]
+            .trim()
+            .parse::<u8>()
+            .map_err(|_| format!("Malformed commands in line: {}", line))?;
+
+        let mut ports = [0; 2];
+        for (port, field) in ports.iter_mut().zip(fields[2..4].iter()) {
+            if field.is_empty() {
+                continue;
+            }
+            if field.len() != BUTTON_LETTERS.len() {
+                return Err(format!("Malformed controller state in line: {}", line));
+            }
+            for (i, c) in field.chars().enumerate() {
+                if c != '.' && c != ' ' {
+                    *port |= 0x80 >> i;
+                }
+            }
+        }
+
+        Ok(MovieFrame {
+            commands: MovieCommands::from_bits_truncate(commands),
+            ports,
+        })
+    }
+}
+
+pub struct Movie {
+    pub rom_filename: String,
+    /// base64 encoded MD5 of PRG and CHR data
+    pub rom_checksum: String,
+    pub guid: String,
+    pub rerecord_count: u32,
+    pub comments: Vec<String>,
+    /// Movies without a savestate start from power-on
+    pub savestate: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom: &Rom) -> Self {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: rom_checksum(rom),
+            guid: random_guid(),
+            rerecord_count: 0,
+            comments: Vec::new(),
+            savestate: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "version {}", FM2_VERSION).unwrap();
+        writeln!(out, "emuVersion {}", EMU_VERSION).unwrap();
+        writeln!(out, "rerecordCount {}", self.rerecord_count).unwrap();
+        writeln!(out, "palFlag 0").unwrap();
+        writeln!(out, "romFilename {}", self.rom_filename).unwrap();
+        writeln!(out, "romChecksum base64:{}", self.rom_checksum).unwrap();
+        writeln!(out, "guid {}", self.guid).unwrap();
+        writeln!(out, "fourscore 0").unwrap();
+        writeln!(out, "microphone 0").unwrap();
+        writeln!(out, "port0 1").unwrap();
+        writeln!(out, "port1 1").unwrap();
+        writeln!(out, "port2 0").unwrap();
+        writeln!(out, "FDS 0").unwrap();
+        writeln!(out, "NewPPU 0").unwrap();
+        for comment in self.comments.iter() {
+            writeln!(out, "comment {}", comment).unwrap();
+        }
+        if let Some(state) = &self.savestate {
+            writeln!(out, "savestate base64:{}", base64::encode(state)).unwrap();
+        }
+        for frame in self.frames.iter() {
+            frame.write_fm2(&mut out);
+        }
+        out
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie {
+            rom_filename: String::new(),
+            rom_checksum: String::new(),
+            guid: String::new(),
+            rerecord_count: 0,
+            comments: Vec::new(),
+            savestate: None,
+            frames: Vec::new(),
+        };
+
+        for line in text.lines() {
+            if line.starts_with('|') {
+                movie.frames.push(MovieFrame::parse_fm2(line)?);
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "version" if value != FM2_VERSION.to_string() => {
+                    return Err(format!("Unsupported FM2 version: {}", value));
+                }
+                "rerecordCount" => {
+                    movie.rerecord_count = value
+                        .parse()
+                        .map_err(|_| format!("Malformed rerecord count: {}", value))?;
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    movie.rom_checksum = value.trim_start_matches("base64:").to_string()
+                }
+                "guid" => movie.guid = value.to_string(),
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => {
+                    let state = base64::decode(value.trim_start_matches("base64:"))
+                        .map_err(|e| format!("Malformed savestate: {}", e))?;
+                    movie.savestate = Some(state);
+                }
+                _ => { /* other keys describe hardware we always emulate the same way */ }
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        Movie::from_fm2(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_fm2())
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    /// Checks that the movie was recorded against the same ROM
+    pub fn matches_rom(&self, rom: &Rom) -> bool {
+        self.rom_checksum.is_empty() || self.rom_checksum == rom_checksum(rom)
+    }
+}
+
+fn rom_checksum(rom: &Rom) -> String {
+    let mut ctx = md5::Context::new();
+    ctx.consume(&rom.prg_rom);
+    ctx.consume(&rom.chr_rom);
+    base64::encode(ctx.compute().0)
+}
+
+fn random_guid() -> String {
+    let b: [u8; 16] = rand::random();
+    format!(
+        "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+/// Appends one frame of input per call. Resets and power cycles requested in
+/// between are attached to the next recorded frame
+pub struct MovieRecorder {
+    movie: Movie,
+    pending: MovieCommands,
+}
+
+impl MovieRecorder {
+    pub fn new(movie: Movie) -> Self {
+        MovieRecorder {
+            movie,
+            pending: MovieCommands::empty(),
+        }
+    }
+
+    /// Starts recording from the given savestate instead of power-on
+    pub fn from_savestate(mut movie: Movie, state: Vec<u8>) -> Self {
+        movie.savestate = Some(state);
+        MovieRecorder::new(movie)
+    }
+
+    pub fn soft_reset(&mut self) {
+        self.pending.insert(MovieCommands::SOFT_RESET);
+    }
+
+    pub fn power(&mut self) {
+        self.pending.insert(MovieCommands::POWER);
+    }
+
+    pub fn record_frame(&mut self, port0: u8, port1: u8) {
+        self.movie.frames.push(MovieFrame {
+            commands: self.pending,
+            ports: [port0, port1],
+        });
+        self.pending = MovieCommands::empty();
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Feeds recorded input back one frame at a time.
+///
+/// Loading a savestate while playing seeks to the frame it was taken at. In
+/// read-only mode playback simply continues from there, otherwise the rest of the
+/// movie is dropped and recording resumes from that frame (a re-record)
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    read_only: bool,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer {
+            movie,
+            frame: 0,
+            read_only: true,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Input for the upcoming frame, `None` once the movie has ended
+    pub fn next_frame(&mut self) -> Option<MovieFrame> {
+        let frame = self.movie.frames.get(self.frame).copied();
+        if frame.is_some() {
+            self.frame += 1;
+        }
+        frame
+    }
+
+    /// To be called after loading a savestate taken at `frame`. Frontends should
+    /// follow up with [`MoviePlayer::resume_recording`] unless the player is read-only
+    pub fn load_state(&mut self, frame: usize) -> Result<(), String> {
+        if frame > self.movie.frames.len() {
+            return Err(format!(
+                "Savestate from frame {} is past the end of the movie ({} frames)",
+                frame,
+                self.movie.frames.len()
+            ));
+        }
+        self.frame = frame;
+        Ok(())
+    }
+
+    /// Drops everything after the current frame and continues recording from it
+    pub fn resume_recording(mut self) -> MovieRecorder {
+        self.movie.frames.truncate(self.frame);
+        self.movie.rerecord_count += 1;
+        MovieRecorder::new(self.movie)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_frame_line_format() {
+        let mut line = String::new();
+        MovieFrame {
+            commands: MovieCommands::SOFT_RESET,
+            ports: [0b1000_0001, 0b0001_0000],
+        }
+        .write_fm2(&mut line);
+        assert_eq!(line, "|1|R......A|...U....||\n");
+    }
+
+    #[test]
+    fn test_record_movie() {
+        let mut recorder = MovieRecorder::new(Movie::new("game.nes", &test::test_rom()));
+        recorder.record_frame(0, 0);
+        recorder.soft_reset();
+        recorder.record_frame(0x01, 0);
+        recorder.record_frame(0x80, 0x40);
+        assert_eq!(recorder.frame_count(), 3);
+
+        let fm2 = recorder.finish().to_fm2();
+        let lines: Vec<&str> = fm2.lines().collect();
+        assert_eq!(lines[0], "version 3");
+        assert!(lines.contains(&"romFilename game.nes"));
+        assert!(lines.iter().any(|l| l.starts_with("romChecksum base64:")));
+        assert!(!lines.iter().any(|l| l.starts_with("savestate")));
+        assert_eq!(
+            &lines[lines.len() - 3..],
+            &[
+                "|0|........|........||",
+                "|1|.......A|........||",
+                "|0|R.......|.L......||",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let movie = Movie::new("game.nes", &test::test_rom());
+        let mut recorder = MovieRecorder::from_savestate(movie, vec![7; 10]);
+        recorder.power();
+        recorder.record_frame(0xFF, 0x00);
+        recorder.record_frame(0x12, 0x34);
+        let movie = recorder.finish();
+
+        let loaded = Movie::from_fm2(&movie.to_fm2()).unwrap();
+        assert_eq!(loaded.rom_filename, movie.rom_filename);
+        assert_eq!(loaded.rom_checksum, movie.rom_checksum);
+        assert_eq!(loaded.guid, movie.guid);
+        assert_eq!(loaded.savestate, movie.savestate);
+        assert_eq!(loaded.frames, movie.frames);
+        assert!(loaded.matches_rom(&test::test_rom()));
+    }
+
+    #[test]
+    fn test_playback_and_rerecord() {
+        let mut movie = Movie::new("game.nes", &test::test_rom());
+        for i in 0..5 {
+            movie.frames.push(MovieFrame {
+                commands: MovieCommands::empty(),
+                ports: [i, 0],
+            });
+        }
+
+        let mut player = MoviePlayer::new(movie);
+        assert!(player.is_read_only());
+        assert_eq!(player.next_frame().unwrap().ports[0], 0);
+        assert_eq!(player.next_frame().unwrap().ports[0], 1);
+        assert_eq!(player.next_frame().unwrap().ports[0], 2);
+
+        player.load_state(1).unwrap();
+        assert_eq!(player.next_frame().unwrap().ports[0], 1);
+        assert!(player.load_state(6).is_err());
+
+        player.load_state(5).unwrap();
+        assert!(player.is_finished());
+        assert_eq!(player.next_frame(), None);
+
+        player.load_state(2).unwrap();
+        player.set_read_only(false);
+        let mut recorder = player.resume_recording();
+        recorder.record_frame(0xFF, 0);
+        let movie = recorder.finish();
+        assert_eq!(movie.rerecord_count, 1);
+        let recorded: Vec<u8> = movie.frames.iter().map(|f| f.ports[0]).collect();
+        assert_eq!(recorded, vec![0, 1, 0xFF]);
+    }
+
+    #[test]
+    fn test_record_from_savestate() {
+        let movie = Movie::new("game.nes", &test::test_rom());
+        let recorder = MovieRecorder::from_savestate(movie, vec![1, 2, 3]);
+        assert!(recorder
+            .movie()
+            .to_fm2()
+            .contains("savestate base64:AQID\n"));
+    }
+}
@@ -0,0 +1,301 @@
+// The console as a whole, for programs that embed the emulator.
+//
+// `Nes` owns the CPU, which owns the bus, PPU and cartridge, and covers what
+// a frontend needs every frame: input, running a frame, resetting and
+// savestates. Debuggers and tools that need more reach the machine itself
+// through `cpu` and `cpu_mut`, the frontends in this crate work on a `CPU`
+// directly.
+//
+// A console owns all of its state, the core has no mutable statics, only
+// constant tables like the opcodes and palettes. A process can run as many
+// consoles as it likes, each on its own thread: `Nes` is `Send` and `Sync`,
+// which the assertion below keeps that way.
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::error::{CpuError, EmuError};
+use crate::joypad::Button;
+use crate::prelude::*;
+use crate::render::frame::Frame;
+use crate::render::renderer::Renderer;
+use crate::savestate::SaveState;
+
+/// Clones share the cartridge's ROM data, and only the original writes the
+/// battery save
+#[derive(Clone)]
+pub struct Nes {
+    cpu: CPU,
+}
+
+const _: fn() = || {
+    fn thread_safe<T: Send + Sync>() {}
+    thread_safe::<Nes>();
+};
+
+impl Nes {
+    /// Inserts the cartridge and powers the console on
+    pub fn new(rom: Rom) -> Self {
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        Nes { cpu }
+    }
+
+    /// Runs until the PPU finishes the current frame. Fails when the game did
+    /// something the core doesn't support, see error.rs, the frame is left
+    /// unfinished then
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        if self.cpu.run_frame() {
+            return Ok(());
+        }
+        match self.cpu.bus.take_fault() {
+            Some(fault) => Err(fault),
+            None => Err(CpuError::Halted {
+                addr: self.cpu.program_counter.wrapping_sub(1),
+            }
+            .into()),
+        }
+    }
+
+    /// Sets the buttons held on controller `port`, 0 or 1, until changed again
+    pub fn set_buttons(&mut self, port: usize, buttons: Button) {
+        self.cpu.bus.set_controller(port, buttons.bits());
+    }
+
+    pub fn buttons(&self, port: usize) -> Button {
+        Button::from_bits_truncate(self.cpu.bus.controller(port))
+    }
+
+    /// Presses the reset button
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Swaps in a rebuilt ROM and resets, for an edit-build-test loop. PRG
+    /// RAM is kept if asked, see `Bus::reload_rom`
+    pub fn reload_rom(&mut self, rom: Rom, keep_prg_ram: bool) -> Result<(), EmuError> {
+        Ok(self.cpu.reload_rom(rom, keep_prg_ram)?)
+    }
+
+    /// Draws the picture the PPU shows now into `frame`, best right after
+    /// `run_frame`
+    pub fn render(&self, renderer: &mut Renderer, frame: &mut Frame) {
+        let bus = &self.cpu.bus;
+        renderer.render(bus.ppu(), bus.mapper(), frame.pixels_mut());
+    }
+
+    /// Moves the audio made since the last call to the end of `out`, see
+    /// apu.rs. Samples not taken for a few frames are dropped
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        let apu = self.cpu.bus.apu_mut();
+        out.extend_from_slice(apu.samples());
+        apu.clear_samples();
+    }
+
+    /// Frames finished since power on
+    pub fn frame_count(&self) -> usize {
+        self.cpu.bus.frame_count()
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState::capture(&self.cpu)
+    }
+
+    /// Refuses states made with another cartridge, nothing changes then
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), String> {
+        state.check_rom(self.cpu.bus.rom_id())?;
+        self.cpu.restore(state)
+    }
+
+    /// `save_state` in the versioned file format of savestate.rs
+    #[cfg(feature = "serde-state")]
+    pub fn save_state_bytes(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    /// Restores a state from `save_state_bytes`, nothing changes if it is
+    /// malformed or was made with another cartridge
+    #[cfg(feature = "serde-state")]
+    pub fn load_state_bytes(&mut self, data: &[u8]) -> Result<(), String> {
+        self.cpu.load_state(data)
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_embedding() {
+        // copies A and B on controller 1 to $10 and $11 until $12 is set,
+        // then halts on BRK
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: LDA #$01
+                 STA $4016
+                 LDA #$00
+                 STA $4016
+                 LDA $4016
+                 STA $10
+                 LDA $4016
+                 STA $11
+                 LDA $12
+                 BEQ loop
+                 BRK",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut nes = Nes::new(rom);
+        nes.set_buttons(0, Button::START | Button::A);
+        nes.run_frame().unwrap();
+        assert_eq!(nes.frame_count(), 1);
+        assert_eq!((nes.cpu().bus.peek(0x10), nes.cpu().bus.peek(0x11)), (1, 0));
+        assert_eq!(nes.buttons(0), Button::START | Button::A);
+
+        let state = nes.save_state();
+        nes.cpu_mut().mem_write(0x12, 1);
+        assert!(matches!(
+            nes.run_frame(),
+            Err(EmuError::Cpu(CpuError::Halted { .. }))
+        ));
+        nes.load_state(&state).unwrap();
+        nes.run_frame().unwrap();
+        assert_eq!(nes.frame_count(), 2);
+        let mut samples = Vec::new();
+        nes.take_samples(&mut samples);
+        assert!(samples.len() > 700);
+        let taken = samples.len();
+        nes.take_samples(&mut samples);
+        assert_eq!(samples.len(), taken);
+    }
+
+    #[test]
+    fn test_sprites_from_oam_dma() {
+        // hides every sprite but one at 64,32 and copies page 2 to OAM
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "LDX #0
+                 LDA #$FF
+                 hide: STA $0200,X
+                 INX
+                 BNE hide
+                 LDA #$1F
+                 STA $0200
+                 LDA #0
+                 STA $0201
+                 STA $0202
+                 LDA #$40
+                 STA $0203
+                 LDA #$3F
+                 STA $2006
+                 LDA #$13
+                 STA $2006
+                 LDA #$16
+                 STA $2007
+                 LDA #$10
+                 STA $2001
+                 LDA #$02
+                 STA $4014
+                 loop: JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .fill_chr(0xFF)
+            .build();
+        let mut nes = Nes::new(rom);
+        nes.run_frame().unwrap();
+        let mut renderer = Renderer::new();
+        let mut frame = Frame::new();
+        nes.render(&mut renderer, &mut frame);
+
+        let backdrop = frame.pixel(0, 0);
+        let sprite = frame.pixel(0x40, 0x20);
+        assert_ne!(sprite, backdrop);
+        assert_eq!(frame.pixel(0x47, 0x27), sprite);
+        assert_eq!(frame.pixel(0x48, 0x20), backdrop);
+        assert_eq!(frame.pixel(0x40, 0x28), backdrop);
+    }
+
+    #[test]
+    #[cfg(feature = "serde-state")]
+    fn test_state_bytes() {
+        let rom = RomBuilder::new()
+            .asm(0x8000, "loop: INC $10\n JMP loop")
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut nes = Nes::new(rom);
+        nes.run_frame().unwrap();
+        let data = nes.save_state_bytes();
+        let mut expected = nes.clone();
+        expected.run_frame().unwrap();
+
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+        nes.load_state_bytes(&data).unwrap();
+        nes.run_frame().unwrap();
+        assert_eq!(nes.save_state(), expected.save_state());
+        assert!(nes.load_state_bytes(&data[..10]).is_err());
+        assert_eq!(nes.save_state(), expected.save_state());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_consoles_on_threads() {
+        use crate::statehash::state_hash;
+
+        // counts frames in $10 and adds up controller 1's first bit in $11
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: LDA #$01
+                 STA $4016
+                 LDA #$00
+                 STA $4016
+                 LDA $4016
+                 AND #$01
+                 CLC
+                 ADC $11
+                 STA $11
+                 INC $10
+                 JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let run = |nes: &mut Nes, seed: usize| {
+            for frame in 0..10 {
+                let buttons = [Button::A, Button::empty(), Button::empty()];
+                nes.set_buttons(0, buttons[(frame + seed) % 3]);
+                nes.run_frame().unwrap();
+            }
+            state_hash(nes.cpu())
+        };
+
+        let expected: Vec<u64> = (0..4)
+            .map(|seed| run(&mut Nes::new(rom.clone()), seed))
+            .collect();
+        let threads: Vec<_> = (0..4)
+            .map(|seed| {
+                let mut nes = Nes::new(rom.clone());
+                std::thread::spawn(move || run(&mut nes, seed))
+            })
+            .collect();
+        let hashes: Vec<u64> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(hashes, expected);
+        assert_ne!(hashes[0], hashes[1]);
+    }
+}
@@ -1,22 +1,26 @@
+use crate::mapper::Mapper;
 use crate::{
     cartridge::Mirroring,
+    error::PpuError,
     registers::{
         addr::AddrRegister, control::ControlRegister, mask::MaskRegister, scroll::ScrollRegister,
         status::StatusRegister,
     },
+    savestate::{self, PpuState},
 };
+use tracing::warn;
 
+#[derive(Clone)]
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
     pub oam_data: [u8; 256],
     addr: AddrRegister,
     pub mirroring: Mirroring,
-    pub ctrl: ControlRegister,
-    pub mask: MaskRegister,
-    pub status: StatusRegister,
-    pub scroll: ScrollRegister,
+    pub(crate) ctrl: ControlRegister,
+    pub(crate) mask: MaskRegister,
+    pub(crate) status: StatusRegister,
+    pub(crate) scroll: ScrollRegister,
     internal_data_buf: u8,
     pub oam_addr: u8,
     scanline: u16,
@@ -25,9 +29,8 @@ pub struct NesPPU {
 }
 
 impl NesPPU {
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(mirroring: Mirroring) -> Self {
         NesPPU {
-            chr_rom,
             palette_table: [0; 32],
             vram: [0; 2048],
             oam_data: [0; 256],
@@ -65,48 +68,42 @@ impl NesPPU {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
 
-    pub fn read_data(&mut self) -> u8 {
+    /// Pattern tables are on the cartridge, `mapper` decides what is there
+    pub fn read_data(&mut self, mapper: &dyn Mapper) -> u8 {
         let addr = self.addr.get();
         self.increment_vram_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = mapper.ppu_read(addr);
                 result
             }
-            0x2000..=0x2fff => {
+            // $3000-$3EFF mirrors the nametables
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr)];
                 result
             }
-            0x3000..=0x3eff => panic!(
-                "addr space 0x3000..0x3eff is not expected to be used, requested = {} ",
-                addr
-            ),
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            // the address register stays below $4000
+            _ => self.palette_table[palette_index(addr)],
         }
     }
 
-    pub fn write_to_data(&mut self, value: u8) {
+    pub fn write_to_data(&mut self, value: u8, mapper: &mut dyn Mapper) {
         let addr = self.addr.get();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+            0..=0x1fff if !mapper.ppu_write(addr, value) => {
+                warn!(target: "ppu", "write of {:02X} to CHR ROM at ${:04X}", value, addr);
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in", addr),
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
+            0..=0x1fff => {}
+            0x2000..=0x3eff => {
+                let index = self.mirror_vram_addr(addr);
+                self.vram[index] = value;
             }
-            0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = value;
+            _ => {
+                self.palette_table[palette_index(addr)] = value;
             }
-            _ => panic!("unexpected access to mirrored space {}", addr),
         }
         self.increment_vram_addr();
     }
@@ -128,6 +125,13 @@ impl NesPPU {
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
+    /// OAM DMA: a page written through OAMDATA, starting at OAMADDR
+    pub fn write_oam_dma(&mut self, page: &[u8; 256]) {
+        for &value in page.iter() {
+            self.write_to_oam_data(value);
+        }
+    }
+
     pub fn read_oam_data(&self) -> u8 {
         self.oam_data[self.oam_addr as usize]
     }
@@ -136,17 +140,19 @@ impl NesPPU {
         self.scroll.write(value);
     }
 
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycle += cycles as usize;
-        if self.cycle >= 341 {
-            self.cycle = self.cycle - 341;
+    /// Runs for `cycles` PPU cycles, returns whether a frame was finished
+    pub fn tick(&mut self, cycles: usize) -> bool {
+        self.cycle += cycles;
+        let mut new_frame = false;
+        while self.cycle >= 341 {
+            self.cycle -= 341;
             self.scanline += 1;
+            // vblank shows in PPUSTATUS either way, PPUCTRL only decides
+            // whether it raises an NMI
             if self.scanline == 241 {
+                self.status.set_vblank_status(true);
                 if self.ctrl.generate_vblank_nmi() {
-                    self.status.set_vblank_status(true);
-                    if self.ctrl.generate_vblank_nmi() {
-                        self.nmi_interrupt = Some(1);
-                    }
+                    self.nmi_interrupt = Some(1);
                 }
             }
 
@@ -154,25 +160,196 @@ impl NesPPU {
                 self.scanline = 0;
                 self.nmi_interrupt = None;
                 self.status.reset_vblank_status();
-                return true;
+                new_frame = true;
             }
         }
-        false
+        new_frame
+    }
+
+    /// PPU cycles until the next point where the PPU does something the CPU
+    /// can notice without reading a register: vblank starting and the frame ending
+    pub fn cycles_until_event(&self) -> usize {
+        let line = if self.scanline < 241 { 241 } else { 262 };
+        (line * 341usize).saturating_sub(self.scanline as usize * 341 + self.cycle)
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// PPU cycle within the current scanline
+    pub fn dot(&self) -> usize {
+        self.cycle
     }
 
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
-    fn mirror_vram_addr(&self, addr: u16) -> u16 {
+    pub fn save_state(&self) -> PpuState {
+        let mut state = PpuState::default();
+        self.save_state_into(&mut state);
+        state
+    }
+
+    /// Like `save_state` but reuses the buffers of an earlier state
+    pub fn save_state_into(&self, state: &mut PpuState) {
+        savestate::copy_into(&mut state.palette_table, &self.palette_table);
+        savestate::copy_into(&mut state.vram, &self.vram);
+        savestate::copy_into(&mut state.oam_data, &self.oam_data);
+        state.mirroring = self.mirroring.clone();
+        state.ctrl = self.ctrl.bits();
+        state.mask = self.mask.bits();
+        state.status = self.status.bits();
+        state.scroll_x = self.scroll.scroll_x;
+        state.scroll_y = self.scroll.scroll_y;
+        state.scroll_latch = self.scroll.latch;
+        state.addr = self.addr.get();
+        state.addr_high_byte = self.addr.expects_high_byte();
+        state.internal_data_buf = self.internal_data_buf;
+        state.oam_addr = self.oam_addr;
+        state.scanline = self.scanline;
+        state.dot = self.cycle as u64;
+        state.nmi_interrupt = self.nmi_interrupt;
+    }
+
+    /// Errors if `load_state` would fail, without changing anything
+    pub fn check_state(&self, state: &PpuState) -> Result<(), PpuError> {
+        savestate::check_len("palette", &state.palette_table, self.palette_table.len())?;
+        savestate::check_len("VRAM", &state.vram, self.vram.len())?;
+        savestate::check_len("OAM", &state.oam_data, self.oam_data.len())?;
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, state: &PpuState) -> Result<(), PpuError> {
+        self.check_state(state)?;
+        self.palette_table.copy_from_slice(&state.palette_table);
+        self.vram.copy_from_slice(&state.vram);
+        self.oam_data.copy_from_slice(&state.oam_data);
+        self.mirroring = state.mirroring.clone();
+        self.ctrl = ControlRegister::from_bits_truncate(state.ctrl);
+        self.mask = MaskRegister::from_bits_truncate(state.mask);
+        self.status = StatusRegister::from_bits_truncate(state.status);
+        self.scroll.scroll_x = state.scroll_x;
+        self.scroll.scroll_y = state.scroll_y;
+        self.scroll.latch = state.scroll_latch;
+        self.addr.restore(state.addr, state.addr_high_byte);
+        self.internal_data_buf = state.internal_data_buf;
+        self.oam_addr = state.oam_addr;
+        self.scanline = state.scanline;
+        self.cycle = state.dot as usize;
+        self.nmi_interrupt = state.nmi_interrupt;
+        Ok(())
+    }
+
+    /// Index into `vram`, masked so the compiler can drop the bounds check.
+    /// With horizontal and vertical mirroring the index is in range already,
+    /// four screen games would need 4K of VRAM and get their upper two
+    /// nametables mirrored instead
+    pub(crate) fn mirror_vram_addr(&self, addr: u16) -> usize {
+        debug_assert!((0x2000..0x4000).contains(&addr));
         let mirrored_vram = addr & 0b10111111111111;
         let vram_index = mirrored_vram - 0x2000;
         let mirrored_nametable = vram_index / 0x400;
-        match (&self.mirroring, mirrored_nametable) {
+        let index = match (&self.mirroring, mirrored_nametable) {
             (Mirroring::HORIZONTAL, 2) | (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
+            (Mirroring::ONE_SCREEN_LOWER, _) => vram_index & 0x3FF,
+            (Mirroring::ONE_SCREEN_UPPER, _) => 0x400 | (vram_index & 0x3FF),
             _ => vram_index,
-        }
+        };
+        (index & 0x07FF) as usize
+    }
+}
+
+/// Index into the palette table for $3F00-$3FFF. The 32 entries repeat through
+/// the whole range and $3F10/$3F14/$3F18/$3F1C are mirrors of
+/// $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    debug_assert!((0x3F00..0x4000).contains(&addr));
+    let index = addr & 0x1F;
+    if index & 0x13 == 0x10 {
+        (index & 0x0F) as usize
+    } else {
+        index as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+    use crate::mapper;
+
+    fn cartridge() -> Box<dyn Mapper> {
+        mapper::for_rom(&RomBuilder::new().chr_pages(0).build())
+    }
+
+    fn write(ppu: &mut NesPPU, cartridge: &mut dyn Mapper, addr: u16, value: u8) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+        ppu.write_to_data(value, cartridge);
+    }
+
+    fn read(ppu: &mut NesPPU, cartridge: &dyn Mapper, addr: u16) -> u8 {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+        ppu.read_data(cartridge)
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = NesPPU::new(Mirroring::HORIZONTAL);
+        let mut cartridge = cartridge();
+        write(&mut ppu, &mut *cartridge, 0x3F10, 0x21);
+        write(&mut ppu, &mut *cartridge, 0x3FE5, 0x16);
+        assert_eq!(ppu.palette_table[0x00], 0x21);
+        assert_eq!(ppu.palette_table[0x05], 0x16);
+        assert_eq!(read(&mut ppu, &*cartridge, 0x3F30), 0x21);
+        assert_eq!(read(&mut ppu, &*cartridge, 0x3F10), 0x21);
+        assert_eq!(read(&mut ppu, &*cartridge, 0x3F05), 0x16);
+    }
+
+    #[test]
+    fn test_nametable_mirrors_above_3000() {
+        let mut ppu = NesPPU::new(Mirroring::VERTICAL);
+        let mut cartridge = cartridge();
+        write(&mut ppu, &mut *cartridge, 0x3405, 0x42);
+        assert_eq!(ppu.vram[0x405], 0x42);
+        read(&mut ppu, &*cartridge, 0x2405);
+        assert_eq!(ppu.read_data(&*cartridge), 0x42);
+    }
+
+    #[test]
+    fn test_four_screen_nametables_stay_in_vram() {
+        let mut ppu = NesPPU::new(Mirroring::FOUR_SCREEN);
+        write(&mut ppu, &mut *cartridge(), 0x2C05, 0x42);
+        assert_eq!(ppu.vram[0x405], 0x42);
+    }
+
+    #[test]
+    fn test_one_screen_mirroring() {
+        let mut ppu = NesPPU::new(Mirroring::ONE_SCREEN_UPPER);
+        let mut cartridge = cartridge();
+        write(&mut ppu, &mut *cartridge, 0x2C05, 0x42);
+        assert_eq!(ppu.vram[0x405], 0x42);
+        ppu.mirroring = Mirroring::ONE_SCREEN_LOWER;
+        write(&mut ppu, &mut *cartridge, 0x2805, 0x43);
+        assert_eq!(ppu.vram[0x005], 0x43);
+    }
+
+    #[test]
+    fn test_pattern_tables_go_through_the_mapper() {
+        let mut ppu = NesPPU::new(Mirroring::HORIZONTAL);
+        let mut chr_ram = cartridge();
+        write(&mut ppu, &mut *chr_ram, 0x1005, 0x42);
+        read(&mut ppu, &*chr_ram, 0x1005);
+        assert_eq!(ppu.read_data(&*chr_ram), 0x42);
+
+        let mut chr_rom = mapper::for_rom(&RomBuilder::new().fill_chr(7).build());
+        write(&mut ppu, &mut *chr_rom, 0x1005, 0x42);
+        read(&mut ppu, &*chr_rom, 0x1005);
+        assert_eq!(ppu.read_data(&*chr_rom), 7);
     }
 }
@@ -0,0 +1,612 @@
+// Netplay: two consoles on two hosts, each player holding one controller.
+//
+// Both sides run the same ROM from power on and exchange nothing but
+// controller input, which works because emulation is deterministic (see
+// replay.rs). Every frame each side sends all of its input the other side
+// hasn't acknowledged yet, so lost, duplicated and reordered packets over UDP
+// only cost time.
+//
+// Input is used `delay` frames after it was given, which gives it time to
+// arrive on the other side. Both sides must use the same delay. When the
+// other side's input for a frame still isn't there, the frame is run with a
+// guess, the last input that arrived, after taking a snapshot of the state
+// before it. Once the real input arrives and differs from the guess the
+// console is rolled back to that snapshot and the frames since are run again
+// with the right input, GGPO style. At most `rollback` frames are run on
+// guesses, then the session waits for the other side. With `rollback` 0
+// nothing is guessed, which is plain delay-based lockstep.
+//
+// Every `HASH_INTERVAL` frames both sides hash the state after the frame,
+// once no rollback can change it any more (see statehash.rs), and send the
+// hash along. Different hashes after the same frame are a desync, which ends
+// the session.
+//
+// A packet, multi-byte values little endian:
+//   first  u32  frame of the first input in the packet
+//   ack    u32  frames of the receiver's input the sender has
+//   hash   u32  frames run before the sender's latest hash, 0 for none
+//          u64  the hash
+//   count  u8   number of inputs that follow, one byte each in RLDUTSBA order
+use crate::cpu::CPU;
+use crate::harness::FrameInput;
+use crate::snapshot::Snapshot;
+use crate::statehash::{snapshot_hash, state_hash};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// Frames between state hashes, once a second
+pub const HASH_INTERVAL: u32 = 60;
+/// Frames of input delay, enough for most connections within a continent
+pub const DEFAULT_DELAY: u32 = 2;
+/// Frames that may run ahead of the other side's input
+pub const DEFAULT_ROLLBACK: u32 = 8;
+
+const HEADER_SIZE: usize = 4 + 4 + 4 + 8 + 1;
+const MAX_PACKET_INPUTS: usize = 255;
+const MAX_PACKET_SIZE: usize = HEADER_SIZE + MAX_PACKET_INPUTS;
+
+/// How packets get to the other side. Packets may be lost, duplicated or
+/// reordered, the session copes with that
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    /// Receives a packet into `buf` without blocking, None if there is none
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+/// A UDP socket that only talks to the other side
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.socket.send(packet) {
+            Ok(_) => Ok(()),
+            // the other side isn't up yet, the input goes out again next frame
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    first: u32,
+    ack: u32,
+    hash: (u32, u64),
+    inputs: Vec<u8>,
+}
+
+impl Packet {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.clear();
+        out.extend_from_slice(&self.first.to_le_bytes());
+        out.extend_from_slice(&self.ack.to_le_bytes());
+        out.extend_from_slice(&self.hash.0.to_le_bytes());
+        out.extend_from_slice(&self.hash.1.to_le_bytes());
+        out.push(self.inputs.len() as u8);
+        out.extend_from_slice(&self.inputs);
+    }
+
+    /// None for anything that isn't a whole packet
+    fn read(data: &[u8], packet: &mut Packet) -> Option<()> {
+        if data.len() < HEADER_SIZE || data.len() != HEADER_SIZE + data[HEADER_SIZE - 1] as usize {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        packet.first = u32_at(0);
+        packet.ack = u32_at(4);
+        packet.hash = (
+            u32_at(8),
+            u64::from_le_bytes(data[12..20].try_into().unwrap()),
+        );
+        packet.inputs.clear();
+        packet.inputs.extend_from_slice(&data[HEADER_SIZE..]);
+        Some(())
+    }
+}
+
+/// One side's input by frame, from the oldest frame still needed
+struct InputQueue {
+    first: u32,
+    inputs: VecDeque<u8>,
+    /// The input of the last frame pushed, kept when it is dropped
+    last: u8,
+}
+
+impl InputQueue {
+    /// Starts with no buttons held for the first `frames` frames
+    fn new(frames: u32) -> Self {
+        InputQueue {
+            first: 0,
+            inputs: (0..frames).map(|_| 0).collect(),
+            last: 0,
+        }
+    }
+
+    /// The frame after the last one pushed
+    fn end(&self) -> u32 {
+        self.first + self.inputs.len() as u32
+    }
+
+    fn get(&self, frame: u32) -> Option<u8> {
+        let index = frame.checked_sub(self.first)?;
+        self.inputs.get(index as usize).copied()
+    }
+
+    fn push(&mut self, input: u8) {
+        self.inputs.push_back(input);
+        self.last = input;
+    }
+
+    fn forget_before(&mut self, frame: u32) {
+        while self.first < frame && self.inputs.pop_front().is_some() {
+            self.first += 1;
+        }
+    }
+
+    fn clear_from(&mut self, frame: u32) {
+        self.inputs.clear();
+        self.first = frame;
+    }
+}
+
+pub struct Session<T: Transport> {
+    transport: T,
+    port: usize,
+    delay: u32,
+    rollback: u32,
+    /// Frames run so far
+    frame: u32,
+    local: InputQueue,
+    /// The other side's input, as far as it arrived without gaps
+    remote: InputQueue,
+    /// The other side's input guessed for the frames that ran before it
+    /// arrived, until it does
+    guesses: InputQueue,
+    /// Frames of this side's input the other side has
+    acked: u32,
+    /// The state before each guessed frame, frame `f` at `f % rollback`
+    snapshots: Vec<Snapshot>,
+    hash: (u32, u64),
+    /// Hashes up to this frame have been compared
+    compared: u32,
+    /// Hashes that arrived before this side had its own for their frame
+    remote_hashes: VecDeque<(u32, u64)>,
+    packet: Packet,
+    buffer: Vec<u8>,
+}
+
+impl<T: Transport> Session<T> {
+    /// `port` is the controller this side plays, 0 or 1, the other side
+    /// plays the other one
+    pub fn new(transport: T, port: usize, delay: u32, rollback: u32) -> Self {
+        assert!(port < 2, "netplay has two controllers");
+        Session {
+            transport,
+            port,
+            delay,
+            rollback,
+            frame: 0,
+            local: InputQueue::new(delay),
+            remote: InputQueue::new(delay),
+            guesses: InputQueue::new(0),
+            acked: delay,
+            snapshots: vec![Snapshot::default(); rollback as usize],
+            hash: (0, 0),
+            compared: 0,
+            remote_hashes: VecDeque::new(),
+            packet: Packet {
+                first: 0,
+                ack: 0,
+                hash: (0, 0),
+                inputs: Vec::with_capacity(MAX_PACKET_INPUTS),
+            },
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+        }
+    }
+
+    /// Frames run so far
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Frames for which the other side's input arrived
+    pub fn confirmed_frame(&self) -> u32 {
+        self.remote.end()
+    }
+
+    /// Gives the buttons this side holds and runs a frame, unless that would
+    /// run too far ahead of the other side. Returns whether a frame ran.
+    /// Errors are a desync, a halted CPU or the transport's
+    pub fn advance(&mut self, cpu: &mut CPU, buttons: u8) -> Result<bool, String> {
+        if self.local.end() <= self.frame + self.delay {
+            self.local.push(buttons);
+        }
+        self.sync(cpu)?;
+        let ahead = (self.frame + 1).saturating_sub(self.remote.end());
+        if ahead > self.rollback {
+            return Ok(false);
+        }
+        self.run_frame(cpu)?;
+        Ok(true)
+    }
+
+    /// Exchanges input and corrects wrong guesses without running a new
+    /// frame, for while the game is paused or waiting for the other side
+    pub fn sync(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        self.receive()?;
+        if let Some(frame) = self.first_wrong_guess(cpu)? {
+            self.roll_back(cpu, frame)?;
+        }
+        let needed = if self.guesses.inputs.is_empty() {
+            self.frame
+        } else {
+            self.guesses.first
+        };
+        self.remote.forget_before(needed);
+        self.local.forget_before(needed.min(self.acked));
+        self.send()
+    }
+
+    fn receive(&mut self) -> Result<(), String> {
+        let mut data = [0; MAX_PACKET_SIZE];
+        while let Some(len) = self.transport.recv(&mut data).map_err(|e| e.to_string())? {
+            if Packet::read(&data[..len], &mut self.packet).is_none() {
+                continue;
+            }
+            for (frame, &input) in (self.packet.first..).zip(&self.packet.inputs) {
+                if frame == self.remote.end() {
+                    self.remote.push(input);
+                }
+            }
+            self.acked = self.acked.max(self.packet.ack.min(self.local.end()));
+            let (frame, hash) = self.packet.hash;
+            if frame > self.compared && !self.remote_hashes.contains(&(frame, hash)) {
+                self.remote_hashes.push_back((frame, hash));
+            }
+        }
+        self.compare_hashes()
+    }
+
+    fn send(&mut self) -> Result<(), String> {
+        let first = self.acked.max(self.local.first);
+        let count = (self.local.end() - first).min(MAX_PACKET_INPUTS as u32);
+        self.packet.first = first;
+        self.packet.ack = self.remote.end();
+        self.packet.hash = self.hash;
+        self.packet.inputs.clear();
+        for frame in first..first + count {
+            let input = self.local.get(frame).unwrap_or(0);
+            self.packet.inputs.push(input);
+        }
+        self.packet.write(&mut self.buffer);
+        self.transport.send(&self.buffer).map_err(|e| e.to_string())
+    }
+
+    /// Drops the guesses the other side's input confirmed, returns the first
+    /// frame that was guessed wrong
+    fn first_wrong_guess(&mut self, cpu: &CPU) -> Result<Option<u32>, String> {
+        while let Some(&guess) = self.guesses.inputs.front() {
+            let frame = self.guesses.first;
+            match self.remote.get(frame) {
+                Some(input) if input == guess => self.guesses.forget_before(frame + 1),
+                Some(_) => return Ok(Some(frame)),
+                None => break,
+            }
+            // the state after the frame is final now, it is the state before
+            // the next guessed frame or the console's
+            if (frame + 1).is_multiple_of(HASH_INTERVAL) {
+                let hash = if frame + 1 == self.frame {
+                    state_hash(cpu)
+                } else {
+                    snapshot_hash(&self.snapshots[((frame + 1) % self.rollback) as usize])
+                };
+                self.hash = (frame + 1, hash);
+                self.compare_hashes()?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Goes back to the state before `frame` and runs the frames since again
+    fn roll_back(&mut self, cpu: &mut CPU, frame: u32) -> Result<(), String> {
+        let end = self.frame;
+        let snapshot = &self.snapshots[(frame % self.rollback) as usize];
+        cpu.restore_snapshot(snapshot).map_err(|e| e.to_string())?;
+        self.frame = frame;
+        self.guesses.clear_from(frame);
+        while self.frame < end {
+            self.run_frame(cpu)?;
+        }
+        Ok(())
+    }
+
+    fn run_frame(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let frame = self.frame;
+        let local = self
+            .local
+            .get(frame)
+            .expect("input is given before its frame");
+        let confirmed = self.remote.get(frame);
+        let remote = match confirmed {
+            Some(input) => input,
+            None => {
+                let guess = self.remote.last;
+                if self.guesses.inputs.is_empty() {
+                    self.guesses.clear_from(frame);
+                }
+                self.guesses.push(guess);
+                let index = (frame % self.rollback) as usize;
+                cpu.snapshot_into(&mut self.snapshots[index]);
+                guess
+            }
+        };
+        let mut input = FrameInput::default();
+        input.pads[self.port] = local;
+        input.pads[1 - self.port] = remote;
+        input.apply(cpu);
+        if !cpu.run_frame() {
+            return Err(format!("CPU halted on BRK in frame {}", frame + 1));
+        }
+        self.frame += 1;
+        if confirmed.is_some() && self.frame.is_multiple_of(HASH_INTERVAL) {
+            self.hash = (self.frame, state_hash(cpu));
+            self.compare_hashes()?;
+        }
+        Ok(())
+    }
+
+    fn compare_hashes(&mut self) -> Result<(), String> {
+        let (frame, local) = self.hash;
+        while let Some(&(remote_frame, remote)) = self.remote_hashes.front() {
+            if remote_frame > frame {
+                break;
+            }
+            self.remote_hashes.pop_front();
+            if remote_frame == frame && remote != local {
+                return Err(format!(
+                    "Desync after frame {}: state hash {:016X} here, {:016X} on the other side",
+                    frame, local, remote
+                ));
+            }
+            self.compared = remote_frame;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+    use crate::cpu::Mem;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Adds up the A button of both controllers, read many times a frame
+    fn console() -> CPU {
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: LDA #1
+                 STA $4016
+                 LDA #0
+                 STA $4016
+                 LDA $4016
+                 AND #1
+                 CLC
+                 ADC $10
+                 STA $10
+                 LDA $4017
+                 AND #1
+                 ADC $11
+                 STA $11
+                 JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu
+    }
+
+    #[derive(Default)]
+    struct Pipe {
+        sent: Vec<Vec<u8>>,
+        inbox: VecDeque<Vec<u8>>,
+    }
+
+    struct TestTransport(Rc<RefCell<Pipe>>);
+
+    impl Transport for TestTransport {
+        fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            self.0.borrow_mut().sent.push(packet.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            Ok(self.0.borrow_mut().inbox.pop_front().map(|packet| {
+                buf[..packet.len()].copy_from_slice(&packet);
+                packet.len()
+            }))
+        }
+    }
+
+    /// The buttons each side holds when it is at `frame`
+    fn buttons(port: usize, frame: u32) -> u8 {
+        ((frame / (3 + port as u32 * 4)) % 2) as u8
+    }
+
+    /// Two sessions exchanging packets `lag` steps late, every `drop`th packet
+    /// lost, until both ran `frames` frames on confirmed input
+    fn play(delay: u32, rollback: u32, lag: usize, drop: usize, frames: u32) -> [CPU; 2] {
+        let pipes = [Rc::default(), Rc::default()];
+        let mut sessions = [0, 1].map(|port| {
+            let pipe: &Rc<RefCell<Pipe>> = &pipes[port];
+            Session::new(TestTransport(pipe.clone()), port, delay, rollback)
+        });
+        let mut cpus = [console(), console()];
+        let mut in_flight: Vec<VecDeque<(usize, Vec<u8>)>> = vec![VecDeque::new(); 2];
+        let mut sent = 0;
+        for step in 0..10_000 {
+            let done = |session: &Session<TestTransport>| {
+                session.frame() >= frames && session.confirmed_frame() >= frames
+            };
+            if sessions.iter().all(done) {
+                return cpus;
+            }
+            for port in 0..2 {
+                let (session, cpu) = (&mut sessions[port], &mut cpus[port]);
+                if session.frame() < frames {
+                    let held = buttons(port, session.frame());
+                    session.advance(cpu, held).unwrap();
+                } else {
+                    session.sync(cpu).unwrap();
+                }
+                for packet in pipes[port].borrow_mut().sent.drain(..) {
+                    sent += 1;
+                    if sent % drop != 0 {
+                        in_flight[1 - port].push_back((step + lag, packet));
+                    }
+                }
+            }
+            for port in 0..2 {
+                while in_flight[port].front().is_some_and(|(due, _)| *due <= step) {
+                    let (_, packet) = in_flight[port].pop_front().unwrap();
+                    pipes[port].borrow_mut().inbox.push_back(packet);
+                }
+            }
+        }
+        panic!("the sessions got stuck");
+    }
+
+    /// The same frames run offline, with each side's input `delay` frames late
+    fn offline(delay: u32, frames: u32) -> CPU {
+        let mut cpu = console();
+        for frame in 0..frames {
+            let pad = |port| match frame.checked_sub(delay) {
+                Some(given) => buttons(port, given),
+                None => 0,
+            };
+            FrameInput::new(pad(0), pad(1)).apply(&mut cpu);
+            cpu.run_frame();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = Packet {
+            first: 70_000,
+            ack: 69_998,
+            hash: (69_960, 0x0123_4567_89AB_CDEF),
+            inputs: vec![0x01, 0x80, 0xFF],
+        };
+        let mut data = Vec::new();
+        packet.write(&mut data);
+        assert_eq!(data.len(), HEADER_SIZE + 3);
+        let mut read = Packet {
+            first: 0,
+            ack: 0,
+            hash: (0, 0),
+            inputs: vec![],
+        };
+        assert_eq!(Packet::read(&data, &mut read), Some(()));
+        assert_eq!(read, packet);
+        assert_eq!(Packet::read(&data[..data.len() - 1], &mut read), None);
+    }
+
+    #[test]
+    fn test_lockstep_matches_offline_run() {
+        let expected = state_hash(&offline(2, 150));
+        for cpu in &play(2, 0, 1, 7, 150) {
+            assert_eq!(state_hash(cpu), expected);
+            assert_ne!(cpu.bus.peek(0x10), 0);
+            assert_ne!(cpu.bus.peek(0x11), 0);
+        }
+    }
+
+    #[test]
+    fn test_rollback_corrects_guesses() {
+        let expected = state_hash(&offline(1, 150));
+        // input arrives 4 frames late, so most frames run on guesses first
+        for cpu in &play(1, 8, 4, 5, 150) {
+            assert_eq!(state_hash(cpu), expected);
+        }
+    }
+
+    #[test]
+    fn test_lockstep_waits_for_input() {
+        let pipe = Rc::default();
+        let mut session = Session::new(TestTransport(Rc::clone(&pipe)), 0, 2, 0);
+        let mut cpu = console();
+        assert!(session.advance(&mut cpu, 0).unwrap());
+        assert!(session.advance(&mut cpu, 0).unwrap());
+        assert!(!session.advance(&mut cpu, 0).unwrap());
+        assert_eq!(session.frame(), 2);
+        // input is resent until it is acknowledged
+        let sent = pipe.borrow().sent.clone();
+        assert_eq!(sent.len(), 3);
+        let mut packet = Packet {
+            first: 0,
+            ack: 0,
+            hash: (0, 0),
+            inputs: vec![],
+        };
+        Packet::read(&sent[2], &mut packet).unwrap();
+        assert_eq!((packet.first, packet.inputs.len()), (2, 3));
+    }
+
+    fn desync_error(rollback: u32) -> Option<String> {
+        let pipes: [Rc<RefCell<Pipe>>; 2] = [Rc::default(), Rc::default()];
+        let mut sessions =
+            [0, 1].map(|port| Session::new(TestTransport(pipes[port].clone()), port, 1, rollback));
+        let mut cpus = [console(), console()];
+        cpus[1].mem_write(0x0200, 1);
+        for _ in 0..HASH_INTERVAL * 2 {
+            for port in 0..2 {
+                if let Err(e) = sessions[port].advance(&mut cpus[port], 0) {
+                    return Some(e);
+                }
+                let sent: Vec<_> = pipes[port].borrow_mut().sent.drain(..).collect();
+                pipes[1 - port].borrow_mut().inbox.extend(sent);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_desync_is_detected() {
+        for rollback in [0, 8] {
+            let error = desync_error(rollback).expect("the desync went unnoticed");
+            assert!(error.starts_with("Desync after frame 60"), "{}", error);
+        }
+    }
+}
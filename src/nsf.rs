@@ -0,0 +1,742 @@
+// NSF music: the sound driver and data of a game ripped out of it, with a
+// header saying where to load it and which routines to call. The player puts
+// the data on the NSF board, mapper 31, calls INIT once with the song number
+// in A and then PLAY at the tune's rate, about once a frame, letting the
+// console idle in between while the APU plays.
+//
+// Three file formats carry it. Plain NSF has a 128 byte header with the
+// album's title, artist and copyright and nothing per song. NSFe replaces the
+// header with chunks and adds per-song titles, durations, fade times and a
+// playlist order. NSF2 is an NSF header and the data followed by NSFe chunks
+// with the same metadata. All three parse into `Nsf`.
+//
+// Only the 2A03's own channels are emulated, tunes for expansion chips (VRC6,
+// FDS, N163 and others) play without those parts. NSF2 tunes whose INIT never
+// returns aren't supported.
+use crate::apu::{CPU_CLOCK, DEFAULT_SAMPLE_RATE};
+use crate::bus::Bus;
+use crate::cartridge::{Mirroring, Rom};
+use crate::cpu::{Mem, CPU};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+const NSF_TAG: &[u8; 5] = b"NESM\x1A";
+const NSFE_TAG: &[u8; 4] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+/// Microseconds between PLAY calls of NTSC tunes that don't say
+const DEFAULT_PLAY_SPEED: u16 = 16639;
+/// Where INIT and PLAY return to. Nothing is mapped there, the CPU never
+/// runs it
+const RETURN_ADDR: u16 = 0x4100;
+/// How long INIT or PLAY may run before the tune counts as hung, a second
+const CALL_LIMIT: usize = CPU_CLOCK as usize;
+
+/// What a file says about one song
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackMeta {
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>,
+}
+
+/// A song of the track list
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackInfo {
+    /// The song number INIT gets, from 0
+    pub song: u8,
+    /// "Track N" if the file has no title for it
+    pub title: String,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>,
+}
+
+/// A parsed NSF, NSFe or NSF2 file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    /// Who ripped the tune, only NSFe and NSF2 say
+    pub ripper: String,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub songs: u8,
+    /// From 0
+    pub starting_song: u8,
+    /// Microseconds between PLAY calls on NTSC
+    pub play_speed: u16,
+    /// The banks of $8000-$FFFF before INIT, None if the tune doesn't switch
+    /// banks
+    pub banks: Option<[u8; 8]>,
+    /// Expansion sound chips the tune was written for, bit 0 VRC6 to bit 5
+    /// Sunsoft 5B
+    pub expansion: u8,
+    pub data: Vec<u8>,
+    /// One for every song
+    pub tracks: Vec<TrackMeta>,
+    /// Song numbers in the order to play them, empty for all of them in order
+    pub playlist: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Nsf, String> {
+        let path = path.as_ref();
+        let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Nsf::new(&raw)
+    }
+
+    /// Parses NSF, NSF2 and NSFe files
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.starts_with(NSF_TAG) {
+            parse_nsf(raw)
+        } else if raw.starts_with(NSFE_TAG) {
+            parse_nsfe(&raw[NSFE_TAG.len()..])
+        } else {
+            Err("not an NSF or NSFe file".to_string())
+        }
+    }
+
+    /// The songs in playlist order, what a frontend shows
+    pub fn track_list(&self) -> Vec<TrackInfo> {
+        let order: Vec<u8> = if self.playlist.is_empty() {
+            (0..self.songs).collect()
+        } else {
+            self.playlist.clone()
+        };
+        order
+            .into_iter()
+            .map(|song| {
+                let meta = &self.tracks[song as usize];
+                TrackInfo {
+                    song,
+                    title: meta
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| format!("Track {}", song + 1)),
+                    duration: meta.duration,
+                    fade: meta.fade,
+                }
+            })
+            .collect()
+    }
+
+    fn empty() -> Nsf {
+        Nsf {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            songs: 1,
+            starting_song: 0,
+            play_speed: DEFAULT_PLAY_SPEED,
+            banks: None,
+            expansion: 0,
+            data: Vec::new(),
+            tracks: Vec::new(),
+            playlist: Vec::new(),
+        }
+    }
+
+    /// Reads NSFe chunks into the fields, the metadata of NSF2 has the same
+    /// layout. Returns the ids of the chunks read
+    fn read_chunks(&mut self, mut raw: &[u8]) -> Result<Vec<[u8; 4]>, String> {
+        let mut read = Vec::new();
+        while raw.len() >= 8 {
+            let len = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+            let id = [raw[4], raw[5], raw[6], raw[7]];
+            let name = String::from_utf8_lossy(&id).to_string();
+            let body = raw
+                .get(8..8usize.saturating_add(len))
+                .ok_or_else(|| format!("the {} chunk is cut off", name))?;
+            raw = &raw[8 + len..];
+            match &id {
+                b"INFO" => self.read_info(body)?,
+                b"DATA" => self.data = body.to_vec(),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    for (bank, &value) in banks.iter_mut().zip(body) {
+                        *bank = value;
+                    }
+                    self.banks = Some(banks);
+                }
+                b"RATE" if body.len() >= 2 => self.play_speed = u16_at(body, 0),
+                b"RATE" => {}
+                b"auth" => {
+                    let mut strings = body.split(|&b| b == 0).map(lossy);
+                    for field in [
+                        &mut self.title,
+                        &mut self.artist,
+                        &mut self.copyright,
+                        &mut self.ripper,
+                    ] {
+                        if let Some(string) = strings.next() {
+                            *field = string;
+                        }
+                    }
+                }
+                b"tlbl" if !body.is_empty() => {
+                    let titles = body.strip_suffix(&[0]).unwrap_or(body);
+                    for (song, title) in titles.split(|&b| b == 0).enumerate() {
+                        self.track_mut(song).title = Some(lossy(title));
+                    }
+                }
+                b"time" | b"fade" => {
+                    for (song, ms) in body.chunks_exact(4).enumerate() {
+                        let ms = i32::from_le_bytes([ms[0], ms[1], ms[2], ms[3]]);
+                        // negative is the player's default
+                        let time = if ms >= 0 {
+                            Some(Duration::from_millis(ms as u64))
+                        } else {
+                            None
+                        };
+                        let track = self.track_mut(song);
+                        if &id == b"time" {
+                            track.duration = time;
+                        } else {
+                            track.fade = time;
+                        }
+                    }
+                }
+                b"plst" => self.playlist = body.to_vec(),
+                b"NEND" => break,
+                // chunks starting with a capital letter are needed to play the tune
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(format!("the {} chunk isn't supported", name))
+                }
+                _ => {}
+            }
+            read.push(id);
+        }
+        Ok(read)
+    }
+
+    fn read_info(&mut self, body: &[u8]) -> Result<(), String> {
+        if body.len() < 8 {
+            return Err("the INFO chunk is too short".to_string());
+        }
+        self.load_addr = u16_at(body, 0);
+        self.init_addr = u16_at(body, 2);
+        self.play_addr = u16_at(body, 4);
+        self.expansion = body[7];
+        self.songs = body.get(8).copied().unwrap_or(1);
+        self.starting_song = body.get(9).copied().unwrap_or(0);
+        Ok(())
+    }
+
+    fn track_mut(&mut self, song: usize) -> &mut TrackMeta {
+        if self.tracks.len() <= song {
+            self.tracks.resize(song + 1, TrackMeta::default());
+        }
+        &mut self.tracks[song]
+    }
+
+    /// Checks what every format needs and fills in the defaults
+    fn finish(mut self) -> Result<Nsf, String> {
+        if self.songs == 0 {
+            return Err("the file has no songs".to_string());
+        }
+        if self.data.is_empty() {
+            return Err("the file has no tune data".to_string());
+        }
+        if self.load_addr < 0x8000 {
+            return Err(format!(
+                "tunes loading at ${:04X}, below $8000, aren't supported",
+                self.load_addr
+            ));
+        }
+        if self.starting_song >= self.songs {
+            self.starting_song = 0;
+        }
+        if self.play_speed == 0 {
+            self.play_speed = DEFAULT_PLAY_SPEED;
+        }
+        let songs = self.songs;
+        self.tracks.resize(songs as usize, TrackMeta::default());
+        self.playlist.retain(|&song| song < songs);
+        if self.expansion != 0 {
+            warn!(target: "apu", "expansion audio {:02X} isn't emulated, those parts stay silent", self.expansion);
+        }
+        Ok(self)
+    }
+
+    /// The NSF board with the data laid out in 4K banks. Tunes that don't
+    /// switch banks get the data at the load address of a 32K ROM
+    fn rom(&self) -> Rom {
+        let padding = match self.banks {
+            Some(_) => self.load_addr as usize & 0xFFF,
+            None => self.load_addr as usize - 0x8000,
+        };
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(&self.data);
+        let size = match self.banks {
+            Some(_) => (prg.len() + 0xFFF) & !0xFFF,
+            None => 0x8000,
+        };
+        prg.resize(size, 0);
+        Rom {
+            prg_rom: prg.into(),
+            chr_rom: Vec::new().into(),
+            mapper: 31,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+            playchoice: None,
+        }
+    }
+
+    fn initial_banks(&self) -> [u8; 8] {
+        self.banks.unwrap_or([0, 1, 2, 3, 4, 5, 6, 7])
+    }
+}
+
+fn parse_nsf(raw: &[u8]) -> Result<Nsf, String> {
+    if raw.len() < HEADER_SIZE {
+        return Err("the NSF header is cut off".to_string());
+    }
+    let version = raw[5];
+    let banks = [
+        raw[0x70], raw[0x71], raw[0x72], raw[0x73], raw[0x74], raw[0x75], raw[0x76], raw[0x77],
+    ];
+    let mut nsf = Nsf {
+        title: lossy(header_string(&raw[0x0E..0x2E])),
+        artist: lossy(header_string(&raw[0x2E..0x4E])),
+        copyright: lossy(header_string(&raw[0x4E..0x6E])),
+        load_addr: u16_at(raw, 0x08),
+        init_addr: u16_at(raw, 0x0A),
+        play_addr: u16_at(raw, 0x0C),
+        songs: raw[6],
+        starting_song: raw[7].saturating_sub(1),
+        play_speed: u16_at(raw, 0x6E),
+        banks: if banks == [0; 8] { None } else { Some(banks) },
+        expansion: raw[0x7B],
+        ..Nsf::empty()
+    };
+
+    // NSF2 says how long the data is, the metadata follows it
+    let data_len = if version >= 2 {
+        u32::from_le_bytes([raw[0x7D], raw[0x7E], raw[0x7F], 0]) as usize
+    } else {
+        0
+    };
+    let data_end = if data_len == 0 {
+        raw.len()
+    } else if HEADER_SIZE + data_len <= raw.len() {
+        HEADER_SIZE + data_len
+    } else {
+        return Err("the NSF2 data runs past the end of the file".to_string());
+    };
+    nsf.data = raw[HEADER_SIZE..data_end].to_vec();
+    if version >= 2 {
+        if raw[0x7C] & 0x20 != 0 {
+            return Err("tunes whose INIT doesn't return aren't supported".to_string());
+        }
+        if data_len != 0 {
+            nsf.read_chunks(&raw[data_end..])?;
+        }
+    }
+    nsf.finish()
+}
+
+fn parse_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+    let mut nsf = Nsf::empty();
+    let read = nsf.read_chunks(raw)?;
+    for needed in [b"INFO", b"DATA"] {
+        if !read.contains(needed) {
+            return Err(format!(
+                "the NSFe file has no {} chunk",
+                String::from_utf8_lossy(needed)
+            ));
+        }
+    }
+    nsf.finish()
+}
+
+/// Header strings are padded with zeros
+fn header_string(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+fn u16_at(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+/// Plays the songs of an `Nsf`, one PLAY call per `run_frame`
+pub struct NsfPlayer {
+    nsf: Nsf,
+    rom: Rom,
+    cpu: CPU,
+    song: u8,
+    sample_rate: u32,
+    /// CPU cycle the song's INIT returned on
+    song_start: usize,
+    /// PLAY calls since then
+    plays: u64,
+    /// Samples handed out since then, for the fade
+    samples_out: u64,
+}
+
+impl NsfPlayer {
+    /// Starts the first song of the playlist, or the file's starting song
+    pub fn new(nsf: Nsf) -> Result<NsfPlayer, String> {
+        let rom = nsf.rom();
+        let song = nsf.playlist.first().copied().unwrap_or(nsf.starting_song);
+        let mut player = NsfPlayer {
+            cpu: CPU::new(Bus::new(rom.clone())),
+            nsf,
+            rom,
+            song,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            song_start: 0,
+            plays: 0,
+            samples_out: 0,
+        };
+        player.start_song(song)?;
+        Ok(player)
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn track_list(&self) -> Vec<TrackInfo> {
+        self.nsf.track_list()
+    }
+
+    /// The song playing, from 0
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    /// Samples per second of `take_samples`, kept across songs
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.cpu.bus.apu_mut().set_sample_rate(rate);
+    }
+
+    /// Switches to `song`, from 0, on a console powered on for it and runs
+    /// the song's INIT
+    pub fn start_song(&mut self, song: u8) -> Result<(), String> {
+        if song >= self.nsf.songs {
+            return Err(format!("there are only {} songs", self.nsf.songs));
+        }
+        // RAM and the APU start out cleared, as the format asks
+        self.cpu = CPU::new(Bus::new(self.rom.clone()));
+        self.cpu.bus.apu_mut().set_sample_rate(self.sample_rate);
+        for addr in 0x4000..=0x4013 {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(0x4015, 0x0F);
+        // no frame counter IRQs, nothing would handle them
+        self.cpu.mem_write(0x4017, 0x40);
+        for (slot, &bank) in self.nsf.initial_banks().iter().enumerate() {
+            self.cpu.mem_write(0x5FF8 + slot as u16, bank);
+        }
+
+        self.song = song;
+        self.cpu.register_a = song;
+        // NTSC
+        self.cpu.register_x = 0;
+        let init = self.nsf.init_addr;
+        self.call(init)?;
+        self.song_start = self.cpu.bus.cycles();
+        self.plays = 0;
+        self.samples_out = 0;
+        Ok(())
+    }
+
+    /// Calls PLAY and lets the console run until the next call is due, the
+    /// APU's samples pile up meanwhile
+    pub fn run_frame(&mut self) -> Result<(), String> {
+        let play = self.nsf.play_addr;
+        self.call(play)?;
+        self.plays += 1;
+        let due = self.song_start
+            + (self.plays * self.nsf.play_speed as u64 * CPU_CLOCK as u64 / 1_000_000) as usize;
+        while self.cpu.bus.cycles() < due {
+            let idle = (due - self.cpu.bus.cycles()).min(u8::MAX as usize);
+            self.cpu.bus.tick(idle as u8);
+        }
+        Ok(())
+    }
+
+    /// How long the song has played
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.plays * self.nsf.play_speed as u64)
+    }
+
+    /// Whether the song has played for its duration and faded out. Songs the
+    /// file gives no duration play until another one starts
+    pub fn finished(&self) -> bool {
+        let meta = &self.nsf.tracks[self.song as usize];
+        match meta.duration {
+            Some(duration) => self.elapsed() >= duration + meta.fade.unwrap_or_default(),
+            None => false,
+        }
+    }
+
+    /// Moves the audio made since the last call to the end of `out`. Past
+    /// the song's duration it fades out over the fade time, or stops
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        let start = out.len();
+        let apu = self.cpu.bus.apu_mut();
+        out.extend_from_slice(apu.samples());
+        apu.clear_samples();
+
+        let meta = &self.nsf.tracks[self.song as usize];
+        if let Some(duration) = meta.duration {
+            let rate = self.sample_rate as f64;
+            let fade_start = duration.as_secs_f64();
+            let fade = meta.fade.unwrap_or_default().as_secs_f64();
+            for (i, sample) in out[start..].iter_mut().enumerate() {
+                let time = (self.samples_out + i as u64) as f64 / rate;
+                let gain = if time < fade_start {
+                    1.0
+                } else if fade > 0.0 {
+                    (1.0 - (time - fade_start) / fade).max(0.0)
+                } else {
+                    0.0
+                };
+                *sample *= gain as f32;
+            }
+        }
+        self.samples_out += (out.len() - start) as u64;
+    }
+
+    /// Runs the routine at `addr` until it returns, as if the driver had
+    /// called it with JSR
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        let return_addr = RETURN_ADDR - 1;
+        let sp = self.cpu.stack_pointer;
+        self.cpu
+            .mem_write(0x0100 + sp as u16, (return_addr >> 8) as u8);
+        self.cpu
+            .mem_write(0x0100 + sp.wrapping_sub(1) as u16, return_addr as u8);
+        self.cpu.stack_pointer = sp.wrapping_sub(2);
+        self.cpu.program_counter = addr;
+
+        let start = self.cpu.bus.cycles();
+        while self.cpu.program_counter != RETURN_ADDR {
+            if self.cpu.bus.cycles() - start > CALL_LIMIT {
+                return Err(format!("the routine at ${:04X} didn't return", addr));
+            }
+            if !self.cpu.step() {
+                return Err(match self.cpu.bus.take_fault() {
+                    Some(fault) => fault.to_string(),
+                    None => format!("BRK at ${:04X}", self.cpu.program_counter.wrapping_sub(1)),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// INIT at $8000 stores the song number at $00 and starts a pulse, PLAY
+    /// at $8020 counts its calls at $01
+    fn tune() -> Vec<u8> {
+        let mut data = vec![
+            0x85, 0x00, // STA $00
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF; STA $4000
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD; STA $4002
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08; STA $4003
+            0x60, // RTS
+        ];
+        data.resize(0x20, 0);
+        data.extend_from_slice(&[0xE6, 0x01, 0x60]); // INC $01; RTS
+        data
+    }
+
+    fn nsf_file(version: u8, songs: u8, data: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0; HEADER_SIZE];
+        raw[..5].copy_from_slice(NSF_TAG);
+        raw[5] = version;
+        raw[6] = songs;
+        raw[7] = 2;
+        raw[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x20, 0x80]);
+        raw[0x0E..0x17].copy_from_slice(b"Test Tune");
+        raw[0x2E..0x34].copy_from_slice(b"Nobody");
+        raw[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = (body.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    /// The metadata chunks of a file with three songs
+    fn metadata() -> Vec<u8> {
+        let millis = |times: &[i32]| {
+            times
+                .iter()
+                .flat_map(|t| t.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        [
+            chunk(b"auth", b"Album\0Composer\0Year\0Ripper\0"),
+            chunk(b"tlbl", b"Intro\0\0Ending\0"),
+            chunk(b"time", &millis(&[90_000, -1, 5_000])),
+            chunk(b"fade", &millis(&[2_000])),
+            chunk(b"plst", &[2, 0, 7]),
+            chunk(b"text", b"ignored"),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let nsf = Nsf::new(&nsf_file(1, 3, &tune())).unwrap();
+        assert_eq!(nsf.title, "Test Tune");
+        assert_eq!(nsf.artist, "Nobody");
+        assert_eq!(
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr),
+            (0x8000, 0x8000, 0x8020)
+        );
+        assert_eq!((nsf.songs, nsf.starting_song), (3, 1));
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.data, tune());
+        let titles: Vec<_> = nsf.track_list().into_iter().map(|t| t.title).collect();
+        assert_eq!(titles, ["Track 1", "Track 2", "Track 3"]);
+
+        assert!(Nsf::new(b"NESM\x1A\x01").is_err());
+        assert!(Nsf::new(&[0; 200]).is_err());
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let mut info = vec![0x00, 0x80, 0x00, 0x80, 0x20, 0x80, 0, 0, 3, 0];
+        let raw = [
+            &b"NSFE"[..],
+            &chunk(b"INFO", &info),
+            &chunk(b"DATA", &tune()),
+            &metadata(),
+            &chunk(b"NEND", &[]),
+        ]
+        .concat();
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!(
+            (&*nsf.title, &*nsf.artist, &*nsf.copyright, &*nsf.ripper),
+            ("Album", "Composer", "Year", "Ripper")
+        );
+        assert_eq!(nsf.play_speed, DEFAULT_PLAY_SPEED);
+        assert_eq!(
+            nsf.track_list(),
+            [
+                TrackInfo {
+                    song: 2,
+                    title: "Ending".to_string(),
+                    duration: Some(Duration::from_secs(5)),
+                    fade: None,
+                },
+                TrackInfo {
+                    song: 0,
+                    title: "Intro".to_string(),
+                    duration: Some(Duration::from_secs(90)),
+                    fade: Some(Duration::from_secs(2)),
+                },
+            ]
+        );
+        // the second title is empty but there
+        assert_eq!(nsf.tracks[1].title.as_deref(), Some(""));
+
+        info.truncate(8);
+        let raw = [
+            &b"NSFE"[..],
+            &chunk(b"INFO", &info),
+            &chunk(b"DATA", &tune()),
+        ]
+        .concat();
+        assert_eq!(Nsf::new(&raw).unwrap().songs, 1);
+        let raw = [&b"NSFE"[..], &chunk(b"DATA", &tune())].concat();
+        assert!(Nsf::new(&raw).unwrap_err().contains("INFO"));
+        let raw = [&raw[..], &chunk(b"INFO", &info), &chunk(b"VRC7", &[])].concat();
+        assert!(Nsf::new(&raw).unwrap_err().contains("VRC7"));
+    }
+
+    #[test]
+    fn test_nsf2_metadata() {
+        let mut raw = nsf_file(2, 3, &tune());
+        raw[0x7D] = tune().len() as u8;
+        raw.extend_from_slice(&metadata());
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!(nsf.data, tune());
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.playlist, [2, 0]);
+
+        raw[0x7C] = 0x20;
+        assert!(Nsf::new(&raw).is_err());
+    }
+
+    #[test]
+    fn test_player_calls_init_and_play() {
+        let mut player = NsfPlayer::new(Nsf::new(&nsf_file(1, 3, &tune())).unwrap()).unwrap();
+        assert_eq!(player.song(), 1);
+        player.start_song(2).unwrap();
+        assert_eq!(player.cpu.mem_read(0x00), 2);
+
+        let start = player.cpu.bus.cycles();
+        for _ in 0..3 {
+            player.run_frame().unwrap();
+        }
+        assert_eq!(player.cpu.mem_read(0x01), 3);
+        assert_eq!(player.elapsed(), Duration::from_micros(3 * 16639));
+        assert!((player.cpu.bus.cycles() - start).abs_diff(3 * 29780) < 100);
+        assert!(!player.finished());
+
+        let mut samples = Vec::new();
+        for _ in 0..10 {
+            player.run_frame().unwrap();
+            player.take_samples(&mut samples);
+        }
+        assert!(samples.iter().any(|s| s.abs() > 0.01));
+        assert!(player.start_song(3).is_err());
+    }
+
+    #[test]
+    fn test_player_stops_after_the_duration() {
+        let mut nsf = Nsf::new(&nsf_file(1, 1, &tune())).unwrap();
+        nsf.tracks[0].duration = Some(Duration::from_millis(10));
+        let mut player = NsfPlayer::new(nsf).unwrap();
+        player.run_frame().unwrap();
+        assert!(player.finished());
+        let mut samples = Vec::new();
+        for _ in 0..10 {
+            player.run_frame().unwrap();
+            player.take_samples(&mut samples);
+        }
+        assert!(samples[samples.len() / 2..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        // bank 0 has a marker, INIT in bank 1 is switched in at $8000 and
+        // reads the marker through $9000
+        let mut data = vec![0; 0x2000];
+        data[0] = 0xAB;
+        data[0x1000..0x1006].copy_from_slice(&[0xAD, 0x00, 0x90, 0x85, 0x00, 0x60]);
+        data[0x1020..0x1023].copy_from_slice(&[0xE6, 0x01, 0x60]);
+        let mut raw = nsf_file(1, 1, &data);
+        raw[0x70..0x78].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!(nsf.banks, Some([1, 0, 0, 0, 0, 0, 0, 0]));
+        let mut player = NsfPlayer::new(nsf).unwrap();
+        assert_eq!(player.cpu.mem_read(0x00), 0xAB);
+        player.run_frame().unwrap();
+        assert_eq!(player.cpu.mem_read(0x01), 1);
+    }
+}
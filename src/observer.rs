@@ -0,0 +1,89 @@
+// Hooks into the CPU loop for tracers, profilers and the like.
+//
+// The CPU is generic over its observer, so the hook is resolved at compile
+// time and an observer that does nothing (`NoopObserver`, the one `step` and
+// `run_frame` use) costs nothing at all. Building without the `trace` feature
+// also compiles out the logging hooks inside the core, see `TRACING`.
+#[cfg(feature = "std")]
+use crate::cdl::CodeDataLog;
+use crate::cpu::CPU;
+#[cfg(feature = "std")]
+use crate::profiler::Profiler;
+
+/// Whether the tracing hooks are compiled in. When false the bus skips event
+/// and PPU write logging entirely, whatever was enabled at runtime
+pub const TRACING: bool = cfg!(feature = "trace");
+
+pub trait Observer {
+    /// Called before every instruction, after a pending NMI has been taken
+    #[inline(always)]
+    fn before_instruction(&mut self, _cpu: &mut CPU) {}
+}
+
+/// Observer without any hooks, for running at full speed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+impl<F: FnMut(&mut CPU)> Observer for F {
+    #[inline(always)]
+    fn before_instruction(&mut self, cpu: &mut CPU) {
+        self(cpu)
+    }
+}
+
+impl<O: Observer> Observer for Option<O> {
+    #[inline(always)]
+    fn before_instruction(&mut self, cpu: &mut CPU) {
+        if let Some(observer) = self {
+            observer.before_instruction(cpu);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Observer for Profiler {
+    fn before_instruction(&mut self, cpu: &mut CPU) {
+        self.sample(cpu);
+    }
+}
+
+#[cfg(feature = "std")]
+impl Observer for CodeDataLog {
+    fn before_instruction(&mut self, cpu: &mut CPU) {
+        self.log(cpu);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    struct Counter {
+        instructions: usize,
+    }
+
+    impl Observer for Counter {
+        fn before_instruction(&mut self, _cpu: &mut CPU) {
+            self.instructions += 1;
+        }
+    }
+
+    #[test]
+    fn test_observer_sees_every_instruction() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDA #1; TAX; INX; BRK
+        cpu.load(vec![0xa9, 0x01, 0xaa, 0xe8, 0x00]);
+        cpu.program_counter = 0x0600;
+        let mut counter = Counter { instructions: 0 };
+        cpu.run_with_observer(&mut counter);
+        assert_eq!(counter.instructions, 4);
+
+        cpu.program_counter = 0x0600;
+        cpu.run_with_observer(&mut NoopObserver);
+        assert_eq!(cpu.register_x, 2);
+    }
+}
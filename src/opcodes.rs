@@ -1,363 +1,519 @@
 use crate::cpu::AddressingMode;
-use std::collections::HashMap;
 
+/// What the CPU does for an opcode. Most instructions are one operation in all
+/// their addressing modes, the ones that behave differently per mode (shifts of
+/// the accumulator, the two JMPs, AHX) get one each
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Brk,
+    Nop,
+    Adc,
+    Sbc,
+    And,
+    Eor,
+    Ora,
+    AslAccumulator,
+    Asl,
+    LsrAccumulator,
+    Lsr,
+    RolAccumulator,
+    Rol,
+    RorAccumulator,
+    Ror,
+    Inc,
+    Inx,
+    Iny,
+    Dec,
+    Dex,
+    Dey,
+    Cmp,
+    Cpy,
+    Cpx,
+    JmpAbsolute,
+    JmpIndirect,
+    Jsr,
+    Rts,
+    Rti,
+    Bne,
+    Bvs,
+    Bvc,
+    Bmi,
+    Beq,
+    Bcs,
+    Bcc,
+    Bpl,
+    Bit,
+    Lda,
+    Ldx,
+    Ldy,
+    Sta,
+    Stx,
+    Sty,
+    Cld,
+    Cli,
+    Clv,
+    Clc,
+    Sec,
+    Sei,
+    Sed,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    Pha,
+    Pla,
+    Php,
+    Plp,
+
+    /* unofficial */
+    Dcp,
+    Rla,
+    Slo,
+    Sre,
+    Axs,
+    Arr,
+    Anc,
+    Alr,
+    /// NOP that reads its operand
+    NopRead,
+    Rra,
+    Isb,
+    Lxa,
+    Xaa,
+    Las,
+    Tas,
+    AhxIndirectY,
+    AhxAbsoluteY,
+    Shx,
+    Shy,
+    Lax,
+    Sax,
+}
+
+impl Operation {
+    const fn has_page_cross_penalty(&self) -> bool {
+        matches!(
+            self,
+            Operation::Adc
+                | Operation::And
+                | Operation::Cmp
+                | Operation::Eor
+                | Operation::Lda
+                | Operation::Ldx
+                | Operation::Ldy
+                | Operation::Ora
+                | Operation::Sbc
+                | Operation::Lax
+                | Operation::NopRead
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct OpCode {
     pub code: u8,
     pub mnemonic: &'static str,
+    pub operation: Operation,
     pub len: u8,
+    /// Base cycles, without page crossing and taken branches
     pub cycles: u8,
     pub mode: AddressingMode,
+    /// Takes a cycle more when indexing crosses a page
+    pub page_cross_penalty: bool,
 }
 
 impl OpCode {
-    fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
+    const fn new(
+        code: u8,
+        mnemonic: &'static str,
+        operation: Operation,
+        len: u8,
+        cycles: u8,
+        mode: AddressingMode,
+    ) -> Self {
+        let indexed = matches!(
+            mode,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+        );
         OpCode {
-            code: code,
-            mnemonic: mnemonic,
-            len: len,
-            cycles: cycles,
-            mode: mode,
+            code,
+            mnemonic,
+            operation,
+            len,
+            cycles,
+            mode,
+            page_cross_penalty: indexed && operation.has_page_cross_penalty(),
         }
     }
 }
 
-lazy_static! {
-    pub static ref CPU_OPS_CODES: Vec<OpCode> = vec![
-        OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
-        OpCode::new(0xea, "NOP", 1, 2, AddressingMode::NoneAddressing),
-
-        /* Arithmetic */
-        OpCode::new(0x69, "ADC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6d, "ADC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x7d, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0x79, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x71, "ADC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0xe9, "SBC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xe5, "SBC", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xf5, "SBC", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xed, "SBC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xfd, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0xf9, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0xe1, "SBC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xf1, "SBC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2d, "AND", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x3d, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0x39, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x31, "AND", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x49, "EOR", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4d, "EOR", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x5d, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0x59, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x51, "EOR", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x09, "ORA", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0d, "ORA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x1d, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0x19, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x11, "ORA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        /* Shifts */
-        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x1e, "ASL", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x5e, "LSR", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x7e, "ROR", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0xe6, "INC", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xf6, "INC", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xee, "INC", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xfe, "INC", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0xe8, "INX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xc8, "INY", 1, 2, AddressingMode::NoneAddressing),
-
-        OpCode::new(0xc6, "DEC", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xd6, "DEC", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xce, "DEC", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xde, "DEC", 3, 7, AddressingMode::Absolute_X),
-
-        OpCode::new(0xca, "DEX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x88, "DEY", 1, 2, AddressingMode::NoneAddressing),
-
-        OpCode::new(0xc9, "CMP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc5, "CMP", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xd5, "CMP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xcd, "CMP", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xdd, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0xd9, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0xc1, "CMP", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xd1, "CMP", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0xc0, "CPY", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc4, "CPY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xcc, "CPY", 3, 4, AddressingMode::Absolute),
-
-        OpCode::new(0xe0, "CPX", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xe4, "CPX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xec, "CPX", 3, 4, AddressingMode::Absolute),
-
-
-        /* Branching */
-
-        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing), //AddressingMode that acts as Immidiate
-        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing), //AddressingMode:Indirect with 6502 bug
-
-        OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
-        OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
-
-        OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing),
-
-        OpCode::new(0xd0, "BNE", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x70, "BVS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x50, "BVC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x30, "BMI", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xf0, "BEQ", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xb0, "BCS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x90, "BCC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x10, "BPL", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-
-        OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x2c, "BIT", 3, 4, AddressingMode::Absolute),
-
-
-        /* Stores, Loads */
-        OpCode::new(0xa9, "LDA", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb5, "LDA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbd, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-        OpCode::new(0xb9, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-        OpCode::new(0xa1, "LDA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xb1, "LDA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-        OpCode::new(0xa2, "LDX", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa6, "LDX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb6, "LDX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0xae, "LDX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbe, "LDX", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-
-        OpCode::new(0xa0, "LDY", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa4, "LDY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb4, "LDY", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xac, "LDY", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbc, "LDY", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-
-
-        OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x8d, "STA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x9d, "STA", 3, 5, AddressingMode::Absolute_X),
-        OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y),
-        OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x86, "STX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x96, "STX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0x8e, "STX", 3, 4, AddressingMode::Absolute),
-
-        OpCode::new(0x84, "STY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x94, "STY", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x8c, "STY", 3, 4, AddressingMode::Absolute),
-
-
-        /* Flags clear */
-
-        OpCode::new(0xD8, "CLD", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x58, "CLI", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb8, "CLV", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x18, "CLC", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x38, "SEC", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x78, "SEI", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf8, "SED", 1, 2, AddressingMode::NoneAddressing),
-
-        OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing),
-
-        /* Stack */
-        OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing),
-        OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing),
-        OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
-        OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
-
-
-        /* unofficial */
-
-        OpCode::new(0xc7, "*DCP", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xd7, "*DCP", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xCF, "*DCP", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xdF, "*DCP", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xdb, "*DCP", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0xd3, "*DCP", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0xc3, "*DCP", 2, 8, AddressingMode::Indirect_X),
-
-
-        OpCode::new(0x27, "*RLA", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x37, "*RLA", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2F, "*RLA", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x3F, "*RLA", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x3b, "*RLA", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x33, "*RLA", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x23, "*RLA", 2, 8, AddressingMode::Indirect_X),
-
-        OpCode::new(0x07, "*SLO", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x17, "*SLO", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0F, "*SLO", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x1f, "*SLO", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x1b, "*SLO", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x03, "*SLO", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x13, "*SLO", 2, 8, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x47, "*SRE", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x57, "*SRE", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4F, "*SRE", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x5f, "*SRE", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x5b, "*SRE", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x43, "*SRE", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x53, "*SRE", 2, 8, AddressingMode::Indirect_Y),
-
-
-        OpCode::new(0x80, "*NOP", 2,2, AddressingMode::Immediate),
-        OpCode::new(0x82, "*NOP", 2,2, AddressingMode::Immediate),
-        OpCode::new(0x89, "*NOP", 2,2, AddressingMode::Immediate),
-        OpCode::new(0xc2, "*NOP", 2,2, AddressingMode::Immediate),
-        OpCode::new(0xe2, "*NOP", 2,2, AddressingMode::Immediate),
-
-
-        OpCode::new(0xCB, "*AXS", 2,2, AddressingMode::Immediate),
-
-        OpCode::new(0x6B, "*ARR", 2,2, AddressingMode::Immediate),
-
-        OpCode::new(0xeb, "*SBC", 2,2, AddressingMode::Immediate),
-
-        OpCode::new(0x0b, "*ANC", 2,2, AddressingMode::Immediate),
-        OpCode::new(0x2b, "*ANC", 2,2, AddressingMode::Immediate),
-
-        OpCode::new(0x4b, "*ALR", 2,2, AddressingMode::Immediate),
-        // OpCode::new(0xCB, "IGN", 3,4 /* or 5*/, AddressingMode::Absolute_X),
-
-        OpCode::new(0x04, "*NOP", 2,3, AddressingMode::ZeroPage),
-        OpCode::new(0x44, "*NOP", 2,3, AddressingMode::ZeroPage),
-        OpCode::new(0x64, "*NOP", 2,3, AddressingMode::ZeroPage),
-        OpCode::new(0x14, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x34, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x74, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0c, "*NOP", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x1c, "*NOP", 3, 4 /*or 5*/, AddressingMode::Absolute_X),
-        OpCode::new(0x3c, "*NOP", 3, 4 /*or 5*/, AddressingMode::Absolute_X),
-        OpCode::new(0x5c, "*NOP", 3, 4 /*or 5*/, AddressingMode::Absolute_X),
-        OpCode::new(0x7c, "*NOP", 3, 4 /*or 5*/, AddressingMode::Absolute_X),
-        OpCode::new(0xdc, "*NOP", 3, 4 /* or 5*/, AddressingMode::Absolute_X),
-        OpCode::new(0xfc, "*NOP", 3, 4 /* or 5*/, AddressingMode::Absolute_X),
-
-        OpCode::new(0x67, "*RRA", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x77, "*RRA", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6f, "*RRA", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x7f, "*RRA", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x7b, "*RRA", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x63, "*RRA", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x73, "*RRA", 2, 8, AddressingMode::Indirect_Y),
-
-
-        OpCode::new(0xe7, "*ISB", 2,5, AddressingMode::ZeroPage),
-        OpCode::new(0xf7, "*ISB", 2,6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xef, "*ISB", 3,6, AddressingMode::Absolute),
-        OpCode::new(0xff, "*ISB", 3,7, AddressingMode::Absolute_X),
-        OpCode::new(0xfb, "*ISB", 3,7, AddressingMode::Absolute_Y),
-        OpCode::new(0xe3, "*ISB", 2,8, AddressingMode::Indirect_X),
-        OpCode::new(0xf3, "*ISB", 2,8, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x02, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x12, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x22, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x32, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x42, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x52, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x62, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x72, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x92, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xd2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-
-        OpCode::new(0x1a, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x3a, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x5a, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x7a, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xda, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        // OpCode::new(0xea, "NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xfa, "*NOP", 1,2, AddressingMode::NoneAddressing),
-
-        OpCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
-        //http://visual6502.org/wiki/index.php?title=6502_Opcode_8B_%28XAA,_ANE%29
-        OpCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
-        OpCode::new(0xbb, "*LAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9b, "*TAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x93, "*AHX", 2, /* guess */ 8, AddressingMode::Indirect_Y), //todo: highly unstable and not used
-        OpCode::new(0x9f, "*AHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9e, "*SHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9c, "*SHY", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_X), //todo: highly unstable and not used
-
-        OpCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0xaf, "*LAX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbf, "*LAX", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xa3, "*LAX", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xb3, "*LAX", 2, 5, AddressingMode::Indirect_Y),
-
-        OpCode::new(0x87, "*SAX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x97, "*SAX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0x8f, "*SAX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x83, "*SAX", 2, 6, AddressingMode::Indirect_X),
-
-    ];
-
-
-    pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
-        let mut map = HashMap::new();
-        for cpuop in &*CPU_OPS_CODES {
-            map.insert(cpuop.code, cpuop);
+#[rustfmt::skip]
+pub static CPU_OPS_CODES: &[OpCode] = &[
+
+    OpCode::new(0x00, "BRK", Operation::Brk, 1, 7, AddressingMode::NoneAddressing),
+    OpCode::new(0xea, "NOP", Operation::Nop, 1, 2, AddressingMode::NoneAddressing),
+
+    /* Arithmetic */
+    OpCode::new(0x69, "ADC", Operation::Adc, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x65, "ADC", Operation::Adc, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x75, "ADC", Operation::Adc, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x6d, "ADC", Operation::Adc, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x7d, "ADC", Operation::Adc, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0x79, "ADC", Operation::Adc, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0x61, "ADC", Operation::Adc, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0x71, "ADC", Operation::Adc, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0xe9, "SBC", Operation::Sbc, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xe5, "SBC", Operation::Sbc, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xf5, "SBC", Operation::Sbc, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xed, "SBC", Operation::Sbc, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xfd, "SBC", Operation::Sbc, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0xf9, "SBC", Operation::Sbc, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0xe1, "SBC", Operation::Sbc, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0xf1, "SBC", Operation::Sbc, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x29, "AND", Operation::And, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x25, "AND", Operation::And, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x35, "AND", Operation::And, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x2d, "AND", Operation::And, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x3d, "AND", Operation::And, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0x39, "AND", Operation::And, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0x21, "AND", Operation::And, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0x31, "AND", Operation::And, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x49, "EOR", Operation::Eor, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x45, "EOR", Operation::Eor, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x55, "EOR", Operation::Eor, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x4d, "EOR", Operation::Eor, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x5d, "EOR", Operation::Eor, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0x59, "EOR", Operation::Eor, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0x41, "EOR", Operation::Eor, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0x51, "EOR", Operation::Eor, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x09, "ORA", Operation::Ora, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0x05, "ORA", Operation::Ora, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x15, "ORA", Operation::Ora, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x0d, "ORA", Operation::Ora, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x1d, "ORA", Operation::Ora, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0x19, "ORA", Operation::Ora, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0x01, "ORA", Operation::Ora, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0x11, "ORA", Operation::Ora, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    /* Shifts */
+    OpCode::new(0x0a, "ASL", Operation::AslAccumulator, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x06, "ASL", Operation::Asl, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x16, "ASL", Operation::Asl, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x0e, "ASL", Operation::Asl, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x1e, "ASL", Operation::Asl, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0x4a, "LSR", Operation::LsrAccumulator, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x46, "LSR", Operation::Lsr, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x56, "LSR", Operation::Lsr, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x4e, "LSR", Operation::Lsr, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x5e, "LSR", Operation::Lsr, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0x2a, "ROL", Operation::RolAccumulator, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x26, "ROL", Operation::Rol, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x36, "ROL", Operation::Rol, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x2e, "ROL", Operation::Rol, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x3e, "ROL", Operation::Rol, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0x6a, "ROR", Operation::RorAccumulator, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x66, "ROR", Operation::Ror, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x76, "ROR", Operation::Ror, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x6e, "ROR", Operation::Ror, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x7e, "ROR", Operation::Ror, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0xe6, "INC", Operation::Inc, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0xf6, "INC", Operation::Inc, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0xee, "INC", Operation::Inc, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0xfe, "INC", Operation::Inc, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0xe8, "INX", Operation::Inx, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xc8, "INY", Operation::Iny, 1, 2, AddressingMode::NoneAddressing),
+
+    OpCode::new(0xc6, "DEC", Operation::Dec, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0xd6, "DEC", Operation::Dec, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0xce, "DEC", Operation::Dec, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0xde, "DEC", Operation::Dec, 3, 7, AddressingMode::Absolute_X),
+
+    OpCode::new(0xca, "DEX", Operation::Dex, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x88, "DEY", Operation::Dey, 1, 2, AddressingMode::NoneAddressing),
+
+    OpCode::new(0xc9, "CMP", Operation::Cmp, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xc5, "CMP", Operation::Cmp, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xd5, "CMP", Operation::Cmp, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xcd, "CMP", Operation::Cmp, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xdd, "CMP", Operation::Cmp, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0xd9, "CMP", Operation::Cmp, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0xc1, "CMP", Operation::Cmp, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0xd1, "CMP", Operation::Cmp, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0xc0, "CPY", Operation::Cpy, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xc4, "CPY", Operation::Cpy, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xcc, "CPY", Operation::Cpy, 3, 4, AddressingMode::Absolute),
+
+    OpCode::new(0xe0, "CPX", Operation::Cpx, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xe4, "CPX", Operation::Cpx, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xec, "CPX", Operation::Cpx, 3, 4, AddressingMode::Absolute),
+
+
+    /* Branching */
+
+    OpCode::new(0x4c, "JMP", Operation::JmpAbsolute, 3, 3, AddressingMode::NoneAddressing), //AddressingMode that acts as Immidiate
+    OpCode::new(0x6c, "JMP", Operation::JmpIndirect, 3, 5, AddressingMode::NoneAddressing), //AddressingMode:Indirect with 6502 bug
+
+    OpCode::new(0x20, "JSR", Operation::Jsr, 3, 6, AddressingMode::NoneAddressing),
+    OpCode::new(0x60, "RTS", Operation::Rts, 1, 6, AddressingMode::NoneAddressing),
+
+    OpCode::new(0x40, "RTI", Operation::Rti, 1, 6, AddressingMode::NoneAddressing),
+
+    OpCode::new(0xd0, "BNE", Operation::Bne, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0x70, "BVS", Operation::Bvs, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0x50, "BVC", Operation::Bvc, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0x30, "BMI", Operation::Bmi, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0xf0, "BEQ", Operation::Beq, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0xb0, "BCS", Operation::Bcs, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0x90, "BCC", Operation::Bcc, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+    OpCode::new(0x10, "BPL", Operation::Bpl, 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+
+    OpCode::new(0x24, "BIT", Operation::Bit, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x2c, "BIT", Operation::Bit, 3, 4, AddressingMode::Absolute),
+
+
+    /* Stores, Loads */
+    OpCode::new(0xa9, "LDA", Operation::Lda, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xa5, "LDA", Operation::Lda, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xb5, "LDA", Operation::Lda, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xad, "LDA", Operation::Lda, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xbd, "LDA", Operation::Lda, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0xb9, "LDA", Operation::Lda, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+    OpCode::new(0xa1, "LDA", Operation::Lda, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0xb1, "LDA", Operation::Lda, 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+    OpCode::new(0xa2, "LDX", Operation::Ldx, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xa6, "LDX", Operation::Ldx, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xb6, "LDX", Operation::Ldx, 2, 4, AddressingMode::ZeroPage_Y),
+    OpCode::new(0xae, "LDX", Operation::Ldx, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xbe, "LDX", Operation::Ldx, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+
+    OpCode::new(0xa0, "LDY", Operation::Ldy, 2, 2, AddressingMode::Immediate),
+    OpCode::new(0xa4, "LDY", Operation::Ldy, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xb4, "LDY", Operation::Ldy, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xac, "LDY", Operation::Ldy, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xbc, "LDY", Operation::Ldy, 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+
+
+    OpCode::new(0x85, "STA", Operation::Sta, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x95, "STA", Operation::Sta, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x8d, "STA", Operation::Sta, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x9d, "STA", Operation::Sta, 3, 5, AddressingMode::Absolute_X),
+    OpCode::new(0x99, "STA", Operation::Sta, 3, 5, AddressingMode::Absolute_Y),
+    OpCode::new(0x81, "STA", Operation::Sta, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0x91, "STA", Operation::Sta, 2, 6, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x86, "STX", Operation::Stx, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x96, "STX", Operation::Stx, 2, 4, AddressingMode::ZeroPage_Y),
+    OpCode::new(0x8e, "STX", Operation::Stx, 3, 4, AddressingMode::Absolute),
+
+    OpCode::new(0x84, "STY", Operation::Sty, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x94, "STY", Operation::Sty, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x8c, "STY", Operation::Sty, 3, 4, AddressingMode::Absolute),
+
+
+    /* Flags clear */
+
+    OpCode::new(0xD8, "CLD", Operation::Cld, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x58, "CLI", Operation::Cli, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xb8, "CLV", Operation::Clv, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x18, "CLC", Operation::Clc, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x38, "SEC", Operation::Sec, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x78, "SEI", Operation::Sei, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xf8, "SED", Operation::Sed, 1, 2, AddressingMode::NoneAddressing),
+
+    OpCode::new(0xaa, "TAX", Operation::Tax, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xa8, "TAY", Operation::Tay, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xba, "TSX", Operation::Tsx, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x8a, "TXA", Operation::Txa, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x9a, "TXS", Operation::Txs, 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x98, "TYA", Operation::Tya, 1, 2, AddressingMode::NoneAddressing),
+
+    /* Stack */
+    OpCode::new(0x48, "PHA", Operation::Pha, 1, 3, AddressingMode::NoneAddressing),
+    OpCode::new(0x68, "PLA", Operation::Pla, 1, 4, AddressingMode::NoneAddressing),
+    OpCode::new(0x08, "PHP", Operation::Php, 1, 3, AddressingMode::NoneAddressing),
+    OpCode::new(0x28, "PLP", Operation::Plp, 1, 4, AddressingMode::NoneAddressing),
+
+
+    /* unofficial */
+
+    OpCode::new(0xc7, "*DCP", Operation::Dcp, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0xd7, "*DCP", Operation::Dcp, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0xCF, "*DCP", Operation::Dcp, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0xDF, "*DCP", Operation::Dcp, 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0xdb, "*DCP", Operation::Dcp, 3, 7, AddressingMode::Absolute_Y),
+    OpCode::new(0xd3, "*DCP", Operation::Dcp, 2, 8, AddressingMode::Indirect_Y),
+    OpCode::new(0xc3, "*DCP", Operation::Dcp, 2, 8, AddressingMode::Indirect_X),
+
+
+    OpCode::new(0x27, "*RLA", Operation::Rla, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x37, "*RLA", Operation::Rla, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x2F, "*RLA", Operation::Rla, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x3F, "*RLA", Operation::Rla, 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0x3b, "*RLA", Operation::Rla, 3, 7, AddressingMode::Absolute_Y),
+    OpCode::new(0x33, "*RLA", Operation::Rla, 2, 8, AddressingMode::Indirect_Y),
+    OpCode::new(0x23, "*RLA", Operation::Rla, 2, 8, AddressingMode::Indirect_X),
+
+    OpCode::new(0x07, "*SLO", Operation::Slo, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x17, "*SLO", Operation::Slo, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x0F, "*SLO", Operation::Slo, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x1f, "*SLO", Operation::Slo, 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0x1b, "*SLO", Operation::Slo, 3, 7, AddressingMode::Absolute_Y),
+    OpCode::new(0x03, "*SLO", Operation::Slo, 2, 8, AddressingMode::Indirect_X),
+    OpCode::new(0x13, "*SLO", Operation::Slo, 2, 8, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x47, "*SRE", Operation::Sre, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x57, "*SRE", Operation::Sre, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x4F, "*SRE", Operation::Sre, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x5f, "*SRE", Operation::Sre, 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0x5b, "*SRE", Operation::Sre, 3, 7, AddressingMode::Absolute_Y),
+    OpCode::new(0x43, "*SRE", Operation::Sre, 2, 8, AddressingMode::Indirect_X),
+    OpCode::new(0x53, "*SRE", Operation::Sre, 2, 8, AddressingMode::Indirect_Y),
+
+
+    OpCode::new(0x80, "*NOP", Operation::Nop, 2,2, AddressingMode::Immediate),
+    OpCode::new(0x82, "*NOP", Operation::Nop, 2,2, AddressingMode::Immediate),
+    OpCode::new(0x89, "*NOP", Operation::Nop, 2,2, AddressingMode::Immediate),
+    OpCode::new(0xc2, "*NOP", Operation::Nop, 2,2, AddressingMode::Immediate),
+    OpCode::new(0xe2, "*NOP", Operation::Nop, 2,2, AddressingMode::Immediate),
+
+
+    OpCode::new(0xCB, "*AXS", Operation::Axs, 2,2, AddressingMode::Immediate),
+
+    OpCode::new(0x6B, "*ARR", Operation::Arr, 2,2, AddressingMode::Immediate),
+
+    OpCode::new(0xeb, "*SBC", Operation::Sbc, 2,2, AddressingMode::Immediate),
+
+    OpCode::new(0x0b, "*ANC", Operation::Anc, 2,2, AddressingMode::Immediate),
+    OpCode::new(0x2b, "*ANC", Operation::Anc, 2,2, AddressingMode::Immediate),
+
+    OpCode::new(0x4b, "*ALR", Operation::Alr, 2,2, AddressingMode::Immediate),
+    // OpCode::new(0xCB, "IGN", 3,4 /* or 5*/, AddressingMode::Absolute_X),
+
+    OpCode::new(0x04, "*NOP", Operation::NopRead, 2,3, AddressingMode::ZeroPage),
+    OpCode::new(0x44, "*NOP", Operation::NopRead, 2,3, AddressingMode::ZeroPage),
+    OpCode::new(0x64, "*NOP", Operation::NopRead, 2,3, AddressingMode::ZeroPage),
+    OpCode::new(0x14, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x34, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x54, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x74, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xd4, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0xf4, "*NOP", Operation::NopRead, 2, 4, AddressingMode::ZeroPage_X),
+    OpCode::new(0x0c, "*NOP", Operation::NopRead, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x1c, "*NOP", Operation::NopRead, 3, 4 /*or 5*/, AddressingMode::Absolute_X),
+    OpCode::new(0x3c, "*NOP", Operation::NopRead, 3, 4 /*or 5*/, AddressingMode::Absolute_X),
+    OpCode::new(0x5c, "*NOP", Operation::NopRead, 3, 4 /*or 5*/, AddressingMode::Absolute_X),
+    OpCode::new(0x7c, "*NOP", Operation::NopRead, 3, 4 /*or 5*/, AddressingMode::Absolute_X),
+    OpCode::new(0xdc, "*NOP", Operation::NopRead, 3, 4 /* or 5*/, AddressingMode::Absolute_X),
+    OpCode::new(0xfc, "*NOP", Operation::NopRead, 3, 4 /* or 5*/, AddressingMode::Absolute_X),
+
+    OpCode::new(0x67, "*RRA", Operation::Rra, 2, 5, AddressingMode::ZeroPage),
+    OpCode::new(0x77, "*RRA", Operation::Rra, 2, 6, AddressingMode::ZeroPage_X),
+    OpCode::new(0x6f, "*RRA", Operation::Rra, 3, 6, AddressingMode::Absolute),
+    OpCode::new(0x7f, "*RRA", Operation::Rra, 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0x7b, "*RRA", Operation::Rra, 3, 7, AddressingMode::Absolute_Y),
+    OpCode::new(0x63, "*RRA", Operation::Rra, 2, 8, AddressingMode::Indirect_X),
+    OpCode::new(0x73, "*RRA", Operation::Rra, 2, 8, AddressingMode::Indirect_Y),
+
+
+    OpCode::new(0xe7, "*ISB", Operation::Isb, 2,5, AddressingMode::ZeroPage),
+    OpCode::new(0xf7, "*ISB", Operation::Isb, 2,6, AddressingMode::ZeroPage_X),
+    OpCode::new(0xef, "*ISB", Operation::Isb, 3,6, AddressingMode::Absolute),
+    OpCode::new(0xff, "*ISB", Operation::Isb, 3,7, AddressingMode::Absolute_X),
+    OpCode::new(0xfb, "*ISB", Operation::Isb, 3,7, AddressingMode::Absolute_Y),
+    OpCode::new(0xe3, "*ISB", Operation::Isb, 2,8, AddressingMode::Indirect_X),
+    OpCode::new(0xf3, "*ISB", Operation::Isb, 2,8, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x02, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x12, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x22, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x32, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x42, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x52, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x62, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x72, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x92, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0xb2, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0xd2, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0xf2, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+
+    OpCode::new(0x1a, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x3a, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x5a, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0x7a, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0xda, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+    // OpCode::new(0xea, "NOP", 1,2, AddressingMode::NoneAddressing),
+    OpCode::new(0xfa, "*NOP", Operation::Nop, 1,2, AddressingMode::NoneAddressing),
+
+    OpCode::new(0xab, "*LXA", Operation::Lxa, 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
+    //http://visual6502.org/wiki/index.php?title=6502_Opcode_8B_%28XAA,_ANE%29
+    OpCode::new(0x8b, "*XAA", Operation::Xaa, 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
+    OpCode::new(0xbb, "*LAS", Operation::Las, 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+    OpCode::new(0x9b, "*TAS", Operation::Tas, 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+    OpCode::new(0x93, "*AHX", Operation::AhxIndirectY, 2, /* guess */ 8, AddressingMode::Indirect_Y), //todo: highly unstable and not used
+    OpCode::new(0x9f, "*AHX", Operation::AhxAbsoluteY, 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+    OpCode::new(0x9e, "*SHX", Operation::Shx, 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+    OpCode::new(0x9c, "*SHY", Operation::Shy, 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_X), //todo: highly unstable and not used
+
+    OpCode::new(0xa7, "*LAX", Operation::Lax, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0xb7, "*LAX", Operation::Lax, 2, 4, AddressingMode::ZeroPage_Y),
+    OpCode::new(0xaf, "*LAX", Operation::Lax, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xbf, "*LAX", Operation::Lax, 3, 4, AddressingMode::Absolute_Y),
+    OpCode::new(0xa3, "*LAX", Operation::Lax, 2, 6, AddressingMode::Indirect_X),
+    OpCode::new(0xb3, "*LAX", Operation::Lax, 2, 5, AddressingMode::Indirect_Y),
+
+    OpCode::new(0x87, "*SAX", Operation::Sax, 2, 3, AddressingMode::ZeroPage),
+    OpCode::new(0x97, "*SAX", Operation::Sax, 2, 4, AddressingMode::ZeroPage_Y),
+    OpCode::new(0x8f, "*SAX", Operation::Sax, 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x83, "*SAX", Operation::Sax, 2, 6, AddressingMode::Indirect_X),
+];
+
+/// `CPU_OPS_CODES` indexed by opcode, decoded at compile time
+pub static OPCODES: [Option<OpCode>; 256] = decode_table(CPU_OPS_CODES);
+
+const fn decode_table(ops: &[OpCode]) -> [Option<OpCode>; 256] {
+    let mut table = [None; 256];
+    let mut i = 0;
+    while i < ops.len() {
+        table[ops[i].code as usize] = Some(ops[i]);
+        i += 1;
+    }
+    table
+}
+
+/// Also usable in constants, e.g. `const JMP: Option<&OpCode> = lookup(0x4c)`
+pub const fn lookup(code: u8) -> Option<&'static OpCode> {
+    OPCODES[code as usize].as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table_is_decoded() {
+        for (code, op) in OPCODES.iter().enumerate() {
+            assert_eq!(op.map(|op| op.code as usize), Some(code));
         }
-        map
-    };
+        let lda = lookup(0xb1).unwrap();
+        assert_eq!(lda.operation, Operation::Lda);
+        assert!(lda.page_cross_penalty);
+        assert!(!lookup(0xa5).unwrap().page_cross_penalty);
+        assert!(!lookup(0x91).unwrap().page_cross_penalty);
+
+        const JMP: Option<&OpCode> = lookup(0x6c);
+        assert_eq!(JMP.map(|op| op.operation), Some(Operation::JmpIndirect));
+    }
 }
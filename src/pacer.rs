@@ -0,0 +1,190 @@
+// Frame pacing for the frontends: runs the emulation at the console's frame
+// rate instead of as fast as the host allows, without busy-looping the host
+// CPU for the whole frame.
+//
+// Waiting is a hybrid. The pacer sleeps until shortly before the deadline,
+// then spins for the rest, since a sleep can overshoot by more than a
+// millisecond depending on the OS. How long to spin adapts to the overshoot
+// seen so far: it grows at once after a late wake-up and shrinks slowly
+// while sleeps are accurate. Deadlines are a fixed schedule, so rounding in
+// one frame doesn't drift the rate. A pacer that falls more than a frame
+// behind starts a new schedule instead of rushing through the missed frames.
+//
+// While idle, when the game is paused or the window minimized, the pacer only
+// sleeps in long steps and keeps no schedule.
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// CPU clock over the 29780.5 CPU cycles of a frame
+pub const NTSC_FRAME_RATE: f64 = 1_789_772.727 / 29_780.5;
+pub const PAL_FRAME_RATE: f64 = 1_662_607.0 / 33_247.5;
+
+const MIN_SPIN: Duration = Duration::from_micros(200);
+const MAX_SPIN: Duration = Duration::from_millis(4);
+const IDLE_WAIT: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[non_exhaustive]
+pub struct PacingStats {
+    /// Frames waited for on schedule
+    pub frames: u64,
+    /// Frames dropped from the schedule because the emulation fell behind
+    pub missed: u64,
+    /// How late frames started, on average and at worst
+    pub mean_jitter: Duration,
+    pub max_jitter: Duration,
+}
+
+pub struct FramePacer {
+    period: Duration,
+    // no deadline before the first frame and after being idle
+    next: Option<Instant>,
+    spin: Duration,
+    idle: bool,
+    stats: PacingStats,
+    total_jitter: Duration,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            period: Duration::from_secs_f64(1.0 / frame_rate),
+            next: None,
+            spin: Duration::from_millis(2),
+            idle: false,
+            stats: PacingStats::default(),
+            total_jitter: Duration::ZERO,
+        }
+    }
+
+    pub fn ntsc() -> Self {
+        FramePacer::new(NTSC_FRAME_RATE)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Paused or minimized: `wait` sleeps without a schedule until this is
+    /// cleared, then the schedule starts over
+    pub fn set_idle(&mut self, idle: bool) {
+        if idle != self.idle {
+            self.next = None;
+        }
+        self.idle = idle;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Waits until the next frame is due, to be called once per frame
+    pub fn wait(&mut self) {
+        if self.idle {
+            thread::sleep(IDLE_WAIT);
+            return;
+        }
+
+        let now = Instant::now();
+        let deadline = match self.next {
+            Some(deadline) => deadline,
+            None => {
+                self.next = Some(now + self.period);
+                return;
+            }
+        };
+        if now > deadline + self.period {
+            let late = now - deadline;
+            self.stats.missed += (late.as_nanos() / self.period.as_nanos()) as u64;
+            self.next = Some(now + self.period);
+            return;
+        }
+
+        self.sleep_until(deadline);
+        self.record(Instant::now().saturating_duration_since(deadline));
+        self.next = Some(deadline + self.period);
+    }
+
+    pub fn stats(&self) -> PacingStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = PacingStats::default();
+        self.total_jitter = Duration::ZERO;
+    }
+
+    fn sleep_until(&mut self, deadline: Instant) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining > self.spin {
+            let request = remaining - self.spin;
+            let start = Instant::now();
+            thread::sleep(request);
+            self.adapt_spin(start.elapsed().saturating_sub(request));
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn adapt_spin(&mut self, overshoot: Duration) {
+        let decayed = self.spin - self.spin / 32;
+        self.spin = (overshoot + MIN_SPIN)
+            .max(decayed)
+            .max(MIN_SPIN)
+            .min(MAX_SPIN);
+    }
+
+    fn record(&mut self, jitter: Duration) {
+        self.stats.frames += 1;
+        self.total_jitter += jitter;
+        self.stats.mean_jitter = self.total_jitter / self.stats.frames as u32;
+        self.stats.max_jitter = self.stats.max_jitter.max(jitter);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_paces_to_frame_rate() {
+        assert!((NTSC_FRAME_RATE - 60.0988).abs() < 0.0001);
+        let mut pacer = FramePacer::new(500.0);
+        pacer.wait();
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait();
+        }
+        assert!(start.elapsed() >= pacer.period() * 9);
+        assert_eq!(pacer.stats().frames, 10);
+        assert_eq!(pacer.stats().missed, 0);
+    }
+
+    #[test]
+    fn test_falling_behind_starts_a_new_schedule() {
+        let mut pacer = FramePacer::new(1000.0);
+        pacer.wait();
+        thread::sleep(Duration::from_millis(10));
+        pacer.wait();
+        assert!(pacer.stats().missed >= 8);
+
+        // no burst of frames to catch up
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= pacer.period() / 2);
+    }
+
+    #[test]
+    fn test_idle_keeps_no_schedule() {
+        let mut pacer = FramePacer::new(1000.0);
+        pacer.wait();
+        pacer.set_idle(true);
+        pacer.wait();
+        pacer.set_idle(false);
+        pacer.wait();
+        pacer.wait();
+        assert_eq!(pacer.stats().missed, 0);
+        assert_eq!(pacer.stats().frames, 1);
+    }
+}
@@ -0,0 +1,188 @@
+// Frame timing statistics, to tell where a stutter in real gameplay comes
+// from, and to spot regressions that synthetic benchmarks don't show.
+//
+// Frontends record three numbers a frame: how long emulating it took, how
+// long presenting it took and how full the audio buffer was afterwards. Only
+// the last `window` frames are kept, so the summary follows what is happening
+// right now. Times are summarized as percentiles, since a single long frame
+// in a second of short ones is what a stutter looks like, and the mean hides
+// it. For the audio buffer the low end matters: an empty buffer is a crackle.
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+/// About 10 seconds at 60 frames per second
+pub const DEFAULT_WINDOW: usize = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles<T> {
+    pub min: T,
+    pub p5: T,
+    pub p50: T,
+    pub p95: T,
+    pub p99: T,
+    pub max: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameTimings {
+    window: usize,
+    emulation: VecDeque<Duration>,
+    present: VecDeque<Duration>,
+    /// Between 0 (empty) and 1 (full)
+    audio_fill: VecDeque<f32>,
+}
+
+impl FrameTimings {
+    /// Keeps the last `window` frames
+    pub fn new(window: usize) -> Self {
+        FrameTimings {
+            window: window.max(1),
+            emulation: VecDeque::new(),
+            present: VecDeque::new(),
+            audio_fill: VecDeque::new(),
+        }
+    }
+
+    pub fn record_emulation(&mut self, time: Duration) {
+        push(&mut self.emulation, time, self.window);
+    }
+
+    pub fn record_present(&mut self, time: Duration) {
+        push(&mut self.present, time, self.window);
+    }
+
+    /// `queued` samples in an audio buffer that holds `capacity`
+    pub fn record_audio_fill(&mut self, queued: usize, capacity: usize) {
+        let fill = if capacity == 0 {
+            0.0
+        } else {
+            queued.min(capacity) as f32 / capacity as f32
+        };
+        push(&mut self.audio_fill, fill, self.window);
+    }
+
+    /// None until a frame was recorded
+    pub fn emulation(&self) -> Option<Percentiles<Duration>> {
+        percentiles(&self.emulation, |a, b| a.cmp(b))
+    }
+
+    pub fn present(&self) -> Option<Percentiles<Duration>> {
+        percentiles(&self.present, |a, b| a.cmp(b))
+    }
+
+    pub fn audio_fill(&self) -> Option<Percentiles<f32>> {
+        percentiles(&self.audio_fill, |a, b| a.total_cmp(b))
+    }
+
+    pub fn clear(&mut self) {
+        self.emulation.clear();
+        self.present.clear();
+        self.audio_fill.clear();
+    }
+}
+
+impl Default for FrameTimings {
+    fn default() -> Self {
+        FrameTimings::new(DEFAULT_WINDOW)
+    }
+}
+
+fn push<T>(samples: &mut VecDeque<T>, sample: T, window: usize) {
+    if samples.len() == window {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Nearest rank percentiles
+fn percentiles<T: Copy>(
+    samples: &VecDeque<T>,
+    cmp: impl FnMut(&T, &T) -> std::cmp::Ordering,
+) -> Option<Percentiles<T>> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<T> = samples.iter().copied().collect();
+    sorted.sort_by(cmp);
+    let rank = |percent: usize| sorted[(sorted.len() * percent).div_ceil(100).max(1) - 1];
+    Some(Percentiles {
+        min: sorted[0],
+        p5: rank(5),
+        p50: rank(50),
+        p95: rank(95),
+        p99: rank(99),
+        max: sorted[sorted.len() - 1],
+    })
+}
+
+/// A short line per number, to fit the performance HUD of the OSD. Times
+/// are the 50th and 99th percentile and the maximum
+impl fmt::Display for FrameTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+        let times = [("EMU", self.emulation()), ("PRESENT", self.present())];
+        for (name, time) in times.iter() {
+            if let Some(time) = time {
+                writeln!(
+                    f,
+                    "{:7} {:4.1} {:4.1} {:4.1} MS",
+                    name,
+                    ms(time.p50),
+                    ms(time.p99),
+                    ms(time.max)
+                )?;
+            }
+        }
+        if let Some(fill) = self.audio_fill() {
+            writeln!(
+                f,
+                "AUDIO   MIN {:3.0}% P5 {:3.0}%",
+                fill.min * 100.0,
+                fill.p5 * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rolling_percentiles() {
+        let mut timings = FrameTimings::new(100);
+        assert!(timings.emulation().is_none());
+        assert_eq!(timings.to_string(), "");
+
+        // a single slow frame in the window only shows as the maximum
+        for i in 0..150u64 {
+            let ms = if i == 120 { 30 } else { i % 10 };
+            timings.record_emulation(Duration::from_millis(ms));
+        }
+        let emulation = timings.emulation().unwrap();
+        assert_eq!(emulation.min, Duration::ZERO);
+        assert_eq!(emulation.p50, Duration::from_millis(5));
+        assert_eq!(emulation.p95, Duration::from_millis(9));
+        assert_eq!(emulation.p99, Duration::from_millis(9));
+        assert_eq!(emulation.max, Duration::from_millis(30));
+
+        // the window rolls on past it
+        for _ in 0..100 {
+            timings.record_emulation(Duration::from_millis(2));
+        }
+        assert_eq!(timings.emulation().unwrap().max, Duration::from_millis(2));
+
+        timings.record_audio_fill(256, 1024);
+        timings.record_audio_fill(4096, 1024);
+        let fill = timings.audio_fill().unwrap();
+        assert_eq!((fill.min, fill.max), (0.25, 1.0));
+        assert_eq!(
+            timings.to_string(),
+            "EMU      2.0  2.0  2.0 MS\nAUDIO   MIN  25% P5  25%\n"
+        );
+        timings.clear();
+        assert!(timings.audio_fill().is_none());
+    }
+}
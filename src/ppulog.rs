@@ -0,0 +1,162 @@
+// Log of CPU writes to the PPU registers ($2000-$2007 and OAM DMA at $4014)
+// stamped with the frame, scanline and dot they landed on. Much cheaper than a
+// full trace when chasing scroll splits or writes outside of vblank.
+use alloc::collections::VecDeque;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+/// Entries kept when no capacity is given, a few frames worth for most games
+pub const DEFAULT_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpuWrite {
+    pub frame: usize,
+    pub scanline: u16,
+    pub dot: usize,
+    /// CPU cycles since power on
+    pub cycle: usize,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for PpuWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {:<6} line {:>3} dot {:>3}  {:<9} ${:04X} = ${:02X}",
+            self.frame,
+            self.scanline,
+            self.dot,
+            register_name(self.addr),
+            self.addr,
+            self.value
+        )
+    }
+}
+
+/// The most recent writes, the oldest ones are dropped once it's full
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpuWriteLog {
+    writes: VecDeque<PpuWrite>,
+    capacity: usize,
+}
+
+impl PpuWriteLog {
+    pub fn new(capacity: usize) -> Self {
+        PpuWriteLog {
+            writes: VecDeque::with_capacity(capacity.min(DEFAULT_CAPACITY)),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, write: PpuWrite) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.writes.len() == self.capacity {
+            self.writes.pop_front();
+        }
+        self.writes.push_back(write);
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Oldest first
+    pub fn writes(&self) -> impl Iterator<Item = &PpuWrite> {
+        self.writes.iter()
+    }
+
+    /// Writes made during the given frame
+    pub fn frame(&self, frame: usize) -> impl Iterator<Item = &PpuWrite> {
+        self.writes.iter().filter(move |write| write.frame == frame)
+    }
+
+    /// Writes to one register, e.g. all of the $2005 writes for scroll splits
+    pub fn register(&self, addr: u16) -> impl Iterator<Item = &PpuWrite> {
+        self.writes.iter().filter(move |write| write.addr == addr)
+    }
+
+    /// Dumps the log as text, one write per line
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for write in &self.writes {
+            writeln!(out, "{}", write)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for PpuWriteLog {
+    fn default() -> Self {
+        PpuWriteLog::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// Whether a write to this address is logged, mirrors are logged at the
+/// canonical address
+pub fn is_ppu_register(addr: u16) -> bool {
+    matches!(addr, 0x2000..=0x2007 | 0x4014)
+}
+
+pub fn register_name(addr: u16) -> &'static str {
+    match addr {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        0x4014 => "OAMDMA",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(frame: usize, addr: u16, value: u8) -> PpuWrite {
+        PpuWrite {
+            frame,
+            scanline: 241,
+            dot: 10,
+            cycle: 0,
+            addr,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_log_keeps_latest_writes() {
+        let mut log = PpuWriteLog::new(3);
+        log.push(write(1, 0x2000, 0x80));
+        log.push(write(1, 0x2005, 0x10));
+        log.push(write(2, 0x2005, 0x20));
+        log.push(write(2, 0x2006, 0x3f));
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.writes().next(), Some(&write(1, 0x2005, 0x10)));
+        assert_eq!(log.frame(2).count(), 2);
+        assert_eq!(log.register(0x2005).count(), 2);
+
+        let mut out = vec![];
+        log.write_to(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text.lines().last(),
+            Some("frame 2      line 241 dot  10  PPUADDR   $2006 = $3F")
+        );
+    }
+}
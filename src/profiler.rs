@@ -0,0 +1,206 @@
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use crate::labels::Labels;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProfileEntry {
+    /// Subroutine or interrupt handler entry point, None for code running
+    /// outside of any call such as the main loop after reset
+    pub addr: Option<u16>,
+    pub calls: usize,
+    /// Cycles spent in the routine itself
+    pub exclusive_cycles: usize,
+    /// Cycles spent in the routine and everything it called
+    pub inclusive_cycles: usize,
+}
+
+/// Attributes CPU cycles to subroutines using the CPU's call stack tracking
+pub struct Profiler {
+    entries: HashMap<Option<u16>, ProfileEntry>,
+    running: bool,
+    last_cycles: usize,
+    /// Call stack targets as of the previous sample, outermost first
+    last_stack: Vec<u16>,
+    total_cycles: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            entries: HashMap::new(),
+            running: false,
+            last_cycles: 0,
+            last_stack: Vec::new(),
+            total_cycles: 0,
+        }
+    }
+
+    /// Starts a capture window, results from earlier windows are kept
+    pub fn start(&mut self, cpu: &CPU) {
+        self.running = true;
+        self.last_cycles = cpu.bus.cycles();
+        self.last_stack.clear();
+        self.last_stack
+            .extend(cpu.call_stack().iter().map(|frame| frame.target));
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.total_cycles = 0;
+    }
+
+    pub fn total_cycles(&self) -> usize {
+        self.total_cycles
+    }
+
+    /// To be called before every instruction. The cycles since the previous
+    /// call go to the routines that were on the call stack back then
+    pub fn sample(&mut self, cpu: &CPU) {
+        if !self.running {
+            return;
+        }
+        let cycles = cpu.bus.cycles();
+        let elapsed = cycles - self.last_cycles;
+        self.last_cycles = cycles;
+        self.total_cycles += elapsed;
+
+        let innermost = self.last_stack.last().copied();
+        self.entry(innermost).exclusive_cycles += elapsed;
+        self.entry(None).inclusive_cycles += elapsed;
+        for i in 0..self.last_stack.len() {
+            let addr = self.last_stack[i];
+            // recursive calls only count once
+            if !self.last_stack[..i].contains(&addr) {
+                self.entry(Some(addr)).inclusive_cycles += elapsed;
+            }
+        }
+
+        let stack = cpu.call_stack();
+        let common = self
+            .last_stack
+            .iter()
+            .zip(stack)
+            .take_while(|(&addr, frame)| addr == frame.target)
+            .count();
+        for frame in &stack[common..] {
+            self.entry(Some(frame.target)).calls += 1;
+        }
+        self.last_stack.truncate(common);
+        self.last_stack
+            .extend(stack[common..].iter().map(|frame| frame.target));
+    }
+
+    /// Entries sorted by exclusive cycles, the hottest first
+    pub fn report(&self) -> Vec<ProfileEntry> {
+        let mut entries: Vec<ProfileEntry> = self.entries.values().copied().collect();
+        entries.sort_by(|a, b| {
+            b.exclusive_cycles
+                .cmp(&a.exclusive_cycles)
+                .then(a.addr.cmp(&b.addr))
+        });
+        entries
+    }
+
+    /// Report as a text table, routines named by their labels where there are any
+    pub fn format_report(&self, labels: &Labels, bus: &Bus) -> String {
+        let total = self.total_cycles.max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<24} {:>8} {:>12} {:>6} {:>12} {:>6}",
+            "ROUTINE", "CALLS", "SELF", "%", "TOTAL", "%"
+        );
+        for entry in self.report() {
+            let name = match entry.addr {
+                Some(addr) => match labels.get(bus, addr) {
+                    Some(name) => name.to_string(),
+                    None => format!("${:04X}", addr),
+                },
+                None => "<top level>".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{:<24} {:>8} {:>12} {:>6.2} {:>12} {:>6.2}",
+                name,
+                entry.calls,
+                entry.exclusive_cycles,
+                entry.exclusive_cycles as f64 * 100.0 / total,
+                entry.inclusive_cycles,
+                entry.inclusive_cycles as f64 * 100.0 / total
+            );
+        }
+        out
+    }
+
+    fn entry(&mut self, addr: Option<u16>) -> &mut ProfileEntry {
+        self.entries.entry(addr).or_insert(ProfileEntry {
+            addr,
+            ..ProfileEntry::default()
+        })
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_cycles_per_subroutine() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: JSR outer; JMP loop
+        // outer: JSR inner; NOP; RTS
+        // inner: NOP; RTS
+        cpu.load(vec![
+            0x20, 0x06, 0x06, 0x4c, 0x00, 0x06, 0x20, 0x0b, 0x06, 0xea, 0x60, 0xea, 0x60,
+        ]);
+        cpu.program_counter = 0x0600;
+
+        let mut profiler = Profiler::new();
+        profiler.start(&cpu);
+        // two iterations of the loop
+        for _ in 0..14 {
+            profiler.sample(&cpu);
+            cpu.step();
+        }
+        profiler.sample(&cpu);
+        profiler.stop();
+
+        let report = profiler.report();
+        let entry = |addr| *report.iter().find(|e| e.addr == addr).unwrap();
+        let top = entry(None);
+        let outer = entry(Some(0x0606));
+        let inner = entry(Some(0x060b));
+        // JSR 6 + JMP 3 at the top level
+        assert_eq!(top.exclusive_cycles, 2 * 9);
+        // JSR 6 + NOP 2 + RTS 6
+        assert_eq!(outer.exclusive_cycles, 2 * 14);
+        // NOP 2 + RTS 6
+        assert_eq!(inner.exclusive_cycles, 2 * 8);
+        assert_eq!(outer.inclusive_cycles, 2 * 22);
+        assert_eq!(top.inclusive_cycles, profiler.total_cycles());
+        assert_eq!((outer.calls, inner.calls), (2, 2));
+        assert_eq!(report[0].addr, Some(0x0606));
+
+        let mut labels = Labels::new();
+        labels.add(0x060b, "inner");
+        let text = profiler.format_report(&labels, &cpu.bus);
+        assert!(text.lines().nth(3).unwrap().starts_with("inner"));
+    }
+}
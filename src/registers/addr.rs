@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct AddrRegister {
     value: (u8, u8),
     hi_ptr: bool,
@@ -44,6 +45,17 @@ impl AddrRegister {
         self.hi_ptr = true;
     }
 
+    /// Whether the next write sets the high byte
+    pub fn expects_high_byte(&self) -> bool {
+        self.hi_ptr
+    }
+
+    /// Puts the register back into a saved state
+    pub fn restore(&mut self, value: u16, hi_ptr: bool) {
+        self.set(value);
+        self.hi_ptr = hi_ptr;
+    }
+
     pub fn get(&self) -> u16 {
         ((self.value.0 as u16) << 8) | (self.value.1 as u16)
     }
@@ -44,6 +44,36 @@ bitflags! {
     pub fn generate_vblank_nmi(&self) -> bool {
         return self.contains(ControlRegister::GENERATE_NMI);
     }
+
+    /// The nametable scrolling starts from, 0 to 3 for $2000 to $2C00
+    pub fn base_nametable(&self) -> usize {
+        (self.bits & 0b11) as usize
+    }
+
+    pub fn sprite_pattern_addr(&self) -> u16 {
+        if !self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+            0
+        } else {
+            0x1000
+        }
+    }
+
+    pub fn background_pattern_addr(&self) -> u16 {
+        if !self.contains(ControlRegister::BACKROUND_PATTERN_ADDR) {
+            0
+        } else {
+            0x1000
+        }
+    }
+
+    /// Sprite height in pixels, 8 or 16
+    pub fn sprite_size(&self) -> usize {
+        if !self.contains(ControlRegister::SPRITE_SIZE) {
+            8
+        } else {
+            16
+        }
+    }
  
     pub fn update(&mut self, data: u8) {
         self.bits = data;
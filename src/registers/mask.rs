@@ -1,3 +1,5 @@
+use crate::prelude::*;
+
 bitflags! {
 
     // 7  bit  0
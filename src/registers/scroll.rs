@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct ScrollRegister {
     pub scroll_x: u8,
     pub scroll_y: u8,
@@ -0,0 +1,188 @@
+// Palette index to RGB conversion, the last step of every frame.
+//
+// The PPU produces one 6-bit palette index per pixel and the frontends want
+// 0x00RRGGBB pixels, so every frame goes through 61440 table lookups. The
+// scalar loop does one lookup per pixel. With the `simd` feature, x86_64 CPUs
+// that have SSSE3 convert 16 pixels at a time instead: the 64 entries of the
+// table are split into four 16-byte tables per color channel, which is what
+// one pshufb can look up in, and the results for the four quarters are blended
+// by the top two bits of the index. Other targets, and CPUs without SSSE3,
+// use the scalar loop, which gives the same result.
+use super::filter::ColorFilter;
+use super::palette::SYSTEM_PALLETE;
+
+#[derive(Clone)]
+pub struct PaletteConverter {
+    // 0x00RRGGBB, the layout of `Frame`
+    table: [u32; 64],
+    // [channel][quarter of the table][index within the quarter], channels in
+    // the order the bytes of a little endian 0x00RRGGBB are stored: B, G, R
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    channels: [[[u8; 16]; 4]; 3],
+}
+
+impl PaletteConverter {
+    pub fn new(palette: &[(u8, u8, u8); 64]) -> Self {
+        let mut table = [0; 64];
+        for (rgb, &(r, g, b)) in table.iter_mut().zip(palette.iter()) {
+            *rgb = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        }
+
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        let channels = {
+            let mut channels = [[[0; 16]; 4]; 3];
+            for (index, &(r, g, b)) in palette.iter().enumerate() {
+                for (channel, value) in channels.iter_mut().zip([b, g, r].iter()) {
+                    channel[index / 16][index % 16] = *value;
+                }
+            }
+            channels
+        };
+
+        PaletteConverter {
+            table,
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            channels,
+        }
+    }
+
+    /// Converts to the palette's colors as `filter` changes them
+    pub fn with_filter(palette: &[(u8, u8, u8); 64], filter: ColorFilter) -> Self {
+        let mut filtered = *palette;
+        for rgb in filtered.iter_mut() {
+            *rgb = filter.apply(*rgb);
+        }
+        PaletteConverter::new(&filtered)
+    }
+
+    /// The color of a palette index, bits above the low 6 are ignored
+    pub fn rgb(&self, index: u8) -> u32 {
+        self.table[(index & 0x3F) as usize]
+    }
+
+    /// Converts palette indices into pixels, as many as the shorter of the two
+    /// slices holds
+    pub fn convert(&self, indices: &[u8], out: &mut [u32]) {
+        let len = indices.len().min(out.len());
+        let (indices, out) = (&indices[..len], &mut out[..len]);
+
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        {
+            if has_ssse3() {
+                let simd_len = len - len % 16;
+                // SAFETY: the CPU supports SSSE3, checked just above
+                unsafe { self.convert_ssse3(&indices[..simd_len], &mut out[..simd_len]) };
+                self.convert_scalar(&indices[simd_len..], &mut out[simd_len..]);
+                return;
+            }
+        }
+
+        self.convert_scalar(indices, out);
+    }
+
+    fn convert_scalar(&self, indices: &[u8], out: &mut [u32]) {
+        for (pixel, &index) in out.iter_mut().zip(indices.iter()) {
+            *pixel = self.rgb(index);
+        }
+    }
+
+    /// `indices` and `out` are the same length, a multiple of 16
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn convert_ssse3(&self, indices: &[u8], out: &mut [u32]) {
+        use core::arch::x86_64::*;
+
+        let mut tables = [[_mm_setzero_si128(); 4]; 3];
+        for (tables, channel) in tables.iter_mut().zip(self.channels.iter()) {
+            for (table, quarter) in tables.iter_mut().zip(channel.iter()) {
+                *table = _mm_loadu_si128(quarter.as_ptr() as *const __m128i);
+            }
+        }
+        let zero = _mm_setzero_si128();
+
+        for (indices, out) in indices.chunks_exact(16).zip(out.chunks_exact_mut(16)) {
+            let index = _mm_loadu_si128(indices.as_ptr() as *const __m128i);
+            // pshufb only looks at the low 4 bits while bit 7 is clear
+            let low = _mm_and_si128(index, _mm_set1_epi8(0x0F));
+            let quarter = _mm_and_si128(_mm_srli_epi16(index, 4), _mm_set1_epi8(0x03));
+            let selected = [
+                _mm_cmpeq_epi8(quarter, _mm_set1_epi8(0)),
+                _mm_cmpeq_epi8(quarter, _mm_set1_epi8(1)),
+                _mm_cmpeq_epi8(quarter, _mm_set1_epi8(2)),
+                _mm_cmpeq_epi8(quarter, _mm_set1_epi8(3)),
+            ];
+
+            let mut channels = [zero; 3];
+            for (channel, tables) in channels.iter_mut().zip(tables.iter()) {
+                for (table, selected) in tables.iter().zip(selected.iter()) {
+                    let values = _mm_and_si128(_mm_shuffle_epi8(*table, low), *selected);
+                    *channel = _mm_or_si128(*channel, values);
+                }
+            }
+            let [b, g, r] = channels;
+
+            // interleave into B G R 0 byte quadruples, 0x00RRGGBB in memory
+            let bg_lo = _mm_unpacklo_epi8(b, g);
+            let bg_hi = _mm_unpackhi_epi8(b, g);
+            let r0_lo = _mm_unpacklo_epi8(r, zero);
+            let r0_hi = _mm_unpackhi_epi8(r, zero);
+            let pixels = [
+                _mm_unpacklo_epi16(bg_lo, r0_lo),
+                _mm_unpackhi_epi16(bg_lo, r0_lo),
+                _mm_unpacklo_epi16(bg_hi, r0_hi),
+                _mm_unpackhi_epi16(bg_hi, r0_hi),
+            ];
+            for (chunk, pixels) in out.chunks_exact_mut(4).zip(pixels.iter()) {
+                _mm_storeu_si128(chunk.as_mut_ptr() as *mut __m128i, *pixels);
+            }
+        }
+    }
+}
+
+impl Default for PaletteConverter {
+    fn default() -> Self {
+        PaletteConverter::new(&SYSTEM_PALLETE)
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", feature = "std"))]
+fn has_ssse3() -> bool {
+    std::is_x86_feature_detected!("ssse3")
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", not(feature = "std")))]
+fn has_ssse3() -> bool {
+    cfg!(target_feature = "ssse3")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_convert_matches_palette() {
+        let converter = PaletteConverter::default();
+        // not a multiple of 16, so the SIMD path leaves a tail
+        let indices: Vec<u8> = (0..=255).chain(0..37).collect();
+        let mut out = vec![0; indices.len()];
+        converter.convert(&indices, &mut out);
+
+        for (&index, &pixel) in indices.iter().zip(out.iter()) {
+            let (r, g, b) = SYSTEM_PALLETE[(index & 0x3F) as usize];
+            let expected = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+            assert_eq!(pixel, expected, "index {:02X}", index);
+        }
+    }
+
+    #[test]
+    fn test_filter_applies_to_every_color() {
+        let filter = ColorFilter::HighContrast;
+        let converter = PaletteConverter::with_filter(&SYSTEM_PALLETE, filter);
+        let mut out = [0; 64];
+        converter.convert(&(0..64).collect::<Vec<u8>>(), &mut out);
+        for (&pixel, &rgb) in out.iter().zip(SYSTEM_PALLETE.iter()) {
+            let (r, g, b) = filter.apply(rgb);
+            assert_eq!(pixel, (r as u32) << 16 | (g as u32) << 8 | b as u32);
+        }
+    }
+}
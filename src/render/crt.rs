@@ -0,0 +1,222 @@
+// CRT-style post-processing applied to an RGB24 frame buffer.
+//
+// The filter works in three passes over the picture:
+//  * phosphor - the previous output fades out instead of disappearing and
+//               bright pixels slightly bleed into their horizontal neighbours
+//  * scanlines - the source picture is scaled up by an integer factor and the
+//               last output row of every source line is darkened
+//  * curvature - barrel distortion, pixels mapped outside of the tube are black
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrtSettings {
+    /// 0.0 - no scanlines, 1.0 - gaps between lines are completely black
+    pub scanlines: f32,
+    /// 0.0 - flat screen, 1.0 - strongly curved tube
+    pub curvature: f32,
+    /// 0.0 - no persistence/glow, 1.0 - maximum persistence/glow
+    pub phosphor: f32,
+}
+
+impl CrtSettings {
+    pub fn off() -> Self {
+        CrtSettings {
+            scanlines: 0.0,
+            curvature: 0.0,
+            phosphor: 0.0,
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.scanlines <= 0.0 && self.curvature <= 0.0 && self.phosphor <= 0.0
+    }
+}
+
+impl Default for CrtSettings {
+    fn default() -> Self {
+        CrtSettings {
+            scanlines: 0.5,
+            curvature: 0.3,
+            phosphor: 0.4,
+        }
+    }
+}
+
+const MAX_CURVATURE: f32 = 0.25;
+const MAX_GLOW: f32 = 0.25;
+
+pub struct CrtFilter {
+    pub settings: CrtSettings,
+    width: usize,
+    height: usize,
+    scale: usize,
+    phosphor: Vec<f32>,
+}
+
+impl CrtFilter {
+    pub fn new(width: usize, height: usize, scale: usize, settings: CrtSettings) -> Self {
+        assert!(scale > 0, "scale factor should be positive");
+        CrtFilter {
+            settings,
+            width,
+            height,
+            scale,
+            phosphor: vec![0.0; width * height * 3],
+        }
+    }
+
+    /// Size of the picture produced by [`CrtFilter::apply`]
+    pub fn output_size(&self) -> (usize, usize) {
+        (self.width * self.scale, self.height * self.scale)
+    }
+
+    /// Forgets the phosphor persistence, e.g. after loading a different game
+    pub fn reset(&mut self) {
+        for v in self.phosphor.iter_mut() {
+            *v = 0.0;
+        }
+    }
+
+    pub fn apply(&mut self, src: &[u8], dst: &mut [u8]) {
+        let (out_width, out_height) = self.output_size();
+        assert_eq!(src.len(), self.width * self.height * 3, "unexpected source size");
+        assert_eq!(dst.len(), out_width * out_height * 3, "unexpected output size");
+
+        self.update_phosphor(src);
+
+        let scanlines = self.settings.scanlines.clamp(0.0, 1.0);
+        let curvature = self.settings.curvature.clamp(0.0, 1.0) * MAX_CURVATURE;
+
+        for oy in 0..out_height {
+            for ox in 0..out_width {
+                let out_idx = (oy * out_width + ox) * 3;
+                let (sx, sy) = match self.warp(ox, oy, curvature) {
+                    Some(pos) => pos,
+                    None => {
+                        dst[out_idx] = 0;
+                        dst[out_idx + 1] = 0;
+                        dst[out_idx + 2] = 0;
+                        continue;
+                    }
+                };
+
+                let src_idx = ((sy / self.scale) * self.width + sx / self.scale) * 3;
+                let is_gap = if self.scale == 1 {
+                    sy % 2 == 1
+                } else {
+                    sy % self.scale == self.scale - 1
+                };
+                let brightness = if is_gap { 1.0 - scanlines } else { 1.0 };
+
+                for c in 0..3 {
+                    dst[out_idx + c] = (self.phosphor[src_idx + c] * brightness).round() as u8;
+                }
+            }
+        }
+    }
+
+    fn update_phosphor(&mut self, src: &[u8]) {
+        let persistence = self.settings.phosphor.clamp(0.0, 1.0);
+        let glow = persistence * MAX_GLOW;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let idx = (y * self.width + x) * 3;
+
+                for c in 0..3 {
+                    let left = if x > 0 { src[idx - 3 + c] as f32 } else { 0.0 };
+                    let right = if x + 1 < self.width { src[idx + 3 + c] as f32 } else { 0.0 };
+                    let bleed = (left + right) / 2.0;
+                    let lit = src[idx + c] as f32 + bleed * glow;
+                    let faded = self.phosphor[idx + c] * persistence;
+                    self.phosphor[idx + c] = lit.max(faded).min(255.0);
+                }
+            }
+        }
+    }
+
+    fn warp(&self, ox: usize, oy: usize, curvature: f32) -> Option<(usize, usize)> {
+        if curvature == 0.0 {
+            return Some((ox, oy));
+        }
+        let (out_width, out_height) = self.output_size();
+        let half_w = out_width as f32 / 2.0;
+        let half_h = out_height as f32 / 2.0;
+
+        // normalized coordinates in [-1.0, 1.0]
+        let nx = (ox as f32 + 0.5 - half_w) / half_w;
+        let ny = (oy as f32 + 0.5 - half_h) / half_h;
+
+        let wx = nx * (1.0 + curvature * ny * ny);
+        let wy = ny * (1.0 + curvature * nx * nx);
+        if wx.abs() > 1.0 || wy.abs() > 1.0 {
+            return None;
+        }
+
+        let sx = (wx * half_w + half_w) as usize;
+        let sy = (wy * half_h + half_h) as usize;
+        Some((sx.min(out_width - 1), sy.min(out_height - 1)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn solid(width: usize, height: usize, value: u8) -> Vec<u8> {
+        vec![value; width * height * 3]
+    }
+
+    #[test]
+    fn test_disabled_filter_is_identity() {
+        let mut filter = CrtFilter::new(4, 4, 1, CrtSettings::off());
+        let src: Vec<u8> = (0..4 * 4 * 3).map(|v| v as u8).collect();
+        let mut dst = vec![0; src.len()];
+        filter.apply(&src, &mut dst);
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_scanlines_darken_gap_rows() {
+        let settings = CrtSettings {
+            scanlines: 1.0,
+            ..CrtSettings::off()
+        };
+        let mut filter = CrtFilter::new(2, 2, 2, settings);
+        let mut dst = vec![0; 4 * 4 * 3];
+        filter.apply(&solid(2, 2, 200), &mut dst);
+
+        let row = |y: usize| &dst[y * 4 * 3..(y + 1) * 4 * 3];
+        assert!(row(0).iter().all(|&v| v == 200));
+        assert!(row(1).iter().all(|&v| v == 0));
+        assert!(row(2).iter().all(|&v| v == 200));
+        assert!(row(3).iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn test_curvature_blanks_corners() {
+        let settings = CrtSettings {
+            curvature: 1.0,
+            ..CrtSettings::off()
+        };
+        let mut filter = CrtFilter::new(32, 32, 1, settings);
+        let mut dst = vec![0; 32 * 32 * 3];
+        filter.apply(&solid(32, 32, 255), &mut dst);
+
+        assert_eq!(&dst[0..3], &[0, 0, 0]);
+        let center = (16 * 32 + 16) * 3;
+        assert_eq!(&dst[center..center + 3], &[255, 255, 255]);
+    }
+
+    #[test]
+    fn test_phosphor_persists_previous_frame() {
+        let settings = CrtSettings {
+            phosphor: 0.5,
+            ..CrtSettings::off()
+        };
+        let mut filter = CrtFilter::new(1, 1, 1, settings);
+        let mut dst = vec![0; 3];
+        filter.apply(&[200, 200, 200], &mut dst);
+        filter.apply(&[0, 0, 0], &mut dst);
+        assert_eq!(dst, vec![100, 100, 100]);
+    }
+}
@@ -0,0 +1,212 @@
+// Which parts of the frame changed since the last one was presented.
+//
+// Most frames of most games only change a few tiles: a score counter, a
+// sprite walking across a still background. `DirtyTracker` compares each
+// finished frame with the previous one, 8x8 pixels at a time to match the
+// NES tile grid, and merges the changed tiles into rectangles. A frontend
+// that keeps its own copy of the screen, a streaming texture or a terminal,
+// then only has to upload or redraw those rectangles.
+//
+// Comparing is cheaper than it sounds: it reads the frame once, which the
+// frontend would otherwise do to upload all of it.
+use super::frame::{HEIGHT, WIDTH};
+use crate::prelude::*;
+
+pub const TILE_SIZE: usize = 8;
+const COLUMNS: usize = WIDTH / TILE_SIZE;
+const ROWS: usize = HEIGHT / TILE_SIZE;
+
+/// An area of the frame in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+pub struct DirtyTracker {
+    previous: Vec<u32>,
+    // bit n of a row is the tile in column n
+    dirty: [u32; ROWS],
+    rects: Vec<Rect>,
+    // the frontend has nothing to compare against yet
+    invalidated: bool,
+}
+
+impl DirtyTracker {
+    pub fn new() -> Self {
+        DirtyTracker {
+            previous: vec![0; WIDTH * HEIGHT],
+            dirty: [0; ROWS],
+            rects: Vec::new(),
+            invalidated: true,
+        }
+    }
+
+    /// Compares a finished frame with the one passed last time and returns
+    /// the areas that differ. The first frame, and the first after
+    /// `invalidate`, is dirty as a whole
+    pub fn update(&mut self, pixels: &[u32]) -> &[Rect] {
+        assert_eq!(pixels.len(), WIDTH * HEIGHT, "not a whole frame");
+        for (row, dirty) in self.dirty.iter_mut().enumerate() {
+            *dirty = 0;
+            let rows = row * TILE_SIZE * WIDTH..(row + 1) * TILE_SIZE * WIDTH;
+            let (new, old) = (&pixels[rows.clone()], &mut self.previous[rows]);
+            for (new, old) in new.chunks_exact(WIDTH).zip(old.chunks_exact_mut(WIDTH)) {
+                for (column, (new, old)) in new
+                    .chunks_exact(TILE_SIZE)
+                    .zip(old.chunks_exact(TILE_SIZE))
+                    .enumerate()
+                {
+                    if new != old {
+                        *dirty |= 1 << column;
+                    }
+                }
+                old.copy_from_slice(new);
+            }
+        }
+        if self.invalidated {
+            self.dirty = [u32::MAX; ROWS];
+            self.invalidated = false;
+        }
+
+        self.merge_rects();
+        &self.rects
+    }
+
+    /// Makes the next frame dirty as a whole, for when the frontend lost what
+    /// it presented, e.g. after a resize or a palette change
+    pub fn invalidate(&mut self) {
+        self.invalidated = true;
+    }
+
+    /// Whether the tile at `column`, `row` changed in the last `update`
+    pub fn is_tile_dirty(&self, column: usize, row: usize) -> bool {
+        self.dirty[row] & (1 << column) != 0
+    }
+
+    /// Scanlines from the first to the last one with a change, empty if
+    /// nothing changed. For frontends that redraw whole lines
+    pub fn dirty_scanlines(&self) -> core::ops::Range<usize> {
+        let first = self.dirty.iter().position(|&row| row != 0);
+        let last = self.dirty.iter().rposition(|&row| row != 0);
+        match (first, last) {
+            (Some(first), Some(last)) => first * TILE_SIZE..(last + 1) * TILE_SIZE,
+            _ => 0..0,
+        }
+    }
+
+    /// Runs of dirty tiles in a row, extended downwards while the rows below
+    /// have the same run
+    fn merge_rects(&mut self) {
+        self.rects.clear();
+        // rects reaching down to the current row, left to right. A row has at
+        // most one run for every other column
+        let mut open = [0; COLUMNS / 2];
+        let mut open_len = 0;
+        for (row, &dirty) in self.dirty.iter().enumerate() {
+            let mut next = [0; COLUMNS / 2];
+            let mut next_len = 0;
+            let mut above = 0;
+            let mut bits = dirty as u64;
+            while bits != 0 {
+                let start = bits.trailing_zeros() as usize;
+                let len = (!(bits >> start)).trailing_zeros() as usize;
+                bits &= !(((1 << len) - 1) << start);
+                let (x, width) = (start * TILE_SIZE, len * TILE_SIZE);
+
+                while above < open_len && self.rects[open[above]].x < x {
+                    above += 1;
+                }
+                let index = match open[..open_len].get(above) {
+                    Some(&index)
+                        if self.rects[index].width == width && self.rects[index].x == x =>
+                    {
+                        self.rects[index].height += TILE_SIZE;
+                        index
+                    }
+                    _ => {
+                        self.rects.push(Rect {
+                            x,
+                            y: row * TILE_SIZE,
+                            width,
+                            height: TILE_SIZE,
+                        });
+                        self.rects.len() - 1
+                    }
+                };
+                next[next_len] = index;
+                next_len += 1;
+            }
+            open = next;
+            open_len = next_len;
+        }
+    }
+}
+
+impl Default for DirtyTracker {
+    fn default() -> Self {
+        DirtyTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::frame::Frame;
+
+    #[test]
+    fn test_only_changed_tiles_are_dirty() {
+        let mut tracker = DirtyTracker::new();
+        let mut frame = Frame::new();
+        assert_eq!(
+            tracker.update(frame.pixels()),
+            [Rect {
+                x: 0,
+                y: 0,
+                width: WIDTH,
+                height: HEIGHT
+            }]
+        );
+        assert_eq!(tracker.update(frame.pixels()), []);
+        assert_eq!(tracker.dirty_scanlines(), 0..0);
+
+        // a two tile wide score counter, and a 16x16 sprite under a tile that
+        // changed too
+        frame.set_pixel(200, 8, (0xFF, 0xFF, 0xFF));
+        frame.set_pixel(215, 12, (0xFF, 0xFF, 0xFF));
+        for (x, y) in [(40, 100), (40, 104), (55, 104), (40, 119), (55, 119)].iter() {
+            frame.set_pixel(*x, *y, (0x10, 0x20, 0x30));
+        }
+        assert_eq!(
+            tracker.update(frame.pixels()),
+            [
+                Rect {
+                    x: 200,
+                    y: 8,
+                    width: 16,
+                    height: 8
+                },
+                Rect {
+                    x: 40,
+                    y: 96,
+                    width: 8,
+                    height: 8
+                },
+                Rect {
+                    x: 40,
+                    y: 104,
+                    width: 16,
+                    height: 16
+                },
+            ]
+        );
+        assert!(tracker.is_tile_dirty(25, 1));
+        assert!(!tracker.is_tile_dirty(24, 1));
+        assert_eq!(tracker.dirty_scanlines(), 8..120);
+
+        tracker.invalidate();
+        assert_eq!(tracker.update(frame.pixels()).len(), 1);
+    }
+}
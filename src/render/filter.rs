@@ -0,0 +1,120 @@
+// Accessibility filters on the colors that reach the screen.
+//
+// The color blindness modes don't simulate the deficiency, they compensate
+// for it (daltonization): the colors are converted to LMS cone responses, the
+// response of a protanope or deuteranope is simulated, and the part of the
+// color they can't see is shifted into the green and blue channels where it
+// makes a visible difference. The high contrast mode pushes colors away from
+// mid gray. Both work on single colors, so `PaletteConverter` applies them to
+// its table once and converting a frame costs the same with or without them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorFilter {
+    #[default]
+    None,
+    Protanopia,
+    Deuteranopia,
+    HighContrast,
+}
+
+const CONTRAST: f32 = 1.6;
+
+impl ColorFilter {
+    pub const ALL: [ColorFilter; 4] = [
+        ColorFilter::None,
+        ColorFilter::Protanopia,
+        ColorFilter::Deuteranopia,
+        ColorFilter::HighContrast,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorFilter::None => "none",
+            ColorFilter::Protanopia => "protanopia",
+            ColorFilter::Deuteranopia => "deuteranopia",
+            ColorFilter::HighContrast => "high-contrast",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ColorFilter::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    /// The filter after this one, for a hotkey that cycles through them
+    pub fn next(&self) -> Self {
+        let index = ColorFilter::ALL.iter().position(|f| f == self).unwrap_or(0);
+        ColorFilter::ALL[(index + 1) % ColorFilter::ALL.len()]
+    }
+
+    pub fn apply(&self, (r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+        let rgb = [r as f32, g as f32, b as f32];
+        let [r, g, b] = match self {
+            ColorFilter::None => return (r, g, b),
+            ColorFilter::Protanopia => {
+                daltonize(rgb, |[_, m, s]| [2.02344 * m - 2.52581 * s, m, s])
+            }
+            ColorFilter::Deuteranopia => {
+                daltonize(rgb, |[l, _, s]| [l, 0.494207 * l + 1.24827 * s, s])
+            }
+            ColorFilter::HighContrast => rgb.map(|c| (c - 127.5) * CONTRAST + 127.5),
+        };
+        (to_u8(r), to_u8(g), to_u8(b))
+    }
+}
+
+fn daltonize(rgb: [f32; 3], simulate: impl Fn([f32; 3]) -> [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    let lms = [
+        17.8824 * r + 43.5161 * g + 4.11935 * b,
+        3.45565 * r + 27.1554 * g + 3.86714 * b,
+        0.0299566 * r + 0.184309 * g + 1.46709 * b,
+    ];
+    let [l, m, s] = simulate(lms);
+    let seen = [
+        0.080_944_45 * l - 0.130_504_4 * m + 0.116_721_07 * s,
+        -0.010_248_533 * l + 0.054_019_33 * m - 0.113_614_71 * s,
+        -0.000_365_296_94 * l - 0.004_121_614_7 * m + 0.693_511_4 * s,
+    ];
+    let [er, eg, eb] = [r - seen[0], g - seen[1], b - seen[2]];
+    [r, g + 0.7 * er + eg, b + 0.7 * er + eb]
+}
+
+fn to_u8(c: f32) -> u8 {
+    (c.clamp(0.0, 255.0) + 0.5) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let red = (0xD8, 0x28, 0x00);
+        assert_eq!(ColorFilter::None.apply(red), red);
+        // grays look the same to everyone
+        for filter in ColorFilter::ALL.iter().take(3) {
+            let (r, g, b) = filter.apply((0x80, 0x80, 0x80));
+            assert!(r.abs_diff(0x80) <= 1 && g.abs_diff(0x80) <= 1 && b.abs_diff(0x80) <= 1);
+        }
+        // red a protanope can't tell from green gets some blue
+        let (r, _, b) = ColorFilter::Protanopia.apply(red);
+        assert_eq!(r, 0xD8);
+        assert!(b > 0x40);
+        let (_, _, b) = ColorFilter::Deuteranopia.apply(red);
+        assert!(b > 0x20);
+
+        assert_eq!(
+            ColorFilter::HighContrast.apply((0x40, 0x80, 0xC0)),
+            (0x1A, 0x80, 0xE7)
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        for filter in ColorFilter::ALL.iter() {
+            assert_eq!(ColorFilter::from_name(filter.name()), Some(*filter));
+        }
+        assert_eq!(ColorFilter::HighContrast.next(), ColorFilter::None);
+        assert_eq!(ColorFilter::None.next(), ColorFilter::Protanopia);
+    }
+}
@@ -0,0 +1,256 @@
+// Frame buffers shared between the emulator and the frontend.
+//
+// A frame is 256x240 pixels, one u32 0x00RRGGBB per pixel, which is the
+// layout of an RGB888 texture. Frontends borrow the pixels, as `&[u32]` or as
+// native endian bytes, and upload them as they are instead of copying the
+// frame into a buffer of their own first. Renderers draw into a `&mut [u32]`
+// of `WIDTH * HEIGHT` pixels, which doesn't have to be a `Frame`: a locked
+// streaming texture or any other caller-provided buffer works the same way.
+//
+// `DoubleBuffer` keeps two frames so the next one can be rendered while the
+// last finished one is still being presented. Swapping only flips an index.
+//
+// When emulation runs on a thread of its own, `triple_buffer` hands frames to
+// the UI thread: the emulation thread renders into its frame and publishes
+// it, the UI thread picks up the latest published one whenever it presents.
+// Publishing and picking up swap the frame with the one in a shared slot
+// under a lock held for nothing but the swap, so neither side ever waits for
+// the other to render or present, and a UI that falls behind skips frames.
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pixels: Vec<u32>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            pixels: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+
+    /// The pixels as native endian bytes, 4 per pixel and `WIDTH * 4` per row
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.pixels)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        self.pixels[y * WIDTH + x] = (rgb.0 as u32) << 16 | (rgb.1 as u32) << 8 | rgb.2 as u32;
+    }
+
+    pub fn fill(&mut self, color: u32) {
+        self.pixels.iter_mut().for_each(|pixel| *pixel = color);
+    }
+
+    /// Converts into an RGB24 buffer for the filters and overlays that work
+    /// on those
+    pub fn write_rgb24(&self, dst: &mut [u8]) {
+        for (pixel, rgb) in self.pixels.iter().zip(dst.chunks_exact_mut(3)) {
+            rgb.copy_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DoubleBuffer {
+    frames: [Frame; 2],
+    front: usize,
+}
+
+impl DoubleBuffer {
+    pub fn new() -> Self {
+        DoubleBuffer::default()
+    }
+
+    /// The last finished frame
+    pub fn front(&self) -> &Frame {
+        &self.frames[self.front]
+    }
+
+    /// The frame being rendered
+    pub fn back_mut(&mut self) -> &mut Frame {
+        &mut self.frames[1 - self.front]
+    }
+
+    /// The frame being rendered and the last finished one, to present while
+    /// the next frame is rendered
+    pub fn split(&mut self) -> (&mut Frame, &Frame) {
+        let (first, second) = self.frames.split_at_mut(1);
+        if self.front == 0 {
+            (&mut second[0], &first[0])
+        } else {
+            (&mut first[0], &second[0])
+        }
+    }
+
+    /// Makes the frame that was being rendered the finished one
+    pub fn swap(&mut self) {
+        self.front = 1 - self.front;
+    }
+}
+
+/// The rendering thread's end of a triple buffer
+#[cfg(feature = "std")]
+pub struct FrameWriter {
+    back: Frame,
+    slot: Arc<Mutex<Slot>>,
+}
+
+/// The presenting thread's end of a triple buffer
+#[cfg(feature = "std")]
+pub struct FrameReader {
+    front: Frame,
+    slot: Arc<Mutex<Slot>>,
+}
+
+#[cfg(feature = "std")]
+struct Slot {
+    frame: Frame,
+    /// Published and not picked up yet
+    fresh: bool,
+}
+
+#[cfg(feature = "std")]
+pub fn triple_buffer() -> (FrameWriter, FrameReader) {
+    let slot = Arc::new(Mutex::new(Slot {
+        frame: Frame::new(),
+        fresh: false,
+    }));
+    (
+        FrameWriter {
+            back: Frame::new(),
+            slot: slot.clone(),
+        },
+        FrameReader {
+            front: Frame::new(),
+            slot,
+        },
+    )
+}
+
+#[cfg(feature = "std")]
+impl FrameWriter {
+    /// The frame being rendered, whatever was in it is left over from an
+    /// older frame
+    pub fn back_mut(&mut self) -> &mut Frame {
+        &mut self.back
+    }
+
+    /// Makes the frame being rendered the latest, replacing one the reader
+    /// hasn't picked up
+    pub fn publish(&mut self) {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        core::mem::swap(&mut self.back, &mut slot.frame);
+        slot.fresh = true;
+    }
+}
+
+#[cfg(feature = "std")]
+impl FrameReader {
+    /// Picks up the latest published frame, returns false if there is none
+    /// since the last call
+    pub fn update(&mut self) -> bool {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        if !slot.fresh {
+            return false;
+        }
+        core::mem::swap(&mut self.front, &mut slot.frame);
+        slot.fresh = false;
+        true
+    }
+
+    /// The frame picked up last
+    pub fn front(&self) -> &Frame {
+        &self.front
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_is_borrowed_not_copied() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0x12, 0x34, 0x56));
+        assert_eq!(frame.pixel(1, 0), 0x0012_3456);
+        assert_eq!(frame.as_bytes().len(), WIDTH * HEIGHT * 4);
+        assert_eq!(frame.as_bytes()[4..8], 0x0012_3456u32.to_ne_bytes()[..]);
+        assert_eq!(
+            frame.as_bytes().as_ptr(),
+            frame.pixels().as_ptr() as *const u8
+        );
+
+        let mut rgb = vec![0; WIDTH * HEIGHT * 3];
+        frame.write_rgb24(&mut rgb);
+        assert_eq!(rgb[..6], [0, 0, 0, 0x12, 0x34, 0x56]);
+    }
+
+    #[test]
+    fn test_double_buffer_swaps_without_copying() {
+        let mut buffers = DoubleBuffer::new();
+        buffers.back_mut().fill(0xFF);
+        let rendered = buffers.back_mut().pixels().as_ptr();
+        buffers.swap();
+        assert_eq!(buffers.front().pixels().as_ptr(), rendered);
+        assert_eq!(buffers.front().pixel(0, 0), 0xFF);
+
+        let (back, front) = buffers.split();
+        back.fill(0x01);
+        assert_eq!(front.pixel(0, 0), 0xFF);
+        buffers.swap();
+        assert_eq!(buffers.front().pixel(0, 0), 0x01);
+        assert_eq!(buffers.back_mut().pixels().as_ptr(), rendered);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_triple_buffer() {
+        let (mut writer, mut reader) = triple_buffer();
+        assert!(!reader.update());
+
+        writer.back_mut().fill(1);
+        writer.publish();
+        writer.back_mut().fill(2);
+        writer.publish();
+        // the reader skips to the latest
+        assert!(reader.update());
+        assert_eq!(reader.front().pixel(0, 0), 2);
+        assert!(!reader.update());
+
+        // and can live on another thread
+        writer.back_mut().fill(3);
+        writer.publish();
+        let reader = std::thread::spawn(move || {
+            assert!(reader.update());
+            reader
+        })
+        .join()
+        .unwrap();
+        assert_eq!(reader.front().pixel(0, 0), 3);
+    }
+}
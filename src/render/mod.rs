@@ -1 +1,11 @@
-pub mod palette;
\ No newline at end of file
+pub mod convert;
+#[cfg(feature = "std")]
+pub mod crt;
+pub mod dirty;
+pub mod filter;
+pub mod frame;
+#[cfg(feature = "std")]
+pub mod osd;
+pub mod palette;
+pub mod renderer;
+pub mod tiles;
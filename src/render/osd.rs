@@ -0,0 +1,403 @@
+use crate::perf::FrameTimings;
+use std::collections::VecDeque;
+
+// On-screen display drawn on top of an RGB24 frame buffer.
+//
+// Frontends push messages (save/load notifications, errors, ...) into the queue,
+// call `tick()` once per emulated frame so that messages expire, and `draw()`
+// right before presenting the frame.
+
+pub const GLYPH_SIZE: usize = 8;
+
+const DEFAULT_MESSAGE_FRAMES: u32 = 180;
+const MAX_MESSAGES: usize = 4;
+const MARGIN: usize = 4;
+const PERF_COLOR: (u8, u8, u8) = (0x2B, 0xF0, 0x35);
+/// Controller buttons from bit 7 down to bit 0, using the FM2 letters
+const BUTTON_LETTERS: &str = "RLDUTSBA";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Info,
+    Warning,
+    Error,
+}
+
+impl MessageKind {
+    fn color(&self) -> (u8, u8, u8) {
+        match self {
+            MessageKind::Info => (0xFF, 0xFF, 0xFF),
+            MessageKind::Warning => (0xFF, 0xD2, 0x30),
+            MessageKind::Error => (0xFF, 0x40, 0x40),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: MessageKind,
+    pub text: String,
+    pub frames_left: u32,
+}
+
+pub struct Osd {
+    messages: VecDeque<Message>,
+    fps: Option<f32>,
+    indicator: Option<String>,
+    perf: Vec<String>,
+    input_display: bool,
+    pads: [u8; 2],
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            messages: VecDeque::new(),
+            fps: None,
+            indicator: None,
+            perf: Vec::new(),
+            input_display: false,
+            pads: [0; 2],
+        }
+    }
+
+    pub fn push(&mut self, kind: MessageKind, text: &str) {
+        self.push_for(kind, text, DEFAULT_MESSAGE_FRAMES);
+    }
+
+    pub fn push_for(&mut self, kind: MessageKind, text: &str, frames: u32) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(Message {
+            kind,
+            text: text.to_string(),
+            frames_left: frames,
+        });
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter()
+    }
+
+    /// FPS counter shown in the top right corner, `None` hides it
+    pub fn set_fps(&mut self, fps: Option<f32>) {
+        self.fps = fps;
+    }
+
+    /// Persistent indicator (e.g. "<< REWIND") shown in the top left corner
+    /// until it is cleared with `None`
+    pub fn set_indicator(&mut self, indicator: Option<&str>) {
+        self.indicator = indicator.map(|s| s.to_string());
+    }
+
+    /// Performance HUD under the indicator: percentiles of the frame times
+    /// and the audio buffer fill, `None` hides it. Meant to be called once a
+    /// frame with the latest timings
+    pub fn set_perf(&mut self, timings: Option<&FrameTimings>) {
+        self.perf.clear();
+        if let Some(timings) = timings {
+            self.perf.push("         P50  P99  MAX".to_string());
+            self.perf.extend(timings.to_string().lines().map(String::from));
+        }
+    }
+
+    /// Shows the buttons held on both controllers in the bottom right corner
+    pub fn set_input_display(&mut self, enabled: bool) {
+        self.input_display = enabled;
+    }
+
+    pub fn is_input_display_enabled(&self) -> bool {
+        self.input_display
+    }
+
+    /// Controller state for the input display, one byte per port in RLDUTSBA order
+    pub fn set_pads(&mut self, pads: [u8; 2]) {
+        self.pads = pads;
+    }
+
+    pub fn tick(&mut self) {
+        for message in self.messages.iter_mut() {
+            message.frames_left = message.frames_left.saturating_sub(1);
+        }
+        self.messages.retain(|m| m.frames_left > 0);
+    }
+
+    pub fn draw(&self, frame: &mut [u8], width: usize, height: usize) {
+        if let Some(ref indicator) = self.indicator {
+            draw_text(frame, width, height, MARGIN, MARGIN, indicator, (0xFF, 0xFF, 0xFF));
+        }
+
+        if let Some(fps) = self.fps {
+            let text = format!("{:.0} FPS", fps);
+            let x = width.saturating_sub(MARGIN + text_width(&text));
+            draw_text(frame, width, height, x, MARGIN, &text, PERF_COLOR);
+        }
+
+        let mut y = MARGIN + GLYPH_SIZE + 2;
+        for line in &self.perf {
+            draw_text(frame, width, height, MARGIN, y, line, PERF_COLOR);
+            y += GLYPH_SIZE + 2;
+        }
+
+        if self.input_display {
+            self.draw_pads(frame, width, height);
+        }
+
+        let mut y = height.saturating_sub(MARGIN + GLYPH_SIZE);
+        for message in self.messages.iter().rev() {
+            draw_text(frame, width, height, MARGIN, y, &message.text, message.kind.color());
+            if y < GLYPH_SIZE + 2 {
+                break;
+            }
+            y -= GLYPH_SIZE + 2;
+        }
+    }
+}
+
+impl Osd {
+    fn draw_pads(&self, frame: &mut [u8], width: usize, height: usize) {
+        let line_width = text_width("1 ") + text_width(BUTTON_LETTERS);
+        let x = width.saturating_sub(MARGIN + line_width);
+        let mut y = height.saturating_sub(MARGIN + 2 * GLYPH_SIZE + 2);
+        for (port, buttons) in self.pads.iter().enumerate() {
+            let label = format!("{} ", port + 1);
+            draw_text(frame, width, height, x, y, &label, (0xFF, 0xFF, 0xFF));
+            for (i, letter) in BUTTON_LETTERS.chars().enumerate() {
+                let pressed = buttons & (0x80 >> i) != 0;
+                let color = if pressed {
+                    (0xFF, 0xD2, 0x30)
+                } else {
+                    (0x50, 0x50, 0x50)
+                };
+                let letter_x = x + text_width(&label) + i * GLYPH_SIZE;
+                let letter = letter.to_string();
+                draw_text(frame, width, height, letter_x, y, &letter, color);
+            }
+            y += GLYPH_SIZE + 2;
+        }
+    }
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Osd::new()
+    }
+}
+
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * GLYPH_SIZE
+}
+
+/// Draws a single line of text with a 1px shadow. Lowercase letters are drawn
+/// as uppercase, characters outside of the font are drawn as '?'
+pub fn draw_text(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    color: (u8, u8, u8),
+) {
+    for (i, ch) in text.chars().enumerate() {
+        let glyph = glyph(ch);
+        let glyph_x = x + i * GLYPH_SIZE;
+        draw_glyph(frame, width, height, glyph_x + 1, y + 1, glyph, (0, 0, 0));
+        draw_glyph(frame, width, height, glyph_x, y, glyph, color);
+    }
+}
+
+fn draw_glyph(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    glyph: &[u8; 8],
+    color: (u8, u8, u8),
+) {
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..GLYPH_SIZE {
+            if bits & (1 << col) == 0 {
+                continue;
+            }
+            let (px, py) = (x + col, y + row);
+            if px >= width || py >= height {
+                continue;
+            }
+            let base = (py * width + px) * 3;
+            frame[base] = color.0;
+            frame[base + 1] = color.1;
+            frame[base + 2] = color.2;
+        }
+    }
+}
+
+fn glyph(ch: char) -> &'static [u8; 8] {
+    let code = ch.to_ascii_uppercase() as u32;
+    if code >= 0x20 && code < 0x20 + FONT.len() as u32 {
+        &FONT[(code - 0x20) as usize]
+    } else {
+        &FONT[('?' as usize) - 0x20]
+    }
+}
+
+// 8x8 font for ASCII $20-$5F, one byte per row, bit 0 is the leftmost pixel
+#[rustfmt::skip]
+static FONT: [[u8; 8]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages_expire() {
+        let mut osd = Osd::new();
+        osd.push_for(MessageKind::Info, "STATE SAVED", 2);
+        osd.tick();
+        assert_eq!(osd.messages().count(), 1);
+        osd.tick();
+        assert_eq!(osd.messages().count(), 0);
+    }
+
+    #[test]
+    fn test_queue_drops_oldest_message() {
+        let mut osd = Osd::new();
+        for i in 0..MAX_MESSAGES + 1 {
+            osd.push(MessageKind::Info, &format!("MSG {}", i));
+        }
+        assert_eq!(osd.messages().count(), MAX_MESSAGES);
+        assert_eq!(osd.messages().next().unwrap().text, "MSG 1");
+    }
+
+    #[test]
+    fn test_draw_text_sets_glyph_pixels() {
+        let (width, height) = (16, 16);
+        let mut frame = vec![0x10; width * height * 3];
+        draw_text(&mut frame, width, height, 0, 0, "-", (0xFF, 0, 0));
+
+        // '-' is a horizontal bar on the 4th row covering columns 0..=5
+        let pixel = |x: usize, y: usize| &frame[(y * width + x) * 3..(y * width + x) * 3 + 3];
+        assert_eq!(pixel(0, 3), &[0xFF, 0, 0]);
+        assert_eq!(pixel(5, 3), &[0xFF, 0, 0]);
+        assert_eq!(pixel(0, 0), &[0x10, 0x10, 0x10]);
+        // shadow
+        assert_eq!(pixel(6, 4), &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_input_display_highlights_pressed_buttons() {
+        let (width, height) = (128, 32);
+        let mut osd = Osd::new();
+        osd.set_pads([0x01, 0x00]); // A on the first controller
+        let mut frame = vec![0; width * height * 3];
+        osd.draw(&mut frame, width, height);
+        assert!(frame.iter().all(|&c| c == 0));
+
+        osd.set_input_display(true);
+        osd.draw(&mut frame, width, height);
+        let colors: Vec<&[u8]> = frame.chunks(3).collect();
+        assert!(colors.contains(&&[0xFF, 0xD2, 0x30][..]));
+        assert!(colors.contains(&&[0x50, 0x50, 0x50][..]));
+    }
+
+    #[test]
+    fn test_perf_hud() {
+        let (width, height) = (256, 64);
+        let mut osd = Osd::new();
+        let mut timings = FrameTimings::new(10);
+        osd.set_perf(Some(&timings));
+        assert_eq!(osd.perf.len(), 1);
+
+        timings.record_emulation(std::time::Duration::from_millis(3));
+        timings.record_audio_fill(1, 2);
+        osd.set_perf(Some(&timings));
+        assert_eq!(osd.perf.len(), 3);
+        assert!(osd.perf.iter().all(|line| text_width(line) <= width));
+        let mut frame = vec![0; width * height * 3];
+        osd.draw(&mut frame, width, height);
+        assert!(frame.chunks(3).any(|c| c == [0x2B, 0xF0, 0x35]));
+
+        osd.set_perf(None);
+        let mut frame = vec![0; width * height * 3];
+        osd.draw(&mut frame, width, height);
+        assert!(frame.iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn test_draw_clips_at_frame_border() {
+        let mut osd = Osd::new();
+        osd.set_fps(Some(60.0));
+        osd.set_indicator(Some("<< REWIND"));
+        osd.set_input_display(true);
+        osd.push(MessageKind::Error, "A VERY LONG ERROR MESSAGE THAT DOES NOT FIT");
+        let mut frame = vec![0; 32 * 16 * 3];
+        osd.draw(&mut frame, 32, 16);
+    }
+}
@@ -1,3 +1,18 @@
+// The colors of the 64 palette indices the PPU outputs.
+//
+// `SYSTEM_PALLETE`, FCEUX's palette, the Sony CXA2025AS decoder's and NESCAP
+// are built in. Palettes from .pal files replace them at runtime: 64 RGB
+// triples, or 512 for files that also have the colors for each of the 8
+// combinations of the PPUMASK emphasis bits, 64 after 64 in the order of the
+// bits. Brightness, saturation and gamma are corrected on the palette rather
+// than per pixel, so they cost nothing per frame.
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+const COLORS: usize = 64;
+const EMPHASIS_COLORS: usize = 8 * COLORS;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -14,4 +29,281 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+/// FCEUX's default palette
+#[rustfmt::skip]
+pub static FCEUX_PALETTE: [(u8, u8, u8); 64] = [
+    (0x74, 0x74, 0x74), (0x24, 0x18, 0x8C), (0x00, 0x00, 0xA8), (0x44, 0x00, 0x9C),
+    (0x8C, 0x00, 0x74), (0xA8, 0x00, 0x10), (0xA4, 0x00, 0x00), (0x7C, 0x08, 0x00),
+    (0x40, 0x2C, 0x00), (0x00, 0x44, 0x00), (0x00, 0x50, 0x00), (0x00, 0x3C, 0x14),
+    (0x18, 0x3C, 0x5C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xBC, 0xBC, 0xBC), (0x00, 0x70, 0xEC), (0x20, 0x38, 0xEC), (0x80, 0x00, 0xF0),
+    (0xBC, 0x00, 0xBC), (0xE4, 0x00, 0x58), (0xD8, 0x28, 0x00), (0xC8, 0x4C, 0x0C),
+    (0x88, 0x70, 0x00), (0x00, 0x94, 0x00), (0x00, 0xA8, 0x00), (0x00, 0x90, 0x38),
+    (0x00, 0x80, 0x88), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFC, 0xFC, 0xFC), (0x3C, 0xBC, 0xFC), (0x5C, 0x94, 0xFC), (0xCC, 0x88, 0xFC),
+    (0xF4, 0x78, 0xFC), (0xFC, 0x74, 0xB4), (0xFC, 0x74, 0x60), (0xFC, 0x98, 0x38),
+    (0xF0, 0xBC, 0x3C), (0x80, 0xD0, 0x10), (0x4C, 0xDC, 0x48), (0x58, 0xF8, 0x98),
+    (0x00, 0xE8, 0xD8), (0x78, 0x78, 0x78), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFC, 0xFC, 0xFC), (0xA8, 0xE4, 0xFC), (0xC4, 0xD4, 0xFC), (0xD4, 0xC8, 0xFC),
+    (0xFC, 0xC4, 0xFC), (0xFC, 0xC4, 0xD8), (0xFC, 0xBC, 0xB0), (0xFC, 0xD8, 0xA8),
+    (0xFC, 0xE4, 0xA0), (0xE0, 0xFC, 0xA0), (0xA8, 0xF0, 0xBC), (0xB0, 0xFC, 0xCC),
+    (0x9C, 0xFC, 0xF0), (0xC4, 0xC4, 0xC4), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+];
+
+/// The colors of the Sony CXA2025AS RGB decoder in US mode, which many
+/// NTSC televisions of the era used
+#[rustfmt::skip]
+pub static SONY_CXA_PALETTE: [(u8, u8, u8); 64] = [
+    (0x58, 0x58, 0x58), (0x00, 0x23, 0x8C), (0x00, 0x13, 0x9B), (0x2D, 0x05, 0x85),
+    (0x5D, 0x00, 0x52), (0x7A, 0x00, 0x17), (0x7A, 0x08, 0x00), (0x5F, 0x18, 0x00),
+    (0x35, 0x2A, 0x00), (0x09, 0x39, 0x00), (0x00, 0x3F, 0x00), (0x00, 0x3C, 0x22),
+    (0x00, 0x32, 0x5D), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xA1, 0xA1, 0xA1), (0x00, 0x53, 0xEE), (0x15, 0x3C, 0xFE), (0x60, 0x28, 0xE4),
+    (0xA9, 0x1D, 0x98), (0xD4, 0x1E, 0x41), (0xD2, 0x2C, 0x00), (0xAA, 0x44, 0x00),
+    (0x6C, 0x5E, 0x00), (0x2D, 0x73, 0x00), (0x00, 0x7D, 0x06), (0x00, 0x78, 0x52),
+    (0x00, 0x69, 0xA9), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFF, 0xFF, 0xFF), (0x1F, 0xA5, 0xFE), (0x5E, 0x89, 0xFE), (0xB5, 0x72, 0xFE),
+    (0xFE, 0x65, 0xF6), (0xFE, 0x67, 0x90), (0xFE, 0x77, 0x3C), (0xFE, 0x93, 0x08),
+    (0xC4, 0xB2, 0x00), (0x79, 0xCA, 0x10), (0x3A, 0xD5, 0x4A), (0x11, 0xD1, 0xA4),
+    (0x06, 0xBF, 0xFE), (0x42, 0x42, 0x42), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFF, 0xFF, 0xFF), (0xA0, 0xD9, 0xFE), (0xBD, 0xCC, 0xFE), (0xE1, 0xC2, 0xFE),
+    (0xFE, 0xBC, 0xFB), (0xFE, 0xBD, 0xD0), (0xFE, 0xC5, 0xA9), (0xFE, 0xD1, 0x8E),
+    (0xE9, 0xDE, 0x86), (0xC7, 0xE9, 0x92), (0xA8, 0xEE, 0xB0), (0x95, 0xEC, 0xD9),
+    (0x91, 0xE4, 0xFE), (0xAC, 0xAC, 0xAC), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+];
+
+/// NESCAP, colors captured from a real NES through a composite capture card
+#[rustfmt::skip]
+pub static NESCAP_PALETTE: [(u8, u8, u8); 64] = [
+    (0x64, 0x63, 0x65), (0x00, 0x15, 0x80), (0x1D, 0x00, 0x90), (0x38, 0x00, 0x82),
+    (0x56, 0x00, 0x5D), (0x5A, 0x00, 0x1A), (0x4F, 0x09, 0x00), (0x38, 0x1B, 0x00),
+    (0x1E, 0x31, 0x00), (0x00, 0x3D, 0x00), (0x00, 0x41, 0x00), (0x00, 0x3A, 0x1B),
+    (0x00, 0x2F, 0x55), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xAF, 0xAD, 0xAF), (0x16, 0x4B, 0xCA), (0x47, 0x2A, 0xE7), (0x6B, 0x1B, 0xDB),
+    (0x96, 0x17, 0xB0), (0x9F, 0x18, 0x5B), (0x96, 0x30, 0x01), (0x7B, 0x48, 0x00),
+    (0x5A, 0x66, 0x00), (0x23, 0x78, 0x00), (0x01, 0x7F, 0x00), (0x00, 0x78, 0x3D),
+    (0x00, 0x6C, 0x8C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFF, 0xFF, 0xFF), (0x60, 0xA6, 0xFF), (0x8F, 0x84, 0xFF), (0xB4, 0x73, 0xFF),
+    (0xE2, 0x6C, 0xFF), (0xF2, 0x68, 0xC3), (0xEF, 0x7E, 0x61), (0xD8, 0x95, 0x27),
+    (0xBA, 0xB3, 0x07), (0x81, 0xC8, 0x07), (0x57, 0xD4, 0x3D), (0x47, 0xCF, 0x7E),
+    (0x4B, 0xC5, 0xCD), (0x4C, 0x4B, 0x4D), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xFF, 0xFF, 0xFF), (0xC2, 0xE0, 0xFF), (0xD5, 0xD2, 0xFF), (0xE3, 0xCB, 0xFF),
+    (0xF7, 0xC8, 0xFF), (0xFE, 0xC6, 0xEE), (0xFE, 0xCE, 0xC6), (0xF6, 0xD7, 0xAE),
+    (0xE9, 0xE4, 0x9F), (0xD3, 0xED, 0x9D), (0xC0, 0xF2, 0xB2), (0xB9, 0xF1, 0xCC),
+    (0xBA, 0xED, 0xED), (0xBA, 0xB9, 0xBB), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+];
+
+/// Brightness, saturation and gamma adjustments of a palette. The default
+/// changes nothing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorCorrection {
+    /// Added to every channel, -1.0 turns everything black and 1.0 white
+    pub brightness: f32,
+    /// 0.0 is grayscale, 1.0 leaves the colors as they are
+    pub saturation: f32,
+    /// Above 1.0 brightens the midtones, below darkens them
+    pub gamma: f32,
+}
+
+impl ColorCorrection {
+    pub fn is_identity(&self) -> bool {
+        *self == ColorCorrection::default()
+    }
+
+    #[cfg(feature = "std")]
+    fn apply(&self, (r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+        let [r, g, b] = [r, g, b].map(|c| c as f32 / 255.0);
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        let [r, g, b] = [r, g, b].map(|c| {
+            let c = luma + (c - luma) * self.saturation + self.brightness;
+            let c = c.clamp(0.0, 1.0).powf(1.0 / self.gamma);
+            (c * 255.0).round() as u8
+        });
+        (r, g, b)
+    }
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection {
+            brightness: 0.0,
+            saturation: 1.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    // 64 colors, or 512 with the emphasis variants
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn system() -> Self {
+        Palette {
+            colors: SYSTEM_PALLETE.to_vec(),
+        }
+    }
+
+    /// The palettes that come with the emulator by the names settings use:
+    /// "system", "fceux", "sony-cxa" and "nescap"
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let colors = match name.to_ascii_lowercase().as_str() {
+            "system" => &SYSTEM_PALLETE,
+            "fceux" => &FCEUX_PALETTE,
+            "sony-cxa" => &SONY_CXA_PALETTE,
+            "nescap" => &NESCAP_PALETTE,
+            _ => return None,
+        };
+        Some(Palette {
+            colors: colors.to_vec(),
+        })
+    }
+
+    /// Parses the contents of a .pal file
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        if data.len() != COLORS * 3 && data.len() != EMPHASIS_COLORS * 3 {
+            return Err(format!(
+                "Palette is {} bytes, expected {} or {}",
+                data.len(),
+                COLORS * 3,
+                EMPHASIS_COLORS * 3
+            ));
+        }
+        Ok(Palette {
+            colors: data.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect(),
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, String> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        Palette::from_pal(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The contents of a .pal file with these colors
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect()
+    }
+
+    /// A copy with the correction applied to every color. Cheap enough to
+    /// redo, and build a new `PaletteConverter` from, whenever a setting changes
+    #[cfg(feature = "std")]
+    pub fn corrected(&self, correction: &ColorCorrection) -> Palette {
+        Palette {
+            colors: self
+                .colors
+                .iter()
+                .map(|&rgb| correction.apply(rgb))
+                .collect(),
+        }
+    }
+
+    pub fn has_emphasis(&self) -> bool {
+        self.colors.len() == EMPHASIS_COLORS
+    }
+
+    /// The 64 colors to show while the emphasis bits, PPUMASK bits 5-7
+    /// shifted down, are set. A palette without emphasis colors has the same
+    /// colors for all of them
+    pub fn colors(&self, emphasis: u8) -> [(u8, u8, u8); 64] {
+        let start = if self.has_emphasis() {
+            (emphasis & 7) as usize * COLORS
+        } else {
+            0
+        };
+        let mut colors = [(0, 0, 0); 64];
+        colors.copy_from_slice(&self.colors[start..start + COLORS]);
+        colors
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::system()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::convert::PaletteConverter;
+
+    #[test]
+    fn test_pal_files() {
+        let system = Palette::system();
+        let pal = system.to_pal();
+        assert_eq!(pal.len(), 192);
+        assert_eq!(Palette::from_pal(&pal), Ok(system.clone()));
+        assert_eq!(system.colors(0b101), SYSTEM_PALLETE);
+
+        // red emphasis turns index $01 red
+        let mut pal: Vec<u8> = pal.iter().cycle().take(1536).copied().collect();
+        pal[(COLORS + 1) * 3..(COLORS + 2) * 3].copy_from_slice(&[0xFF, 0, 0]);
+        let palette = Palette::from_pal(&pal).unwrap();
+        assert!(palette.has_emphasis());
+        assert_eq!(palette.colors(0)[1], SYSTEM_PALLETE[1]);
+        assert_eq!(palette.colors(1)[1], (0xFF, 0, 0));
+        assert_eq!(
+            PaletteConverter::new(&palette.colors(1)).rgb(0x01),
+            0xFF0000
+        );
+        assert_eq!(palette.to_pal(), pal);
+
+        assert!(Palette::from_pal(&pal[..191]).is_err());
+    }
+
+    #[test]
+    fn test_builtin_palettes() {
+        assert_eq!(Palette::builtin("system"), Some(Palette::system()));
+        assert_eq!(Palette::builtin("FCEUX").unwrap().colors(0), FCEUX_PALETTE);
+        assert_eq!(
+            Palette::builtin("sony-cxa").unwrap().colors(0)[0x16],
+            (0xD2, 0x2C, 0x00)
+        );
+        assert_eq!(
+            Palette::builtin("NESCAP").unwrap().colors(0),
+            NESCAP_PALETTE
+        );
+        assert_eq!(Palette::builtin("smooth"), None);
+    }
+
+    #[test]
+    fn test_color_correction() {
+        let palette = Palette::builtin("fceux").unwrap();
+        assert_eq!(palette.corrected(&ColorCorrection::default()), palette);
+
+        let gray = ColorCorrection {
+            saturation: 0.0,
+            ..ColorCorrection::default()
+        };
+        for (r, g, b) in palette.corrected(&gray).colors(0).iter() {
+            assert!(r == g && g == b);
+        }
+
+        // $16 is (0xD8, 0x28, 0x00)
+        let brighter = ColorCorrection {
+            brightness: 0.2,
+            ..ColorCorrection::default()
+        };
+        assert_eq!(
+            palette.corrected(&brighter).colors(0)[0x16],
+            (0xFF, 0x5B, 0x33)
+        );
+        let gamma = ColorCorrection {
+            gamma: 2.0,
+            ..ColorCorrection::default()
+        };
+        let (r, g, b) = palette.corrected(&gamma).colors(0)[0x16];
+        assert!(r > 0xD8 && g > 0x28 && b == 0);
+        assert!(!gamma.is_identity());
+    }
+}
@@ -0,0 +1,433 @@
+// Drawing the PPU's picture, a whole frame at a time.
+//
+// The renderer looks at the PPU as it is when called, which is usually right
+// after a frame finished, and draws all 240 lines from that: the background
+// from the nametables, scrolled by PPUSCROLL from the base nametable of
+// PPUCTRL and wrapping around the four nametables the way the mirroring lays
+// them out, then the 64 sprites of OAM on top. Changes in the middle of a
+// frame, like a status bar split, don't show, and neither does the limit of
+// eight sprites per line.
+//
+// Pixels are palette indices first, which `PaletteConverter` turns into
+// 0x00RRGGBB with the colors of the renderer's palette, as its color filter
+// changes them. Palettes with emphasis colors switch to the ones for the
+// PPUMASK emphasis bits of the frame. A sprite hides the background unless its priority bit puts it
+// behind, then it only shows where the background is transparent. Among
+// sprites the lower OAM index wins, even a sprite that is itself behind the
+// background, so it cuts through the sprites after it like on hardware.
+//
+// Tile rows come from a `TileCache` keyed by their offset in the cartridge's
+// CHR memory rather than by PPU address, so a bank switch only changes which
+// rows are looked up and leaves none of them stale. CHR RAM can change under
+// the cache, so the renderer keeps a copy of it and forgets the rows whose
+// bytes differ from the last frame's. A different cartridge starts over.
+use super::convert::PaletteConverter;
+use super::filter::ColorFilter;
+use super::frame::{HEIGHT, WIDTH};
+use super::palette::Palette;
+use super::tiles::TileCache;
+use crate::mapper::Mapper;
+use crate::nes_ppu::NesPPU;
+use crate::prelude::*;
+use alloc::sync::Arc;
+
+const NAMETABLES: u16 = 0x2000;
+const NAMETABLE_SIZE: usize = 0x400;
+const ATTRIBUTE_TABLE: usize = 0x3C0;
+const SPRITE_PALETTES: usize = 0x10;
+
+const FLIP_VERTICAL: u8 = 0b1000_0000;
+const FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const BEHIND_BACKGROUND: u8 = 0b0010_0000;
+
+#[derive(Clone)]
+pub struct Renderer {
+    indices: Vec<u8>,
+    /// Where the background isn't transparent, for sprite priority
+    opaque: Vec<bool>,
+    /// Where a sprite was drawn already, lower OAM indices go first
+    sprite: Vec<bool>,
+    palette: Palette,
+    filter: ColorFilter,
+    /// The emphasis bits the converter has the colors for
+    emphasis: u8,
+    converter: PaletteConverter,
+    tiles: TileCache,
+    /// The CHR ROM the cached rows were decoded from
+    chr_rom: Option<Arc<[u8]>>,
+    /// CHR RAM as it was when the last frame was drawn
+    chr_ram: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer::with_palette(Palette::system())
+    }
+
+    pub fn with_palette(palette: Palette) -> Self {
+        Renderer {
+            indices: vec![0; WIDTH * HEIGHT],
+            opaque: vec![false; WIDTH * HEIGHT],
+            sprite: vec![false; WIDTH * HEIGHT],
+            converter: PaletteConverter::new(&palette.colors(0)),
+            palette,
+            filter: ColorFilter::None,
+            emphasis: 0,
+            tiles: TileCache::new(),
+            chr_rom: None,
+            chr_ram: Vec::new(),
+        }
+    }
+
+    /// Takes effect with the next frame, so it can change while a game runs
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.update_converter();
+    }
+
+    pub fn set_filter(&mut self, filter: ColorFilter) {
+        self.filter = filter;
+        self.update_converter();
+    }
+
+    pub fn filter(&self) -> ColorFilter {
+        self.filter
+    }
+
+    /// Draws the picture into `pixels`, `WIDTH * HEIGHT` of them
+    pub fn render(&mut self, ppu: &NesPPU, cartridge: &dyn Mapper, pixels: &mut [u32]) {
+        let emphasis = ppu.mask.bits() >> 5;
+        if self.palette.has_emphasis() && emphasis != self.emphasis {
+            self.emphasis = emphasis;
+            self.update_converter();
+        }
+        self.render_indices(ppu, cartridge);
+        self.converter.convert(&self.indices, pixels);
+    }
+
+    /// The palette indices of the frame rendered last
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    fn update_converter(&mut self) {
+        let colors = self.palette.colors(self.emphasis);
+        self.converter = PaletteConverter::with_filter(&colors, self.filter);
+    }
+
+    fn render_indices(&mut self, ppu: &NesPPU, cartridge: &dyn Mapper) {
+        self.update_tiles(cartridge);
+        self.indices.fill(ppu.palette_table[0] & 0x3F);
+        self.opaque.fill(false);
+        if ppu.mask.show_background() {
+            self.draw_background(ppu, cartridge);
+        }
+        if ppu.mask.show_sprites() {
+            self.sprite.fill(false);
+            self.draw_sprites(ppu, cartridge);
+        }
+        if ppu.mask.is_grayscale() {
+            self.indices.iter_mut().for_each(|index| *index &= 0x30);
+        }
+    }
+
+    /// Forgets the cached rows that CHR memory no longer matches
+    fn update_tiles(&mut self, cartridge: &dyn Mapper) {
+        let memory = cartridge.memory();
+        if !memory.chr_ram {
+            // ROM only changes with the cartridge, and holding on to it keeps
+            // a new one from being mistaken for it
+            if !matches!(&self.chr_rom, Some(rom) if Arc::ptr_eq(rom, &memory.chr)) {
+                self.chr_rom = Some(memory.chr.clone());
+                self.tiles.clear();
+            }
+            return;
+        }
+        if self.chr_rom.take().is_some() || self.chr_ram.len() != memory.chr.len() {
+            self.chr_ram.clear();
+            self.chr_ram.extend_from_slice(&memory.chr);
+            self.tiles.clear();
+            return;
+        }
+        for (offset, (old, &new)) in self.chr_ram.iter_mut().zip(memory.chr.iter()).enumerate() {
+            if *old != new {
+                *old = new;
+                self.tiles.invalidate(offset);
+            }
+        }
+    }
+
+    /// Row `row` of the tile at `tile_addr` in the pattern tables. Banks are
+    /// at least 4K, so the whole tile is where its first byte is mapped
+    fn tile_row(&mut self, cartridge: &dyn Mapper, tile_addr: u16, row: usize) -> [u8; 8] {
+        let offset = cartridge.chr_offset(tile_addr);
+        self.tiles.row(&cartridge.memory().chr, offset, row)
+    }
+
+    fn draw_background(&mut self, ppu: &NesPPU, cartridge: &dyn Mapper) {
+        let pattern_table = ppu.ctrl.background_pattern_addr();
+        let base = ppu.ctrl.base_nametable();
+        // in the 512x480 pixels of the four nametables
+        let scroll_x = ppu.scroll.scroll_x as usize + (base & 1) * WIDTH;
+        let scroll_y = ppu.scroll.scroll_y as usize + (base >> 1) * HEIGHT;
+        let left = if ppu.mask.leftmost_8pxl_background() {
+            0
+        } else {
+            8
+        };
+
+        for y in 0..HEIGHT {
+            let world_y = (y + scroll_y) % (2 * HEIGHT);
+            let (tile_y, fine_y) = ((world_y % HEIGHT) / 8, world_y % 8);
+            let mut x = 0;
+            while x < WIDTH {
+                let world_x = (x + scroll_x) % (2 * WIDTH);
+                let (tile_x, fine_x) = ((world_x % WIDTH) / 8, world_x % 8);
+                let nametable = (world_y / HEIGHT) * 2 + world_x / WIDTH;
+                let vram = |offset: usize| {
+                    let addr = NAMETABLES + (nametable * NAMETABLE_SIZE + offset) as u16;
+                    ppu.vram[ppu.mirror_vram_addr(addr)]
+                };
+                let tile = vram(tile_y * 32 + tile_x);
+                let attribute = vram(ATTRIBUTE_TABLE + tile_y / 4 * 8 + tile_x / 4);
+                // each byte covers 4x4 tiles, two bits per 2x2
+                let palette = (attribute >> ((tile_y & 2) * 2 + (tile_x & 2))) & 0b11;
+                let tile_addr = pattern_table + tile as u16 * 16;
+
+                let row = self.tile_row(cartridge, tile_addr, fine_y);
+                for (i, &value) in row.iter().enumerate().skip(fine_x) {
+                    let screen_x = x + i - fine_x;
+                    if screen_x >= WIDTH {
+                        break;
+                    }
+                    if value == 0 || screen_x < left {
+                        continue;
+                    }
+                    let pixel = y * WIDTH + screen_x;
+                    self.indices[pixel] = palette_entry(ppu, palette as usize * 4 + value as usize);
+                    self.opaque[pixel] = true;
+                }
+                x += 8 - fine_x;
+            }
+        }
+    }
+
+    fn draw_sprites(&mut self, ppu: &NesPPU, cartridge: &dyn Mapper) {
+        let height = ppu.ctrl.sprite_size();
+        let left = if ppu.mask.leftmost_8pxl_sprite() {
+            0
+        } else {
+            8
+        };
+
+        for sprite in ppu.oam_data.chunks_exact(4) {
+            // sprites show one line below their Y
+            let (top, tile, attributes, sprite_x) = (
+                sprite[0] as usize + 1,
+                sprite[1] as u16,
+                sprite[2],
+                sprite[3] as usize,
+            );
+            let palette = SPRITE_PALETTES + (attributes & 0b11) as usize * 4;
+
+            for line in 0..height {
+                let y = top + line;
+                if y >= HEIGHT {
+                    break;
+                }
+                let row = if attributes & FLIP_VERTICAL != 0 {
+                    height - 1 - line
+                } else {
+                    line
+                };
+                // 8x16 sprites take their pattern table from bit 0 of the tile
+                let tile_addr = if height == 16 {
+                    (tile & 1) * 0x1000 + (tile & 0xFE) * 16 + (row as u16 / 8) * 16
+                } else {
+                    ppu.ctrl.sprite_pattern_addr() + tile * 16
+                };
+
+                let pixels = self.tile_row(cartridge, tile_addr, row % 8);
+                for (i, &value) in pixels.iter().enumerate() {
+                    let column = if attributes & FLIP_HORIZONTAL != 0 {
+                        7 - i
+                    } else {
+                        i
+                    };
+                    let x = sprite_x + column;
+                    if value == 0 || x >= WIDTH || x < left {
+                        continue;
+                    }
+                    let pixel = y * WIDTH + x;
+                    if self.sprite[pixel] {
+                        continue;
+                    }
+                    self.sprite[pixel] = true;
+                    if attributes & BEHIND_BACKGROUND == 0 || !self.opaque[pixel] {
+                        self.indices[pixel] = palette_entry(ppu, palette + value as usize);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer::new()
+    }
+}
+
+fn palette_entry(ppu: &NesPPU, entry: usize) -> u8 {
+    ppu.palette_table[entry] & 0x3F
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{Mirroring, RomBuilder};
+    use crate::mapper;
+
+    /// CHR RAM with tile 1 solid in color 1 and tile 2 a column of color 3
+    /// at its left edge
+    fn cartridge() -> Box<dyn Mapper> {
+        let mut cartridge = mapper::for_rom(&RomBuilder::new().chr_pages(0).build());
+        for row in 0..8 {
+            cartridge.ppu_write(0x10 + row, 0xFF);
+            cartridge.ppu_write(0x20 + row, 0x80);
+            cartridge.ppu_write(0x28 + row, 0x80);
+        }
+        cartridge
+    }
+
+    fn ppu() -> NesPPU {
+        let mut ppu = NesPPU::new(Mirroring::VERTICAL);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[3] = 0x03;
+        ppu.palette_table[5] = 0x05;
+        ppu.palette_table[0x11] = 0x11;
+        ppu.palette_table[0x13] = 0x13;
+        // show background and sprites, including the leftmost 8 pixels
+        ppu.write_to_mask(0b0001_1110);
+        ppu
+    }
+
+    fn render(ppu: &NesPPU) -> Vec<u8> {
+        let mut renderer = Renderer::new();
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        renderer.render(ppu, &*cartridge(), &mut pixels);
+        assert_eq!(pixels[0], renderer.converter.rgb(renderer.indices[0]));
+        renderer.indices().to_vec()
+    }
+
+    #[test]
+    fn test_background() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        // palette 1 for the bottom right 2x2 tiles of the top left 4x4
+        ppu.vram[0x3C0] = 0b0100_0000;
+        ppu.vram[2 * 32 + 2] = 1;
+        let indices = render(&ppu);
+        assert_eq!(indices[7], 0x01);
+        assert_eq!(indices[8], 0x0F);
+        assert_eq!(indices[16 * WIDTH + 16], 0x05);
+
+        // scrolled 4 pixels right and into the second nametable, which
+        // vertical mirroring puts at $2400
+        ppu.write_to_scroll(4);
+        ppu.write_to_scroll(0);
+        ppu.vram[0x400] = 2;
+        let indices = render(&ppu);
+        assert_eq!(indices[3], 0x01);
+        assert_eq!(indices[4], 0x0F);
+        assert_eq!(indices[WIDTH - 4], 0x03);
+
+        ppu.write_to_mask(0b0000_1001);
+        let indices = render(&ppu);
+        assert_eq!(indices[3], 0x0F & 0x30);
+        assert_eq!(indices[16 * WIDTH + 12], 0x05 & 0x30);
+    }
+
+    #[test]
+    fn test_sprites() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        // at (0, 0) and flipped, so its column of color 3 is at x 7, y 1-8
+        ppu.oam_data[..4].copy_from_slice(&[0, 2, FLIP_HORIZONTAL, 0]);
+        // behind the background, in front of the background color only
+        ppu.oam_data[4..8].copy_from_slice(&[0, 1, BEHIND_BACKGROUND, 0]);
+        ppu.oam_data[8..12].copy_from_slice(&[0, 1, 0, 8]);
+        let indices = render(&ppu);
+        assert_eq!(indices[9 * WIDTH], 0x0F);
+        assert_eq!(indices[WIDTH + 7], 0x13);
+        assert_eq!(indices[WIDTH + 6], 0x01);
+        assert_eq!(indices[8 * WIDTH + 6], 0x11);
+        assert_eq!(indices[WIDTH + 8], 0x11);
+
+        // the behind sprite still hides the sprite after it
+        ppu.oam_data[8..12].copy_from_slice(&[0, 1, 0, 0]);
+        let indices = render(&ppu);
+        assert_eq!(indices[WIDTH + 6], 0x01);
+
+        // hidden in the leftmost 8 pixels
+        ppu.write_to_mask(0b0001_1010);
+        let indices = render(&ppu);
+        assert_eq!(indices[WIDTH + 7], 0x01);
+    }
+
+    #[test]
+    fn test_palette_and_filter() {
+        let mut ppu = ppu();
+        let mut renderer = Renderer::new();
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        let cartridge = cartridge();
+
+        // $0F is black in the system palette, white in this one with red
+        // emphasis
+        let mut pal = Palette::system().to_pal().repeat(8);
+        pal[(64 + 0x0F) * 3..(64 + 0x10) * 3].copy_from_slice(&[0xFF; 3]);
+        renderer.set_palette(Palette::from_pal(&pal).unwrap());
+        renderer.render(&ppu, &*cartridge, &mut pixels);
+        assert_eq!(pixels[0], 0x050505);
+        ppu.write_to_mask(0b0011_1110);
+        renderer.render(&ppu, &*cartridge, &mut pixels);
+        assert_eq!(pixels[0], 0xFFFFFF);
+
+        renderer.set_filter(ColorFilter::HighContrast);
+        ppu.write_to_mask(0b0001_1110);
+        renderer.render(&ppu, &*cartridge, &mut pixels);
+        assert_eq!(pixels[0], 0x000000);
+        assert_eq!(renderer.filter(), ColorFilter::HighContrast);
+    }
+
+    #[test]
+    fn test_tiles_follow_chr() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        let mut renderer = Renderer::new();
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        let mut cartridge = cartridge();
+        renderer.render(&ppu, &*cartridge, &mut pixels);
+        assert_eq!(renderer.indices()[WIDTH], 0x01);
+
+        // the second row of tile 1 is color 3 now
+        cartridge.ppu_write(0x19, 0xFF);
+        renderer.render(&ppu, &*cartridge, &mut pixels);
+        assert_eq!(renderer.indices()[0], 0x01);
+        assert_eq!(renderer.indices()[WIDTH], 0x03);
+
+        // CNROM with bank 1 solid in color 1, bank 0 in color 0
+        let mut rom = RomBuilder::new().mapper(3).build();
+        rom.chr_rom = (0..0x4000)
+            .map(|i| if i < 0x2000 || i % 16 >= 8 { 0 } else { 0xFF })
+            .collect::<Vec<_>>()
+            .into();
+        let mut cnrom = mapper::for_rom(&rom);
+        renderer.render(&ppu, &*cnrom, &mut pixels);
+        assert_eq!(renderer.indices()[0], 0x0F);
+        cnrom.cpu_write(0x8000, 1);
+        renderer.render(&ppu, &*cnrom, &mut pixels);
+        assert_eq!(renderer.indices()[0], 0x01);
+    }
+}
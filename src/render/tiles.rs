@@ -0,0 +1,99 @@
+// Decoded CHR tile rows for the background and sprite renderers.
+//
+// A row of a tile is two bytes 8 apart in CHR memory, one per bitplane, that
+// combine into eight 2 bit palette indices, leftmost pixel in bit 7. Picking
+// them apart bit by bit is most of the work of drawing a tile and the same few
+// hundred tiles are drawn every frame, so decoded rows are kept here.
+//
+// The cache can't see CHR memory change: writes to CHR RAM have to be passed to
+// `invalidate`. Callers that look rows up by PPU address have to `clear` it on
+// bank switches, ones that look them up by offset into all of CHR, like the
+// renderer, don't.
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Default)]
+pub struct TileCache {
+    rows: Vec<[u8; 8]>,
+    decoded: Vec<bool>,
+}
+
+impl TileCache {
+    pub fn new() -> Self {
+        TileCache {
+            rows: Vec::new(),
+            decoded: Vec::new(),
+        }
+    }
+
+    /// Row `row` (0-7) of the tile starting at `tile_addr` in `chr`
+    pub fn row(&mut self, chr: &[u8], tile_addr: usize, row: usize) -> [u8; 8] {
+        let index = row_index(tile_addr + row);
+        if index >= self.rows.len() {
+            self.rows.resize(index + 1, [0; 8]);
+            self.decoded.resize(index + 1, false);
+        }
+        if !self.decoded[index] {
+            let lo = chr.get(tile_addr + row).copied().unwrap_or(0);
+            let hi = chr.get(tile_addr + row + 8).copied().unwrap_or(0);
+            self.rows[index] = decode_row(lo, hi);
+            self.decoded[index] = true;
+        }
+        self.rows[index]
+    }
+
+    /// Forgets the row that the CHR byte at `chr_addr` is part of
+    pub fn invalidate(&mut self, chr_addr: usize) {
+        if let Some(decoded) = self.decoded.get_mut(row_index(chr_addr)) {
+            *decoded = false;
+        }
+    }
+
+    /// Forgets all rows, for when a different CHR bank is mapped in
+    pub fn clear(&mut self) {
+        self.decoded.iter_mut().for_each(|decoded| *decoded = false);
+    }
+}
+
+// both bitplane bytes of a row map to the same entry
+fn row_index(chr_addr: usize) -> usize {
+    (chr_addr >> 4) * 8 + (chr_addr & 7)
+}
+
+/// The eight palette indices of a row, left to right
+pub fn decode_row(lo: u8, hi: u8) -> [u8; 8] {
+    let mut pixels = [0; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let bit = 7 - i;
+        *pixel = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rows_are_cached_until_invalidated() {
+        assert_eq!(
+            decode_row(0b1010_0001, 0b1100_0001),
+            [3, 2, 1, 0, 0, 0, 0, 3]
+        );
+
+        let mut chr = vec![0u8; 0x2000];
+        // tile 1 in the second pattern table, row 2
+        chr[0x1012] = 0xF0;
+        chr[0x101A] = 0x0F;
+        let mut cache = TileCache::new();
+        assert_eq!(cache.row(&chr, 0x1010, 2), [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        chr[0x101A] = 0xFF;
+        assert_eq!(cache.row(&chr, 0x1010, 2), [1, 1, 1, 1, 2, 2, 2, 2]);
+        cache.invalidate(0x101A);
+        assert_eq!(cache.row(&chr, 0x1010, 2), [3, 3, 3, 3, 2, 2, 2, 2]);
+
+        chr[0x1012] = 0;
+        cache.clear();
+        assert_eq!(cache.row(&chr, 0x1010, 2), [2; 8]);
+    }
+}
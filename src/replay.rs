@@ -0,0 +1,277 @@
+// Replay logs for checking that emulation is deterministic.
+//
+// A session records the input of every frame plus a hash of the machine state
+// every `interval` frames. Replaying the inputs from power-on has to produce
+// the same hashes, otherwise something outside the input (uninitialized state,
+// host time, iteration order) leaks into emulation and movies and netplay will
+// desync. The file is text, a few header lines followed by one line per frame:
+//
+//   01 00
+//   01 00 9A3C01F2C477B3D0
+//
+// with the two controller bytes in RLDUTSBA order and the state hash on the
+// frames it was taken.
+//
+// The core keeps its side of that: it reads no clocks, has no randomness and
+// does no floating point math, and the hash has the same layout everywhere
+// (see statehash.rs), so a log verifies on any host. Floats only appear in
+// what is drawn and in frame pacing. What the host adds on top is up to the
+// frontend, whose --deterministic mode, implied by --record-replay, starts
+// from power on and leaves out battery saves, savestates, cheats and
+// cartridge swaps. `test_hashes_are_portable` pins the hashes of a fixed run.
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::harness::FrameInput;
+use std::fmt::Write;
+use std::path::Path;
+
+const REPLAY_VERSION: u32 = 4;
+/// Once a second
+pub const DEFAULT_HASH_INTERVAL: usize = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayFrame {
+    pub input: FrameInput,
+    pub hash: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayLog {
+    /// CRC32 of PRG and CHR data
+    pub rom_crc: u32,
+    pub interval: usize,
+    pub frames: Vec<ReplayFrame>,
+}
+
+impl ReplayLog {
+    pub fn new(rom: &Rom, interval: usize) -> Self {
+        ReplayLog {
+            rom_crc: rom_crc(rom),
+            interval: interval.max(1),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn matches_rom(&self, rom: &Rom) -> bool {
+        self.rom_crc == rom_crc(rom)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "version {}", REPLAY_VERSION).unwrap();
+        writeln!(out, "rom {:08X}", self.rom_crc).unwrap();
+        writeln!(out, "interval {}", self.interval).unwrap();
+        for frame in &self.frames {
+            let [pad1, pad2] = frame.input.pads;
+            write!(out, "{:02X} {:02X}", pad1, pad2).unwrap();
+            if let Some(hash) = frame.hash {
+                write!(out, " {:016X}", hash).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<ReplayLog, String> {
+        let mut log = ReplayLog {
+            rom_crc: 0,
+            interval: DEFAULT_HASH_INTERVAL,
+            frames: Vec::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let hex = |s: &str| u32::from_str_radix(s, 16);
+            let malformed = || format!("line {}: malformed replay line: {}", i + 1, line);
+            match fields.as_slice() {
+                [] => {}
+                ["version", version] => {
+                    if version.parse::<u32>() != Ok(REPLAY_VERSION) {
+                        return Err(format!("Unsupported replay version {}", version));
+                    }
+                }
+                ["rom", crc] => log.rom_crc = hex(crc).map_err(|_| malformed())?,
+                ["interval", n] => log.interval = n.parse().map_err(|_| malformed())?,
+                [pad1, pad2, rest @ ..] if rest.len() <= 1 => {
+                    let pad1 = u8::from_str_radix(pad1, 16).map_err(|_| malformed())?;
+                    let pad2 = u8::from_str_radix(pad2, 16).map_err(|_| malformed())?;
+                    let hash = match rest.first() {
+                        Some(hash) => Some(u64::from_str_radix(hash, 16).map_err(|_| malformed())?),
+                        None => None,
+                    };
+                    log.frames.push(ReplayFrame {
+                        input: FrameInput::new(pad1, pad2),
+                        hash,
+                    });
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ReplayLog, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        ReplayLog::from_text(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_text())
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+}
+
+fn rom_crc(rom: &Rom) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&rom.prg_rom);
+    hasher.update(&rom.chr_rom);
+    hasher.finalize()
+}
+
+pub struct ReplayRecorder {
+    log: ReplayLog,
+}
+
+impl ReplayRecorder {
+    pub fn new(rom: &Rom, interval: usize) -> Self {
+        ReplayRecorder {
+            log: ReplayLog::new(rom, interval),
+        }
+    }
+
+    /// To be called after each frame with the input that frame ran with
+    pub fn record_frame(&mut self, input: FrameInput, cpu: &CPU) {
+        let frame = self.log.frames.len() + 1;
+        let hash = if frame.is_multiple_of(self.log.interval) {
+            Some(cpu.state_hash())
+        } else {
+            None
+        };
+        self.log.frames.push(ReplayFrame { input, hash });
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.log.frames.len()
+    }
+
+    pub fn log(&self) -> &ReplayLog {
+        &self.log
+    }
+
+    pub fn finish(self) -> ReplayLog {
+        self.log
+    }
+}
+
+/// Replays the log from power-on and compares every recorded hash. Returns the
+/// number of hashes checked, or where the replay first diverged
+pub fn verify(rom: Rom, log: &ReplayLog) -> Result<usize, String> {
+    if !log.matches_rom(&rom) {
+        return Err("Replay was recorded with a different ROM".to_string());
+    }
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    let mut checked = 0;
+    for (i, frame) in log.frames.iter().enumerate() {
+        frame.input.apply(&mut cpu);
+        if !cpu.run_frame() {
+            return Err(format!("CPU halted on BRK in frame {}", i + 1));
+        }
+        if let Some(expected) = frame.hash {
+            let actual = cpu.state_hash();
+            if actual != expected {
+                return Err(format!(
+                    "State diverged at frame {}: expected {:016X}, got {:016X}",
+                    i + 1,
+                    expected,
+                    actual
+                ));
+            }
+            checked += 1;
+        }
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    // loop: strobe the controller, add the A button to $10, JMP loop
+    fn input_rom() -> Rom {
+        RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: LDA #1
+                 STA $4016
+                 LDA #0
+                 STA $4016
+                 LDA $4016
+                 AND #1
+                 CLC
+                 ADC $10
+                 STA $10
+                 JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build()
+    }
+
+    fn record(rom: Rom, inputs: &[u8]) -> ReplayLog {
+        let mut recorder = ReplayRecorder::new(&rom, 2);
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        for &pad in inputs {
+            let input = FrameInput::new(pad, 0);
+            input.apply(&mut cpu);
+            cpu.run_frame();
+            recorder.record_frame(input, &cpu);
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_replay_verifies() {
+        let log = record(input_rom(), &[0, 1, 1, 0, 1]);
+        assert_eq!(log.frames.iter().filter(|f| f.hash.is_some()).count(), 2);
+        assert_eq!(verify(input_rom(), &log), Ok(2));
+
+        let parsed = ReplayLog::from_text(&log.to_text()).unwrap();
+        assert_eq!(parsed, log);
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let mut log = record(input_rom(), &[0, 1, 1, 0, 1]);
+        log.frames[2].input = FrameInput::new(0, 0);
+        assert!(verify(input_rom(), &log)
+            .unwrap_err()
+            .starts_with("State diverged at frame 4"));
+
+        let other = RomBuilder::new().fill_prg(0xea).build();
+        assert!(verify(other, &log).is_err());
+        assert!(ReplayLog::from_text("01 02 03 04").is_err());
+    }
+
+    #[test]
+    fn test_hashes_are_portable() {
+        // the same on every host and build, a change here means emulation or
+        // the hash changed and recorded replays no longer verify
+        let log = record(input_rom(), &[1, 0, 1, 1, 0, 0, 1, 0]);
+        let hashes: Vec<u64> = log.frames.iter().filter_map(|f| f.hash).collect();
+        assert_eq!(
+            hashes,
+            vec![
+                0xA0BB_E51D_65BC_A19A,
+                0xDC40_6FFE_4AB8_5916,
+                0x220B_1E97_ED8E_86C2,
+                0x3E25_0E28_024A_B859,
+            ]
+        );
+    }
+}
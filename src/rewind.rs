@@ -0,0 +1,289 @@
+// Rewind history kept as deltas between consecutive savestates.
+//
+// Consecutive frames only differ in a few hundred bytes, so rather than a full
+// state per entry the buffer stores the XOR against the previous state with
+// the zero runs squeezed out. Every `keyframe_interval` entries a full state is
+// stored instead, which bounds the work to rebuild a state and lets the oldest
+// history be dropped a group at a time once the memory budget runs out.
+use crate::cpu::CPU;
+use crate::savestate::SaveState;
+use std::collections::VecDeque;
+
+/// A keyframe every 2 seconds of history at one entry per frame
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 120;
+/// 16 MB
+pub const DEFAULT_BUDGET: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    Keyframe(Vec<u8>),
+    /// Run length encoded XOR against the entry before it
+    Delta(Vec<u8>),
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        match self {
+            Entry::Keyframe(data) | Entry::Delta(data) => data.len(),
+        }
+    }
+}
+
+pub struct RewindBuffer {
+    entries: VecDeque<Entry>,
+    /// The state the newest entry decodes to
+    newest: Option<Vec<u8>>,
+    since_keyframe: usize,
+    keyframe_interval: usize,
+    budget: usize,
+    used: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize, keyframe_interval: usize) -> Self {
+        RewindBuffer {
+            entries: VecDeque::new(),
+            newest: None,
+            since_keyframe: 0,
+            keyframe_interval: keyframe_interval.max(1),
+            budget,
+            used: 0,
+        }
+    }
+
+    /// Saves the current state, typically once per frame
+    pub fn push(&mut self, cpu: &CPU) {
+        let state = SaveState::capture(cpu).to_uncompressed_bytes();
+        let entry = match self.newest.as_ref() {
+            Some(newest)
+                if newest.len() == state.len() && self.since_keyframe < self.keyframe_interval =>
+            {
+                self.since_keyframe += 1;
+                Entry::Delta(encode_delta(newest, &state))
+            }
+            _ => {
+                self.since_keyframe = 1;
+                Entry::Keyframe(state.clone())
+            }
+        };
+        self.used += entry.size();
+        self.entries.push_back(entry);
+        self.newest = Some(state);
+        self.evict();
+    }
+
+    /// Restores the newest saved state and drops it, so every call goes one
+    /// step further back. Returns false when there is no history left
+    pub fn rewind(&mut self, cpu: &mut CPU) -> bool {
+        let state = match self.newest.take() {
+            Some(state) => state,
+            None => return false,
+        };
+        // only fails for a state of another build, which can't be in here
+        let _ = cpu.load_state(&state);
+
+        let entry = self.entries.pop_back().expect("newest state has an entry");
+        self.used -= entry.size();
+        self.newest = match entry {
+            Entry::Delta(delta) => {
+                let mut previous = state;
+                apply_delta(&mut previous, &delta);
+                self.since_keyframe -= 1;
+                Some(previous)
+            }
+            Entry::Keyframe(_) => self.rebuild_newest(),
+        };
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes taken by the stored entries
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.newest = None;
+        self.since_keyframe = 0;
+        self.used = 0;
+    }
+
+    /// Decodes the newest entry by replaying deltas from the keyframe before it
+    fn rebuild_newest(&mut self) -> Option<Vec<u8>> {
+        let start = self
+            .entries
+            .iter()
+            .rposition(|entry| matches!(entry, Entry::Keyframe(_)))?;
+        let mut state = match &self.entries[start] {
+            Entry::Keyframe(data) => data.clone(),
+            Entry::Delta(_) => unreachable!(),
+        };
+        for entry in self.entries.iter().skip(start + 1) {
+            if let Entry::Delta(delta) = entry {
+                apply_delta(&mut state, delta);
+            }
+        }
+        self.since_keyframe = self.entries.len() - start;
+        Some(state)
+    }
+
+    /// Drops the oldest keyframe and its deltas until the budget is met. The
+    /// newest group always stays
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let next_keyframe = self
+                .entries
+                .iter()
+                .skip(1)
+                .position(|entry| matches!(entry, Entry::Keyframe(_)));
+            let count = match next_keyframe {
+                Some(position) => position + 1,
+                None => return,
+            };
+            for entry in self.entries.drain(..count) {
+                self.used -= entry.size();
+            }
+        }
+    }
+}
+
+impl Default for RewindBuffer {
+    fn default() -> Self {
+        RewindBuffer::new(DEFAULT_BUDGET, DEFAULT_KEYFRAME_INTERVAL)
+    }
+}
+
+/// XOR of the two states as chunks of (zero count, literal count, literals),
+/// counts as u16 little endian
+fn encode_delta(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let xor: Vec<u8> = previous.iter().zip(current).map(|(a, b)| a ^ b).collect();
+    let mut i = 0;
+    while i < xor.len() {
+        let zeros = xor[i..]
+            .iter()
+            .take(u16::MAX as usize)
+            .take_while(|&&b| b == 0)
+            .count();
+        i += zeros;
+        let literals = xor[i..]
+            .iter()
+            .take(u16::MAX as usize)
+            .take_while(|&&b| b != 0)
+            .count();
+        out.extend_from_slice(&(zeros as u16).to_le_bytes());
+        out.extend_from_slice(&(literals as u16).to_le_bytes());
+        out.extend_from_slice(&xor[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+/// Turns either state into the other, XOR being its own inverse
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+    while i + 4 <= delta.len() {
+        let zeros = u16::from_le_bytes([delta[i], delta[i + 1]]) as usize;
+        let literals = u16::from_le_bytes([delta[i + 2], delta[i + 3]]) as usize;
+        i += 4;
+        pos += zeros;
+        for (byte, x) in state[pos..pos + literals].iter_mut().zip(&delta[i..]) {
+            *byte ^= x;
+        }
+        pos += literals;
+        i += literals;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+
+    fn counting_cpu() -> CPU {
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: INC $10
+                 LDX $10
+                 TXA
+                 STA $0300,X
+                 JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let a = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        let b = vec![0, 1, 9, 3, 4, 5, 0, 0];
+        let delta = encode_delta(&a, &b);
+        assert_eq!(delta, vec![2, 0, 1, 0, 11, 3, 0, 2, 0, 6, 7]);
+        let mut state = a.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, b);
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, a);
+    }
+
+    #[test]
+    fn test_rewind_restores_states_in_reverse() {
+        let mut cpu = counting_cpu();
+        let mut rewind = RewindBuffer::new(DEFAULT_BUDGET, 4);
+        let mut states = vec![];
+        for _ in 0..10 {
+            cpu.run_frame();
+            rewind.push(&cpu);
+            states.push(SaveState::capture(&cpu));
+        }
+        let full = SaveState::capture(&cpu).to_uncompressed_bytes().len();
+        assert!(rewind.memory_used() < full * 4);
+
+        for expected in states.iter().rev() {
+            assert!(rewind.rewind(&mut cpu));
+            assert_eq!(&SaveState::capture(&cpu), expected);
+        }
+        assert!(!rewind.rewind(&mut cpu));
+        assert_eq!(rewind.memory_used(), 0);
+
+        // history can be added to again after rewinding
+        cpu.run_frame();
+        rewind.push(&cpu);
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn test_oldest_groups_are_evicted() {
+        let mut cpu = counting_cpu();
+        cpu.run_frame();
+        let full = SaveState::capture(&cpu).to_uncompressed_bytes().len();
+        let mut rewind = RewindBuffer::new(full * 2, 3);
+        for _ in 0..10 {
+            cpu.run_frame();
+            rewind.push(&cpu);
+        }
+        assert!(rewind.memory_used() <= full * 2);
+        // groups of 3, the last one partial
+        assert_eq!(rewind.len() % 3, 1);
+        let expected = SaveState::capture(&cpu);
+        assert!(rewind.rewind(&mut cpu));
+        assert_eq!(SaveState::capture(&cpu), expected);
+        while rewind.rewind(&mut cpu) {}
+        assert!(rewind.is_empty());
+    }
+}
@@ -0,0 +1,480 @@
+use crate::cartridge::{self, Mirroring};
+use crate::error::RomError;
+use crate::pacer::{NTSC_FRAME_RATE, PAL_FRAME_RATE};
+use std::fmt;
+use std::path::Path;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+
+/// Copiers like the Front Fareast Magic Card put a header of their own in
+/// front of the iNES one
+const COPIER_HEADER_SIZE: usize = 512;
+
+/// A known dump, with what its header should say
+struct DatabaseEntry {
+    /// Of the ROM data without the iNES header
+    crc32: u32,
+    name: &'static str,
+    region: Region,
+    mapper: u16,
+    mirroring: Mirroring,
+}
+
+const ROM_DATABASE: &[DatabaseEntry] = &[DatabaseEntry {
+    crc32: 0x158B_0388,
+    name: "nestest",
+    region: Region::Ntsc,
+    mapper: 0,
+    mirroring: Mirroring::HORIZONTAL,
+}];
+
+/// Release tags of GoodNES and No-Intro file names, "Game (Europe).nes"
+const PAL_TAGS: &[&str] = &[
+    "e",
+    "europe",
+    "pal",
+    "a",
+    "australia",
+    "f",
+    "france",
+    "g",
+    "germany",
+    "i",
+    "italy",
+    "s",
+    "spain",
+    "sw",
+    "sweden",
+    "nl",
+    "netherlands",
+    "uk",
+];
+const NTSC_TAGS: &[&str] = &["u", "usa", "j", "japan", "ju", "k", "korea", "ntsc"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Multi,
+    Dendy,
+}
+
+impl Region {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Multi => "Multi-region",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    /// Accepts `name` results and the short forms, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "multi" | "multi-region" => Some(Region::Multi),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    /// The rate to pace frames at. Multi-region games run as NTSC, a Dendy
+    /// runs at the PAL rate too. Only the pacing follows the region, the
+    /// core emulates NTSC timing either way
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc | Region::Multi => NTSC_FRAME_RATE,
+            Region::Pal | Region::Dendy => PAL_FRAME_RATE,
+        }
+    }
+
+    /// The region the tags of a GoodNES or No-Intro file name stand for.
+    /// "(USA, Europe)" is multi-region, names without a known tag give None
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let (mut pal, mut ntsc) = (false, false);
+        let tags = name
+            .split(['(', '['])
+            .skip(1)
+            .filter_map(|tag| tag.split([')', ']']).next());
+        for tag in tags.flat_map(|tag| tag.split(',')) {
+            let tag = tag.trim().to_ascii_lowercase();
+            pal |= PAL_TAGS.contains(&tag.as_str());
+            ntsc |= NTSC_TAGS.contains(&tag.as_str());
+        }
+        match (ntsc, pal) {
+            (true, true) => Some(Region::Multi),
+            (true, false) => Some(Region::Ntsc),
+            (false, true) => Some(Region::Pal),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Everything the iNES header says about a ROM plus its hashes, parsed
+/// without loading the cartridge
+#[derive(Debug)]
+pub struct RomInfo {
+    pub nes2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    /// A PlayChoice-10 dump, INST-ROM and PROM follow CHR ROM
+    pub playchoice: bool,
+    /// What the header says. iNES headers rarely set their PAL bit, see
+    /// `detect_region` for a better guess
+    pub region: Region,
+    /// CRC32 and SHA1 of the PRG and CHR data, the way ROM databases list them
+    pub crc32: u32,
+    pub sha1: String,
+    pub database_match: Option<&'static str>,
+}
+
+impl RomInfo {
+    pub fn new(raw: &[u8]) -> Result<RomInfo, String> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let nes2 = (raw[7] >> 2) & 0b11 == 0b10;
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        let mut submapper = 0;
+        let prg_rom_size;
+        let chr_rom_size;
+        let region;
+        if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_rom_size = nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE);
+            chr_rom_size = nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE);
+            region = match raw[12] & 0b11 {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                2 => Region::Multi,
+                _ => Region::Dendy,
+            };
+        } else {
+            prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+            chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+            region = if raw[9] & 1 != 0 {
+                Region::Pal
+            } else {
+                Region::Ntsc
+            };
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FOUR_SCREEN,
+            (false, true) => Mirroring::VERTICAL,
+            (false, false) => Mirroring::HORIZONTAL,
+        };
+        let trainer = raw[6] & 0b100 != 0;
+        // NES 2.0 made the PlayChoice-10 flag one of the console types
+        let playchoice = if nes2 {
+            raw[7] & 0b11 == 2
+        } else {
+            raw[7] & 0b10 != 0
+        };
+
+        // hash whatever is there, a truncated dump simply won't match the database
+        let data_start = (HEADER_SIZE + if trainer { TRAINER_SIZE } else { 0 }).min(raw.len());
+        let data_end = (data_start + prg_rom_size + chr_rom_size).min(raw.len());
+        let data = &raw[data_start..data_end];
+        let crc32 = crc32fast::hash(data);
+        let database_match = database_entry(crc32).map(|entry| entry.name);
+
+        Ok(RomInfo {
+            nes2,
+            mapper,
+            submapper,
+            prg_rom_size,
+            chr_rom_size,
+            mirroring,
+            battery: raw[6] & 0b10 != 0,
+            trainer,
+            playchoice,
+            region,
+            crc32,
+            sha1: sha1_smol::Sha1::from(data).digest().to_string(),
+            database_match,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RomInfo, String> {
+        RomInfo::new(&cartridge::read_image(path).map_err(|e| e.to_string())?)
+    }
+
+    /// The console the game was made for. A NES 2.0 header decides, then the
+    /// database, then the PAL bit of an iNES header, then the tags of the
+    /// file name the ROM was loaded from. NTSC when nothing tells
+    pub fn detect_region(&self, file_name: Option<&str>) -> Region {
+        if self.nes2 {
+            return self.region;
+        }
+        if let Some(entry) = database_entry(self.crc32) {
+            return entry.region;
+        }
+        if self.region == Region::Pal {
+            return Region::Pal;
+        }
+        file_name
+            .and_then(Region::from_file_name)
+            .unwrap_or(Region::Ntsc)
+    }
+}
+
+fn database_entry(crc32: u32) -> Option<&'static DatabaseEntry> {
+    ROM_DATABASE.iter().find(|entry| entry.crc32 == crc32)
+}
+
+/// A copy of a ROM image with its header cleaned up, and what was wrong
+#[derive(Debug)]
+pub struct HeaderRepair {
+    pub image: Vec<u8>,
+    /// Empty when the header was fine
+    pub fixes: Vec<String>,
+}
+
+/// Cleans up what old dumping tools left in ROM images: a copier header in
+/// front of the iNES one, text like "DiskDude!" in the unused bytes 7-15 of
+/// an iNES header, which turns into a bogus mapper number, and a mapper or
+/// mirroring the database knows better. Fails when the result still isn't
+/// an image the loader accepts
+pub fn repair_header(raw: &[u8]) -> Result<HeaderRepair, String> {
+    let mut fixes = Vec::new();
+    let mut image = raw.to_vec();
+    if !image.starts_with(&NES_TAG)
+        && image.get(COPIER_HEADER_SIZE..COPIER_HEADER_SIZE + 4) == Some(&NES_TAG[..])
+    {
+        image.drain(..COPIER_HEADER_SIZE);
+        fixes.push(format!(
+            "Removed a {} byte copier header",
+            COPIER_HEADER_SIZE
+        ));
+    }
+    let mut info = RomInfo::new(&image)?;
+
+    if !info.nes2 && image[HEADER_SIZE - 4..HEADER_SIZE].iter().any(|&b| b != 0) {
+        let garbage = String::from_utf8_lossy(&image[7..HEADER_SIZE]).to_string();
+        image[7..HEADER_SIZE].fill(0);
+        fixes.push(format!(
+            "Cleared bytes 7-15 of the header ({:?}), the mapper was {} and is {}",
+            garbage.trim_end_matches('\0'),
+            info.mapper,
+            info.mapper & 0x0F
+        ));
+        info = RomInfo::new(&image)?;
+    }
+
+    if let Some(entry) = database_entry(info.crc32) {
+        if info.mapper != entry.mapper {
+            image[6] = (image[6] & 0x0F) | (entry.mapper as u8) << 4;
+            image[7] = (image[7] & 0x0F) | (entry.mapper as u8 & 0xF0);
+            if info.nes2 {
+                image[8] = (image[8] & 0xF0) | (entry.mapper >> 8) as u8;
+            }
+            fixes.push(format!(
+                "Set the mapper to {} instead of {}, from the database",
+                entry.mapper, info.mapper
+            ));
+        }
+        if info.mirroring != entry.mirroring {
+            image[6] &= !0b1001;
+            image[6] |= match entry.mirroring {
+                Mirroring::VERTICAL => 0b0001,
+                Mirroring::FOUR_SCREEN => 0b1000,
+                _ => 0b0000,
+            };
+            fixes.push(format!(
+                "Set the mirroring to {} instead of {}, from the database",
+                mirroring_name(&entry.mirroring),
+                mirroring_name(&info.mirroring)
+            ));
+        }
+    }
+
+    // the loader has the last word, it doesn't take NES 2.0 headers yet though
+    match cartridge::Rom::new(&image) {
+        Ok(_) | Err(RomError::Nes2Unsupported) => Ok(HeaderRepair { image, fixes }),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn mirroring_name(mirroring: &Mirroring) -> &'static str {
+    match mirroring {
+        Mirroring::VERTICAL => "vertical",
+        Mirroring::HORIZONTAL => "horizontal",
+        Mirroring::FOUR_SCREEN => "four-screen",
+        Mirroring::ONE_SCREEN_LOWER | Mirroring::ONE_SCREEN_UPPER => "one-screen",
+    }
+}
+
+/// NES 2.0 sizes are either a 12 bit page count or, when the MSB nibble is $F,
+/// 2^exponent * multiplier bytes
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * page_size
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+        let mirroring = match self.mirroring {
+            Mirroring::VERTICAL => "Vertical",
+            Mirroring::HORIZONTAL => "Horizontal",
+            Mirroring::FOUR_SCREEN => "Four-screen",
+            Mirroring::ONE_SCREEN_LOWER | Mirroring::ONE_SCREEN_UPPER => "One-screen",
+        };
+
+        writeln!(
+            f,
+            "Format:     {}",
+            if self.nes2 { "NES 2.0" } else { "iNES" }
+        )?;
+        writeln!(f, "Mapper:     {}", self.mapper)?;
+        writeln!(f, "Submapper:  {}", self.submapper)?;
+        writeln!(f, "PRG ROM:    {} KiB", self.prg_rom_size / 1024)?;
+        writeln!(f, "CHR ROM:    {} KiB", self.chr_rom_size / 1024)?;
+        writeln!(f, "Mirroring:  {}", mirroring)?;
+        writeln!(f, "Battery:    {}", yes_no(self.battery))?;
+        writeln!(f, "Trainer:    {}", yes_no(self.trainer))?;
+        writeln!(f, "PlayChoice: {}", yes_no(self.playchoice))?;
+        writeln!(f, "Region:     {}", self.region.name())?;
+        writeln!(f, "CRC32:      {:08X}", self.crc32)?;
+        writeln!(f, "SHA1:       {}", self.sha1)?;
+        match self.database_match {
+            Some(name) => write!(f, "Database:   {}", name),
+            None => write!(f, "Database:   no match"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(header: [u8; 16], data_len: usize) -> Vec<u8> {
+        let mut raw = header.to_vec();
+        raw.extend(vec![0xEA; data_len]);
+        raw
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let raw = image(
+            [
+                0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x13, 0x10, 0, 1, 0, 0, 0, 0, 0, 0,
+            ],
+            2 * PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE,
+        );
+        let info = RomInfo::new(&raw).unwrap();
+        assert!(!info.nes2);
+        assert_eq!(info.mapper, 0x11);
+        assert_eq!(info.prg_rom_size, 32 * 1024);
+        assert_eq!(info.chr_rom_size, 8 * 1024);
+        assert_eq!(info.mirroring, Mirroring::VERTICAL);
+        assert!(info.battery);
+        assert!(!info.trainer);
+        assert!(!info.playchoice);
+        assert_eq!(info.region, Region::Pal);
+        assert_eq!(info.crc32, crc32fast::hash(&raw[16..]));
+        assert_eq!(info.sha1.len(), 40);
+        assert_eq!(info.database_match, None);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let raw = image(
+            [
+                0x4E, 0x45, 0x53, 0x1A, 0x07, 0x00, 0x48, 0x08, 0x31, 0xF1, 0, 0, 3, 0, 0, 0,
+            ],
+            256,
+        );
+        let info = RomInfo::new(&raw).unwrap();
+        assert!(info.nes2);
+        assert_eq!(info.mapper, 0x104);
+        assert_eq!(info.submapper, 3);
+        assert_eq!(info.prg_rom_size, 0x107 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(info.chr_rom_size, 1);
+        assert_eq!(info.mirroring, Mirroring::FOUR_SCREEN);
+        assert_eq!(info.region, Region::Dendy);
+        assert!(!info.playchoice);
+    }
+
+    #[test]
+    fn test_database_match() {
+        let info = RomInfo::from_file("nestest.nes").unwrap();
+        assert_eq!(info.database_match, Some("nestest"));
+        assert!(info.to_string().contains("CRC32:      158B0388"));
+    }
+
+    #[test]
+    fn test_detect_region() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let info = RomInfo::new(&image(header, PRG_ROM_PAGE_SIZE)).unwrap();
+        assert_eq!(info.detect_region(None), Region::Ntsc);
+        assert_eq!(
+            info.detect_region(Some("Game (Europe) [!].nes")),
+            Region::Pal
+        );
+        assert_eq!(info.detect_region(Some("Game (U) (E).nes")), Region::Multi);
+        assert_eq!(
+            info.detect_region(Some("Game (USA, Europe).nes")),
+            Region::Multi
+        );
+        assert_eq!(info.detect_region(Some("Eggs (Rev 1).nes")), Region::Ntsc);
+
+        // the PAL bit beats the name
+        header[9] = 1;
+        let info = RomInfo::new(&image(header, PRG_ROM_PAGE_SIZE)).unwrap();
+        assert_eq!(info.detect_region(Some("Game (USA).nes")), Region::Pal);
+
+        // and so does the database, nestest is in it as NTSC
+        let info = RomInfo::from_file("nestest.nes").unwrap();
+        assert_eq!(info.detect_region(Some("nestest (E).nes")), Region::Ntsc);
+        assert_eq!(Region::Dendy.frame_rate(), PAL_FRAME_RATE);
+    }
+
+    #[test]
+    fn test_repair_header() {
+        let nestest = std::fs::read("nestest.nes").unwrap();
+        let repaired = repair_header(&nestest).unwrap();
+        assert_eq!(repaired.image, nestest);
+        assert!(repaired.fixes.is_empty());
+
+        // a copier header, "DiskDude!" and mapper 1 with vertical mirroring
+        let mut broken = vec![0; COPIER_HEADER_SIZE];
+        broken.extend(&nestest);
+        let header = &mut broken[COPIER_HEADER_SIZE..];
+        header[6] = 0x11;
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let repaired = repair_header(&broken).unwrap();
+        assert_eq!(repaired.image, nestest);
+        assert_eq!(repaired.fixes.len(), 4);
+        assert!(repaired.fixes[1].contains("DiskDude!"));
+
+        assert!(repair_header(&nestest[..100]).is_err());
+        assert!(repair_header(b"not a ROM").is_err());
+    }
+
+    #[test]
+    fn test_rejects_non_ines() {
+        assert!(RomInfo::new(b"NES").is_err());
+    }
+}
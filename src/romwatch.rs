@@ -0,0 +1,108 @@
+// Watching the loaded ROM file for homebrew development: rebuild the game and
+// the emulator picks the new ROM up without being restarted.
+//
+// The watcher polls the file's size and modification time, once a frame is
+// cheap enough, instead of depending on the OS's file notifications. A change
+// is only reported once the file looks the same on two polls in a row, so a
+// ROM the assembler is still writing isn't loaded half-way. A missing file,
+// as between a build deleting and rewriting it, isn't a change either.
+use crate::cartridge::Rom;
+use crate::error::RomError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+#[derive(Debug)]
+pub struct RomWatcher {
+    path: PathBuf,
+    loaded: Option<Stamp>,
+    /// A change seen on the last poll, not reported until it settles
+    pending: Option<Stamp>,
+}
+
+impl RomWatcher {
+    /// Watches `path` for changes from now on
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        RomWatcher {
+            loaded: stamp(&path),
+            path,
+            pending: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The rebuilt ROM once the file changed and settled, None until then
+    pub fn poll(&mut self) -> Option<Result<Rom, RomError>> {
+        let current = stamp(&self.path)?;
+        if Some(current) == self.loaded {
+            self.pending = None;
+            return None;
+        }
+        if self.pending != Some(current) {
+            self.pending = Some(current);
+            return None;
+        }
+        self.loaded = Some(current);
+        self.pending = None;
+        Some(Rom::from_file(&self.path))
+    }
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(Stamp {
+        modified: metadata.modified().ok()?,
+        len: metadata.len(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("romwatch_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.nes");
+        fs::write(&path, RomBuilder::new().prg_pages(1).build_image()).unwrap();
+
+        let mut watcher = RomWatcher::new(&path);
+        assert!(watcher.poll().is_none());
+        let rebuilt = RomBuilder::new()
+            .prg_pages(2)
+            .data(0x8000, &[0x42])
+            .reset_vector(0x8000)
+            .build_image();
+        fs::write(&path, rebuilt).unwrap();
+        // reported once the file stays the same for a poll
+        assert!(watcher.poll().is_none());
+        let rom = watcher.poll().unwrap().unwrap();
+        assert!(watcher.poll().is_none());
+
+        let mut cpu = CPU::new(Bus::new(RomBuilder::new().prg_pages(1).build()));
+        cpu.bus.prg_ram_mut()[0] = 7;
+        cpu.reload_rom(rom.clone(), true).unwrap();
+        assert_eq!((cpu.bus.peek(0x8000), cpu.bus.prg_ram()[0]), (0x42, 7));
+        assert_eq!(cpu.program_counter, 0x8000);
+        cpu.reload_rom(rom, false).unwrap();
+        assert_eq!(cpu.bus.prg_ram()[0], 0);
+
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cpu::CPU;
+use crate::harness::FrameInput;
+use crate::snapshot::Snapshot;
+
+/// Hides the game's own input lag: every frame the real machine advances one
+/// frame, then a copy of it runs `frames` more frames with the same input and
+/// the copy is what gets displayed
+pub struct RunAhead {
+    frames: usize,
+    ahead: Option<CPU>,
+    // the copy is brought up to date through a snapshot, which reuses its
+    // buffers, instead of a clone of the whole machine every frame
+    snapshot: Snapshot,
+}
+
+impl RunAhead {
+    pub fn new(frames: usize) -> Self {
+        RunAhead {
+            frames,
+            ahead: None,
+            snapshot: Snapshot::default(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn set_frames(&mut self, frames: usize) {
+        self.frames = frames;
+    }
+
+    /// Advances `cpu` by one frame and returns the machine to render from
+    pub fn run_frame<'a>(&'a mut self, cpu: &'a mut CPU, input: FrameInput) -> &'a CPU {
+        input.apply(cpu);
+        if !cpu.run_frame() || self.frames == 0 {
+            return cpu;
+        }
+
+        cpu.snapshot_into(&mut self.snapshot);
+        let restored = match self.ahead.as_mut() {
+            Some(ahead) => ahead.restore_snapshot(&self.snapshot).is_ok(),
+            None => false,
+        };
+        // first frame, or another cartridge
+        if !restored {
+            self.ahead = Some(cpu.clone());
+        }
+        let ahead = self.ahead.as_mut().expect("set above");
+        for _ in 0..self.frames {
+            if !ahead.run_frame() {
+                break;
+            }
+        }
+        ahead
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_run_ahead_shows_future_frames() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: strobe the controller, read A into $10, JMP loop
+        cpu.load(vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x85,
+            0x10, 0x4c, 0x00, 0x06,
+        ]);
+        cpu.program_counter = 0x0600;
+
+        let mut run_ahead = RunAhead::new(2);
+        let shown = run_ahead.run_frame(&mut cpu, FrameInput::new(0x01, 0));
+        assert_eq!(shown.bus.frame_count(), 3);
+        assert_eq!(shown.bus.peek(0x10), 1);
+        assert_eq!(cpu.bus.frame_count(), 1);
+
+        let shown = run_ahead.run_frame(&mut cpu, FrameInput::default());
+        assert_eq!(shown.bus.frame_count(), 4);
+        assert_eq!(shown.bus.peek(0x10), 0);
+
+        run_ahead.set_frames(0);
+        let shown = run_ahead.run_frame(&mut cpu, FrameInput::default());
+        assert_eq!(shown.bus.frame_count(), 3);
+    }
+}
@@ -0,0 +1,920 @@
+// Savestates: everything needed to resume emulation exactly where it left off,
+// apart from the ROM itself.
+//
+// The state is split into one plain struct per component so the serialized
+// layout doesn't change whenever internals get refactored. Each component
+// converts to and from its struct (`Bus::save_state`, `NesPPU::load_state`,
+// ...) and is serialized with bincode into its own section of a container:
+//
+//   "NESS"  format version (u16)  core version (u8 length + text)  flags (u8)
+//   section count (u16), then per section: tag (4 bytes), length (u32), data
+//
+// all little endian. With the `zstd` feature the section data is compressed,
+// which the COMPRESSED flag records; RAM and VRAM are mostly runs of the same
+// bytes, so states shrink to a fraction of their size.
+//
+// Sections are looked up by tag and unknown ones skipped, so optional sections
+// can be added without a version bump. There are four so far: ROM, the SHA1
+// and mapper of the cartridge the state was made with (loading a state made
+// for another ROM is refused unless forced, see `CPU::force_load_state`),
+// MAPR, the mapper's registers and CHR RAM, left out for boards that have
+// neither, APU, the sound channels and frame counter, and THMB, a small
+// picture of the screen for load menus. States without MAPR or APU leave the
+// mapper or APU as they are when loaded. Changing the layout of an existing
+// section needs a new FORMAT_VERSION and a step in `migrate`, older states
+// are then converted on load instead of desyncing.
+// Debugger bookkeeping like the shadow call stack isn't part of the state.
+use crate::cartridge::Mirroring;
+use crate::cpu::CPU;
+use crate::error::StateSizeError;
+use crate::prelude::*;
+#[cfg(feature = "serde-state")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde-state")]
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 4] = b"NESS";
+pub const FORMAT_VERSION: u16 = 3;
+/// Section data is zstd compressed
+const COMPRESSED: u8 = 0b0000_0001;
+#[cfg(feature = "zstd")]
+const COMPRESSION_LEVEL: i32 = 3;
+/// Far more than any section needs, decompressing stops there
+#[cfg(feature = "zstd")]
+const MAX_SECTION_SIZE: usize = 4 * 1024 * 1024;
+const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(feature = "serde-state")]
+const CPU_SECTION: [u8; 4] = *b"CPU ";
+#[cfg(feature = "serde-state")]
+const BUS_SECTION: [u8; 4] = *b"BUS ";
+#[cfg(feature = "serde-state")]
+const PPU_SECTION: [u8; 4] = *b"PPU ";
+#[cfg(feature = "serde-state")]
+const ROM_SECTION: [u8; 4] = *b"ROM ";
+#[cfg(feature = "serde-state")]
+const MAPPER_SECTION: [u8; 4] = *b"MAPR";
+#[cfg(feature = "serde-state")]
+const APU_SECTION: [u8; 4] = *b"APU ";
+#[cfg(feature = "serde-state")]
+const THUMBNAIL_SECTION: [u8; 4] = *b"THMB";
+
+/// Thumbnails are the frame scaled down by this in both directions
+pub const THUMBNAIL_SCALE: usize = 4;
+
+/// Identifies the cartridge a state belongs to
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct RomId {
+    /// Of the PRG and CHR data, see `Rom::sha1`
+    pub sha1: String,
+    pub mapper: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+}
+
+impl CpuState {
+    pub fn capture(cpu: &CPU) -> Self {
+        CpuState {
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            status: cpu.status.bits(),
+            program_counter: cpu.program_counter,
+            stack_pointer: cpu.stack_pointer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct BusState {
+    pub ram: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub cycles: u64,
+    pub frames: u64,
+    pub controllers: [u8; 2],
+    pub controller_shift: [u8; 2],
+    pub controller_strobe: bool,
+    /// An OAM DMA the CPU hasn't sat out the stall of yet
+    pub oam_dma: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct PpuState {
+    pub palette_table: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub oam_data: Vec<u8>,
+    pub mirroring: Mirroring,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    pub scroll_latch: bool,
+    pub addr: u16,
+    /// Whether the next PPUADDR write sets the high byte
+    pub addr_high_byte: bool,
+    pub internal_data_buf: u8,
+    pub oam_addr: u8,
+    pub scanline: u16,
+    pub dot: u64,
+    pub nmi_interrupt: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct MapperState {
+    /// As the mapper lays them out, see `Mapper::save_registers`
+    pub registers: Vec<u8>,
+    /// Empty for cartridges with CHR ROM
+    pub chr_ram: Vec<u8>,
+}
+
+impl MapperState {
+    /// NROM has neither registers nor CHR RAM
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.chr_ram.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct EnvelopeState {
+    pub start: bool,
+    pub looping: bool,
+    pub constant: bool,
+    pub volume: u8,
+    pub divider: u8,
+    pub decay: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct LengthState {
+    pub enabled: bool,
+    pub halted: bool,
+    pub count: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct PulseState {
+    pub duty: u8,
+    pub step: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub envelope: EnvelopeState,
+    pub length: LengthState,
+    pub sweep_enabled: bool,
+    pub sweep_period: u8,
+    pub sweep_negate: bool,
+    pub sweep_shift: u8,
+    pub sweep_reload: bool,
+    pub sweep_divider: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct TriangleState {
+    pub step: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub length: LengthState,
+    pub linear_reload_value: u8,
+    pub linear_counter: u8,
+    pub linear_reload: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct NoiseState {
+    pub short_mode: bool,
+    pub period: u16,
+    pub timer: u16,
+    pub shift: u16,
+    pub envelope: EnvelopeState,
+    pub length: LengthState,
+}
+
+/// The resampler and output filter aren't saved, they only shape the sound
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct ApuState {
+    pub pulse: [PulseState; 2],
+    pub triangle: TriangleState,
+    pub noise: NoiseState,
+    pub five_step: bool,
+    pub irq_inhibit: bool,
+    pub frame_irq: bool,
+    pub frame_cycle: u32,
+    pub odd_cycle: bool,
+    /// CPU cycles the APU is behind the bus, it catches up lazily
+    pub pending_cycles: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct SaveState {
+    pub cpu: CpuState,
+    pub bus: BusState,
+    pub ppu: PpuState,
+    /// None for boards without registers and CHR RAM, and for states written
+    /// before mappers were supported
+    pub mapper: Option<MapperState>,
+    /// None for states written before the APU was emulated
+    pub apu: Option<ApuState>,
+    /// None for states written before ROMs were recorded
+    pub rom: Option<RomId>,
+    /// Only there when the frontend adds one, `capture` can't see the screen
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// Downscaled RGB24 copy of the screen at the time the state was saved
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde-state", derive(Serialize, Deserialize))]
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Averages every `scale` x `scale` block of an RGB24 frame into one pixel
+    pub fn from_frame(frame: &[u8], width: usize, height: usize, scale: usize) -> Self {
+        assert_eq!(
+            frame.len(),
+            width * height * 3,
+            "frame is not {}x{} RGB24",
+            width,
+            height
+        );
+        let scale = scale.max(1);
+        let (thumb_width, thumb_height) = (width / scale, height / scale);
+        let mut pixels = Vec::with_capacity(thumb_width * thumb_height * 3);
+        for y in 0..thumb_height {
+            for x in 0..thumb_width {
+                for channel in 0..3 {
+                    let mut sum = 0;
+                    for dy in 0..scale {
+                        let row = (y * scale + dy) * width;
+                        for dx in 0..scale {
+                            sum += frame[(row + x * scale + dx) * 3 + channel] as usize;
+                        }
+                    }
+                    pixels.push((sum / (scale * scale)) as u8);
+                }
+            }
+        }
+        Thumbnail {
+            width: thumb_width as u16,
+            height: thumb_height as u16,
+            pixels,
+        }
+    }
+}
+
+impl SaveState {
+    pub fn capture(cpu: &CPU) -> Self {
+        SaveState {
+            cpu: CpuState::capture(cpu),
+            bus: cpu.bus.save_state(),
+            ppu: cpu.bus.save_ppu_state(),
+            mapper: Some(cpu.bus.save_mapper_state()).filter(|state| !state.is_empty()),
+            apu: Some(cpu.bus.save_apu_state()),
+            rom: Some(cpu.bus.rom_id().clone()),
+            thumbnail: None,
+        }
+    }
+
+    /// Errors when the state was made with a different ROM or ROM revision,
+    /// states that don't record their ROM are let through
+    pub fn check_rom(&self, rom: &RomId) -> Result<(), String> {
+        match &self.rom {
+            Some(saved) if saved != rom => Err(format!(
+                "Savestate was made with a different ROM (mapper {}, SHA1 {}), this one is mapper {}, SHA1 {}",
+                saved.mapper, saved.sha1, rom.mapper, rom.sha1
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "serde-state")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_container(cfg!(feature = "zstd")).to_bytes()
+    }
+
+    /// Uncompressed states of one console all have the same size and layout,
+    /// which delta encoding relies on
+    #[cfg(feature = "serde-state")]
+    pub fn to_uncompressed_bytes(&self) -> Vec<u8> {
+        self.to_container(false).to_bytes()
+    }
+
+    #[cfg(feature = "serde-state")]
+    fn to_container(&self, compressed: bool) -> Container {
+        let mut container = Container::new();
+        container.compressed = compressed;
+        container.add(CPU_SECTION, encode(&self.cpu));
+        container.add(BUS_SECTION, encode(&self.bus));
+        container.add(PPU_SECTION, encode(&self.ppu));
+        if let Some(mapper) = &self.mapper {
+            container.add(MAPPER_SECTION, encode(mapper));
+        }
+        if let Some(apu) = &self.apu {
+            container.add(APU_SECTION, encode(apu));
+        }
+        if let Some(rom) = &self.rom {
+            container.add(ROM_SECTION, encode(rom));
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            container.add(THUMBNAIL_SECTION, encode(thumbnail));
+        }
+        container
+    }
+
+    #[cfg(feature = "serde-state")]
+    pub fn from_bytes(data: &[u8]) -> Result<SaveState, String> {
+        let container = migrate(Container::from_bytes(data)?)?;
+        Ok(SaveState {
+            cpu: decode(&container, CPU_SECTION)?,
+            bus: decode(&container, BUS_SECTION)?,
+            ppu: decode(&container, PPU_SECTION)?,
+            mapper: decode_optional(&container, MAPPER_SECTION)?,
+            apu: decode_optional(&container, APU_SECTION)?,
+            rom: decode_optional(&container, ROM_SECTION)?,
+            thumbnail: decode_optional(&container, THUMBNAIL_SECTION)?,
+        })
+    }
+}
+
+/// The thumbnail of a state without restoring anything, for load menus
+#[cfg(feature = "serde-state")]
+pub fn read_thumbnail(data: &[u8]) -> Result<Option<Thumbnail>, String> {
+    let container = migrate(Container::from_bytes(data)?)?;
+    decode_optional(&container, THUMBNAIL_SECTION)
+}
+
+#[cfg(feature = "serde-state")]
+fn encode<T: Serialize>(section: &T) -> Vec<u8> {
+    bincode::serialize(section).expect("savestates always serialize")
+}
+
+#[cfg(feature = "serde-state")]
+fn decode<T: DeserializeOwned>(container: &Container, tag: [u8; 4]) -> Result<T, String> {
+    let name = String::from_utf8_lossy(&tag).trim().to_string();
+    let data = container
+        .section(tag)
+        .ok_or_else(|| format!("Savestate has no {} section", name))?;
+    bincode::deserialize(data)
+        .map_err(|e| format!("Malformed {} section in savestate: {}", name, e))
+}
+
+#[cfg(feature = "serde-state")]
+fn decode_optional<T: DeserializeOwned>(
+    container: &Container,
+    tag: [u8; 4],
+) -> Result<Option<T>, String> {
+    match container.section(tag) {
+        Some(_) => decode(container, tag).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Brings a container written by an older format version up to date
+#[cfg(feature = "serde-state")]
+fn migrate(mut container: Container) -> Result<Container, String> {
+    match container.format_version {
+        FORMAT_VERSION => Ok(container),
+        // version 1 had no flags in the header yet, which `from_bytes` reads
+        // around. Neither it nor version 2 had OAM DMA, so no DMA is pending
+        // at the end of the BUS section
+        1 | 2 => {
+            if let Some(bus) = container
+                .sections
+                .iter_mut()
+                .find(|section| section.tag == BUS_SECTION)
+            {
+                bus.data.push(false as u8);
+            }
+            Ok(Container {
+                format_version: FORMAT_VERSION,
+                ..container
+            })
+        }
+        version if version > FORMAT_VERSION => Err(format!(
+            "Savestate format {} is newer than this emulator supports ({}), it was made by version {}",
+            version, FORMAT_VERSION, container.core_version
+        )),
+        version => Err(format!(
+            "Savestate format {} from version {} can't be loaded anymore",
+            version, container.core_version
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub tag: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// The versioned file layout savestates are stored in, see the top of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    pub format_version: u16,
+    /// Version of the emulator that wrote the state
+    pub core_version: String,
+    /// Whether `to_bytes` compresses the sections. They are always kept
+    /// uncompressed in memory
+    pub compressed: bool,
+    pub sections: Vec<Section>,
+}
+
+impl Container {
+    pub fn new() -> Self {
+        Container {
+            format_version: FORMAT_VERSION,
+            core_version: CORE_VERSION.to_string(),
+            compressed: false,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Adds a section, replacing an earlier one with the same tag
+    pub fn add(&mut self, tag: [u8; 4], data: Vec<u8>) {
+        self.sections.retain(|section| section.tag != tag);
+        self.sections.push(Section { tag, data });
+    }
+
+    pub fn section(&self, tag: [u8; 4]) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|section| section.tag == tag)
+            .map(|section| section.data.as_slice())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.format_version.to_le_bytes());
+        let core_version = &self.core_version.as_bytes()[..self.core_version.len().min(255)];
+        out.push(core_version.len() as u8);
+        out.extend_from_slice(core_version);
+        out.push(if self.compressed { COMPRESSED } else { 0 });
+        out.extend_from_slice(&(self.sections.len() as u16).to_le_bytes());
+        for section in &self.sections {
+            let compressed;
+            let data = if self.compressed {
+                compressed = compress(&section.data);
+                &compressed
+            } else {
+                &section.data
+            };
+            out.extend_from_slice(&section.tag);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Container, String> {
+        if !data.starts_with(MAGIC) {
+            return Err("Not a savestate".to_string());
+        }
+        let mut reader = Reader {
+            data,
+            pos: MAGIC.len(),
+        };
+        let format_version = u16::from_le_bytes(reader.array()?);
+        let len = reader.take(1)?[0] as usize;
+        let core_version = String::from_utf8_lossy(reader.take(len)?).to_string();
+        let flags = if format_version >= 2 {
+            reader.take(1)?[0]
+        } else {
+            0
+        };
+        let compressed = flags & COMPRESSED != 0;
+        let count = u16::from_le_bytes(reader.array()?);
+        let mut sections = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let tag = reader.array()?;
+            let len = u32::from_le_bytes(reader.array()?) as usize;
+            let data = reader.take(len)?;
+            let data = if compressed {
+                decompress(data)?
+            } else {
+                data.to_vec()
+            };
+            sections.push(Section { tag, data });
+        }
+        Ok(Container {
+            format_version,
+            core_version,
+            compressed,
+            sections,
+        })
+    }
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Container::new()
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn compress(data: &[u8]) -> Vec<u8> {
+    zstd::bulk::compress(data, COMPRESSION_LEVEL).expect("compressing to memory can't fail")
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+    let malformed = |e| format!("Malformed compressed savestate: {}", e);
+    // a few bytes can claim gigabytes, read one past the limit to tell
+    let mut out = Vec::new();
+    zstd::stream::Decoder::new(data)
+        .map_err(malformed)?
+        .take(MAX_SECTION_SIZE as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(malformed)?;
+    if out.len() > MAX_SECTION_SIZE {
+        return Err("Compressed savestate section is too large".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress(_data: &[u8]) -> Vec<u8> {
+    panic!("savestate compression needs the zstd feature")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_data: &[u8]) -> Result<Vec<u8>, String> {
+    Err("Savestate is compressed, loading it needs the zstd feature".to_string())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len());
+        match end {
+            Some(end) => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            None => Err("Savestate is truncated".to_string()),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+/// Replaces the contents of `buffer`, keeping its allocation
+pub fn copy_into(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.clear();
+    buffer.extend_from_slice(data);
+}
+
+/// Checks that a saved buffer has the size the component expects
+pub fn check_len(
+    section: &'static str,
+    data: &[u8],
+    expected: usize,
+) -> Result<(), StateSizeError> {
+    if data.len() != expected {
+        return Err(StateSizeError {
+            section,
+            len: data.len(),
+            expected,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "serde-state"))]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+    use crate::cpu::Mem;
+
+    fn running_cpu() -> CPU {
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "LDA #$80
+                 STA $2000
+                 loop: INC $10
+                 LDX $10
+                 STX $6000
+                 JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = running_cpu();
+        cpu.run_frame();
+        cpu.bus.set_controller(0, 0x81);
+        let state = cpu.save_state();
+
+        let mut expected = cpu.clone();
+        for _ in 0..3 {
+            expected.run_frame();
+        }
+        for _ in 0..5 {
+            cpu.run_frame();
+        }
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.bus.controller(0), 0x81);
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        assert_eq!(SaveState::capture(&cpu), SaveState::capture(&expected));
+        assert_eq!(cpu.bus.peek(0x10), expected.bus.peek(0x10));
+    }
+
+    #[test]
+    fn test_pending_oam_dma_is_saved() {
+        let mut cpu = running_cpu();
+        cpu.mem_write(0x4014, 0x02);
+        let state = cpu.save_state();
+        let mut expected = cpu.clone();
+        expected.step();
+
+        let mut loaded = running_cpu();
+        loaded.load_state(&state).unwrap();
+        loaded.step();
+        assert_eq!(loaded.bus.cycles(), expected.bus.cycles());
+        assert!(loaded.bus.cycles() > 513);
+    }
+
+    #[test]
+    fn test_load_rejects_bad_states() {
+        let mut cpu = running_cpu();
+        assert!(cpu.load_state(&[1, 2, 3]).is_err());
+        let mut container = Container::new();
+        container.compressed = cfg!(feature = "zstd");
+        container.add(CPU_SECTION, vec![1]);
+        assert!(SaveState::from_bytes(&container.to_bytes())
+            .unwrap_err()
+            .starts_with("Malformed CPU section"));
+
+        let mut state = SaveState::capture(&cpu);
+        state.bus.ram.truncate(16);
+        let err = cpu.load_state(&state.to_bytes()).unwrap_err();
+        assert_eq!(err, "Malformed savestate: RAM is 16 bytes, expected 2048");
+    }
+
+    #[test]
+    fn test_container_versions_and_sections() {
+        let cpu = running_cpu();
+        let data = cpu.save_state();
+        assert!(data.starts_with(b"NESS\x03\x00"));
+
+        let mut container = Container::from_bytes(&data).unwrap();
+        assert_eq!(container.core_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(container.sections.len(), 5);
+
+        // sections this version doesn't know about are skipped
+        container.add(*b"XTRA", vec![1, 2, 3]);
+        assert!(SaveState::from_bytes(&container.to_bytes()).is_ok());
+
+        container.format_version = FORMAT_VERSION + 1;
+        let err = SaveState::from_bytes(&container.to_bytes()).unwrap_err();
+        assert!(err.starts_with("Savestate format 4 is newer"));
+
+        container.format_version = FORMAT_VERSION;
+        container
+            .sections
+            .retain(|section| section.tag != PPU_SECTION);
+        let err = SaveState::from_bytes(&container.to_bytes()).unwrap_err();
+        assert_eq!(err, "Savestate has no PPU section");
+
+        assert_eq!(
+            SaveState::from_bytes(&data[..data.len() - 1]),
+            Err("Savestate is truncated".to_string())
+        );
+        assert_eq!(
+            SaveState::from_bytes(b"PNG"),
+            Err("Not a savestate".to_string())
+        );
+    }
+
+    #[test]
+    fn test_old_states_are_migrated() {
+        let state = SaveState {
+            mapper: None,
+            apu: None,
+            rom: None,
+            thumbnail: None,
+            ..SaveState::capture(&running_cpu())
+        };
+        let mut container = Container::new();
+        container.add(CPU_SECTION, encode(&state.cpu));
+        // versions 1 and 2 had no pending OAM DMA at the end of BUS
+        let mut bus = encode(&state.bus);
+        assert_eq!(bus.pop(), Some(0));
+        container.add(BUS_SECTION, bus);
+        container.add(PPU_SECTION, encode(&state.ppu));
+        container.format_version = 2;
+        let mut data = container.to_bytes();
+        assert_eq!(SaveState::from_bytes(&data), Ok(state.clone()));
+        // version 1 had no flags byte after the core version either
+        let flags_at = 4 + 2 + 1 + CORE_VERSION.len();
+        data.remove(flags_at);
+        data[4] = 1;
+        assert_eq!(SaveState::from_bytes(&data), Ok(state));
+    }
+
+    #[test]
+    fn test_states_of_other_roms_are_refused() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        let other_rom = RomBuilder::new().fill_prg(0xEA).build();
+        let mut other = CPU::new(Bus::new(other_rom));
+        other.reset();
+        let before = SaveState::capture(&other);
+
+        let err = other.load_state(&state).unwrap_err();
+        assert!(err.starts_with("Savestate was made with a different ROM (mapper 0, SHA1 "));
+        assert_eq!(SaveState::capture(&other), before);
+
+        let warning = other.force_load_state(&state).unwrap();
+        assert_eq!(warning, Some(err));
+        assert_eq!(other.program_counter, cpu.program_counter);
+        assert_eq!(cpu.force_load_state(&state), Ok(None));
+
+        // states without a ROM section load anywhere
+        let mut container = Container::from_bytes(&state).unwrap();
+        container
+            .sections
+            .retain(|section| section.tag != ROM_SECTION);
+        assert!(other.load_state(&container.to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_mapper_state() {
+        // selects PRG bank 1 and writes to CHR RAM
+        let rom = RomBuilder::new()
+            .mapper(2)
+            .chr_pages(0)
+            .asm(
+                0xC000,
+                "LDA #$01
+                 STA $8000
+                 LDA #$00
+                 STA $2006
+                 STA $2006
+                 LDA #$42
+                 STA $2007
+                 BRK",
+            )
+            .unwrap()
+            .reset_vector(0xC000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        let before = cpu.save_state();
+        assert!(SaveState::from_bytes(&before).unwrap().mapper.is_some());
+        cpu.run();
+        let after = SaveState::capture(&cpu);
+        assert_eq!(after.mapper.as_ref().unwrap().registers, vec![1]);
+
+        cpu.load_state(&before).unwrap();
+        assert_eq!(cpu.bus.mapper().ppu_read(0), 0);
+        cpu.load_state(&after.to_bytes()).unwrap();
+        assert_eq!(cpu.bus.mapper().ppu_read(0), 0x42);
+        assert_eq!(cpu.bus.prg_rom_offset(0x8000), Some(0x4000));
+
+        // NROM states don't need the section
+        let state = SaveState::from_bytes(&running_cpu().save_state()).unwrap();
+        assert!(state.mapper.is_none());
+    }
+
+    #[test]
+    fn test_apu_state() {
+        // a square wave on pulse 1, the frame IRQ stays pending with
+        // interrupts disabled
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "LDA #$01
+                 STA $4015
+                 LDA #$BF
+                 STA $4000
+                 LDA #$FD
+                 STA $4002
+                 LDA #$08
+                 STA $4003
+                 LDA #$00
+                 STA $4017
+                 loop: JMP loop",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+        let state = cpu.save_state();
+        let apu = SaveState::from_bytes(&state).unwrap().apu.unwrap();
+        assert!(apu.frame_irq);
+        assert!(apu.pulse[0].length.count > 0);
+
+        let mut expected = cpu.clone();
+        expected.run_frame();
+        // acknowledging clears the IRQ, loading brings it back
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        cpu.mem_read(0x4015);
+        assert!(!cpu.bus.irq_pending());
+        cpu.load_state(&state).unwrap();
+        assert!(cpu.bus.irq_pending());
+        cpu.run_frame();
+        assert_eq!(SaveState::capture(&cpu), SaveState::capture(&expected));
+    }
+
+    #[test]
+    fn test_thumbnails() {
+        let mut frame = vec![0; 8 * 4 * 3];
+        // left half white, the top right pixel red
+        for y in 0..4 {
+            for x in 0..4 {
+                frame[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3].copy_from_slice(&[255, 255, 255]);
+            }
+        }
+        frame[7 * 3] = 255;
+        let thumbnail = Thumbnail::from_frame(&frame, 8, 4, 2);
+        assert_eq!((thumbnail.width, thumbnail.height), (4, 2));
+        assert_eq!(&thumbnail.pixels[..3], &[255, 255, 255]);
+        assert_eq!(&thumbnail.pixels[9..12], &[63, 0, 0]);
+        assert_eq!(&thumbnail.pixels[12..15], &[255, 255, 255]);
+
+        let cpu = running_cpu();
+        assert_eq!(read_thumbnail(&cpu.save_state()), Ok(None));
+        let mut state = SaveState::capture(&cpu);
+        state.thumbnail = Some(thumbnail.clone());
+        let data = state.to_bytes();
+        assert_eq!(read_thumbnail(&data), Ok(Some(thumbnail)));
+        assert_eq!(SaveState::from_bytes(&data), Ok(state));
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compressed_states() {
+        let mut cpu = running_cpu();
+        cpu.run_frame();
+        let data = cpu.save_state();
+        let container = Container::from_bytes(&data).unwrap();
+        assert!(container.compressed);
+        let raw: usize = container.sections.iter().map(|s| s.data.len()).sum();
+        assert!(data.len() * 4 < raw);
+        assert!(cpu.load_state(&data).is_ok());
+
+        // a section that decompresses to more than any state needs
+        let bomb = compress(&vec![0; MAX_SECTION_SIZE + 1]);
+        let mut container = Container::new();
+        container.compressed = true;
+        let mut data = container.to_bytes();
+        data.truncate(data.len() - 2);
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(b"CPU ");
+        data.extend_from_slice(&(bomb.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bomb);
+        assert!(Container::from_bytes(&data).is_err());
+    }
+}
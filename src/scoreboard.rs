@@ -0,0 +1,319 @@
+// Runs a directory of test ROMs headless and collects pass/fail per ROM, so
+// accuracy can be tracked over time.
+//
+// Results are read the way blargg's test ROMs report them: once $6001-$6003
+// hold DE B0 61, $6000 is the status (0x80 while running, 0x81 when the ROM
+// wants a reset, otherwise the result code where 0 is a pass) and $6004 starts
+// a zero terminated message. ROMs that only print their result on screen end
+// up as timeouts.
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::crashdump;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS: u16 = 0x6000;
+const MESSAGE: u16 = 0x6004;
+const RUNNING: u8 = 0x80;
+const RESET_REQUESTED: u8 = 0x81;
+/// The ROMs want the reset to come at least 100ms after they ask for it
+const RESET_DELAY_FRAMES: usize = 6;
+/// A minute of emulated time, enough for the slowest blargg tests
+pub const DEFAULT_MAX_FRAMES: usize = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// Result code reported by the ROM
+    Failed(u8),
+    /// No result within the frame limit
+    Timeout,
+    /// The ROM couldn't be loaded or brought the emulator down
+    Error(String),
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Passed => "pass",
+            Outcome::Failed(_) => "fail",
+            Outcome::Timeout => "timeout",
+            Outcome::Error(_) => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    /// What the ROM wrote to its message buffer, or the error
+    pub message: String,
+    pub frames: usize,
+}
+
+/// Runs one ROM until it reports a result or `max_frames` have passed
+pub fn run_rom(name: &str, rom: Rom, max_frames: usize) -> TestResult {
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    let mut frames = 0;
+    let mut reset_at = None;
+    let result = crashdump::catch_panic(|| {
+        while frames < max_frames {
+            if !cpu.run_frame() {
+                return Err(match cpu.bus.take_fault() {
+                    Some(fault) => fault.to_string(),
+                    None => "CPU halted on BRK".to_string(),
+                });
+            }
+            frames += 1;
+            if !has_signature(&cpu) {
+                continue;
+            }
+            match cpu.bus.peek(STATUS) {
+                RUNNING => {}
+                RESET_REQUESTED => match reset_at {
+                    Some(frame) if frames >= frame => {
+                        reset_at = None;
+                        cpu.reset();
+                    }
+                    Some(_) => {}
+                    None => reset_at = Some(frames + RESET_DELAY_FRAMES),
+                },
+                0 => return Ok(Outcome::Passed),
+                code => return Ok(Outcome::Failed(code)),
+            }
+        }
+        Ok(Outcome::Timeout)
+    });
+    let (outcome, message) = match result {
+        Ok(Ok(outcome)) => (outcome, read_message(&cpu)),
+        Ok(Err(e)) => (Outcome::Error(e.clone()), e),
+        Err(e) => (Outcome::Error(e.clone()), e),
+    };
+    TestResult {
+        name: name.to_string(),
+        outcome,
+        message,
+        frames,
+    }
+}
+
+fn has_signature(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.bus.peek(STATUS + 1 + i) == SIGNATURE[i as usize])
+}
+
+fn read_message(cpu: &CPU) -> String {
+    if !has_signature(cpu) {
+        return String::new();
+    }
+    let bytes: Vec<u8> = (MESSAGE..0x8000)
+        .map(|addr| cpu.bus.peek(addr))
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scoreboard {
+    pub results: Vec<TestResult>,
+}
+
+impl Scoreboard {
+    /// Runs every .nes file under `dir`, subdirectories included, in path order
+    pub fn run_dir<P: AsRef<Path>>(dir: P, max_frames: usize) -> Result<Scoreboard, String> {
+        let dir = dir.as_ref();
+        let mut paths = vec![];
+        find_roms(dir, &mut paths)?;
+        paths.sort();
+
+        let mut results = vec![];
+        for path in paths {
+            let name = path
+                .strip_prefix(dir)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            results.push(match Rom::from_file(&path) {
+                Ok(rom) => run_rom(&name, rom, max_frames),
+                Err(e) => TestResult {
+                    name,
+                    outcome: Outcome::Error(e.to_string()),
+                    message: e.to_string(),
+                    frames: 0,
+                },
+            });
+        }
+        Ok(Scoreboard { results })
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome == Outcome::Passed)
+            .count()
+    }
+
+    /// The report as one JSON object, for tracking results across runs
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "{{\"passed\":{},\"total\":{},\"tests\":[",
+            self.passed(),
+            self.results.len()
+        );
+        for (i, result) in self.results.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let code = match result.outcome {
+                Outcome::Failed(code) => code.to_string(),
+                _ => "null".to_string(),
+            };
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"result\":\"{}\",\"code\":{},\"message\":\"{}\",\"frames\":{}}}",
+                json_escape(&result.name),
+                result.outcome.name(),
+                code,
+                json_escape(&result.message),
+                result.frames
+            );
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl fmt::Display for Scoreboard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for result in &self.results {
+            let outcome = match result.outcome {
+                Outcome::Failed(code) => format!("fail #{}", code),
+                ref outcome => outcome.name().to_string(),
+            };
+            // only the first line, the full message is in the JSON report
+            let message = result.message.lines().next().unwrap_or("");
+            writeln!(f, "{:<8} {:<40} {}", outcome, result.name, message)?;
+        }
+        write!(f, "{}/{} passed", self.passed(), self.results.len())
+    }
+}
+
+fn find_roms(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Can't read {}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_dir() {
+            find_roms(&path, paths)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn json_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::RomBuilder;
+
+    /// ROM that reports `code` and "ok" the way blargg's tests do
+    fn reporting_rom(code: u8) -> RomBuilder {
+        let source = format!(
+            "LDA #$DE
+             STA $6001
+             LDA #$B0
+             STA $6002
+             LDA #$61
+             STA $6003
+             LDA #$6F
+             STA $6004
+             LDA #$6B
+             STA $6005
+             LDA #0
+             STA $6006
+             LDA #{}
+             STA $6000
+             loop: JMP loop",
+            code
+        );
+        RomBuilder::new()
+            .asm(0x8000, &source)
+            .unwrap()
+            .reset_vector(0x8000)
+    }
+
+    #[test]
+    fn test_run_rom_outcomes() {
+        let result = run_rom("pass", reporting_rom(0).build(), 10);
+        assert_eq!(result.outcome, Outcome::Passed);
+        assert_eq!(result.message, "ok");
+        assert_eq!(result.frames, 1);
+
+        let result = run_rom("fail", reporting_rom(3).build(), 10);
+        assert_eq!(result.outcome, Outcome::Failed(3));
+
+        let looping = RomBuilder::new()
+            .asm(0x8000, "loop: JMP loop")
+            .unwrap()
+            .reset_vector(0x8000);
+        let result = run_rom("timeout", looping.build(), 5);
+        assert_eq!((result.outcome, result.frames), (Outcome::Timeout, 5));
+
+        let crashing = RomBuilder::new()
+            .asm(0x8000, "STA $8000")
+            .unwrap()
+            .reset_vector(0x8000);
+        let result = run_rom("crash", crashing.build(), 5);
+        assert!(result.message.contains("Cartridge ROM"));
+        assert_eq!(result.outcome.name(), "error");
+    }
+
+    #[test]
+    fn test_run_dir_report() {
+        let dir = std::env::temp_dir().join(format!("nes_scoreboard_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("cpu")).unwrap();
+        std::fs::write(dir.join("cpu/a.nes"), reporting_rom(0).build_image()).unwrap();
+        std::fs::write(dir.join("b.nes"), reporting_rom(2).build_image()).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a rom").unwrap();
+
+        let scoreboard = Scoreboard::run_dir(&dir, 10).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = scoreboard.results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["b.nes", "cpu/a.nes"]);
+        assert_eq!(scoreboard.passed(), 1);
+        assert!(scoreboard.to_string().ends_with("1/2 passed"));
+        assert_eq!(
+            scoreboard.to_json(),
+            "{\"passed\":1,\"total\":2,\"tests\":[\
+             {\"name\":\"b.nes\",\"result\":\"fail\",\"code\":2,\"message\":\"ok\",\"frames\":1},\
+             {\"name\":\"cpu/a.nes\",\"result\":\"pass\",\"code\":null,\"message\":\"ok\",\"frames\":1}]}"
+        );
+        assert!(Scoreboard::run_dir(dir.join("missing"), 10).is_err());
+    }
+}
@@ -0,0 +1,461 @@
+// Lua scripting host, modelled after FCEUX's scripting API:
+//
+//   memory.readbyte(addr)  memory.readbytesigned(addr)  memory.readword(addr)
+//   memory.writebyte(addr, value)
+//   memory.readregion(region, offset)  memory.writeregion(region, offset, value)
+//                                      -- cpu, ram, prgram, vram, oam, palette
+//   memory.getregister(name)  memory.setregister(name, value)   -- a, x, y, s, p, pc
+//   emu.frameadvance()  emu.framecount()  emu.message(text)
+//   emu.registerbefore(fn)  emu.registerafter(fn)
+//   joypad.set(port, {A=true, B=false, select=..., start=..., up=..., down=..., left=..., right=...})
+//   gui.text(x, y, text [, color])  gui.pixel(x, y, color)  gui.box(x1, y1, x2, y2, color)
+//
+// The script body runs as a coroutine, emu.frameadvance() suspends it until the
+// next frame has been emulated.
+use crate::cpu::{CpuFlags, Mem, CPU};
+use crate::memview::MemoryRegion;
+use crate::render::osd::{self, MessageKind, Osd};
+use mlua::{Function, Lua, Table, Thread, ThreadStatus, Value};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+const MAIN_THREAD: &str = "nes_main_thread";
+const BEFORE_FRAME: &str = "nes_before_frame";
+const AFTER_FRAME: &str = "nes_after_frame";
+
+type Color = (u8, u8, u8);
+
+enum Drawing {
+    Text(usize, usize, String, Color),
+    Pixel(usize, usize, Color),
+    Box(usize, usize, usize, usize, Color),
+}
+
+#[derive(Default)]
+struct ScriptState {
+    frame: u64,
+    input: [Option<u8>; 2],
+    drawings: Vec<Drawing>,
+    messages: Vec<String>,
+}
+
+pub struct ScriptHost {
+    lua: Lua,
+    state: Rc<RefCell<ScriptState>>,
+}
+
+impl ScriptHost {
+    pub fn new(source: &str, name: &str) -> Result<Self, String> {
+        let host = ScriptHost {
+            lua: Lua::new(),
+            state: Rc::new(RefCell::new(ScriptState::default())),
+        };
+        host.install_api().map_err(|e| e.to_string())?;
+
+        let main = host
+            .lua
+            .load(source)
+            .set_name(name)
+            .into_function()
+            .map_err(|e| e.to_string())?;
+        let thread = host.lua.create_thread(main).map_err(|e| e.to_string())?;
+        host.lua
+            .set_named_registry_value(MAIN_THREAD, thread)
+            .map_err(|e| e.to_string())?;
+        Ok(host)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        ScriptHost::new(&source, &path.display().to_string())
+    }
+
+    /// Runs the registerbefore callback. Drawings from the previous frame are dropped
+    pub fn before_frame(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        self.state.borrow_mut().drawings.clear();
+        self.with_machine(cpu, |lua| call_registered(lua, BEFORE_FRAME))
+    }
+
+    /// Resumes the script body and runs the registerafter callback. Messages posted
+    /// by the script are forwarded to the OSD
+    pub fn after_frame(&mut self, cpu: &mut CPU, osd: &mut Osd) -> Result<(), String> {
+        let result = self.with_machine(cpu, |lua| {
+            let thread: Thread = lua.named_registry_value(MAIN_THREAD)?;
+            if thread.status() == ThreadStatus::Resumable {
+                thread.resume::<_, ()>(())?;
+            }
+            call_registered(lua, AFTER_FRAME)
+        });
+
+        let mut state = self.state.borrow_mut();
+        state.frame += 1;
+        for message in state.messages.drain(..) {
+            osd.push(MessageKind::Info, &message);
+        }
+        result
+    }
+
+    /// Controller state requested with joypad.set for the upcoming frame
+    pub fn take_input(&mut self, port: usize) -> Option<u8> {
+        self.state.borrow_mut().input.get_mut(port)?.take()
+    }
+
+    pub fn draw(&self, frame: &mut [u8], width: usize, height: usize) {
+        for drawing in self.state.borrow().drawings.iter() {
+            match *drawing {
+                Drawing::Text(x, y, ref text, color) => {
+                    osd::draw_text(frame, width, height, x, y, text, color)
+                }
+                Drawing::Pixel(x, y, color) => put_pixel(frame, width, height, x, y, color),
+                Drawing::Box(x1, y1, x2, y2, color) => {
+                    for x in x1..=x2 {
+                        put_pixel(frame, width, height, x, y1, color);
+                        put_pixel(frame, width, height, x, y2, color);
+                    }
+                    for y in y1..=y2 {
+                        put_pixel(frame, width, height, x1, y, color);
+                        put_pixel(frame, width, height, x2, y, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn install_api(&self) -> mlua::Result<()> {
+        let lua = &self.lua;
+        let globals = lua.globals();
+
+        // memory functions are bound to the machine for the duration of a callback
+        globals.set("memory", lua.create_table()?)?;
+
+        let emu = lua.create_table()?;
+        let coroutine: Table = globals.get("coroutine")?;
+        emu.set("frameadvance", coroutine.get::<_, Function>("yield")?)?;
+        let state = self.state.clone();
+        emu.set(
+            "framecount",
+            lua.create_function(move |_, ()| Ok(state.borrow().frame))?,
+        )?;
+        let state = self.state.clone();
+        emu.set(
+            "message",
+            lua.create_function(move |_, text: String| {
+                state.borrow_mut().messages.push(text);
+                Ok(())
+            })?,
+        )?;
+        emu.set(
+            "registerbefore",
+            lua.create_function(|lua, f: Option<Function>| {
+                lua.set_named_registry_value(BEFORE_FRAME, f)
+            })?,
+        )?;
+        emu.set(
+            "registerafter",
+            lua.create_function(|lua, f: Option<Function>| {
+                lua.set_named_registry_value(AFTER_FRAME, f)
+            })?,
+        )?;
+        globals.set("emu", emu)?;
+
+        let joypad = lua.create_table()?;
+        let state = self.state.clone();
+        joypad.set(
+            "set",
+            lua.create_function(move |_, (port, buttons): (usize, Table)| {
+                let mut state = state.borrow_mut();
+                match state.input.get_mut(port.wrapping_sub(1)) {
+                    Some(input) => *input = Some(buttons_to_byte(&buttons)?),
+                    None => {
+                        return Err(mlua::Error::RuntimeError(format!("invalid port {}", port)))
+                    }
+                }
+                Ok(())
+            })?,
+        )?;
+        globals.set("joypad", joypad)?;
+
+        let gui = lua.create_table()?;
+        let state = self.state.clone();
+        gui.set(
+            "text",
+            lua.create_function(
+                move |_, (x, y, text, color): (usize, usize, String, Value)| {
+                    let color = parse_color(color, (0xFF, 0xFF, 0xFF))?;
+                    state
+                        .borrow_mut()
+                        .drawings
+                        .push(Drawing::Text(x, y, text, color));
+                    Ok(())
+                },
+            )?,
+        )?;
+        let state = self.state.clone();
+        gui.set(
+            "pixel",
+            lua.create_function(move |_, (x, y, color): (usize, usize, Value)| {
+                let color = parse_color(color, (0xFF, 0xFF, 0xFF))?;
+                state
+                    .borrow_mut()
+                    .drawings
+                    .push(Drawing::Pixel(x, y, color));
+                Ok(())
+            })?,
+        )?;
+        let state = self.state.clone();
+        gui.set(
+            "box",
+            lua.create_function(
+                move |_, (x1, y1, x2, y2, color): (usize, usize, usize, usize, Value)| {
+                    let color = parse_color(color, (0xFF, 0xFF, 0xFF))?;
+                    let drawing =
+                        Drawing::Box(x1.min(x2), y1.min(y2), x1.max(x2), y1.max(y2), color);
+                    state.borrow_mut().drawings.push(drawing);
+                    Ok(())
+                },
+            )?,
+        )?;
+        globals.set("gui", gui)?;
+        Ok(())
+    }
+
+    fn with_machine<F>(&self, cpu: &mut CPU, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&Lua) -> mlua::Result<()>,
+    {
+        let cpu = RefCell::new(cpu);
+        let lua = &self.lua;
+        lua.scope(|scope| {
+            let memory: Table = lua.globals().get("memory")?;
+            memory.set(
+                "readbyte",
+                scope.create_function(|_, addr: u16| Ok(cpu.borrow_mut().mem_read(addr)))?,
+            )?;
+            memory.set(
+                "readbytesigned",
+                scope.create_function(|_, addr: u16| Ok(cpu.borrow_mut().mem_read(addr) as i8))?,
+            )?;
+            memory.set(
+                "readword",
+                scope.create_function(|_, addr: u16| Ok(cpu.borrow_mut().mem_read_u16(addr)))?,
+            )?;
+            memory.set(
+                "writebyte",
+                scope.create_function(|_, (addr, value): (u16, u8)| {
+                    cpu.borrow_mut().mem_write(addr, value);
+                    Ok(())
+                })?,
+            )?;
+            memory.set(
+                "readregion",
+                scope.create_function(|_, (name, offset): (String, usize)| {
+                    let region = memory_region(&name)?;
+                    Ok(region.read(&cpu.borrow(), offset))
+                })?,
+            )?;
+            memory.set(
+                "writeregion",
+                scope.create_function(|_, (name, offset, value): (String, usize, u8)| {
+                    let region = memory_region(&name)?;
+                    region
+                        .write(&mut cpu.borrow_mut(), offset, value)
+                        .map_err(mlua::Error::RuntimeError)
+                })?,
+            )?;
+            memory.set(
+                "getregister",
+                scope.create_function(|_, name: String| {
+                    let cpu = cpu.borrow();
+                    Ok(match name.to_ascii_lowercase().as_str() {
+                        "a" => cpu.register_a as u16,
+                        "x" => cpu.register_x as u16,
+                        "y" => cpu.register_y as u16,
+                        "s" => cpu.stack_pointer as u16,
+                        "p" => cpu.status.bits() as u16,
+                        "pc" => cpu.program_counter,
+                        _ => return Err(unknown_register(&name)),
+                    })
+                })?,
+            )?;
+            memory.set(
+                "setregister",
+                scope.create_function(|_, (name, value): (String, u16)| {
+                    let mut cpu = cpu.borrow_mut();
+                    match name.to_ascii_lowercase().as_str() {
+                        "a" => cpu.register_a = value as u8,
+                        "x" => cpu.register_x = value as u8,
+                        "y" => cpu.register_y = value as u8,
+                        "s" => cpu.stack_pointer = value as u8,
+                        "p" => cpu.status = CpuFlags::from_bits_truncate(value as u8),
+                        "pc" => cpu.program_counter = value,
+                        _ => return Err(unknown_register(&name)),
+                    }
+                    Ok(())
+                })?,
+            )?;
+            f(lua)
+        })
+        .map_err(|e| e.to_string())
+    }
+}
+
+fn call_registered(lua: &Lua, key: &str) -> mlua::Result<()> {
+    match lua.named_registry_value::<Option<Function>>(key)? {
+        Some(callback) => callback.call(()),
+        None => Ok(()),
+    }
+}
+
+fn memory_region(name: &str) -> mlua::Result<MemoryRegion> {
+    MemoryRegion::from_name(name)
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("unknown memory region '{}'", name)))
+}
+
+fn unknown_register(name: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("unknown register '{}'", name))
+}
+
+fn buttons_to_byte(buttons: &Table) -> mlua::Result<u8> {
+    // same bit order as the FM2 movies: RLDUTSBA
+    const NAMES: [(&str, u8); 8] = [
+        ("right", 0x80),
+        ("left", 0x40),
+        ("down", 0x20),
+        ("up", 0x10),
+        ("start", 0x08),
+        ("select", 0x04),
+        ("B", 0x02),
+        ("A", 0x01),
+    ];
+    let mut result = 0;
+    for &(name, bit) in NAMES.iter() {
+        if buttons.get::<_, Option<bool>>(name)?.unwrap_or(false) {
+            result |= bit;
+        }
+    }
+    Ok(result)
+}
+
+fn parse_color(value: Value, default: Color) -> mlua::Result<Color> {
+    match value {
+        Value::Nil => Ok(default),
+        Value::Integer(rgb) => Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        Value::String(name) => {
+            let name = name.to_str()?.to_ascii_lowercase();
+            match name.as_str() {
+                "white" => Ok((0xFF, 0xFF, 0xFF)),
+                "black" => Ok((0x00, 0x00, 0x00)),
+                "red" => Ok((0xFF, 0x00, 0x00)),
+                "green" => Ok((0x00, 0xFF, 0x00)),
+                "blue" => Ok((0x00, 0x00, 0xFF)),
+                "yellow" => Ok((0xFF, 0xFF, 0x00)),
+                _ if name.len() == 7 && name.starts_with('#') => {
+                    u32::from_str_radix(&name[1..], 16)
+                        .map(|rgb| ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+                        .map_err(|_| mlua::Error::RuntimeError(format!("invalid color '{}'", name)))
+                }
+                _ => Err(mlua::Error::RuntimeError(format!(
+                    "invalid color '{}'",
+                    name
+                ))),
+            }
+        }
+        _ => Err(mlua::Error::RuntimeError("invalid color".to_string())),
+    }
+}
+
+fn put_pixel(frame: &mut [u8], width: usize, height: usize, x: usize, y: usize, color: Color) {
+    if x >= width || y >= height {
+        return;
+    }
+    let idx = (y * width + x) * 3;
+    frame[idx] = color.0;
+    frame[idx + 1] = color.1;
+    frame[idx + 2] = color.2;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    fn test_cpu() -> CPU {
+        CPU::new(Bus::new(test::test_rom()))
+    }
+
+    #[test]
+    fn test_memory_and_registers() {
+        let mut cpu = test_cpu();
+        cpu.register_x = 0x42;
+        let mut host = ScriptHost::new(
+            r#"
+            while true do
+                memory.writebyte(0x10, memory.readbyte(0x10) + 1)
+                memory.setregister("a", memory.getregister("x"))
+                memory.writeregion("palette", 1, memory.readregion("ram", 0x10))
+                emu.frameadvance()
+            end
+            "#,
+            "test",
+        )
+        .unwrap();
+
+        let mut osd = Osd::new();
+        for _ in 0..3 {
+            host.before_frame(&mut cpu).unwrap();
+            host.after_frame(&mut cpu, &mut osd).unwrap();
+        }
+        assert_eq!(cpu.mem_read(0x10), 3);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.bus.ppu().palette_table[1], 3);
+    }
+
+    #[test]
+    fn test_callbacks_input_and_drawing() {
+        let mut cpu = test_cpu();
+        let mut host = ScriptHost::new(
+            r##"
+            emu.registerbefore(function()
+                joypad.set(1, {A = true, right = true})
+            end)
+            emu.registerafter(function()
+                gui.pixel(0, 0, "#102030")
+                emu.message("frame " .. emu.framecount())
+            end)
+            "##,
+            "test",
+        )
+        .unwrap();
+
+        let mut osd = Osd::new();
+        host.before_frame(&mut cpu).unwrap();
+        host.after_frame(&mut cpu, &mut osd).unwrap();
+        assert_eq!(host.take_input(0), None);
+
+        host.before_frame(&mut cpu).unwrap();
+        assert_eq!(host.take_input(0), Some(0x81));
+        assert_eq!(host.take_input(1), None);
+        host.after_frame(&mut cpu, &mut osd).unwrap();
+
+        let messages: Vec<&str> = osd.messages().map(|m| m.text.as_str()).collect();
+        assert_eq!(messages, vec!["frame 0", "frame 1"]);
+
+        let mut frame = vec![0; 4 * 4 * 3];
+        host.draw(&mut frame, 4, 4);
+        assert_eq!(&frame[0..3], &[0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn test_script_errors_are_reported() {
+        let mut cpu = test_cpu();
+        assert!(ScriptHost::new("this is not lua", "test").is_err());
+
+        let mut host = ScriptHost::new("memory.getregister('q')", "test").unwrap();
+        let err = host.after_frame(&mut cpu, &mut Osd::new()).unwrap_err();
+        assert!(err.contains("unknown register 'q'"));
+    }
+}
@@ -0,0 +1,134 @@
+// In-memory snapshots for run-ahead, rollback and anything else that needs to
+// go back in time several times per frame.
+//
+// Unlike savestates nothing is serialized or compressed: a snapshot is the
+// component state structs plus the debugger's call stack, copied as they are.
+// Taking a snapshot into one that was used before only copies into its
+// existing buffers, so once a `SnapshotPool` has warmed up no allocation
+// happens at all. Snapshots only make sense for the console they were taken
+// from and are never written anywhere, so they have no versioning either.
+use crate::cpu::{CallFrame, StackOrigin, CPU, MAX_CALL_DEPTH};
+use crate::prelude::*;
+use crate::savestate::{ApuState, BusState, CpuState, MapperState, PpuState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub(crate) cpu: CpuState,
+    pub(crate) bus: BusState,
+    pub(crate) ppu: PpuState,
+    pub(crate) mapper: MapperState,
+    pub(crate) apu: ApuState,
+    pub(crate) call_stack: Vec<CallFrame>,
+    pub(crate) stack_origins: [StackOrigin; 256],
+}
+
+impl Snapshot {
+    /// Frame counter at the time the snapshot was taken
+    pub fn frame(&self) -> u64 {
+        self.bus.frames
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            cpu: CpuState::default(),
+            bus: BusState::default(),
+            ppu: PpuState::default(),
+            mapper: MapperState::default(),
+            apu: ApuState::default(),
+            call_stack: Vec::with_capacity(MAX_CALL_DEPTH),
+            stack_origins: [StackOrigin::Unknown; 256],
+        }
+    }
+}
+
+/// Snapshots that are no longer needed, kept to take the next ones into
+#[derive(Default)]
+pub struct SnapshotPool {
+    free: Vec<Snapshot>,
+}
+
+impl SnapshotPool {
+    pub fn new() -> Self {
+        SnapshotPool { free: Vec::new() }
+    }
+
+    /// Takes a snapshot of `cpu`, reusing a released one when there is one
+    pub fn take(&mut self, cpu: &CPU) -> Snapshot {
+        let mut snapshot = self.free.pop().unwrap_or_default();
+        cpu.snapshot_into(&mut snapshot);
+        snapshot
+    }
+
+    pub fn release(&mut self, snapshot: Snapshot) {
+        self.free.push(snapshot);
+    }
+
+    /// Number of snapshots ready for reuse
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::RomBuilder;
+    use crate::savestate::SaveState;
+
+    fn counting_cpu() -> CPU {
+        let rom = RomBuilder::new()
+            .asm(
+                0x8000,
+                "loop: INC $10
+                 JSR count
+                 JMP loop
+                 count: INC $6000
+                 RTS",
+            )
+            .unwrap()
+            .reset_vector(0x8000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut cpu = counting_cpu();
+        cpu.run_frame();
+        let snapshot = cpu.snapshot();
+        assert_eq!(snapshot.frame(), 1);
+        let expected = SaveState::capture(&cpu);
+
+        let mut future = cpu.clone();
+        future.run_frame();
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        cpu.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(SaveState::capture(&cpu), expected);
+        cpu.run_frame();
+        assert_eq!(SaveState::capture(&cpu), SaveState::capture(&future));
+        assert_eq!(cpu.call_stack(), future.call_stack());
+    }
+
+    #[test]
+    fn test_pool_reuses_snapshots() {
+        let mut cpu = counting_cpu();
+        let mut pool = SnapshotPool::new();
+        let first = pool.take(&cpu);
+        let ram = first.bus.ram.as_ptr();
+        pool.release(first);
+        assert_eq!(pool.available(), 1);
+
+        cpu.run_frame();
+        let second = pool.take(&cpu);
+        assert_eq!(second.bus.ram.as_ptr(), ram);
+        assert_eq!(second, cpu.snapshot());
+        assert_eq!(pool.available(), 0);
+    }
+}
@@ -0,0 +1,355 @@
+// Canonical hash of the console state, for desync detection in netplay,
+// replay verification and regression tests.
+//
+// Two consoles that will behave the same from here on hash the same, on any
+// host and in any build. Included, in this order:
+//   CPU     A, X, Y, P, SP, PC
+//   bus     RAM, PRG RAM, cycle and frame counters, controller buttons, shift
+//           registers and strobe, and a pending OAM DMA stall, only while
+//           there is one, so hashes stayed as they were before OAM DMA
+//   PPU     VRAM, OAM, palette, PPUCTRL, PPUMASK, PPUSTATUS, OAMADDR, scroll
+//           and its latch, PPUADDR and its latch, the PPUDATA read buffer,
+//           scanline, dot and a pending NMI
+//   mapper  registers and CHR RAM, only for boards that have either, so NROM
+//           consoles hash the same as before there were mappers
+//   APU     each channel's registers, timers, envelope, length counter and
+//           sweep, the frame counter and its IRQ, and the cycles the APU is
+//           behind the bus
+// which is everything a savestate restores apart from the mirroring, which
+// the header or the mapper's registers decide. Left out is what can't differ
+// between two runs of the same ROM or doesn't affect emulation: the ROM itself,
+// debugger bookkeeping (call stack, stack origins), logs and heatmaps, audio
+// samples and host timing.
+//
+// The hash is 64 bit FNV-1a over the fields above, multi-byte values little
+// endian and buffers prefixed with their length.
+use crate::cpu::CPU;
+use crate::savestate::{
+    ApuState, BusState, CpuState, EnvelopeState, LengthState, MapperState, NoiseState, PpuState,
+    PulseState, TriangleState,
+};
+use crate::snapshot::Snapshot;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+struct Fnv(u64);
+
+impl Fnv {
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn buffer(&mut self, buffer: &[u8]) {
+        self.bytes(&(buffer.len() as u64).to_le_bytes());
+        self.bytes(buffer);
+    }
+}
+
+pub fn state_hash(cpu: &CPU) -> u64 {
+    hash_states(
+        &CpuState::capture(cpu),
+        &cpu.bus.save_state(),
+        &cpu.bus.save_ppu_state(),
+        &cpu.bus.save_mapper_state(),
+        &cpu.bus.save_apu_state(),
+    )
+}
+
+/// `state_hash` of the console at the time the snapshot was taken
+pub fn snapshot_hash(snapshot: &Snapshot) -> u64 {
+    hash_states(
+        &snapshot.cpu,
+        &snapshot.bus,
+        &snapshot.ppu,
+        &snapshot.mapper,
+        &snapshot.apu,
+    )
+}
+
+/// The fields are destructured so a new one can't be added to the state
+/// without deciding whether it belongs in the hash
+fn hash_states(
+    cpu: &CpuState,
+    bus: &BusState,
+    ppu: &PpuState,
+    mapper: &MapperState,
+    apu: &ApuState,
+) -> u64 {
+    let mut hash = Fnv(FNV_OFFSET);
+
+    let CpuState {
+        a,
+        x,
+        y,
+        status,
+        program_counter,
+        stack_pointer,
+    } = cpu;
+    hash.bytes(&[*a, *x, *y, *status, *stack_pointer]);
+    hash.bytes(&program_counter.to_le_bytes());
+
+    let BusState {
+        ram,
+        prg_ram,
+        cycles,
+        frames,
+        controllers,
+        controller_shift,
+        controller_strobe,
+        oam_dma,
+    } = bus;
+    hash.buffer(ram);
+    hash.buffer(prg_ram);
+    hash.bytes(&cycles.to_le_bytes());
+    hash.bytes(&frames.to_le_bytes());
+    hash.bytes(controllers);
+    hash.bytes(controller_shift);
+    hash.bytes(&[*controller_strobe as u8]);
+    if *oam_dma {
+        hash.bytes(&[1]);
+    }
+
+    let PpuState {
+        palette_table,
+        vram,
+        oam_data,
+        mirroring: _,
+        ctrl,
+        mask,
+        status,
+        scroll_x,
+        scroll_y,
+        scroll_latch,
+        addr,
+        addr_high_byte,
+        internal_data_buf,
+        oam_addr,
+        scanline,
+        dot,
+        nmi_interrupt,
+    } = ppu;
+    hash.buffer(vram);
+    hash.buffer(oam_data);
+    hash.buffer(palette_table);
+    hash.bytes(&[*ctrl, *mask, *status, *oam_addr]);
+    hash.bytes(&[*scroll_x, *scroll_y, *scroll_latch as u8]);
+    hash.bytes(&addr.to_le_bytes());
+    hash.bytes(&[*addr_high_byte as u8, *internal_data_buf]);
+    hash.bytes(&scanline.to_le_bytes());
+    hash.bytes(&dot.to_le_bytes());
+    match nmi_interrupt {
+        Some(value) => hash.bytes(&[1, *value]),
+        None => hash.bytes(&[0]),
+    }
+
+    let MapperState { registers, chr_ram } = mapper;
+    if !mapper.is_empty() {
+        hash.buffer(registers);
+        hash.buffer(chr_ram);
+    }
+
+    let ApuState {
+        pulse,
+        triangle,
+        noise,
+        five_step,
+        irq_inhibit,
+        frame_irq,
+        frame_cycle,
+        odd_cycle,
+        pending_cycles,
+    } = apu;
+    for pulse in pulse {
+        hash_pulse(&mut hash, pulse);
+    }
+    hash_triangle(&mut hash, triangle);
+    hash_noise(&mut hash, noise);
+    hash.bytes(&[*five_step as u8, *irq_inhibit as u8, *frame_irq as u8]);
+    hash.bytes(&frame_cycle.to_le_bytes());
+    hash.bytes(&[*odd_cycle as u8]);
+    hash.bytes(&pending_cycles.to_le_bytes());
+    hash.0
+}
+
+fn hash_pulse(hash: &mut Fnv, pulse: &PulseState) {
+    let PulseState {
+        duty,
+        step,
+        period,
+        timer,
+        envelope,
+        length,
+        sweep_enabled,
+        sweep_period,
+        sweep_negate,
+        sweep_shift,
+        sweep_reload,
+        sweep_divider,
+    } = pulse;
+    hash.bytes(&[*duty, *step]);
+    hash.bytes(&period.to_le_bytes());
+    hash.bytes(&timer.to_le_bytes());
+    hash_envelope(hash, envelope);
+    hash_length(hash, length);
+    hash.bytes(&[*sweep_enabled as u8, *sweep_period, *sweep_negate as u8]);
+    hash.bytes(&[*sweep_shift, *sweep_reload as u8, *sweep_divider]);
+}
+
+fn hash_triangle(hash: &mut Fnv, triangle: &TriangleState) {
+    let TriangleState {
+        step,
+        period,
+        timer,
+        length,
+        linear_reload_value,
+        linear_counter,
+        linear_reload,
+    } = triangle;
+    hash.bytes(&[*step]);
+    hash.bytes(&period.to_le_bytes());
+    hash.bytes(&timer.to_le_bytes());
+    hash_length(hash, length);
+    hash.bytes(&[*linear_reload_value, *linear_counter, *linear_reload as u8]);
+}
+
+fn hash_noise(hash: &mut Fnv, noise: &NoiseState) {
+    let NoiseState {
+        short_mode,
+        period,
+        timer,
+        shift,
+        envelope,
+        length,
+    } = noise;
+    hash.bytes(&[*short_mode as u8]);
+    hash.bytes(&period.to_le_bytes());
+    hash.bytes(&timer.to_le_bytes());
+    hash.bytes(&shift.to_le_bytes());
+    hash_envelope(hash, envelope);
+    hash_length(hash, length);
+}
+
+fn hash_envelope(hash: &mut Fnv, envelope: &EnvelopeState) {
+    let EnvelopeState {
+        start,
+        looping,
+        constant,
+        volume,
+        divider,
+        decay,
+    } = envelope;
+    hash.bytes(&[*start as u8, *looping as u8, *constant as u8]);
+    hash.bytes(&[*volume, *divider, *decay]);
+}
+
+fn hash_length(hash: &mut Fnv, length: &LengthState) {
+    let LengthState {
+        enabled,
+        halted,
+        count,
+    } = length;
+    hash.bytes(&[*enabled as u8, *halted as u8, *count]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{Mirroring, RomBuilder};
+    use crate::savestate::SaveState;
+
+    fn console(mirroring: Mirroring) -> CPU {
+        let rom = RomBuilder::new()
+            .asm(0x8000, "loop: INC $10\n JSR sub\n JMP loop\n sub: RTS")
+            .unwrap()
+            .reset_vector(0x8000)
+            .mirroring(mirroring)
+            .build();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_hash_covers_emulation_state() {
+        let mut cpu = console(Mirroring::HORIZONTAL);
+        cpu.run_frame();
+        while cpu.call_stack().is_empty() {
+            cpu.step();
+        }
+        let hash = state_hash(&cpu);
+        assert_eq!(snapshot_hash(&cpu.snapshot()), hash);
+        let state = SaveState::capture(&cpu);
+
+        let changes: Vec<fn(&mut SaveState)> = vec![
+            |s| s.cpu.a ^= 1,
+            |s| s.cpu.program_counter ^= 1,
+            |s| s.bus.ram[0x7FF] ^= 1,
+            |s| s.bus.prg_ram[0] ^= 1,
+            |s| s.bus.cycles += 1,
+            |s| s.bus.controllers[1] ^= 1,
+            |s| s.bus.oam_dma = true,
+            |s| s.ppu.vram[0] ^= 1,
+            |s| s.ppu.palette_table[31] ^= 1,
+            |s| s.ppu.scroll_latch ^= true,
+            |s| s.ppu.internal_data_buf ^= 1,
+            |s| s.ppu.dot += 1,
+            |s| s.ppu.nmi_interrupt = Some(1),
+            |s| s.apu.as_mut().unwrap().pulse[1].envelope.decay ^= 1,
+            |s| s.apu.as_mut().unwrap().noise.shift ^= 2,
+            |s| s.apu.as_mut().unwrap().frame_irq ^= true,
+        ];
+        for change in changes {
+            let mut changed = state.clone();
+            change(&mut changed);
+            let mut other = cpu.clone();
+            other.restore(&changed).unwrap();
+            assert_ne!(state_hash(&other), hash);
+        }
+
+        // debugger bookkeeping and logging don't count
+        let mut other = cpu.clone();
+        other.restore(&state).unwrap();
+        other.bus.set_event_logging(true);
+        other.bus.set_heatmap(true);
+        assert!(other.call_stack().is_empty());
+        assert_eq!(state_hash(&other), hash);
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        // the cartridge's mirroring isn't part of the state
+        assert_eq!(
+            state_hash(&console(Mirroring::VERTICAL)),
+            state_hash(&console(Mirroring::HORIZONTAL))
+        );
+
+        // changing this value breaks every recorded replay and netplay between
+        // versions, only do it together with a replay format version bump
+        let cpu = CpuState {
+            a: 1,
+            program_counter: 0x8000,
+            ..CpuState::default()
+        };
+        let bus = BusState {
+            ram: vec![2; 4],
+            frames: 3,
+            ..BusState::default()
+        };
+        let ppu = PpuState {
+            vram: vec![4; 4],
+            nmi_interrupt: Some(5),
+            ..PpuState::default()
+        };
+        let mapper = MapperState::default();
+        let mut apu = ApuState::default();
+        apu.noise.shift = 6;
+        assert_eq!(
+            hash_states(&cpu, &bus, &ppu, &mapper, &apu),
+            0xF62C_50A4_A77B_E519
+        );
+    }
+}
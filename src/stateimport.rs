@@ -0,0 +1,383 @@
+// Best-effort import of savestates from other emulators, so progress made
+// elsewhere can be carried over.
+//
+// Only the parts with a documented, stable layout are read: CPU registers,
+// internal RAM, nametable, palette and sprite RAM, cartridge RAM and, where the
+// format has them, the PPU registers. Everything else (timing, controllers,
+// the APU) comes from the console the state is imported into, so games resume
+// at the next frame rather than the exact cycle they were saved at. Mapper
+// registers aren't imported since the only cartridge board emulated so far,
+// NROM, has none.
+//
+// FCEUX .fc0-.fc9/.fcs files are "FCSX", the uncompressed size (u32), the
+// FCEUX version (u32) and the compressed size (u32, all ones when stored
+// uncompressed), followed by zlib data. That data is a list of sections, a
+// type byte and a u32 length, each holding chunks of a 4 byte name, a u32
+// length and the value.
+//
+// Mesen 2 .mss files are "MSS", the emulator and format versions, the console
+// type, a compressed screenshot and the ROM name, followed by the console
+// state as zlib compressed "name\0", u32 length, value entries. The names
+// depend on Mesen's internals and have changed between releases, so they are
+// matched on their last component only.
+use crate::cpu::CPU;
+use crate::savestate::SaveState;
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+
+const FCEUX_MAGIC: &[u8; 4] = b"FCSX";
+const MESEN_MAGIC: &[u8; 3] = b"MSS";
+const MESEN_NES_CONSOLE: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForeignFormat {
+    Fceux,
+    Mesen,
+}
+
+impl ForeignFormat {
+    pub fn detect(data: &[u8]) -> Option<ForeignFormat> {
+        if data.starts_with(FCEUX_MAGIC) {
+            Some(ForeignFormat::Fceux)
+        } else if data.starts_with(MESEN_MAGIC) {
+            Some(ForeignFormat::Mesen)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForeignFormat::Fceux => "FCEUX",
+            ForeignFormat::Mesen => "Mesen",
+        }
+    }
+}
+
+/// Converts a foreign savestate into a native one, using `cpu` for everything
+/// the foreign state doesn't have
+pub fn import(data: &[u8], cpu: &CPU) -> Result<SaveState, String> {
+    let mut state = SaveState::capture(cpu);
+    match ForeignFormat::detect(data) {
+        Some(ForeignFormat::Fceux) => import_fceux(data, &mut state)?,
+        Some(ForeignFormat::Mesen) => import_mesen(data, &mut state)?,
+        None => return Err("Not an FCEUX or Mesen savestate".to_string()),
+    }
+    // the foreign state was made from whatever ROM the other emulator had
+    state.rom = None;
+    Ok(state)
+}
+
+fn import_fceux(data: &[u8], state: &mut SaveState) -> Result<(), String> {
+    let mut reader = Reader::new(data, "FCEUX");
+    reader.take(FCEUX_MAGIC.len())?;
+    let size = reader.u32()? as usize;
+    let _version = reader.u32()?;
+    let compressed_size = reader.u32()?;
+    let body = if compressed_size == u32::MAX {
+        reader.take(size)?.to_vec()
+    } else {
+        inflate(reader.take(compressed_size as usize)?, "FCEUX")?
+    };
+
+    let mut sections = Reader::new(&body, "FCEUX");
+    let mut found_cpu = false;
+    while !sections.at_end() {
+        let _kind = sections.take(1)?[0];
+        let len = sections.u32()? as usize;
+        let mut chunks = Reader::new(sections.take(len)?, "FCEUX");
+        while !chunks.at_end() {
+            let name = chunks.take(4)?;
+            let len = chunks.u32()? as usize;
+            let value = chunks.take(len)?;
+            let byte = value.first().copied().unwrap_or(0);
+            let word = || u16::from_le_bytes([byte, value.get(1).copied().unwrap_or(0)]);
+            match name {
+                b"PC\0\0" => {
+                    state.cpu.program_counter = word();
+                    found_cpu = true;
+                }
+                b"A\0\0\0" => state.cpu.a = byte,
+                b"X\0\0\0" => state.cpu.x = byte,
+                b"Y\0\0\0" => state.cpu.y = byte,
+                b"P\0\0\0" => state.cpu.status = byte,
+                b"S\0\0\0" => state.cpu.stack_pointer = byte,
+                b"RAM\0" => copy_into(&mut state.bus.ram, value),
+                b"WRAM" => copy_into(&mut state.bus.prg_ram, value),
+                b"NTAR" => copy_into(&mut state.ppu.vram, value),
+                b"PRAM" => copy_into(&mut state.ppu.palette_table, value),
+                b"SPRA" => copy_into(&mut state.ppu.oam_data, value),
+                b"PPUR" if value.len() >= 4 => {
+                    state.ppu.ctrl = value[0];
+                    state.ppu.mask = value[1];
+                    state.ppu.status = value[2];
+                    state.ppu.oam_addr = value[3];
+                }
+                b"VTGL" => {
+                    state.ppu.scroll_latch = byte != 0;
+                    state.ppu.addr_high_byte = byte == 0;
+                }
+                b"RADD" => state.ppu.addr = word() & 0x3FFF,
+                b"TADD" => {
+                    // loopy's t register: yyy NN YYYYY XXXXX
+                    let t = word();
+                    state.ppu.scroll_x = (state.ppu.scroll_x & 0b111) | ((t & 0x1F) << 3) as u8;
+                    state.ppu.scroll_y = ((((t >> 5) & 0x1F) << 3) | ((t >> 12) & 0b111)) as u8;
+                }
+                b"XOFF" => state.ppu.scroll_x = (state.ppu.scroll_x & !0b111) | (byte & 0b111),
+                b"VBUF" => state.ppu.internal_data_buf = byte,
+                _ => {}
+            }
+        }
+    }
+    if !found_cpu {
+        return Err("FCEUX savestate has no CPU state".to_string());
+    }
+    Ok(())
+}
+
+fn import_mesen(data: &[u8], state: &mut SaveState) -> Result<(), String> {
+    let mut reader = Reader::new(data, "Mesen");
+    reader.take(MESEN_MAGIC.len())?;
+    let _emu_version = reader.u32()?;
+    let _format_version = reader.u32()?;
+    let console = reader.u32()?;
+    if console != MESEN_NES_CONSOLE {
+        return Err("Mesen savestate is not for the NES".to_string());
+    }
+    // screenshot: uncompressed size, width, height, scale, then the data
+    reader.take(16)?;
+    let len = reader.u32()? as usize;
+    reader.take(len)?;
+    let len = reader.u32()? as usize;
+    reader.take(len)?;
+
+    // the state itself, zlib compressed with or without its sizes in front
+    let rest = reader.take(data.len() - reader.pos)?;
+    let body = match rest {
+        [0x78, ..] => inflate(rest, "Mesen")?,
+        [_, _, _, _, _, _, _, _, 0x78, ..] => inflate(&rest[8..], "Mesen")?,
+        _ => rest.to_vec(),
+    };
+
+    let mut entries = Reader::new(&body, "Mesen");
+    let mut found_cpu = false;
+    while !entries.at_end() {
+        let name_len = entries.data[entries.pos..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| "Mesen savestate is truncated".to_string())?;
+        let name = String::from_utf8_lossy(entries.take(name_len)?).to_lowercase();
+        entries.take(1)?;
+        let len = entries.u32()? as usize;
+        let value = entries.take(len)?;
+
+        let last = name.rsplit('.').next().unwrap_or("");
+        let in_cpu = name.split('.').any(|part| part == "cpu");
+        let byte = value.first().copied().unwrap_or(0);
+        match (in_cpu, last) {
+            (true, "pc") => {
+                state.cpu.program_counter =
+                    u16::from_le_bytes([byte, value.get(1).copied().unwrap_or(0)]);
+                found_cpu = true;
+            }
+            (true, "a") => state.cpu.a = byte,
+            (true, "x") => state.cpu.x = byte,
+            (true, "y") => state.cpu.y = byte,
+            (true, "sp") => state.cpu.stack_pointer = byte,
+            (true, "ps") => state.cpu.status = byte,
+            (_, "internalram") => copy_into(&mut state.bus.ram, value),
+            (_, "workram") | (_, "saveram") => copy_into(&mut state.bus.prg_ram, value),
+            (_, "nametableram") | (_, "ciram") => copy_into(&mut state.ppu.vram, value),
+            (_, "paletteram") => copy_into(&mut state.ppu.palette_table, value),
+            (_, "spriteram") => copy_into(&mut state.ppu.oam_data, value),
+            _ => {}
+        }
+    }
+    if !found_cpu {
+        return Err("Mesen savestate has no CPU state".to_string());
+    }
+    Ok(())
+}
+
+/// Copies as much of `value` as fits, a shorter value only fills the start
+fn copy_into(target: &mut [u8], value: &[u8]) {
+    let len = target.len().min(value.len());
+    target[..len].copy_from_slice(&value[..len]);
+}
+
+fn inflate(data: &[u8], format: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data)
+        .read_to_end(&mut out)
+        .map_err(|e| format!("Malformed {} savestate: {}", format, e))?;
+    Ok(out)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], format: &'static str) -> Self {
+        Reader {
+            data,
+            pos: 0,
+            format,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        match self.pos.checked_add(len) {
+            Some(end) if end <= self.data.len() => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            _ => Err(format!("{} savestate is truncated", self.format)),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn chunk(out: &mut Vec<u8>, name: &[u8; 4], value: &[u8]) {
+        out.extend_from_slice(name);
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+
+    fn section(out: &mut Vec<u8>, kind: u8, chunks: &[u8]) {
+        out.push(kind);
+        out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        out.extend_from_slice(chunks);
+    }
+
+    fn console() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_import_fceux() {
+        let mut cpu_chunks = vec![];
+        chunk(&mut cpu_chunks, b"PC\0\0", &[0x34, 0x82]);
+        chunk(&mut cpu_chunks, b"A\0\0\0", &[0x11]);
+        chunk(&mut cpu_chunks, b"S\0\0\0", &[0xF9]);
+        chunk(&mut cpu_chunks, b"RAM\0", &[0xAB; 0x800]);
+        let mut ppu_chunks = vec![];
+        chunk(&mut ppu_chunks, b"NTAR", &[0x24; 0x800]);
+        chunk(&mut ppu_chunks, b"PPUR", &[0x90, 0x1E, 0x80, 0x00]);
+        chunk(
+            &mut ppu_chunks,
+            b"TADD",
+            &(0x3000u16 | 0x0065).to_le_bytes(),
+        );
+        chunk(&mut ppu_chunks, b"XOFF", &[5]);
+        chunk(&mut ppu_chunks, b"JUNK", &[1, 2, 3]);
+        let mut body = vec![];
+        section(&mut body, 1, &cpu_chunks);
+        section(&mut body, 3, &ppu_chunks);
+
+        let compressed = deflate(&body);
+        let mut data = FCEUX_MAGIC.to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend_from_slice(&22020u32.to_le_bytes());
+        data.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        data.extend_from_slice(&compressed);
+
+        let mut cpu = console();
+        let state = import(&data, &cpu).unwrap();
+        assert_eq!(
+            (
+                state.cpu.program_counter,
+                state.cpu.a,
+                state.cpu.stack_pointer
+            ),
+            (0x8234, 0x11, 0xF9)
+        );
+        assert_eq!(state.bus.ram, vec![0xAB; 0x800]);
+        assert_eq!(state.ppu.vram, vec![0x24; 0x800]);
+        assert_eq!((state.ppu.ctrl, state.ppu.mask), (0x90, 0x1E));
+        // coarse x 5, coarse y 3, fine y 3
+        assert_eq!(
+            (state.ppu.scroll_x, state.ppu.scroll_y),
+            (5 * 8 + 5, 3 * 8 + 3)
+        );
+        assert_eq!(state.rom, None);
+        cpu.restore(&state).unwrap();
+        assert_eq!(cpu.bus.peek(0x0010), 0xAB);
+
+        assert_eq!(
+            import(&data[..20], &cpu),
+            Err("FCEUX savestate is truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn test_import_mesen() {
+        let mut body = vec![];
+        for (name, value) in [
+            ("cpu.pc", vec![0x00, 0x90]),
+            ("cpu.x", vec![0x07]),
+            ("cpu.ps", vec![0x24]),
+            ("apu.square1.x", vec![0x99]),
+            ("memoryManager.internalRam", vec![0x5A; 0x800]),
+            ("ppu.paletteRam", vec![0x0F; 0x20]),
+        ]
+        .iter()
+        {
+            body.extend_from_slice(name.as_bytes());
+            body.push(0);
+            body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            body.extend_from_slice(value);
+        }
+        let mut data = MESEN_MAGIC.to_vec();
+        for value in [0x20000u32, 3, MESEN_NES_CONSOLE, 0, 256, 240, 100].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(b"game.nes");
+        data.extend_from_slice(&deflate(&body));
+
+        let cpu = console();
+        let state = import(&data, &cpu).unwrap();
+        assert_eq!(
+            (state.cpu.program_counter, state.cpu.x, state.cpu.status),
+            (0x9000, 0x07, 0x24)
+        );
+        assert_eq!(state.bus.ram, vec![0x5A; 0x800]);
+        assert_eq!(state.ppu.palette_table, vec![0x0F; 0x20]);
+
+        assert_eq!(
+            import(b"NESS", &cpu),
+            Err("Not an FCEUX or Mesen savestate".to_string())
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// Where battery saves, savestates, screenshots and config are kept.
+//
+// By default that's the usual per-user location of each platform:
+//   Linux    $XDG_DATA_HOME (~/.local/share) and $XDG_CONFIG_HOME (~/.config)
+//   macOS    ~/Library/Application Support
+//   Windows  %APPDATA%
+// with a nes_emulator directory in it. Portable installs put everything in one
+// directory of their choosing instead, see `Storage::portable`.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "nes_emulator";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    BatterySaves,
+    Savestates,
+    Screenshots,
+    Config,
+}
+
+impl Kind {
+    fn subdir(&self) -> &'static str {
+        match self {
+            Kind::BatterySaves => "saves",
+            Kind::Savestates => "states",
+            Kind::Screenshots => "screenshots",
+            Kind::Config => "config",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Linux
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Storage {
+    data_dir: PathBuf,
+    config_dir: PathBuf,
+}
+
+impl Storage {
+    /// The per-user directories of the platform this runs on
+    pub fn new() -> Self {
+        Storage::for_platform(Platform::current(), |name| std::env::var_os(name))
+    }
+
+    /// Everything in `dir`, for installs that live on a USB stick and the like
+    pub fn portable<P: AsRef<Path>>(dir: P) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        Storage {
+            config_dir: dir.join(Kind::Config.subdir()),
+            data_dir: dir,
+        }
+    }
+
+    /// Resolves the directories of `platform` with `env` looking up environment
+    /// variables. Without a home directory the current directory is used
+    pub fn for_platform<F>(platform: Platform, env: F) -> Self
+    where
+        F: Fn(&str) -> Option<std::ffi::OsString>,
+    {
+        let var = |name: &str| env(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        let home = var("HOME").unwrap_or_else(|| PathBuf::from("."));
+        let (data, config) = match platform {
+            Platform::Linux => (
+                var("XDG_DATA_HOME").unwrap_or_else(|| home.join(".local/share")),
+                var("XDG_CONFIG_HOME").unwrap_or_else(|| home.join(".config")),
+            ),
+            Platform::MacOs => {
+                let support = home.join("Library/Application Support");
+                (support.clone(), support)
+            }
+            Platform::Windows => {
+                let appdata = var("APPDATA")
+                    .or_else(|| var("USERPROFILE").map(|p| p.join("AppData").join("Roaming")))
+                    .unwrap_or_else(|| PathBuf::from("."));
+                (appdata.clone(), appdata)
+            }
+        };
+        let data_dir = data.join(APP_DIR);
+        let config_dir = config.join(APP_DIR);
+        // on macOS and Windows both are the same directory, keep config apart
+        let config_dir = if config_dir == data_dir {
+            data_dir.join(Kind::Config.subdir())
+        } else {
+            config_dir
+        };
+        Storage {
+            data_dir,
+            config_dir,
+        }
+    }
+
+    pub fn dir(&self, kind: Kind) -> PathBuf {
+        match kind {
+            Kind::Config => self.config_dir.clone(),
+            _ => self.data_dir.join(kind.subdir()),
+        }
+    }
+
+    /// `dir`, created if it doesn't exist yet
+    pub fn create_dir(&self, kind: Kind) -> Result<PathBuf, String> {
+        let dir = self.dir(kind);
+        fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        Ok(dir)
+    }
+
+    /// A file named after the ROM in the directory for `kind`, e.g. the .sav of
+    /// mario.nes is saves/mario.sav
+    pub fn rom_file<P: AsRef<Path>>(&self, kind: Kind, rom_path: P, extension: &str) -> PathBuf {
+        let mut name = rom_path
+            .as_ref()
+            .file_stem()
+            .map_or_else(|| "rom".into(), |stem| stem.to_os_string());
+        name.push(".");
+        name.push(extension);
+        self.dir(kind).join(name)
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<OsString> {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| OsString::from(value))
+        }
+    }
+
+    #[test]
+    fn test_platform_dirs() {
+        let linux = Storage::for_platform(Platform::Linux, env(&[("HOME", "/home/nes")]));
+        assert_eq!(
+            linux.dir(Kind::BatterySaves),
+            Path::new("/home/nes/.local/share/nes_emulator/saves")
+        );
+        assert_eq!(
+            linux.dir(Kind::Config),
+            Path::new("/home/nes/.config/nes_emulator")
+        );
+        let xdg = Storage::for_platform(
+            Platform::Linux,
+            env(&[
+                ("HOME", "/home/nes"),
+                ("XDG_DATA_HOME", "/data"),
+                ("XDG_CONFIG_HOME", ""),
+            ]),
+        );
+        assert_eq!(
+            xdg.dir(Kind::Savestates),
+            Path::new("/data/nes_emulator/states")
+        );
+        assert_eq!(
+            xdg.dir(Kind::Config),
+            Path::new("/home/nes/.config/nes_emulator")
+        );
+
+        let mac = Storage::for_platform(Platform::MacOs, env(&[("HOME", "/Users/nes")]));
+        assert_eq!(
+            mac.dir(Kind::Screenshots),
+            Path::new("/Users/nes/Library/Application Support/nes_emulator/screenshots")
+        );
+        assert_eq!(
+            mac.dir(Kind::Config),
+            Path::new("/Users/nes/Library/Application Support/nes_emulator/config")
+        );
+
+        let windows = Storage::for_platform(Platform::Windows, env(&[("APPDATA", "C:/AppData")]));
+        assert_eq!(
+            windows.dir(Kind::BatterySaves),
+            Path::new("C:/AppData/nes_emulator/saves")
+        );
+    }
+
+    #[test]
+    fn test_portable_and_rom_files() {
+        let storage = Storage::portable("/stick/nes");
+        assert_eq!(storage.dir(Kind::Config), Path::new("/stick/nes/config"));
+        assert_eq!(
+            storage.rom_file(Kind::BatterySaves, "roms/Zelda (U).nes", "sav"),
+            Path::new("/stick/nes/saves/Zelda (U).sav")
+        );
+        assert_eq!(
+            storage.rom_file(Kind::Savestates, "smb.v1.1.nes", "state"),
+            Path::new("/stick/nes/states/smb.v1.1.state")
+        );
+
+        let dir = std::env::temp_dir().join(format!("storage_test_{}", std::process::id()));
+        let storage = Storage::portable(&dir);
+        let states = storage.create_dir(Kind::Savestates).unwrap();
+        assert!(states.is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
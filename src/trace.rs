@@ -1,133 +1,332 @@
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
 use crate::cpu::CPU;
-use crate::opcodes;
-use std::collections::HashMap;
+use crate::disasm::RawInstruction;
+use crate::labels::Labels;
+use std::fmt::{self, Write};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// Same layout as nestest.log and FCEUX, including the PPU and CYC columns
+    Nestest,
+    /// Layout of Mesen's trace logger
+    Mesen,
+    /// One comma separated row per instruction, see `TraceFormat::header`
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "nestest" | "fceux" => Some(TraceFormat::Nestest),
+            "mesen" => Some(TraceFormat::Mesen),
+            "csv" => Some(TraceFormat::Csv),
+            "json" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Line to start a new file with
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            TraceFormat::Csv => Some("pc,bytes,instruction,a,x,y,p,sp,scanline,dot,cycle"),
+            _ => None,
+        }
+    }
+}
+
+/// nestest.log line without the timing columns
 pub fn trace(cpu: &CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+    let mut line = String::new();
+    let _ = write_trace(&mut line, cpu);
+    line
+}
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+/// `trace` followed by the nestest.log timing columns: PPU scanline and dot
+/// and the CPU cycles since power on
+pub fn trace_with_timing(cpu: &CPU) -> String {
+    trace_as(cpu, TraceFormat::Nestest)
+}
 
-    let begin = cpu.program_counter;
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
+pub fn trace_as(cpu: &CPU, format: TraceFormat) -> String {
+    let mut line = String::new();
+    let _ = write_trace_as(&mut line, cpu, format, None);
+    line
+}
+
+/// Appends the `trace` line to `out`, so loggers can reuse one buffer
+pub fn write_trace<W: Write>(out: &mut W, cpu: &CPU) -> fmt::Result {
+    write_trace_with_labels(out, cpu, None)
+}
 
-    let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
-        _ => {
-            let addr = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+/// `write_trace` with operand addresses replaced by their labels
+pub fn write_trace_with_labels<W: Write>(
+    out: &mut W,
+    cpu: &CPU,
+    labels: Option<&Labels>,
+) -> fmt::Result {
+    let ins = RawInstruction::decode(&cpu.bus, cpu.program_counter);
+    let mut asm = Column::new(out);
+    write!(asm, "{:04X}  ", ins.addr)?;
+    write_hex_bytes(&mut asm, &ins)?;
+    asm.pad(14)?;
+    write!(asm, " {: >4}", ins.mnemonic)?;
+    if ins.has_operand() {
+        asm.write_char(' ')?;
+        write_annotated_operand(&mut asm, &ins, cpu, labels)?;
+    }
+    asm.pad(47)?;
+
+    write!(
+        out,
+        " A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
+    )
+}
+
+pub fn write_trace_with_timing<W: Write>(out: &mut W, cpu: &CPU) -> fmt::Result {
+    write_trace(out, cpu)?;
+    write_timing(out, cpu)
+}
+
+/// Appends the instruction at PC in the given format
+pub fn write_trace_as<W: Write>(
+    out: &mut W,
+    cpu: &CPU,
+    format: TraceFormat,
+    labels: Option<&Labels>,
+) -> fmt::Result {
+    match format {
+        TraceFormat::Nestest => {
+            write_trace_with_labels(out, cpu, labels)?;
+            write_timing(out, cpu)
         }
-    };
-
-    let tmp = match ops.len {
-        1 => match ops.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
-            hex_dump.push(address);
-
-            match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::NoneAddressing => {
-                    // assuming local jumps: BNE, BVS, etc....
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
-                }
-
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    ops.mode, ops.code
-                ),
-            }
+        TraceFormat::Mesen => write_mesen(out, cpu, labels),
+        TraceFormat::Csv => write_csv(out, cpu, labels),
+        TraceFormat::Json => write_json(out, cpu, labels),
+    }
+}
+
+/// Appends the PPU and CYC columns
+pub fn write_timing<W: Write>(out: &mut W, cpu: &CPU) -> fmt::Result {
+    let (scanline, dot) = cpu.bus.ppu_position();
+    write!(
+        out,
+        " PPU:{:>3},{:>3} CYC:{}",
+        scanline,
+        dot,
+        cpu.bus.cycles()
+    )
+}
+
+fn write_mesen<W: Write>(out: &mut W, cpu: &CPU, labels: Option<&Labels>) -> fmt::Result {
+    let ins = RawInstruction::decode(&cpu.bus, cpu.program_counter);
+    let mut asm = Column::new(out);
+    write!(asm, "{:04X}  ", ins.addr)?;
+    write_hex_bytes(&mut asm, &ins)?;
+    asm.pad(14)?;
+    write!(asm, "  {}", ins.mnemonic)?;
+    if ins.has_operand() {
+        asm.write_char(' ')?;
+        write_annotated_operand(&mut asm, &ins, cpu, labels)?;
+    }
+    asm.pad(48)?;
+
+    write!(
+        out,
+        " A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:",
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer,
+    )?;
+    for (i, flag) in "NVUBDIZC".chars().enumerate() {
+        if cpu.status.bits() & (0x80 >> i) != 0 {
+            out.write_char(flag)?;
+        } else {
+            out.write_char(flag.to_ascii_lowercase())?;
         }
-        3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address = cpu.mem_read_u16(begin + 1);
-
-            match ops.mode {
-                AddressingMode::NoneAddressing => {
-                    if ops.code == 0x6c {
-                        //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-
-                        // let jmp_addr = cpu.mem_read_u16(address);
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::Absolute_X => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Absolute_Y => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    ops.mode, ops.code
-                ),
-            }
+    }
+    let (scanline, dot) = cpu.bus.ppu_position();
+    write!(
+        out,
+        " V:{:<3} H:{:<3} Fr:{} Cycle:{}",
+        scanline,
+        dot,
+        cpu.bus.frame_count(),
+        cpu.bus.cycles()
+    )
+}
+
+fn write_csv<W: Write>(out: &mut W, cpu: &CPU, labels: Option<&Labels>) -> fmt::Result {
+    let ins = RawInstruction::decode(&cpu.bus, cpu.program_counter);
+    write!(out, "{:04X},", ins.addr)?;
+    write_hex_bytes(out, &ins)?;
+    out.write_str(",\"")?;
+    write_instruction(&mut Escaped::csv(out), &ins, cpu, labels)?;
+    let (scanline, dot) = cpu.bus.ppu_position();
+    write!(
+        out,
+        "\",{:02X},{:02X},{:02X},{:02X},{:02X},{},{},{}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        scanline,
+        dot,
+        cpu.bus.cycles()
+    )
+}
+
+fn write_json<W: Write>(out: &mut W, cpu: &CPU, labels: Option<&Labels>) -> fmt::Result {
+    let ins = RawInstruction::decode(&cpu.bus, cpu.program_counter);
+    write!(out, "{{\"pc\":{},\"bytes\":[", ins.addr)?;
+    for (i, byte) in ins.bytes().iter().enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
         }
-        _ => String::from(""),
-    };
-
-    let hex_str = hex_dump
-        .iter()
-        .map(|z| format!("{:02x}", z))
-        .collect::<Vec<String>>()
-        .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-        .trim()
-        .to_string();
-
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
+        write!(out, "{}", byte)?;
+    }
+    out.write_str("],\"instruction\":\"")?;
+    write_instruction(&mut Escaped::json(out), &ins, cpu, labels)?;
+    let (scanline, dot) = cpu.bus.ppu_position();
+    write!(
+        out,
+        "\",\"a\":{},\"x\":{},\"y\":{},\"p\":{},\"sp\":{},\"scanline\":{},\"dot\":{},\"cycle\":{}}}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        scanline,
+        dot,
+        cpu.bus.cycles()
     )
-    .to_ascii_uppercase()
+}
+
+fn write_hex_bytes<W: Write>(out: &mut W, ins: &RawInstruction) -> fmt::Result {
+    for (i, byte) in ins.bytes().iter().enumerate() {
+        if i > 0 {
+            out.write_char(' ')?;
+        }
+        write!(out, "{:02X}", byte)?;
+    }
+    Ok(())
+}
+
+/// Mnemonic and operand, without the annotations
+fn write_instruction<W: Write>(
+    out: &mut W,
+    ins: &RawInstruction,
+    cpu: &CPU,
+    labels: Option<&Labels>,
+) -> fmt::Result {
+    out.write_str(ins.mnemonic)?;
+    if ins.has_operand() {
+        out.write_char(' ')?;
+        ins.write_operand(out, labels.and_then(|labels| ins.label(labels, &cpu.bus)))?;
+    }
+    Ok(())
+}
+
+/// Operand followed by the address it resolves to and the value found there
+fn write_annotated_operand<W: Write>(
+    out: &mut W,
+    ins: &RawInstruction,
+    cpu: &CPU,
+    labels: Option<&Labels>,
+) -> fmt::Result {
+    let peek = |addr: u16| cpu.bus.peek(addr);
+    ins.write_operand(out, labels.and_then(|labels| ins.label(labels, &cpu.bus)))?;
+
+    match (ins.mode, ins.effective_address(cpu)) {
+        (AddressingMode::ZeroPage, Some(addr)) | (AddressingMode::Absolute, Some(addr)) => {
+            write!(out, " = {:02X}", peek(addr))
+        }
+        (AddressingMode::ZeroPage_X, Some(addr)) | (AddressingMode::ZeroPage_Y, Some(addr)) => {
+            write!(out, " @ {:02X} = {:02X}", addr, peek(addr))
+        }
+        (AddressingMode::Absolute_X, Some(addr)) | (AddressingMode::Absolute_Y, Some(addr)) => {
+            write!(out, " @ {:04X} = {:02X}", addr, peek(addr))
+        }
+        (AddressingMode::Indirect_X, Some(addr)) => write!(
+            out,
+            " @ {:02X} = {:04X} = {:02X}",
+            ins.bytes()[1].wrapping_add(cpu.register_x),
+            addr,
+            peek(addr)
+        ),
+        (AddressingMode::Indirect_Y, Some(addr)) => write!(
+            out,
+            " = {:04X} @ {:04X} = {:02X}",
+            addr.wrapping_sub(cpu.register_y as u16),
+            addr,
+            peek(addr)
+        ),
+        _ if ins.is_jmp_indirect() => write!(out, " = {:04X}", ins.target.unwrap_or(0)),
+        _ => Ok(()),
+    }
+}
+
+/// Counts the characters written through it, to pad a column in place
+/// instead of formatting it into a string first
+struct Column<'a, W> {
+    out: &'a mut W,
+    width: usize,
+}
+
+impl<'a, W: Write> Column<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Column { out, width: 0 }
+    }
+
+    /// Spaces up to `width` characters, nothing if the column is wider
+    fn pad(&mut self, width: usize) -> fmt::Result {
+        while self.width < width {
+            self.write_char(' ')?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Column<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.width += s.chars().count();
+        self.out.write_str(s)
+    }
+}
+
+/// Escapes quotes, for the instruction column of CSV and JSON lines
+struct Escaped<'a, W> {
+    out: &'a mut W,
+    escape: fn(char) -> Option<&'static str>,
+}
+
+impl<'a, W: Write> Escaped<'a, W> {
+    fn csv(out: &'a mut W) -> Self {
+        let escape = |c| if c == '"' { Some("\"\"") } else { None };
+        Escaped { out, escape }
+    }
+
+    fn json(out: &'a mut W) -> Self {
+        let escape = |c| match c {
+            '"' => Some("\\\""),
+            '\\' => Some("\\\\"),
+            _ => None,
+        };
+        Escaped { out, escape }
+    }
+}
+
+impl<W: Write> Write for Escaped<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match (self.escape)(c) {
+                Some(escaped) => self.out.write_str(escaped)?,
+                None => self.out.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -135,6 +334,7 @@ mod test {
     use super::*;
     use crate::bus::Bus;
     use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
 
     #[test]
     fn test_format_trace() {
@@ -194,4 +394,77 @@ mod test {
             result[0]
         );
     }
+
+    #[test]
+    fn test_format_with_labels() {
+        let mut bus = Bus::new(test_rom());
+        // LDA $10
+        bus.mem_write(100, 0xa5);
+        bus.mem_write(101, 0x10);
+        let mut cpu = CPU::new(bus);
+        let mut labels = Labels::new();
+        labels.add(0x10, "frame_counter");
+
+        cpu.program_counter = 0x64;
+        let mut line = String::new();
+        write_trace_with_labels(&mut line, &cpu, Some(&labels)).unwrap();
+        assert_eq!(
+            "0064  A5 10     LDA frame_counter = 00          A:00 X:00 Y:00 P:24 SP:FD",
+            line
+        );
+
+        // labels are escaped in place in the quoted columns
+        labels.add(0x10, "a\"b\\c");
+        line.clear();
+        write_trace_as(&mut line, &cpu, TraceFormat::Csv, Some(&labels)).unwrap();
+        assert!(line.starts_with("0064,A5 10,\"LDA a\"\"b\\c\",00,"));
+        line.clear();
+        write_trace_as(&mut line, &cpu, TraceFormat::Json, Some(&labels)).unwrap();
+        assert!(line.contains(r#""instruction":"LDA a\"b\\c","#));
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut bus = Bus::new(test_rom());
+        // LDA ($33),Y
+        bus.mem_write(100, 0xb1);
+        bus.mem_write(101, 0x33);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu.bus.tick(7);
+
+        assert_eq!(
+            trace_as(&cpu, TraceFormat::Nestest),
+            "0064  B1 33     LDA ($33),Y = 0000 @ 0000 = 00  A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+        assert_eq!(
+            trace_as(&cpu, TraceFormat::Mesen),
+            "0064  B1 33     LDA ($33),Y = 0000 @ 0000 = 00   A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Fr:0 Cycle:7"
+        );
+        assert_eq!(
+            trace_as(&cpu, TraceFormat::Csv),
+            "0064,B1 33,\"LDA ($33),Y\",00,00,00,24,FD,0,21,7"
+        );
+        assert_eq!(
+            trace_as(&cpu, TraceFormat::Json),
+            r#"{"pc":100,"bytes":[177,51],"instruction":"LDA ($33),Y","a":0,"x":0,"y":0,"p":36,"sp":253,"scanline":0,"dot":21,"cycle":7}"#
+        );
+        assert_eq!(TraceFormat::from_name("JSON"), Some(TraceFormat::Json));
+        assert_eq!(TraceFormat::from_name("xml"), None);
+    }
+
+    #[test]
+    fn test_nestest_timing_columns() {
+        let rom = crate::cartridge::Rom::from_file("nestest.nes").unwrap();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        cpu.program_counter = 0xC000;
+
+        // the log diverges at line 8981, the first access to an APU register
+        let expected = std::fs::read_to_string("nestest.log").unwrap();
+        for (n, line) in expected.lines().take(8980).enumerate() {
+            assert_eq!(trace_with_timing(&cpu), line, "line {}", n + 1);
+            cpu.step();
+        }
+    }
 }
@@ -0,0 +1,321 @@
+// Compares a trace log against one from a reference emulator and finds the
+// first instruction where they disagree.
+//
+// Lines are reduced to the CPU state they show, so the layout doesn't matter:
+// nestest.log, FCEUX and Mesen style logs (including the `P:nvUbdIzc` flag
+// notation) and our own CSV output all parse. Lines without a state, such as
+// headers or frame markers, are skipped. The B and unused status bits are
+// ignored since emulators disagree on how to show them, and cycle counts are
+// compared relative to the first line of each log since they start counting
+// at different points.
+use std::fmt::Write;
+
+/// Status bits that are compared, everything except B and the unused bit
+const FLAGS_MASK: u8 = 0b1100_1111;
+const FLAG_LETTERS: &[u8; 8] = b"NVUBDIZC";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub a: Option<u8>,
+    pub x: Option<u8>,
+    pub y: Option<u8>,
+    pub p: Option<u8>,
+    pub sp: Option<u8>,
+    pub cycle: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    /// 1-based line number in the file
+    pub line: usize,
+    pub text: String,
+    pub state: TraceState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Index into both logs after alignment
+    pub index: usize,
+    /// Names of the fields that differ, e.g. "PC" or "A"
+    pub fields: Vec<&'static str>,
+}
+
+/// Reads the CPU state out of one trace line, None for lines without one
+pub fn parse_line(text: &str) -> Option<TraceState> {
+    let text = text.trim();
+    if text.contains(',') && !text.contains(':') {
+        return parse_csv(text);
+    }
+    let mut tokens = text.split_whitespace();
+    let first = tokens.next()?.trim_start_matches('$');
+    let pc = parse_hex(first.split(':').next()?)?;
+    if first.split(':').next()?.len() != 4 {
+        return None;
+    }
+
+    let mut state = TraceState {
+        pc,
+        ..TraceState::default()
+    };
+    let mut registers = 0;
+    for token in tokens {
+        let (name, value) = match token.split_once(':') {
+            Some((name, value)) if !value.is_empty() => (name, value),
+            _ => continue,
+        };
+        let hex = parse_hex(value).filter(|v| *v <= 0xFF).map(|v| v as u8);
+        match name {
+            "A" => state.a = hex,
+            "X" => state.x = hex,
+            "Y" => state.y = hex,
+            "S" | "SP" => state.sp = hex,
+            "P" => state.p = hex.or_else(|| parse_flags(value)),
+            "CYC" | "Cycle" => state.cycle = value.parse().ok(),
+            _ => continue,
+        }
+        registers += 1;
+    }
+    // a lone address isn't a trace line
+    if registers == 0 {
+        return None;
+    }
+    Some(state)
+}
+
+/// pc,bytes,instruction,a,x,y,p,sp,scanline,dot,cycle as written by
+/// `TraceFormat::Csv`
+fn parse_csv(text: &str) -> Option<TraceState> {
+    // the instruction is quoted and may contain commas, e.g. "LDA $10,X"
+    let (head, rest) = text.split_once('"')?;
+    let (_, tail) = rest.rsplit_once('"')?;
+    let pc = parse_hex(head.split(',').next()?)?;
+    let fields: Vec<&str> = tail.trim_start_matches(',').split(',').collect();
+    if fields.len() < 8 {
+        return None;
+    }
+    let byte = |i: usize| parse_hex(fields[i]).map(|v| v as u8);
+    Some(TraceState {
+        pc,
+        a: byte(0),
+        x: byte(1),
+        y: byte(2),
+        p: byte(3),
+        sp: byte(4),
+        cycle: fields[7].parse().ok(),
+    })
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    if text.is_empty() || text.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(text, 16).ok()
+}
+
+/// "nvUbdIzc", set flags in upper case
+fn parse_flags(text: &str) -> Option<u8> {
+    if text.len() != 8 {
+        return None;
+    }
+    let mut p = 0;
+    for (i, c) in text.bytes().enumerate() {
+        if c.to_ascii_uppercase() != FLAG_LETTERS[i] {
+            return None;
+        }
+        if c.is_ascii_uppercase() {
+            p |= 0x80 >> i;
+        }
+    }
+    Some(p)
+}
+
+/// Every line of a log that has a CPU state, in order
+pub fn parse_log(text: &str) -> Vec<TraceLine> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, text)| {
+            parse_line(text).map(|state| TraceLine {
+                line: i + 1,
+                text: text.to_string(),
+                state,
+            })
+        })
+        .collect()
+}
+
+/// Skips leading lines of `mine` until it reaches the reference's first PC,
+/// for logs that start before the point the reference starts at
+pub fn align<'a>(mine: &'a [TraceLine], reference: &[TraceLine]) -> &'a [TraceLine] {
+    let start = match reference.first() {
+        Some(first) => mine
+            .iter()
+            .position(|line| line.state.pc == first.state.pc)
+            .unwrap_or(0),
+        None => 0,
+    };
+    &mine[start..]
+}
+
+/// First instruction where the logs disagree on a field both of them show.
+/// Cycles are only compared when `compare_cycles` is set
+pub fn first_divergence(
+    mine: &[TraceLine],
+    reference: &[TraceLine],
+    compare_cycles: bool,
+) -> Option<Divergence> {
+    let base = |log: &[TraceLine]| log.first().and_then(|line| line.state.cycle);
+    let (mine_base, reference_base) = (base(mine), base(reference));
+    for (index, (m, r)) in mine.iter().zip(reference).enumerate() {
+        let (m, r) = (&m.state, &r.state);
+        let mut fields = vec![];
+        if m.pc != r.pc {
+            fields.push("PC");
+        }
+        let registers = [
+            ("A", m.a, r.a),
+            ("X", m.x, r.x),
+            ("Y", m.y, r.y),
+            (
+                "P",
+                m.p.map(|p| p & FLAGS_MASK),
+                r.p.map(|p| p & FLAGS_MASK),
+            ),
+            ("SP", m.sp, r.sp),
+        ];
+        for (name, m, r) in registers.iter() {
+            if let (Some(m), Some(r)) = (m, r) {
+                if m != r {
+                    fields.push(*name);
+                }
+            }
+        }
+        if compare_cycles {
+            let relative = |cycle: Option<usize>, base: Option<usize>| cycle?.checked_sub(base?);
+            let (m, r) = (
+                relative(m.cycle, mine_base),
+                relative(r.cycle, reference_base),
+            );
+            if m.is_some() && r.is_some() && m != r {
+                fields.push("CYC");
+            }
+        }
+        if !fields.is_empty() {
+            return Some(Divergence { index, fields });
+        }
+    }
+    None
+}
+
+/// The divergence with `context` matching instructions before it, as text
+pub fn report(
+    mine: &[TraceLine],
+    reference: &[TraceLine],
+    divergence: &Divergence,
+    context: usize,
+) -> String {
+    let index = divergence.index;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "First divergence at instruction {} (mine line {}, reference line {}): {}",
+        index + 1,
+        mine[index].line,
+        reference[index].line,
+        divergence.fields.join(", ")
+    );
+    for (name, log) in [("mine", mine), ("reference", reference)].iter() {
+        let _ = writeln!(out, "{}:", name);
+        for line in &log[index.saturating_sub(context)..index] {
+            let _ = writeln!(out, "  {:>7}  {}", line.line, line.text.trim_end());
+        }
+        let _ = writeln!(
+            out,
+            "> {:>7}  {}",
+            log[index].line,
+            log[index].text.trim_end()
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        let nestest = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7";
+        let state = parse_line(nestest).unwrap();
+        assert_eq!(
+            (state.pc, state.p, state.sp),
+            (0xc000, Some(0x24), Some(0xfd))
+        );
+        assert_eq!(state.cycle, Some(7));
+
+        let fceux =
+            "$C5F5:A2 00     LDX #$00                        A:00 X:00 Y:00 S:FD P:nvUbdIzc";
+        let state = parse_line(fceux).unwrap();
+        assert_eq!(
+            (state.pc, state.p, state.sp),
+            (0xc5f5, Some(0x24), Some(0xfd))
+        );
+
+        let mesen =
+            "8000  A9 10     LDA #$10   A:00 X:00 Y:00 S:FD P:nvubdIzc V:0   H:21  Fr:0 Cycle:7";
+        let state = parse_line(mesen).unwrap();
+        assert_eq!(
+            (state.pc, state.p, state.cycle),
+            (0x8000, Some(0x04), Some(7))
+        );
+
+        let csv = "0064,B5 10,\"LDA $10,X\",01,02,03,24,FD,0,21,7";
+        let state = parse_line(csv).unwrap();
+        assert_eq!(
+            (state.pc, state.a, state.x, state.cycle),
+            (0x64, Some(1), Some(2), Some(7))
+        );
+
+        assert_eq!(
+            parse_line("pc,bytes,instruction,a,x,y,p,sp,scanline,dot,cycle"),
+            None
+        );
+        assert_eq!(parse_line("Log started"), None);
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let mine = parse_log(
+            "0600  A9 01     LDA #$01  A:00 X:00 Y:00 P:24 SP:FD CYC:0\n\
+             C000  4C F5 C5  JMP $C5F5 A:00 X:00 Y:00 P:24 SP:FD CYC:7\n\
+             C5F5  A2 00     LDX #$00  A:00 X:00 Y:00 P:34 SP:FD CYC:10\n\
+             C5F7  86 00     STX $00   A:00 X:00 Y:00 P:26 SP:FD CYC:13\n\
+             C5F9  86 10     STX $10   A:00 X:01 Y:00 P:26 SP:FD CYC:16\n",
+        );
+        let reference = parse_log(
+            "FCEUX trace log\n\
+             $C000:4C F5 C5  JMP $C5F5   A:00 X:00 Y:00 S:FD P:nvUbdIzc CYC:0\n\
+             $C5F5:A2 00     LDX #$00    A:00 X:00 Y:00 S:FD P:nvUbdIzc CYC:3\n\
+             $C5F7:86 00     STX $00     A:00 X:00 Y:00 S:FD P:nvUbdIZc CYC:5\n\
+             $C5F9:86 10     STX $10     A:00 X:00 Y:00 S:FD P:nvUbdIZc CYC:8\n",
+        );
+        let mine = align(&mine, &reference);
+        assert_eq!(mine[0].line, 2);
+
+        let divergence = first_divergence(mine, &reference, true).unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.fields, vec!["CYC"]);
+        let divergence = first_divergence(mine, &reference, false).unwrap();
+        assert_eq!(divergence.index, 3);
+        assert_eq!(divergence.fields, vec!["X"]);
+
+        let text = report(mine, &reference, &divergence, 1);
+        assert!(text
+            .starts_with("First divergence at instruction 4 (mine line 5, reference line 5): X\n"));
+        assert!(text.contains("\nreference:\n        4  $C5F7:86 00"));
+        assert!(text.contains("\n>       5  C5F9  86 10     STX $10   A:00 X:01"));
+
+        assert_eq!(first_divergence(&mine[..3], &reference, false), None);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::cpu::CPU;
+use crate::disasm::RawInstruction;
+use crate::labels::Labels;
+use crate::trace::{self, TraceFormat};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeLimit {
+    Unlimited,
+    /// Stop logging once the file reaches the given size in bytes
+    Cap(u64),
+    /// Move the file to `<path>.1` once it reaches the given size and start over,
+    /// so at most two files worth of the most recent trace are kept
+    Rotate(u64),
+}
+
+/// Restricts tracing to instructions of interest. Every kind of condition that
+/// was added has to match, any one of several conditions of the same kind will do
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    pc_ranges: Vec<RangeInclusive<u16>>,
+    mnemonics: Vec<String>,
+    opcodes: Vec<u8>,
+    access_ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        TraceFilter::default()
+    }
+
+    pub fn add_pc_range(&mut self, range: RangeInclusive<u16>) {
+        self.pc_ranges.push(range);
+    }
+
+    pub fn add_mnemonic(&mut self, mnemonic: &str) {
+        self.mnemonics.push(mnemonic.to_ascii_uppercase());
+    }
+
+    pub fn add_opcode(&mut self, opcode: u8) {
+        self.opcodes.push(opcode);
+    }
+
+    /// Instructions reading or writing memory in the range, including jumps into it
+    pub fn add_access_range(&mut self, range: RangeInclusive<u16>) {
+        self.access_ranges.push(range);
+    }
+
+    /// Parses a "--trace-op" argument, either a mnemonic such as "LDA" or an
+    /// opcode byte such as "$A9"
+    pub fn add_op(&mut self, op: &str) -> Result<(), String> {
+        match op.strip_prefix('$') {
+            Some(hex) => {
+                let opcode =
+                    u8::from_str_radix(hex, 16).map_err(|_| format!("Invalid opcode: {}", op))?;
+                self.add_opcode(opcode);
+            }
+            None => self.add_mnemonic(op),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pc_ranges.is_empty()
+            && self.mnemonics.is_empty()
+            && self.opcodes.is_empty()
+            && self.access_ranges.is_empty()
+    }
+
+    /// Checks the instruction at PC, before it is executed
+    pub fn matches(&self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter;
+        if !self.pc_ranges.is_empty() && !self.pc_ranges.iter().any(|r| r.contains(&pc)) {
+            return false;
+        }
+        if self.mnemonics.is_empty() && self.opcodes.is_empty() && self.access_ranges.is_empty() {
+            return true;
+        }
+
+        let ins = RawInstruction::decode(&cpu.bus, pc);
+        if !self.opcodes.is_empty() || !self.mnemonics.is_empty() {
+            let opcode_match = self.opcodes.contains(&ins.bytes()[0]);
+            let mnemonic_match = self
+                .mnemonics
+                .iter()
+                .any(|m| m.trim_start_matches('*') == ins.mnemonic.trim_start_matches('*'));
+            if !opcode_match && !mnemonic_match {
+                return false;
+            }
+        }
+        if !self.access_ranges.is_empty() {
+            let touched = [ins.effective_address(cpu), ins.target];
+            let in_range = |addr: &Option<u16>| match addr {
+                Some(addr) => self.access_ranges.iter().any(|r| r.contains(addr)),
+                None => false,
+            };
+            if !touched.iter().any(in_range) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses "8000-80FF" or a single address such as "$2002"
+pub fn parse_range(arg: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |addr: &str| {
+        u16::from_str_radix(addr.trim().trim_start_matches('$'), 16)
+            .map_err(|_| format!("Invalid address range: {}", arg))
+    };
+    match arg.split_once('-') {
+        Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+        None => {
+            let addr = parse(arg)?;
+            Ok(addr..=addr)
+        }
+    }
+}
+
+/// Writes one trace line per instruction to a file, meant for captures of
+/// millions of instructions. Logging can be started and stopped at any time,
+/// restarting appends to the same file
+pub struct TraceLogger {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    line: String,
+    format: TraceFormat,
+    timing: bool,
+    limit: SizeLimit,
+    file_size: u64,
+    filter: TraceFilter,
+    labels: Labels,
+}
+
+impl TraceLogger {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        TraceLogger {
+            path: path.as_ref().to_path_buf(),
+            writer: None,
+            line: String::with_capacity(128),
+            format: TraceFormat::Nestest,
+            timing: true,
+            limit: SizeLimit::Unlimited,
+            file_size: 0,
+            filter: TraceFilter::new(),
+            labels: Labels::new(),
+        }
+    }
+
+    pub fn set_format(&mut self, format: TraceFormat) {
+        self.format = format;
+    }
+
+    /// Whether to append the PPU and CYC columns to the nestest format, on by default
+    pub fn set_timing(&mut self, timing: bool) {
+        self.timing = timing;
+    }
+
+    pub fn set_size_limit(&mut self, limit: SizeLimit) {
+        self.limit = limit;
+    }
+
+    pub fn set_filter(&mut self, filter: TraceFilter) {
+        self.filter = filter;
+    }
+
+    /// Shows label names instead of operand addresses
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Size of the current file, including lines still sitting in the buffer
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        if self.writer.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file_size = file.metadata()?.len();
+            let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+            if let (0, Some(header)) = (self.file_size, self.format.header()) {
+                writeln!(writer, "{}", header)?;
+                self.file_size = header.len() as u64 + 1;
+            }
+            self.writer = Some(writer);
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs the instruction at PC, to be called before it is executed
+    pub fn log(&mut self, cpu: &CPU) -> io::Result<()> {
+        if self.writer.is_none() || !self.filter.matches(cpu) {
+            return Ok(());
+        }
+
+        self.line.clear();
+        let labels = Some(&self.labels);
+        let _ = match self.format {
+            TraceFormat::Nestest if !self.timing => {
+                trace::write_trace_with_labels(&mut self.line, cpu, labels)
+            }
+            format => trace::write_trace_as(&mut self.line, cpu, format, labels),
+        };
+        self.line.push('\n');
+
+        match self.limit {
+            SizeLimit::Cap(max) if self.file_size + self.line.len() as u64 > max => {
+                return self.stop();
+            }
+            SizeLimit::Rotate(max) if self.file_size + self.line.len() as u64 > max => {
+                self.rotate()?;
+            }
+            _ => {}
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(self.line.as_bytes())?;
+            self.file_size += self.line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.stop()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.start()
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    fn test_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // loop: INX; JMP loop
+        cpu.load(vec![0xe8, 0x4c, 0x00, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn run(logger: &mut TraceLogger, cpu: &mut CPU, instructions: usize) {
+        for _ in 0..instructions {
+            logger.log(cpu).unwrap();
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn test_start_stop() {
+        let path = temp_path("trace-start-stop.log");
+        let mut cpu = test_cpu();
+        let mut logger = TraceLogger::new(&path);
+        logger.set_timing(false);
+
+        run(&mut logger, &mut cpu, 2);
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 2);
+        logger.stop().unwrap();
+        run(&mut logger, &mut cpu, 2);
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 1);
+        logger.stop().unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let pcs: Vec<&str> = log.lines().map(|line| &line[0..4]).collect();
+        assert_eq!(pcs, vec!["0600", "0601", "0600"]);
+        assert!(!log.contains("CYC"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_csv_header() {
+        let path = temp_path("trace-csv.log");
+        let mut cpu = test_cpu();
+        let mut logger = TraceLogger::new(&path);
+        logger.set_format(TraceFormat::Csv);
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 2);
+        logger.stop().unwrap();
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 1);
+        logger.stop().unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(Some(lines[0]), TraceFormat::Csv.header());
+        assert!(lines[1].starts_with("0600,E8,\"INX\","));
+        assert_eq!(logger.file_size(), log.len() as u64);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut cpu = test_cpu();
+        // LDA $2002; STA $10; JMP $0600
+        cpu.load(vec![0xad, 0x02, 0x20, 0x85, 0x10, 0x4c, 0x00, 0x06]);
+        let traced_pcs = |filter: &TraceFilter, cpu: &mut CPU| {
+            cpu.program_counter = 0x0600;
+            let mut pcs = vec![];
+            for _ in 0..3 {
+                if filter.matches(cpu) {
+                    pcs.push(cpu.program_counter);
+                }
+                cpu.step();
+            }
+            pcs
+        };
+
+        let mut filter = TraceFilter::new();
+        assert!(filter.is_empty());
+        assert_eq!(traced_pcs(&filter, &mut cpu), vec![0x0600, 0x0603, 0x0605]);
+
+        filter.add_access_range(parse_range("2000-2007").unwrap());
+        filter.add_access_range(parse_range("$0600").unwrap());
+        assert_eq!(traced_pcs(&filter, &mut cpu), vec![0x0600, 0x0605]);
+
+        filter.add_op("sta").unwrap();
+        filter.add_op("$4C").unwrap();
+        assert_eq!(traced_pcs(&filter, &mut cpu), vec![0x0605]);
+
+        let mut filter = TraceFilter::new();
+        filter.add_pc_range(parse_range("0601-0604").unwrap());
+        assert_eq!(traced_pcs(&filter, &mut cpu), vec![0x0603]);
+
+        assert!(parse_range("12-zz").is_err());
+        assert!(filter.add_op("$GG").is_err());
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let path = temp_path("trace-cap.log");
+        let mut cpu = test_cpu();
+        let mut logger = TraceLogger::new(&path);
+        logger.set_timing(false);
+        let line_len = trace::trace(&cpu).len() as u64 + 1;
+        logger.set_size_limit(SizeLimit::Cap(line_len * 3));
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 10);
+        assert!(!logger.is_running());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_path("trace-rotate.log");
+        let mut logger = TraceLogger::new(&path);
+        logger.set_timing(false);
+        logger.set_size_limit(SizeLimit::Rotate(line_len * 3));
+        logger.start().unwrap();
+        run(&mut logger, &mut cpu, 7);
+        logger.stop().unwrap();
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 3);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
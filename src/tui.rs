@@ -0,0 +1,748 @@
+// Terminal debugger.
+//
+// Commands (an empty line repeats the previous one):
+//   s, step          execute one instruction
+//   n, next          step over JSR
+//   o, out           run until the current subroutine or NMI handler returns
+//   c, continue      run until a breakpoint, Esc interrupts
+//   b, break ADDR    toggle a breakpoint, clicking a disassembly line does the same
+//   b ADDR if EXPR   breakpoint that only stops when EXPR is true, for example
+//                    "b NMI if a == 0x3F && scanline > 200" or "b C000 if read(0x2002)"
+//   g, goto ADDR     show disassembly at ADDR, Up/Down and the mouse wheel scroll it
+//   f, follow        make the disassembly follow PC again
+//   m, mem ADDR      show memory starting at ADDR
+//   r, region NAME   memory pane region: cpu, ram, prgram, vram, oam, palette
+//   w, write ADDR XX write a byte into the memory pane region
+//   a, asm ADDR INS  assemble an instruction over the code at ADDR, ROM included
+//   watch EXPR       add an expression to the watch pane, see expr.rs for the syntax
+//   unwatch N        remove the Nth watch
+//   q, quit          leave the debugger and let the game run
+//
+// ADDR is hex, with or without '$', or the name of a loaded label.
+use crate::asm;
+use crate::cdl::CodeDataLog;
+use crate::cpu::{CallKind, CpuFlags, CPU};
+use crate::debugger::{Debugger, StopReason};
+use crate::disasm;
+use crate::expr::Expr;
+use crate::labels::Labels;
+use crate::memview::{MemoryRegion, MemoryViewer};
+use crossterm::event::{
+    self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, MouseButton,
+    MouseEventKind,
+};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::ExecutableCommand;
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::layout::{Constraint, Direction, Layout, Margin, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::io;
+use std::time::Duration;
+
+const INSTRUCTIONS_PER_POLL: usize = 10_000;
+const MEMORY_ROW: usize = 16;
+
+pub struct TuiDebugger {
+    debugger: Debugger,
+    disasm: DisasmView,
+    input: String,
+    last_command: String,
+    memory: MemoryViewer,
+    memory_addr: usize,
+    status: String,
+    running: bool,
+    cdl: Option<CodeDataLog>,
+    labels: Labels,
+    /// Frame the watches were last updated in while running
+    watch_frame: usize,
+}
+
+impl TuiDebugger {
+    pub fn new() -> Self {
+        TuiDebugger {
+            debugger: Debugger::new(),
+            disasm: DisasmView::new(),
+            input: String::new(),
+            last_command: String::new(),
+            memory: MemoryViewer::new(MemoryRegion::Cpu),
+            memory_addr: 0,
+            status: "s: step  n: next  c: continue  b ADDR: breakpoint  m ADDR: memory  q: quit"
+                .to_string(),
+            running: false,
+            cdl: None,
+            labels: Labels::new(),
+            watch_frame: 0,
+        }
+    }
+
+    /// Bytes the log marks as data only are listed as `.db` in the disassembly
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cdl = cdl;
+    }
+
+    pub fn take_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take()
+    }
+
+    /// Names shown in the disassembly and accepted wherever an address is
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    /// Takes over the terminal until the user quits
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        terminal::enable_raw_mode()?;
+        io::stdout().execute(EnterAlternateScreen)?;
+        io::stdout().execute(EnableMouseCapture)?;
+        let result = Terminal::new(CrosstermBackend::new(io::stdout()))
+            .and_then(|mut terminal| self.event_loop(cpu, &mut terminal));
+        terminal::disable_raw_mode()?;
+        io::stdout().execute(DisableMouseCapture)?;
+        io::stdout().execute(LeaveAlternateScreen)?;
+        result
+    }
+
+    fn event_loop<B: Backend>(
+        &mut self,
+        cpu: &mut CPU,
+        terminal: &mut Terminal<B>,
+    ) -> io::Result<()> {
+        loop {
+            terminal.draw(|f| self.draw(f, cpu))?;
+
+            if self.running {
+                if let Some(reason) = self.debugger.resume(cpu, INSTRUCTIONS_PER_POLL) {
+                    self.stopped(cpu, reason);
+                } else if cpu.bus.frame_count() != self.watch_frame {
+                    self.watch_frame = cpu.bus.frame_count();
+                    self.debugger.update_watches(cpu);
+                }
+                if event::poll(Duration::from_millis(0))? {
+                    if let Event::Key(key) = event::read()? {
+                        if key.code == KeyCode::Esc {
+                            self.stopped(cpu, StopReason::Step);
+                            self.status = format!("Interrupted at ${:04X}", cpu.program_counter);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                Event::Mouse(mouse) => {
+                    match mouse.kind {
+                        MouseEventKind::Down(MouseButton::Left) => {
+                            if let Some(addr) = self.disasm.addr_at(mouse.column, mouse.row) {
+                                self.toggle_breakpoint(addr);
+                            }
+                        }
+                        MouseEventKind::ScrollDown => self.disasm.scroll_down(),
+                        MouseEventKind::ScrollUp => self.disasm.scroll_up(),
+                        _ => { /* do nothing */ }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char(c) => self.input.push(c),
+                KeyCode::Down => self.disasm.scroll_down(),
+                KeyCode::Up => self.disasm.scroll_up(),
+                KeyCode::Backspace => {
+                    self.input.pop();
+                }
+                KeyCode::Enter => {
+                    let mut command = std::mem::take(&mut self.input);
+                    if command.trim().is_empty() {
+                        command = self.last_command.clone();
+                    }
+                    if matches!(command.trim(), "q" | "quit") {
+                        return Ok(());
+                    }
+                    if let Err(e) = self.execute(cpu, &command) {
+                        self.status = e;
+                    }
+                    self.last_command = command;
+                }
+                _ => { /* do nothing */ }
+            }
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut CPU, command: &str) -> Result<(), String> {
+        let mut parts = command.split_whitespace();
+        let name = parts.next().unwrap_or("");
+        let arg = parts.next();
+        match name {
+            "s" | "step" => {
+                let reason = self.debugger.step(cpu);
+                self.stopped(cpu, reason);
+            }
+            "n" | "next" => match self.debugger.step_over(cpu, INSTRUCTIONS_PER_POLL * 100) {
+                Some(reason) => self.stopped(cpu, reason),
+                None => self.status = "Subroutine didn't return, still running".to_string(),
+            },
+            "o" | "out" => match self.debugger.step_out(cpu, INSTRUCTIONS_PER_POLL * 100) {
+                Some(reason) => self.stopped(cpu, reason),
+                None => self.status = "Subroutine didn't return, still running".to_string(),
+            },
+            "c" | "continue" => {
+                self.running = true;
+                self.status = "Running, Esc to interrupt".to_string();
+            }
+            "b" | "break" => {
+                let addr = self.parse_addr(cpu, arg)?;
+                let condition: Vec<&str> = parts.skip_while(|&word| word == "if").collect();
+                if condition.is_empty() {
+                    self.toggle_breakpoint(addr);
+                } else {
+                    let condition = condition.join(" ");
+                    self.debugger
+                        .add_conditional_breakpoint(addr, Expr::parse(&condition)?);
+                    self.status = format!("Breakpoint set at ${:04X} if {}", addr, condition);
+                }
+            }
+            "g" | "goto" => self.disasm.goto(self.parse_addr(cpu, arg)?),
+            "f" | "follow" => self.disasm.follow_pc = true,
+            "m" | "mem" => {
+                let addr = self.parse_addr(cpu, arg)? as usize % self.memory.region().size();
+                self.memory_addr = addr & !(MEMORY_ROW - 1);
+            }
+            "r" | "region" => {
+                let name = arg.unwrap_or("");
+                match MemoryRegion::from_name(name) {
+                    Some(region) => {
+                        self.memory.set_region(region);
+                        self.memory.update(cpu);
+                        self.memory_addr = 0;
+                    }
+                    None => return Err(format!("Unknown memory region: {}", name)),
+                }
+            }
+            "w" | "write" => {
+                let addr = self.parse_addr(cpu, arg)?;
+                let value = parts
+                    .next()
+                    .and_then(|v| u8::from_str_radix(v.trim_start_matches('$'), 16).ok())
+                    .ok_or_else(|| "Byte value expected".to_string())?;
+                self.memory.write(cpu, addr as usize, value)?;
+            }
+            "a" | "asm" => {
+                let addr = self.parse_addr(cpu, arg)?;
+                let source: Vec<&str> = parts.collect();
+                let code = asm::assemble(&source.join(" "), addr)?;
+                for (i, byte) in code.iter().enumerate() {
+                    cpu.bus
+                        .patch(addr.wrapping_add(i as u16), *byte)
+                        .map_err(|e| e.to_string())?;
+                }
+                self.memory.update(cpu);
+                self.status = format!("{} bytes written at ${:04X}", code.len(), addr);
+            }
+            "watch" => {
+                let text: Vec<&str> = arg.into_iter().chain(parts).collect();
+                self.debugger.add_watch(&text.join(" "), cpu)?;
+            }
+            "unwatch" => {
+                let index = arg
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| "Watch number expected".to_string())?;
+                if !self.debugger.remove_watch(index) {
+                    return Err(format!("No watch number {}", index));
+                }
+            }
+            "" => {}
+            _ => return Err(format!("Unknown command: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Hex address or label name
+    fn parse_addr(&self, cpu: &CPU, arg: Option<&str>) -> Result<u16, String> {
+        match arg.and_then(|name| self.labels.address_of(&cpu.bus, name)) {
+            Some(addr) => Ok(addr),
+            None => parse_addr(arg),
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, addr: u16) {
+        if self.debugger.add_breakpoint(addr) {
+            self.status = format!("Breakpoint set at ${:04X}", addr);
+        } else {
+            self.debugger.remove_breakpoint(addr);
+            self.status = format!("Breakpoint at ${:04X} removed", addr);
+        }
+    }
+
+    fn stopped(&mut self, cpu: &CPU, reason: StopReason) {
+        self.running = false;
+        self.memory.update(cpu);
+        self.debugger.update_watches(cpu);
+        self.status = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(addr) => format!("Breakpoint at ${:04X}", addr),
+            StopReason::Halted => "CPU halted on BRK".to_string(),
+        };
+    }
+
+    fn draw(&mut self, f: &mut Frame, cpu: &CPU) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(10),
+                Constraint::Length(10),
+                Constraint::Length(3),
+            ])
+            .split(f.size());
+        let top = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(30), Constraint::Length(24)])
+            .split(rows[0]);
+        let side = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(6),
+                Constraint::Length(8),
+                Constraint::Min(3),
+            ])
+            .split(top[1]);
+
+        let cdl = self.cdl.as_ref();
+        self.disasm
+            .draw(f, cpu, &self.debugger, cdl, &self.labels, top[0]);
+        draw_registers(f, cpu, side[0]);
+        draw_call_stack(f, cpu, &self.labels, side[1]);
+        draw_stack(f, cpu, side[2]);
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(30), Constraint::Length(24)])
+            .split(rows[1]);
+        self.draw_memory(f, cpu, bottom[0]);
+        draw_watches(f, &self.debugger, bottom[1]);
+
+        let prompt = Paragraph::new(vec![
+            Line::from(format!("> {}", self.input)),
+            Line::from(Span::styled(
+                self.status.as_str(),
+                Style::default().fg(Color::Yellow),
+            )),
+        ]);
+        f.render_widget(prompt, rows[2]);
+    }
+
+    fn draw_memory(&self, f: &mut Frame, cpu: &CPU, area: Rect) {
+        let size = self.memory.region().size();
+        let mut lines = vec![];
+        for row in 0..area.height.saturating_sub(2) as usize {
+            let start = (self.memory_addr + row * MEMORY_ROW) % size;
+            let mut spans = vec![Span::raw(format!("{:04X} ", start))];
+            for offset in start..start + MEMORY_ROW {
+                let text = format!(" {:02X}", self.memory.read(cpu, offset));
+                if self.memory.is_recently_changed(offset) {
+                    spans.push(Span::styled(text, Style::default().fg(Color::Yellow)));
+                } else {
+                    spans.push(Span::raw(text));
+                }
+            }
+            lines.push(Line::from(spans));
+        }
+        let title = format!("Memory: {}", self.memory.region().name());
+        let block = Block::default().borders(Borders::ALL).title(title);
+        f.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+/// Disassembly pane. While following PC it only scrolls once PC leaves the
+/// visible lines, so loops stay in place
+struct DisasmView {
+    start: u16,
+    follow_pc: bool,
+    area: Rect,
+    addrs: Vec<u16>,
+}
+
+impl DisasmView {
+    fn new() -> Self {
+        DisasmView {
+            start: 0,
+            follow_pc: true,
+            area: Rect::default(),
+            addrs: Vec::new(),
+        }
+    }
+
+    fn goto(&mut self, addr: u16) {
+        self.start = addr;
+        self.follow_pc = false;
+    }
+
+    fn scroll_down(&mut self) {
+        if let Some(&next) = self.addrs.get(1) {
+            self.goto(next);
+        }
+    }
+
+    /// Instructions can't be decoded backwards, so this moves by a single byte
+    fn scroll_up(&mut self) {
+        self.goto(self.start.wrapping_sub(1));
+    }
+
+    /// Address of the instruction drawn at the given terminal cell
+    fn addr_at(&self, column: u16, row: u16) -> Option<u16> {
+        let inner = self.area.inner(&Margin::new(1, 1));
+        if column < inner.x || column >= inner.right() || row < inner.y {
+            return None;
+        }
+        self.addrs.get((row - inner.y) as usize).copied()
+    }
+
+    fn draw(
+        &mut self,
+        f: &mut Frame,
+        cpu: &CPU,
+        debugger: &Debugger,
+        cdl: Option<&CodeDataLog>,
+        labels: &Labels,
+        area: Rect,
+    ) {
+        let visible = area.height.saturating_sub(2) as usize;
+        if self.follow_pc {
+            // keep a couple of lines of look-ahead below PC
+            let lookahead = visible.saturating_sub(2).max(1);
+            if !self
+                .addrs
+                .iter()
+                .take(lookahead)
+                .any(|&a| a == cpu.program_counter)
+            {
+                self.start = cpu.program_counter;
+            }
+        }
+
+        self.area = area;
+        self.addrs.clear();
+        let mut addr = self.start;
+        let mut lines = vec![];
+        for _ in 0..visible {
+            let instruction = match cdl {
+                Some(cdl) if cdl.is_data_only(&cpu.bus, addr) => disasm::data_byte(&cpu.bus, addr),
+                _ => disasm::disassemble_one(&cpu.bus, addr),
+            };
+            let breakpoint = debugger.has_breakpoint(addr);
+            let mut style = Style::default();
+            if addr == cpu.program_counter {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            if breakpoint {
+                style = style.fg(Color::Red);
+            }
+            let marker = if breakpoint { "*" } else { " " };
+            let label = match labels.get(&cpu.bus, addr) {
+                Some(name) => format!("{}: ", name),
+                None => String::new(),
+            };
+            lines.push(Line::from(Span::styled(
+                format!(
+                    "{}{:04X}  {}{}",
+                    marker,
+                    addr,
+                    label,
+                    instruction.to_labeled_string(labels, &cpu.bus)
+                ),
+                style,
+            )));
+            self.addrs.push(addr);
+            addr = instruction.next_addr();
+        }
+
+        let title = if self.follow_pc {
+            "Disassembly".to_string()
+        } else {
+            format!("Disassembly @ ${:04X}", self.start)
+        };
+        let block = Block::default().borders(Borders::ALL).title(title);
+        f.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+impl Default for TuiDebugger {
+    fn default() -> Self {
+        TuiDebugger::new()
+    }
+}
+
+fn draw_registers(f: &mut Frame, cpu: &CPU, area: Rect) {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if cpu.status.bits() & (0x80 >> i) != 0 {
+                c
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    let lines = vec![
+        Line::from(format!(
+            "PC:{:04X}  SP:{:02X}",
+            cpu.program_counter, cpu.stack_pointer
+        )),
+        Line::from(format!(
+            "A:{:02X} X:{:02X} Y:{:02X}",
+            cpu.register_a, cpu.register_x, cpu.register_y
+        )),
+        Line::from(format!("P:{:02X} {}", cpu.status.bits(), flags)),
+        Line::from(if cpu.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            "IRQ disabled"
+        } else {
+            ""
+        }),
+    ];
+    let block = Block::default().borders(Borders::ALL).title("Registers");
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Watch expressions, the ones that changed with the last update highlighted
+fn draw_watches(f: &mut Frame, debugger: &Debugger, area: Rect) {
+    let lines: Vec<Line> = debugger
+        .watches()
+        .iter()
+        .enumerate()
+        .map(|(i, watch)| {
+            let text = if (0..=0xFFFF).contains(&watch.value) {
+                format!("{} {} = ${:02X}", i, watch.text, watch.value)
+            } else {
+                format!("{} {} = {}", i, watch.text, watch.value)
+            };
+            if watch.changed {
+                Line::from(Span::styled(text, Style::default().fg(Color::Yellow)))
+            } else {
+                Line::from(text)
+            }
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).title("Watch");
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Backtrace, innermost call first
+fn draw_call_stack(f: &mut Frame, cpu: &CPU, labels: &Labels, area: Rect) {
+    let lines: Vec<Line> = cpu
+        .call_stack()
+        .iter()
+        .rev()
+        .take(area.height.saturating_sub(2) as usize)
+        .map(|frame| {
+            let name = match labels.get(&cpu.bus, frame.target) {
+                Some(name) => name.to_string(),
+                None => format!("${:04X}", frame.target),
+            };
+            match frame.kind {
+                CallKind::Subroutine => Line::from(format!("{} <- {:04X}", name, frame.call_site)),
+                CallKind::Nmi => Line::from(format!("NMI {} @ {:04X}", name, frame.call_site)),
+                CallKind::Irq => Line::from(format!("IRQ {} @ {:04X}", name, frame.call_site)),
+            }
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).title("Calls");
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn draw_stack(f: &mut Frame, cpu: &CPU, area: Rect) {
+    let lines: Vec<Line> = cpu
+        .stack_entries()
+        .iter()
+        .take(area.height.saturating_sub(2) as usize)
+        .map(|entry| {
+            Line::from(format!(
+                "{:04X}  {:02X}  {}",
+                entry.addr,
+                entry.value,
+                entry.origin.name()
+            ))
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).title("Stack");
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn parse_addr(arg: Option<&str>) -> Result<u16, String> {
+    let arg = arg.ok_or_else(|| "Address expected".to_string())?;
+    u16::from_str_radix(arg.trim_start_matches('$'), 16)
+        .map_err(|_| format!("Invalid address: {}", arg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use ratatui::backend::TestBackend;
+
+    fn test_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+
+        tui.execute(&mut cpu, "s").unwrap();
+        assert_eq!(cpu.program_counter, 0x0602);
+        tui.execute(&mut cpu, "b $0603").unwrap();
+        assert!(tui.debugger.has_breakpoint(0x0603));
+        tui.execute(&mut cpu, "c").unwrap();
+        assert!(tui.running);
+        tui.execute(&mut cpu, "m 1234").unwrap();
+        assert_eq!(tui.memory_addr, 0x1230);
+
+        tui.execute(&mut cpu, "r oam").unwrap();
+        tui.execute(&mut cpu, "w 10 7f").unwrap();
+        assert_eq!(cpu.bus.ppu().oam_data[0x10], 0x7f);
+        assert!(tui.execute(&mut cpu, "r rom").is_err());
+        assert!(tui.execute(&mut cpu, "b").is_err());
+
+        tui.execute(&mut cpu, "b 0602 if x == 5").unwrap();
+        assert!(tui.debugger.condition(0x0602).is_some());
+        assert!(tui.execute(&mut cpu, "b 0602 if x ==").is_err());
+        assert!(tui.execute(&mut cpu, "frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+        let mut labels = Labels::new();
+        labels.add(0x0602, "loop");
+        tui.set_labels(labels);
+
+        tui.execute(&mut cpu, "b loop").unwrap();
+        assert!(tui.debugger.has_breakpoint(0x0602));
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("0602  loop: INX"));
+        assert!(text.contains("0603  JMP loop"));
+    }
+
+    #[test]
+    fn test_call_stack_pane() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // JSR sub; sub: INX
+        cpu.load(vec![0x20, 0x03, 0x06, 0xe8]);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        let mut tui = TuiDebugger::new();
+        let mut labels = Labels::new();
+        labels.add(0x0603, "sub");
+        tui.set_labels(labels);
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("sub <- 0600"));
+        assert!(text.contains("01FC  02  JSR return"));
+    }
+
+    #[test]
+    fn test_assemble_command() {
+        let mut cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+        tui.execute(&mut cpu, "a 0602 LDX #$10").unwrap();
+        assert_eq!(cpu.bus.peek(0x0602), 0xa2);
+        assert_eq!(cpu.bus.peek(0x0603), 0x10);
+        tui.execute(&mut cpu, "asm 8000 JMP $8000").unwrap();
+        assert_eq!(cpu.bus.peek(0x8000), 0x4c);
+        assert!(tui.execute(&mut cpu, "a 0602 FOO").is_err());
+    }
+
+    #[test]
+    fn test_watch_pane() {
+        let mut cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+        tui.execute(&mut cpu, "watch x + 1").unwrap();
+        tui.execute(&mut cpu, "watch word($fd)").unwrap();
+        tui.execute(&mut cpu, "s").unwrap();
+        tui.execute(&mut cpu, "unwatch 1").unwrap();
+        assert!(tui.execute(&mut cpu, "unwatch 1").is_err());
+        assert!(tui.execute(&mut cpu, "watch").is_err());
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("0 x + 1 = $01"));
+        assert!(!text.contains("word"));
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        // JSR sub; NOP; sub: INX; RTS
+        cpu.load(vec![0x20, 0x04, 0x06, 0xea, 0xe8, 0x60]);
+        cpu.program_counter = 0x0600;
+        let mut tui = TuiDebugger::new();
+        tui.execute(&mut cpu, "s").unwrap();
+        tui.execute(&mut cpu, "o").unwrap();
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_draw() {
+        let cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("0600  LDX #$00"));
+        assert!(text.contains("PC:0600"));
+    }
+
+    #[test]
+    fn test_disassembly_view() {
+        let mut cpu = test_cpu();
+        let mut tui = TuiDebugger::new();
+        let mut terminal = Terminal::new(TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        assert_eq!(&tui.disasm.addrs[..3], &[0x0600, 0x0602, 0x0603]);
+
+        // PC moving inside the window doesn't scroll it
+        tui.execute(&mut cpu, "s").unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        assert_eq!(tui.disasm.addrs[0], 0x0600);
+
+        // clicking the second line toggles a breakpoint at its address
+        let area = tui.disasm.area;
+        assert_eq!(tui.disasm.addr_at(area.x + 3, area.y + 2), Some(0x0602));
+        assert_eq!(tui.disasm.addr_at(area.x, area.y + 2), None);
+
+        tui.execute(&mut cpu, "g 0603").unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        assert_eq!(tui.disasm.addrs[0], 0x0603);
+        tui.disasm.scroll_down();
+        assert_eq!(tui.disasm.start, 0x0606);
+
+        tui.execute(&mut cpu, "f").unwrap();
+        terminal.draw(|f| tui.draw(f, &cpu)).unwrap();
+        assert_eq!(tui.disasm.addrs[0], 0x0602);
+    }
+}
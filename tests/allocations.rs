@@ -0,0 +1,119 @@
+// Steady-state emulation must not touch the heap. Buffers are allocated when
+// a subsystem is created or first used and reused from then on, so a frame
+// after the first few allocates nothing, including with the debugging hooks
+// that run per instruction.
+//
+// This needs its own test binary: the allocator counts per thread, and only
+// a global allocator sees every allocation.
+use nes_book_emu::bus::Bus;
+use nes_book_emu::cartridge::Rom;
+use nes_book_emu::cdl::CodeDataLog;
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::harness::FrameInput;
+use nes_book_emu::runahead::RunAhead;
+use nes_book_emu::trace::{self, TraceFormat};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const NESTEST: &[u8] = include_bytes!("../nestest.nes");
+
+fn console() -> CPU {
+    let rom = Rom::new(&NESTEST.to_vec()).unwrap();
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    cpu
+}
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+#[test]
+fn test_frames_dont_allocate() {
+    let mut cpu = console();
+    cpu.bus.set_event_logging(true);
+    cpu.bus.set_ppu_write_logging(true);
+    cpu.bus.set_heatmap(true);
+    for _ in 0..3 {
+        cpu.run_frame();
+    }
+
+    let count = allocations(|| {
+        for _ in 0..10 {
+            cpu.run_frame();
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_per_instruction_hooks_dont_allocate() {
+    let mut cpu = console();
+    // automated mode, see nestest.log
+    cpu.program_counter = 0xC000;
+    let mut cdl = CodeDataLog::for_bus(&cpu.bus);
+    let mut line = String::new();
+    let mut run = |cpu: &mut CPU, steps: usize| {
+        for _ in 0..steps {
+            let formats = [
+                TraceFormat::Nestest,
+                TraceFormat::Mesen,
+                TraceFormat::Csv,
+                TraceFormat::Json,
+            ];
+            for format in formats.iter() {
+                line.clear();
+                trace::write_trace_as(&mut line, cpu, *format, None).unwrap();
+            }
+            cdl.log(cpu);
+            cpu.step();
+        }
+    };
+    run(&mut cpu, 100);
+
+    let count = allocations(|| run(&mut cpu, 5000));
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_run_ahead_doesnt_allocate() {
+    let mut cpu = console();
+    let mut run_ahead = RunAhead::new(2);
+    for _ in 0..3 {
+        run_ahead.run_frame(&mut cpu, FrameInput::default());
+    }
+
+    let count = allocations(|| {
+        for _ in 0..10 {
+            run_ahead.run_frame(&mut cpu, FrameInput::default());
+        }
+    });
+    assert_eq!(count, 0);
+}